@@ -0,0 +1,187 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use shiba_jit::{codegen::x86_64::*, ir::*, reg_alloc};
+use std::collections::BTreeSet;
+
+/// A finalized program along with every register it defines
+struct Program {
+    ctx: Context,
+    registers: Vec<RegisterIndex>,
+    /// How many registers had to be spilled when generating code for it
+    spills: usize,
+}
+
+impl Program {
+    fn new(ctx: Context, registers: Vec<RegisterIndex>) -> Self {
+        let code = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
+        let spills = code.spill_report.spills.len();
+        Program {
+            ctx,
+            registers,
+            spills,
+        }
+    }
+}
+
+fn record(registers: &mut Vec<RegisterIndex>, v: Value) -> Value {
+    if let Value::Register(r) = v {
+        registers.push(r);
+    }
+    v
+}
+
+/// The loop from the `conditional_print` example
+fn tight_loop() -> Program {
+    let mut registers = vec![];
+    let mut ctx = Context::new();
+    let hello_world_const = ctx.add_constant(b"Hello, world\n");
+    let start = ctx.new_basic_block();
+    let start_bb = ctx.build_basic_block(start);
+    let counter = record(&mut registers, start_bb.alloca(PrimitiveValue::U32, 4));
+    start_bb.store(counter, Value::u32(0));
+
+    let mut header_registers = vec![];
+    let mut body_registers = vec![];
+    let exit = ctx.build_while(
+        start,
+        |header| {
+            let loaded = record(&mut header_registers, header.load(counter));
+            record(
+                &mut header_registers,
+                header.subtract(Value::u32(4), loaded),
+            )
+        },
+        |body| {
+            body.push_instruction(IR::PrintConstant {
+                constant_ref: hello_world_const,
+            });
+            let loaded = record(&mut body_registers, body.load(counter));
+            let added = record(&mut body_registers, body.add(loaded, Value::u32(1)));
+            body.store(counter, added);
+        },
+    );
+    registers.extend(header_registers);
+    registers.extend(body_registers);
+    ctx.build_basic_block(exit).ret();
+    ctx.finalize();
+
+    Program::new(ctx, registers)
+}
+
+/// A single block with more values live at once than there are machine
+/// registers to hold them, so some have to be spilled
+fn high_pressure_block() -> Program {
+    let mut registers = vec![];
+    let mut ctx = Context::new();
+    let start = ctx.new_basic_block();
+    let bb = ctx.build_basic_block(start);
+    let slot = record(&mut registers, bb.alloca(PrimitiveValue::U32, 4));
+    bb.store(slot, Value::u32(1));
+    // loaded so the values aren't constants the adds can be folded into
+    let base = record(&mut registers, bb.load(slot));
+    let mut values = vec![];
+    for i in 0..20 {
+        values.push(record(&mut registers, bb.add(base, Value::u32(i))));
+    }
+    // summed from the last, so they're all live until the first is used
+    let mut sum = values[values.len() - 1];
+    for v in values.iter().rev().skip(1) {
+        sum = record(&mut registers, bb.add(sum, *v));
+    }
+    bb.store(slot, sum);
+    bb.ret();
+    ctx.finalize();
+
+    let program = Program::new(ctx, registers);
+    assert!(program.spills > 0, "high_pressure_block doesn't spill");
+    program
+}
+
+/// A long chain of if/else diamonds, each testing a value defined at the start
+fn deep_cfg() -> Program {
+    let mut registers = vec![];
+    let mut ctx = Context::new();
+    let even = ctx.add_constant(b"even\n");
+    let odd = ctx.add_constant(b"odd\n");
+    let mut current = ctx.new_basic_block();
+    let bb = ctx.build_basic_block(current);
+    let slot = record(&mut registers, bb.alloca(PrimitiveValue::U32, 4));
+    bb.store(slot, Value::u32(0));
+    let cond = record(&mut registers, bb.load(slot));
+    for _ in 0..64 {
+        current = ctx.build_if(
+            current,
+            cond,
+            |t| {
+                t.push_instruction(IR::PrintConstant { constant_ref: odd });
+            },
+            |e| {
+                e.push_instruction(IR::PrintConstant { constant_ref: even });
+            },
+        );
+    }
+    ctx.build_basic_block(current).ret();
+    ctx.finalize();
+
+    Program::new(ctx, registers)
+}
+
+fn programs() -> Vec<(&'static str, Program)> {
+    vec![
+        ("tight_loop", tight_loop()),
+        ("high_pressure_block", high_pressure_block()),
+        ("deep_cfg", deep_cfg()),
+    ]
+}
+
+/// Report how many machine registers each program needs, and how many
+/// registers had to be spilled when generating code for it
+fn report_register_usage(programs: &[(&'static str, Program)]) {
+    for (name, program) in programs {
+        let code = generate_code_with_options(&program.ctx, &CodeGenOptions::default()).unwrap();
+        let machine_registers = code.register_map.values().collect::<BTreeSet<_>>();
+        println!(
+            "{}: {} registers allocated to {} machine registers, {} spills",
+            name,
+            code.register_map.len(),
+            machine_registers.len(),
+            program.spills
+        );
+    }
+}
+
+fn bench_allocation(c: &mut Criterion) {
+    let programs = programs();
+    report_register_usage(&programs);
+
+    for (name, program) in &programs {
+        let bbm = program.ctx.basic_blocks();
+        c.bench_function(&format!("compute_graph/{}", name), |b| {
+            b.iter(|| reg_alloc::compute_graph(black_box(bbm)))
+        });
+        // it panics rather than spill, so only programs that fit are timed
+        if program.spills == 0 {
+            c.bench_function(&format!("compute_register_map/{}", name), |b| {
+                b.iter(|| compute_register_map(black_box(bbm), &CodeGenOptions::default()))
+            });
+        }
+        c.bench_function(&format!("liveness_queries/{}", name), |b| {
+            b.iter(|| {
+                let gq = reg_alloc::GraphQuery::new(reg_alloc::compute_graph(bbm), bbm);
+                let mut live = 0;
+                for (block_idx, _) in bbm.iterate_basic_blocks() {
+                    for r in &program.registers {
+                        live += gq.is_live_in(*r, block_idx) as u32;
+                        live += gq.is_live_out(*r, block_idx) as u32;
+                    }
+                }
+                live
+            })
+        });
+        c.bench_function(&format!("generate_code/{}", name), |b| {
+            b.iter(|| generate_code(black_box(&program.ctx)).unwrap())
+        });
+    }
+}
+
+criterion_group!(benches, bench_allocation);
+criterion_main!(benches);
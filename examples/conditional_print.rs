@@ -6,8 +6,8 @@ fn main() {
     // Load constant strings and get a handle to them
     let hello_world_const = ctx.add_constant(b"Hello, world\n");
     let end_const = ctx.add_constant(b"Goodbye, world\n");
-    // create all our basic blocks ahead of time (currently the first basic
-    // block is implicitly the entry-point)
+    // create all our basic blocks ahead of time (the first basic block is the
+    // entry-point unless another is picked with `Context::set_entry`)
     let prog_start = ctx.new_basic_block();
     let loop_inner = ctx.new_basic_block();
     let loop_outer = ctx.new_basic_block();
@@ -17,8 +17,7 @@ fn main() {
     let prog_start_bb = ctx.build_basic_block(prog_start);
     let counter = prog_start_bb.alloca(PrimitiveValue::U32, 4);
     prog_start_bb.store(counter, Value::u32(0));
-    // TODO: look at other APIs; seems like we shouldn't need a jump
-    prog_start_bb.jump(loop_inner);
+    prog_start_bb.then(loop_inner);
 
     // inside of the loop, print out the string, update the counter,
     // and evaluate the condition
@@ -52,16 +51,16 @@ fn main() {
     println!("IR finished!");
 
     println!("Compiling...");
-    let (executable_buffer, offset) = generate_code(&ctx).unwrap();
+    let code = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
     println!("Compilation finished!");
-    let hello_fn: extern "C" fn() = unsafe { std::mem::transmute(executable_buffer.ptr(offset)) };
+    let hello_fn: JitFunction<extern "C" fn()> = unsafe { code.into_function() };
 
-    im_going_to_break_here(hello_fn);
+    im_going_to_break_here(&hello_fn);
 }
 
 // useful for setting breakpoints to walk through the generated code
 #[inline(never)]
 #[no_mangle]
-fn im_going_to_break_here(f: extern "C" fn()) {
-    f()
+fn im_going_to_break_here(f: &JitFunction<extern "C" fn()>) {
+    f.call()
 }
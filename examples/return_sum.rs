@@ -0,0 +1,48 @@
+use shiba_jit::{codegen::x86_64::*, ir::*};
+
+/// Sums the numbers below `n` in a loop and hands the result back to Rust
+fn main() {
+    let n = 10;
+
+    let mut ctx = Context::new();
+    let prog_start = ctx.new_basic_block();
+
+    let prog_start_bb = ctx.build_basic_block(prog_start);
+    let counter = prog_start_bb.alloca(PrimitiveValue::U32, 4);
+    let sum = prog_start_bb.alloca(PrimitiveValue::U32, 4);
+    prog_start_bb.store(counter, Value::u32(0));
+    prog_start_bb.store(sum, Value::u32(0));
+
+    // loop while `n - counter` is non-zero
+    let loop_exit = ctx.build_while(
+        prog_start,
+        |header| {
+            let loaded_counter = header.load(counter);
+            header.subtract(Value::u32(n), loaded_counter)
+        },
+        |body| {
+            let loaded_counter = body.load(counter);
+            let loaded_sum = body.load(sum);
+            let new_sum = body.add(loaded_sum, loaded_counter);
+            body.store(sum, new_sum);
+            let new_counter = body.add(loaded_counter, Value::u32(1));
+            body.store(counter, new_counter);
+        },
+    );
+
+    let loop_exit_bb = ctx.build_basic_block(loop_exit);
+    let result = loop_exit_bb.load(sum);
+    loop_exit_bb.ret_value(result);
+
+    // keep the counter and the sum in registers rather than going through
+    // the stack every iteration
+    ssa::construct(&mut ctx);
+    ctx.finalize();
+
+    let code = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
+    let sum_fn: JitFunction<extern "C" fn() -> u32> = unsafe { code.into_function() };
+
+    let result = sum_fn.call();
+    println!("The sum of 0..{} is {}", n, result);
+    assert_eq!(result, (0..n).sum::<u32>());
+}
@@ -0,0 +1,47 @@
+use shiba_jit::{codegen::x86_64::*, ir::*};
+
+/// The same program as `conditional_print`, but with the loop built by
+/// `Context::build_while`
+fn main() {
+    let mut ctx = Context::new();
+    let hello_world_const = ctx.add_constant(b"Hello, world\n");
+    let end_const = ctx.add_constant(b"Goodbye, world\n");
+    let prog_start = ctx.new_basic_block();
+
+    let prog_start_bb = ctx.build_basic_block(prog_start);
+    let counter = prog_start_bb.alloca(PrimitiveValue::U32, 4);
+    prog_start_bb.store(counter, Value::u32(0));
+
+    // loop while `4 - counter` is non-zero
+    let loop_exit = ctx.build_while(
+        prog_start,
+        |header| {
+            let loaded_counter = header.load(counter);
+            header.subtract(Value::u32(4), loaded_counter)
+        },
+        |body| {
+            body.push_instruction(IR::PrintConstant {
+                constant_ref: hello_world_const,
+            });
+            let loaded_counter = body.load(counter);
+            let add_result = body.add(loaded_counter, Value::u32(1));
+            body.store(counter, add_result);
+        },
+    );
+
+    let loop_exit_bb = ctx.build_basic_block(loop_exit);
+    loop_exit_bb.push_instruction(IR::PrintConstant {
+        constant_ref: end_const,
+    });
+    loop_exit_bb.ret();
+
+    ctx.finalize();
+    println!("IR finished!");
+
+    println!("Compiling...");
+    let code = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
+    println!("Compilation finished!");
+    let hello_fn: JitFunction<extern "C" fn()> = unsafe { code.into_function() };
+
+    hello_fn.call();
+}
@@ -0,0 +1,15 @@
+//! Builds a function from the input and generates code for it, which
+//! shouldn't panic for any IR that's built.  See `program.rs` for how the
+//! input is read.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use shiba_jit::codegen::x86_64::generate_code;
+
+mod program;
+
+fuzz_target!(|data: &[u8]| {
+    let ctx = program::build_context(data);
+    // type errors and the like are reported, not panicked on
+    let _ = generate_code(&ctx);
+});
@@ -0,0 +1,51 @@
+//! Builds a function from the input like the `codegen` target, then runs it
+//! both in the interpreter and as generated code.  They should return the
+//! same value or trap with the same code.
+//!
+//! Programs the interpreter can't finish, like those dividing by zero or
+//! looping forever, aren't run as generated code.  What's printed isn't
+//! compared since generated code prints to stdout.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use shiba_jit::{
+    codegen::x86_64::{generate_code_with_options, set_trap_handler, CodeGenOptions},
+    ir::interp,
+};
+use std::sync::Mutex;
+
+mod program;
+
+static TRAPPED: Mutex<Option<u64>> = Mutex::new(None);
+
+fn record_trap(code: u64) {
+    *TRAPPED.lock().unwrap() = Some(code);
+}
+
+fuzz_target!(|data: &[u8]| {
+    let ctx = program::build_context(data);
+    let expected = match interp::run(&ctx) {
+        Ok(expected) => expected,
+        Err(_) => return,
+    };
+    let code = match generate_code_with_options(&ctx, &CodeGenOptions::default()) {
+        Ok(code) => code,
+        Err(_) => return,
+    };
+    let function = unsafe { code.into_function::<extern "C" fn() -> u64>() };
+
+    set_trap_handler(Some(record_trap));
+    *TRAPPED.lock().unwrap() = None;
+    let returned = function.call();
+    let trapped = TRAPPED.lock().unwrap().take();
+
+    assert_eq!(trapped, expected.trap, "trap codes differ");
+    if let (None, Some(value), Some(_type)) = (trapped, expected.return_value, expected.return_type)
+    {
+        assert_eq!(
+            interp::truncate(returned, _type),
+            value,
+            "return values differ"
+        );
+    }
+});
@@ -0,0 +1,229 @@
+//! Builds a function from fuzzer input, for the fuzz targets to share.
+//!
+//! The input is a list of instructions rather than anything `arbitrary`
+//! derives, so corpus entries can be written by hand.  The first byte picks
+//! the type used for arithmetic and the second how many blocks there are
+//! (1 to 8).  Then each instruction is an opcode byte followed by its
+//! operands, with the blocks filled in order; a terminator ends the block
+//! being filled.  Blocks left over when the input runs out return.
+//!
+//! | opcode | instruction      | operands                         |
+//! |--------|------------------|----------------------------------|
+//! | 0 - 4  | add .. remainder | value, value                     |
+//! | 5      | copy             | value                            |
+//! | 6      | print int        | value                            |
+//! | 7      | print constant   |                                  |
+//! | 8      | store to slot    | value                            |
+//! | 9      | load from slot   |                                  |
+//! | 10     | truncate checked | value, type, trap block          |
+//! | 11     | jump             | block                            |
+//! | 12     | jump if equal    | value, true block, false block   |
+//! | 13     | return           |                                  |
+//! | 14     | return value     | value                            |
+//! | 15     | trap             | code                             |
+//!
+//! A value byte with the top bit clear picks a register defined earlier in
+//! the block or in the entry block; otherwise, or if there are none of the
+//! right type, it's an immediate and the next byte is its value.  Opcodes,
+//! types, and blocks wrap around.
+
+use shiba_jit::ir::*;
+
+/// A few more registers than there are machine registers for, so some of them
+/// get spilled
+const MAX_REGISTERS: usize = 16;
+
+const TYPES: [PrimitiveValue; 8] = [
+    PrimitiveValue::U8,
+    PrimitiveValue::I8,
+    PrimitiveValue::U16,
+    PrimitiveValue::I16,
+    PrimitiveValue::U32,
+    PrimitiveValue::I32,
+    PrimitiveValue::U64,
+    PrimitiveValue::I64,
+];
+
+struct Input<'a> {
+    bytes: std::slice::Iter<'a, u8>,
+}
+
+impl<'a> Input<'a> {
+    fn byte(&mut self) -> Option<u8> {
+        self.bytes.next().copied()
+    }
+
+    fn primitive(&mut self) -> Option<PrimitiveValue> {
+        Some(TYPES[self.byte()? as usize % TYPES.len()])
+    }
+
+    fn block(&mut self, blocks: &[BasicBlockIndex]) -> Option<BasicBlockIndex> {
+        Some(blocks[self.byte()? as usize % blocks.len()])
+    }
+
+    /// A value of `_type`, or of any type if it's `None`
+    fn value(
+        &mut self,
+        registers: &[(Value, PrimitiveValue)],
+        _type: Option<PrimitiveValue>,
+        default_type: PrimitiveValue,
+    ) -> Option<(Value, PrimitiveValue)> {
+        let choice = self.byte()?;
+        let candidates = registers
+            .iter()
+            .filter(|(_, t)| _type.is_none_or(|_type| *t == _type))
+            .collect::<Vec<_>>();
+        if choice & 0x80 == 0 && !candidates.is_empty() {
+            return Some(*candidates[choice as usize % candidates.len()]);
+        }
+        let _type = _type.unwrap_or(default_type);
+        let value = self.byte()? as usize;
+        Some((Value::Immediate { _type, value }, _type))
+    }
+}
+
+/// What's shared by all of the blocks being built
+struct Function {
+    blocks: Vec<BasicBlockIndex>,
+    /// The registers defined so far
+    register_count: usize,
+    /// The stack slot loads and stores go through
+    slot: Value,
+    constant: ConstantIndex,
+    arithmetic_type: PrimitiveValue,
+}
+
+/// Build the instructions for one block, returning `None` when the input runs
+/// out
+fn build_block(
+    input: &mut Input,
+    bb: &mut BasicBlock,
+    function: &mut Function,
+    registers: &mut Vec<(Value, PrimitiveValue)>,
+) -> Option<()> {
+    let blocks = &function.blocks[..];
+    let (slot, constant, arithmetic_type) =
+        (function.slot, function.constant, function.arithmetic_type);
+    loop {
+        let opcode = input.byte()? % 16;
+        let defines = matches!(opcode, 0..=5 | 9 | 10);
+        if defines && function.register_count >= MAX_REGISTERS {
+            continue;
+        }
+        match opcode {
+            0..=4 => {
+                let (v1, _type) = input.value(registers, None, arithmetic_type)?;
+                let (v2, _) = input.value(registers, Some(_type), arithmetic_type)?;
+                let dest = match opcode {
+                    0 => bb.add(v1, v2),
+                    1 => bb.subtract(v1, v2),
+                    2 => bb.multiply(v1, v2),
+                    3 => bb.divide(v1, v2),
+                    _ => bb.remainder(v1, v2),
+                };
+                registers.push((dest, _type));
+            }
+            5 => {
+                let (src, _type) = input.value(registers, None, arithmetic_type)?;
+                registers.push((bb.copy(src), _type));
+            }
+            6 => {
+                let (src, _type) = input.value(registers, None, arithmetic_type)?;
+                bb.print_int(src, _type);
+            }
+            7 => {
+                bb.push_instruction(IR::PrintConstant {
+                    constant_ref: constant,
+                });
+            }
+            8 => {
+                let (src, _) =
+                    input.value(registers, Some(PrimitiveValue::U32), arithmetic_type)?;
+                bb.store(slot, src);
+            }
+            9 => registers.push((bb.load(slot), PrimitiveValue::U32)),
+            10 => {
+                let (src, _) = input.value(registers, None, arithmetic_type)?;
+                let dest_type = input.primitive()?;
+                let trap = input.block(blocks)?;
+                registers.push((bb.truncate_checked(src, dest_type, trap), dest_type));
+            }
+            11 => {
+                bb.jump(input.block(blocks)?);
+                return Some(());
+            }
+            12 => {
+                let (src, _) = input.value(registers, None, arithmetic_type)?;
+                let true_target = input.block(blocks)?;
+                let false_target = input.block(blocks)?;
+                // both the same is a plain jump, and conditions known ahead
+                // of time aren't supported
+                match src {
+                    _ if true_target == false_target => bb.jump(true_target),
+                    Value::Immediate { value: 0, .. } => bb.jump(true_target),
+                    Value::Immediate { .. } => bb.jump(false_target),
+                    Value::Register(_) => bb.jump_if_equal(src, true_target, false_target),
+                }
+                return Some(());
+            }
+            13 => {
+                bb.ret();
+                return Some(());
+            }
+            14 => {
+                let (value, _) = input.value(registers, None, arithmetic_type)?;
+                bb.ret_value(value);
+                return Some(());
+            }
+            _ => {
+                bb.trap(input.byte()? as u64);
+                return Some(());
+            }
+        }
+        if defines {
+            function.register_count += 1;
+        }
+    }
+}
+
+pub fn build_context(data: &[u8]) -> Context {
+    let mut input = Input { bytes: data.iter() };
+    let arithmetic_type = input.primitive().unwrap_or(PrimitiveValue::U32);
+    let block_count = input.byte().map_or(1, |b| 1 + b as usize % 8);
+
+    let mut ctx = Context::new();
+    let constant = ctx.add_constant(b"fuzz\n");
+    let blocks = (0..block_count)
+        .map(|_| ctx.new_basic_block())
+        .collect::<Vec<_>>();
+    let slot = ctx
+        .build_basic_block(blocks[0])
+        .alloca(PrimitiveValue::U32, 4);
+    let mut function = Function {
+        blocks: blocks.clone(),
+        register_count: 1,
+        slot,
+        constant,
+        arithmetic_type,
+    };
+
+    let mut entry_registers = vec![];
+    let mut out_of_input = false;
+    for (i, &block) in blocks.iter().enumerate() {
+        // everything defined in the entry block dominates the other blocks
+        let mut registers = entry_registers.clone();
+        let bb = ctx.build_basic_block(block);
+        if !out_of_input {
+            out_of_input = build_block(&mut input, bb, &mut function, &mut registers).is_none();
+        }
+        if !bb.is_terminated() {
+            bb.ret();
+        }
+        if i == 0 {
+            entry_registers = registers;
+        }
+    }
+
+    ctx.finalize();
+    ctx
+}
@@ -0,0 +1,127 @@
+//! Memoization of generated code.
+
+use super::x86_64::{generate_code_with_options, CodeGenError, CodeGenOptions, GeneratedCode};
+use crate::ir::Context;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+/// Records everything fed to it so that it can be used as an exact key.
+///
+/// Comparing the full bytes rather than a digest means two different
+/// programs can never be confused for each other.
+#[derive(Debug, Default)]
+struct KeyRecorder {
+    bytes: Vec<u8>,
+}
+
+impl Hasher for KeyRecorder {
+    fn write(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    // only the recorded bytes are used
+    fn finish(&self) -> u64 {
+        0
+    }
+}
+
+fn cache_key(ctx: &Context) -> Vec<u8> {
+    let mut recorder = KeyRecorder::default();
+    ctx.hash(&mut recorder);
+    recorder.bytes
+}
+
+/// Caches the code generated for a [`Context`], keyed by its constants and
+/// instruction stream.
+///
+/// Registers are keyed by their index, so a program that's rebuilt from
+/// scratch will only hit the cache if it got the same register indices.
+#[derive(Debug, Default)]
+pub struct CodeCache {
+    options: CodeGenOptions,
+    entries: HashMap<Vec<u8>, GeneratedCode>,
+    compilations: usize,
+}
+
+impl CodeCache {
+    pub fn new(options: CodeGenOptions) -> Self {
+        Self {
+            options,
+            entries: HashMap::new(),
+            compilations: 0,
+        }
+    }
+
+    /// Get the code for a finalized `Context`, generating it if it hasn't been
+    /// seen before
+    pub fn get_or_compile(&mut self, ctx: &Context) -> Result<&GeneratedCode, CodeGenError> {
+        let key = cache_key(ctx);
+        if !self.entries.contains_key(&key) {
+            let generated = generate_code_with_options(ctx, &self.options)?;
+            self.compilations += 1;
+            self.entries.insert(key.clone(), generated);
+        }
+        Ok(&self.entries[&key])
+    }
+
+    /// How many times code has actually been generated
+    pub fn compilations(&self) -> usize {
+        self.compilations
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Drop all of the cached code
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::*;
+
+    fn returns(value: u64) -> Context {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        ctx.build_basic_block(entry).ret_value(Value::u64(value));
+        ctx.finalize();
+        ctx
+    }
+
+    #[test]
+    fn same_context_is_compiled_once() {
+        let mut cache = CodeCache::default();
+        let ctx = returns(1);
+        let first = cache.get_or_compile(&ctx).unwrap().code().as_ptr();
+        let second = cache.get_or_compile(&ctx).unwrap().code().as_ptr();
+        assert_eq!(first, second);
+        assert_eq!(cache.compilations(), 1);
+
+        // built again from scratch, it's the same program
+        cache.get_or_compile(&returns(1)).unwrap();
+        assert_eq!(cache.compilations(), 1);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn different_contexts_are_compiled_separately() {
+        let mut cache = CodeCache::default();
+        cache.get_or_compile(&returns(1)).unwrap();
+        cache.get_or_compile(&returns(2)).unwrap();
+        assert_eq!(cache.compilations(), 2);
+        assert_eq!(cache.len(), 2);
+
+        cache.clear();
+        assert!(cache.is_empty());
+        cache.get_or_compile(&returns(1)).unwrap();
+        assert_eq!(cache.compilations(), 3);
+    }
+}
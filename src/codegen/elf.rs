@@ -0,0 +1,311 @@
+//! Writing generated code out as an ELF64 relocatable object file, so it can
+//! be linked ahead of time with the rest of a program.
+//!
+//! The object has the function in `.text`, the constants in `.rodata`, and a
+//! global symbol for the function.  Host functions called by the code are
+//! left as undefined symbols (`shiba_jit_guest_print` and friends), which the
+//! program it's linked into has to provide; linking against this crate does.
+//! Calls to them go through the PLT, so the object can be linked into a
+//! position independent executable.
+//!
+//! The layout follows the System V ABI, "Object Files" chapter, and its
+//! x86_64 supplement for the relocation types.
+
+use super::x86_64::{
+    generate_code_to_link, CodeGenError, CodeGenErrorReason, CodeGenOptions, RelocationTarget,
+};
+use crate::ir::*;
+use std::collections::*;
+
+const ET_REL: u16 = 1;
+const EM_X86_64: u16 = 62;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+
+/// Section header indices
+const TEXT: u16 = 1;
+const RODATA: u16 = 2;
+const SYMTAB: u32 = 4;
+const STRTAB: u32 = 5;
+const SHSTRTAB: u16 = 6;
+
+/// The symbol for the start of `.rodata`
+const RODATA_SYMBOL: u64 = 2;
+
+/// Generate code for `ctx` and wrap it in a relocatable object file defining
+/// a function called `symbol_name`.
+///
+/// Code that uses linear memory or bounds checks refers to addresses in this
+/// process and is rejected with [`CodeGenErrorReason::NotRelocatable`].
+pub fn emit_elf(ctx: &Context, symbol_name: &str) -> Result<Vec<u8>, CodeGenError> {
+    if ctx.linear_memory.is_some() || ctx.memory_bounds.is_some() {
+        return Err(CodeGenError {
+            location: 0,
+            reason: CodeGenErrorReason::NotRelocatable("linear memory is at a fixed address"),
+        });
+    }
+    let options = CodeGenOptions {
+        position_independent: true,
+        ..CodeGenOptions::default()
+    };
+    let generated = generate_code_to_link(ctx, &options)?;
+    let start = generated.start.0;
+    let mut text = generated.code().to_vec();
+
+    // the constants are laid out again in .rodata, so the code can be moved
+    // independently of them
+    let mut rodata = vec![];
+    let mut constant_offsets = BTreeMap::new();
+    for (i, constant) in ctx.constants.iter().enumerate() {
+        constant_offsets.insert(ConstantIndex::new(i as _), rodata.len());
+        rodata.extend_from_slice(constant);
+    }
+
+    // =====================================================
+    // symbols and relocations
+    let mut strtab = vec![0];
+    let mut symtab = vec![0; 24];
+    write_symbol(&mut symtab, 0, STB_LOCAL, STT_SECTION, TEXT, 0);
+    write_symbol(&mut symtab, 0, STB_LOCAL, STT_SECTION, RODATA, 0);
+    let first_global = (symtab.len() / 24) as u32;
+    let entry_name = push_str(&mut strtab, symbol_name);
+    write_symbol(
+        &mut symtab,
+        entry_name,
+        STB_GLOBAL,
+        STT_FUNC,
+        TEXT,
+        text.len() as u64,
+    );
+    let mut host_symbols: BTreeMap<&'static str, u64> = BTreeMap::new();
+
+    let mut rela = vec![];
+    for relocation in &generated.relocations {
+        let offset = relocation.offset.0 - start;
+        let (symbol, kind, addend, width) = match relocation.target {
+            RelocationTarget::Constant(constant) => {
+                let target = constant_offsets[&constant] as i64;
+                // the displacement is from the end of the field
+                (RODATA_SYMBOL, R_X86_64_PC32, target - 4, 4)
+            }
+            RelocationTarget::HostFunctionCall(name) => {
+                let symbol = *host_symbols.entry(name).or_insert_with(|| {
+                    let name = push_str(&mut strtab, name);
+                    write_symbol(&mut symtab, name, STB_GLOBAL, STT_NOTYPE, 0, 0);
+                    (symtab.len() / 24 - 1) as u64
+                });
+                (symbol, R_X86_64_PLT32, -4, 4)
+            }
+            // not made with `position_independent`
+            RelocationTarget::HostFunction(_) => unreachable!("absolute host call in an object"),
+        };
+        // the linker fills the field in; don't leave this process's addresses
+        for b in &mut text[offset..offset + width] {
+            *b = 0;
+        }
+        rela.extend_from_slice(&(offset as u64).to_le_bytes());
+        rela.extend_from_slice(&((symbol << 32) | kind as u64).to_le_bytes());
+        rela.extend_from_slice(&addend.to_le_bytes());
+    }
+
+    let mut shstrtab = vec![0];
+    let names = [
+        push_str(&mut shstrtab, ".text"),
+        push_str(&mut shstrtab, ".rodata"),
+        push_str(&mut shstrtab, ".rela.text"),
+        push_str(&mut shstrtab, ".symtab"),
+        push_str(&mut shstrtab, ".strtab"),
+        push_str(&mut shstrtab, ".shstrtab"),
+        push_str(&mut shstrtab, ".note.GNU-stack"),
+    ];
+
+    // =====================================================
+    // lay out the file
+    let mut out = vec![0; 64];
+    let mut sections = vec![0; 64];
+    let mut add_section = |out: &mut Vec<u8>, header: SectionHeader, data: &[u8]| {
+        pad_to(out, header.align);
+        let offset = out.len() as u64;
+        out.extend_from_slice(data);
+        header.write(&mut sections, offset, data.len() as u64);
+    };
+    add_section(
+        &mut out,
+        SectionHeader {
+            name: names[0],
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC | SHF_EXECINSTR,
+            align: 16,
+            ..SectionHeader::default()
+        },
+        &text,
+    );
+    add_section(
+        &mut out,
+        SectionHeader {
+            name: names[1],
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC,
+            align: 1,
+            ..SectionHeader::default()
+        },
+        &rodata,
+    );
+    add_section(
+        &mut out,
+        SectionHeader {
+            name: names[2],
+            kind: SHT_RELA,
+            flags: SHF_INFO_LINK,
+            link: SYMTAB,
+            info: TEXT as u32,
+            align: 8,
+            entry_size: 24,
+        },
+        &rela,
+    );
+    add_section(
+        &mut out,
+        SectionHeader {
+            name: names[3],
+            kind: SHT_SYMTAB,
+            link: STRTAB,
+            info: first_global,
+            align: 8,
+            entry_size: 24,
+            ..SectionHeader::default()
+        },
+        &symtab,
+    );
+    add_section(
+        &mut out,
+        SectionHeader {
+            name: names[4],
+            kind: SHT_STRTAB,
+            align: 1,
+            ..SectionHeader::default()
+        },
+        &strtab,
+    );
+    add_section(
+        &mut out,
+        SectionHeader {
+            name: names[5],
+            kind: SHT_STRTAB,
+            align: 1,
+            ..SectionHeader::default()
+        },
+        &shstrtab,
+    );
+    // marks the stack as not needing to be executable
+    add_section(
+        &mut out,
+        SectionHeader {
+            name: names[6],
+            kind: SHT_PROGBITS,
+            align: 1,
+            ..SectionHeader::default()
+        },
+        &[],
+    );
+
+    pad_to(&mut out, 8);
+    let section_headers = out.len() as u64;
+    out.extend_from_slice(&sections);
+    write_file_header(&mut out, section_headers, (sections.len() / 64) as u16);
+    Ok(out)
+}
+
+#[derive(Default)]
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entry_size: u64,
+}
+
+impl SectionHeader {
+    fn write(&self, out: &mut Vec<u8>, offset: u64, size: u64) {
+        out.extend_from_slice(&self.name.to_le_bytes());
+        out.extend_from_slice(&self.kind.to_le_bytes());
+        out.extend_from_slice(&self.flags.to_le_bytes());
+        // address
+        out.extend_from_slice(&0u64.to_le_bytes());
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&size.to_le_bytes());
+        out.extend_from_slice(&self.link.to_le_bytes());
+        out.extend_from_slice(&self.info.to_le_bytes());
+        out.extend_from_slice(&self.align.to_le_bytes());
+        out.extend_from_slice(&self.entry_size.to_le_bytes());
+    }
+}
+
+/// Fill in the 64 byte header reserved at the start of `out`
+fn write_file_header(out: &mut [u8], section_headers: u64, section_count: u16) {
+    let mut header = vec![];
+    header.extend_from_slice(b"\x7fELF");
+    // 64 bit, little endian, version 1, System V ABI
+    header.extend_from_slice(&[2, 1, 1, 0]);
+    header.resize(16, 0);
+    header.extend_from_slice(&ET_REL.to_le_bytes());
+    header.extend_from_slice(&EM_X86_64.to_le_bytes());
+    header.extend_from_slice(&1u32.to_le_bytes());
+    // entry point and program headers
+    header.extend_from_slice(&0u64.to_le_bytes());
+    header.extend_from_slice(&0u64.to_le_bytes());
+    header.extend_from_slice(&section_headers.to_le_bytes());
+    // flags
+    header.extend_from_slice(&0u32.to_le_bytes());
+    // header size, program header entry size and count
+    header.extend_from_slice(&64u16.to_le_bytes());
+    header.extend_from_slice(&0u16.to_le_bytes());
+    header.extend_from_slice(&0u16.to_le_bytes());
+    header.extend_from_slice(&64u16.to_le_bytes());
+    header.extend_from_slice(&section_count.to_le_bytes());
+    header.extend_from_slice(&SHSTRTAB.to_le_bytes());
+    out[..64].copy_from_slice(&header);
+}
+
+fn write_symbol(out: &mut Vec<u8>, name: u32, bind: u8, kind: u8, section: u16, size: u64) {
+    out.extend_from_slice(&name.to_le_bytes());
+    out.push((bind << 4) | kind);
+    // default visibility
+    out.push(0);
+    out.extend_from_slice(&section.to_le_bytes());
+    // everything is at the start of its section
+    out.extend_from_slice(&0u64.to_le_bytes());
+    out.extend_from_slice(&size.to_le_bytes());
+}
+
+/// Add a null terminated string to a string table, returning its offset
+fn push_str(table: &mut Vec<u8>, s: &str) -> u32 {
+    let offset = table.len() as u32;
+    table.extend_from_slice(s.as_bytes());
+    table.push(0);
+    offset
+}
+
+fn pad_to(out: &mut Vec<u8>, align: u64) {
+    let align = align as usize;
+    let padding = (align - out.len() % align) % align;
+    out.resize(out.len() + padding, 0);
+}
@@ -0,0 +1,72 @@
+//! Detection of optional CPU features that lowerings may take advantage of.
+
+/// Optional instruction set extensions.  So far lowerings only use `bmi2`,
+/// for shifts by a register.
+///
+/// `Default` is the baseline x86_64 feature set, i.e. everything disabled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct CpuFeatures {
+    pub cmov: bool,
+    pub sse2: bool,
+    pub sse4_1: bool,
+    pub popcnt: bool,
+    pub lzcnt: bool,
+    pub bmi1: bool,
+    pub bmi2: bool,
+    pub avx: bool,
+    pub avx2: bool,
+}
+
+lazy_static! {
+    static ref HOST_FEATURES: CpuFeatures = detect();
+}
+
+/// The features supported by the CPU we're running on.
+///
+/// This is only probed once.
+pub fn cpu_features() -> CpuFeatures {
+    *HOST_FEATURES
+}
+
+#[cfg(target_arch = "x86_64")]
+fn detect() -> CpuFeatures {
+    // `is_x86_feature_detected` doesn't know about cmov, so check its cpuid bit directly
+    #[allow(unused_unsafe)]
+    let leaf1 = unsafe { std::arch::x86_64::__cpuid(1) };
+    CpuFeatures {
+        cmov: leaf1.edx & (1 << 15) != 0,
+        sse2: is_x86_feature_detected!("sse2"),
+        sse4_1: is_x86_feature_detected!("sse4.1"),
+        popcnt: is_x86_feature_detected!("popcnt"),
+        lzcnt: is_x86_feature_detected!("lzcnt"),
+        bmi1: is_x86_feature_detected!("bmi1"),
+        bmi2: is_x86_feature_detected!("bmi2"),
+        avx: is_x86_feature_detected!("avx"),
+        avx2: is_x86_feature_detected!("avx2"),
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn detect() -> CpuFeatures {
+    CpuFeatures::default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn host_features_are_consistent() {
+        let features = cpu_features();
+        // part of the x86_64 baseline
+        assert!(features.cmov);
+        assert!(features.sse2);
+        // each of these needs the one before it
+        assert!(!features.avx2 || features.avx);
+        assert!(!features.avx || features.sse4_1);
+        assert!(!features.sse4_1 || features.sse2);
+        // probed once
+        assert_eq!(features, cpu_features());
+    }
+}
@@ -1 +1,8 @@
+pub mod cache;
+pub mod elf;
+mod features;
+pub mod unwind;
 pub mod x86_64;
+
+pub use elf::emit_elf;
+pub use features::{cpu_features, CpuFeatures};
@@ -0,0 +1,217 @@
+//! Unwind information (`.eh_frame` / CFI) for generated x86_64 code.
+//!
+//! Without this, the platform unwinder can't walk through a JITted frame, so
+//! backtraces taken from host functions called by generated code stop at the
+//! JIT boundary.
+//!
+//! The layout follows the System V x86_64 psABI; see the DWARF 4 standard,
+//! section 6.4 "Call Frame Information" for the encoding.
+
+/// DWARF register numbers for x86_64
+const DW_REG_RBX: u8 = 3;
+const DW_REG_RBP: u8 = 6;
+const DW_REG_RSP: u8 = 7;
+const DW_REG_RA: u8 = 16;
+
+const DW_CFA_NOP: u8 = 0x00;
+const DW_CFA_ADVANCE_LOC: u8 = 0x40;
+const DW_CFA_ADVANCE_LOC1: u8 = 0x02;
+const DW_CFA_ADVANCE_LOC2: u8 = 0x03;
+const DW_CFA_ADVANCE_LOC4: u8 = 0x04;
+const DW_CFA_OFFSET: u8 = 0x80;
+const DW_CFA_DEF_CFA: u8 = 0x0c;
+const DW_CFA_DEF_CFA_REGISTER: u8 = 0x0d;
+const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0e;
+
+/// Pointer encoding for the FDE's `pc_begin`: a plain 8 byte address
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+
+/// Where, relative to the start of the function, each step of the prologue ends.
+///
+/// The prologue this describes is:
+/// ```text
+/// push rbp
+/// mov rbp, rsp
+/// sub rsp, frame_size
+/// push rbx
+/// push r12 ; and whichever other callee-saved registers are in `saved`
+/// ...
+/// ```
+#[derive(Debug, Clone)]
+pub struct PrologueLayout {
+    pub push_rbp: usize,
+    pub set_rbp: usize,
+    pub push_rbx: usize,
+    pub frame_size: usize,
+    pub push_saved: usize,
+    /// The DWARF numbers of the registers pushed after rbx, in order
+    pub saved: Vec<u8>,
+}
+
+/// Build an `.eh_frame` section (one CIE, one FDE, and a zero terminator)
+/// describing a function at `code_start` of length `code_len`.
+pub fn build_eh_frame(code_start: *const u8, code_len: usize, layout: PrologueLayout) -> Vec<u8> {
+    let mut out = vec![];
+
+    // =====================================================
+    // CIE
+    let cie_start = out.len();
+    let mut cie = vec![];
+    // CIE id
+    cie.extend_from_slice(&0u32.to_le_bytes());
+    // version
+    cie.push(1);
+    // augmentation string
+    cie.extend_from_slice(b"zR\0");
+    // code alignment factor
+    write_uleb128(&mut cie, 1);
+    // data alignment factor
+    write_sleb128(&mut cie, -8);
+    // return address register
+    write_uleb128(&mut cie, DW_REG_RA as u64);
+    // augmentation data: just the FDE pointer encoding
+    write_uleb128(&mut cie, 1);
+    cie.push(DW_EH_PE_ABSPTR);
+    // on entry the CFA is rsp + 8 and the return address is at CFA - 8
+    cie.push(DW_CFA_DEF_CFA);
+    write_uleb128(&mut cie, DW_REG_RSP as u64);
+    write_uleb128(&mut cie, 8);
+    cie.push(DW_CFA_OFFSET | DW_REG_RA);
+    write_uleb128(&mut cie, 1);
+    push_entry(&mut out, &cie);
+
+    // =====================================================
+    // FDE
+    let mut fde = vec![];
+    // offset back to the CIE, relative to this field
+    let cie_pointer = (out.len() + 4 - cie_start) as u32;
+    fde.extend_from_slice(&cie_pointer.to_le_bytes());
+    fde.extend_from_slice(&(code_start as u64).to_le_bytes());
+    fde.extend_from_slice(&(code_len as u64).to_le_bytes());
+    // no augmentation data
+    write_uleb128(&mut fde, 0);
+
+    // push rbp
+    write_advance_loc(&mut fde, layout.push_rbp);
+    fde.push(DW_CFA_DEF_CFA_OFFSET);
+    write_uleb128(&mut fde, 16);
+    fde.push(DW_CFA_OFFSET | DW_REG_RBP);
+    write_uleb128(&mut fde, 2);
+    // mov rbp, rsp
+    write_advance_loc(&mut fde, layout.set_rbp - layout.push_rbp);
+    fde.push(DW_CFA_DEF_CFA_REGISTER);
+    write_uleb128(&mut fde, DW_REG_RBP as u64);
+    // sub rsp, frame_size; push rbx
+    // rdi and rsi are also saved, but they're caller-saved so the unwinder
+    // doesn't need to know about them
+    write_advance_loc(&mut fde, layout.push_rbx - layout.set_rbp);
+    fde.push(DW_CFA_OFFSET | DW_REG_RBX);
+    write_uleb128(&mut fde, (24 + layout.frame_size as u64) / 8);
+    // the rest of the callee-saved registers are pushed at once, so they're
+    // all described as saved once the last one is
+    if !layout.saved.is_empty() {
+        write_advance_loc(&mut fde, layout.push_saved - layout.push_rbx);
+        for (k, reg) in layout.saved.iter().enumerate() {
+            fde.push(DW_CFA_OFFSET | reg);
+            write_uleb128(&mut fde, (32 + layout.frame_size as u64) / 8 + k as u64);
+        }
+    }
+    // NOTE: the epilogue isn't described, so unwinding from the final `ret`
+    // of a function will be off by a frame.
+    push_entry(&mut out, &fde);
+
+    // zero terminator, expected by libgcc's `__register_frame`
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out
+}
+
+/// Write a length prefixed CIE or FDE, padded to pointer alignment with nops
+fn push_entry(out: &mut Vec<u8>, entry: &[u8]) {
+    let padding = (8 - (entry.len() + 4) % 8) % 8;
+    let len = (entry.len() + padding) as u32;
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(entry);
+    out.resize(out.len() + padding, DW_CFA_NOP);
+}
+
+fn write_advance_loc(out: &mut Vec<u8>, delta: usize) {
+    if delta < 0x40 {
+        out.push(DW_CFA_ADVANCE_LOC | delta as u8);
+    } else if delta <= u8::MAX as usize {
+        out.push(DW_CFA_ADVANCE_LOC1);
+        out.push(delta as u8);
+    } else if delta <= u16::MAX as usize {
+        out.push(DW_CFA_ADVANCE_LOC2);
+        out.extend_from_slice(&(delta as u16).to_le_bytes());
+    } else {
+        out.push(DW_CFA_ADVANCE_LOC4);
+        out.extend_from_slice(&(delta as u32).to_le_bytes());
+    }
+}
+
+fn write_uleb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_sleb128(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+extern "C" {
+    fn __register_frame(begin: *const u8);
+    fn __deregister_frame(begin: *const u8);
+}
+
+/// An `.eh_frame` that's been handed to the platform unwinder.
+///
+/// The frame is deregistered when this is dropped, so it must not outlive the
+/// code it describes.
+#[derive(Debug)]
+pub struct UnwindRegistration {
+    eh_frame: Vec<u8>,
+}
+
+impl UnwindRegistration {
+    pub fn new(eh_frame: Vec<u8>) -> Self {
+        unsafe { __register_frame(Self::registration_ptr(&eh_frame)) };
+        Self { eh_frame }
+    }
+
+    pub fn eh_frame(&self) -> &[u8] {
+        &self.eh_frame
+    }
+
+    // libgcc takes the whole section; the LLVM libunwind used on macOS takes a single FDE
+    #[cfg(target_os = "macos")]
+    fn registration_ptr(eh_frame: &[u8]) -> *const u8 {
+        let cie_len = u32::from_le_bytes([eh_frame[0], eh_frame[1], eh_frame[2], eh_frame[3]]);
+        eh_frame[cie_len as usize + 4..].as_ptr()
+    }
+    #[cfg(not(target_os = "macos"))]
+    fn registration_ptr(eh_frame: &[u8]) -> *const u8 {
+        eh_frame.as_ptr()
+    }
+}
+
+impl Drop for UnwindRegistration {
+    fn drop(&mut self) {
+        unsafe { __deregister_frame(Self::registration_ptr(&self.eh_frame)) };
+    }
+}
@@ -1,6 +1,11 @@
+use super::unwind::{self, PrologueLayout, UnwindRegistration};
+use super::{cpu_features, CpuFeatures};
 use crate::ir::*;
 use crate::reg_alloc;
+use crate::schedule;
+use smallvec::SmallVec;
 use std::collections::*;
+use std::sync::{Arc, Mutex};
 
 use dynasmrt::x64::Assembler;
 use dynasmrt::{mmap::ExecutableBuffer, AssemblyOffset, DynamicLabel, DynasmApi, DynasmLabelApi};
@@ -11,23 +16,454 @@ struct Register {
     value: RegisterValueLocation,
 }
 
+/// Where the value of a register comes from
 #[derive(Debug, Clone)]
 pub enum RegisterValueLocation {
+    /// It's always this, truncated to the register's type
     Constant(usize),
-    DependsOn(Vec<usize>),
+    /// It's computed from these registers
+    DependsOn(Vec<RegisterIndex>),
+    /// It's loaded from the stack slot this far below rbp, and stays what's
+    /// there until the slot is written to
     Memory(usize),
 }
 
+/// Find what's known about the value of every register in the program
+fn compute_register_values(
+    bbm: &BasicBlockManager,
+    types: &BTreeMap<RegisterIndex, PrimitiveValue>,
+) -> BTreeMap<RegisterIndex, Register> {
+    let instructions = || {
+        bbm.iterate_basic_blocks()
+            .flat_map(|(_, bb)| bb.iterate_instructions())
+    };
+    let register = |dest: &RegisterIndex, value| Register {
+        _type: types.get(dest).copied().unwrap_or(PrimitiveValue::U64),
+        value,
+    };
+    let slots = stack_slot_offsets(bbm);
+    // copies can come before what they copy in block order, so keep going
+    // until they've all been resolved
+    let mut values: BTreeMap<RegisterIndex, Register> = BTreeMap::new();
+    loop {
+        let mut changed = false;
+        for inst in instructions() {
+            for dest in inst.get_defined_registers() {
+                if values.contains_key(dest) {
+                    continue;
+                }
+                let value = match *inst {
+                    IR::Copy {
+                        src: Value::Immediate { _type, value },
+                        ..
+                    } => RegisterValueLocation::Constant(truncate(value, _type)),
+                    IR::Copy {
+                        src: Value::Register(r),
+                        ..
+                    } => match values.get(&r) {
+                        Some(Register {
+                            value: RegisterValueLocation::Constant(c),
+                            ..
+                        }) => RegisterValueLocation::Constant(*c),
+                        Some(_) => RegisterValueLocation::DependsOn(vec![r]),
+                        None => continue,
+                    },
+                    // the value isn't extended, so it's exactly what's in
+                    // the slot
+                    IR::Load {
+                        src_register: Value::Register(slot),
+                        extend_to: None,
+                        ..
+                    } if slots.contains_key(&slot) => {
+                        RegisterValueLocation::Memory(slots[&slot] as usize)
+                    }
+                    _ => RegisterValueLocation::DependsOn(
+                        inst.get_used_registers().into_iter().copied().collect(),
+                    ),
+                };
+                values.insert(*dest, register(dest, value));
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    // copies of each other that nothing else defines
+    for inst in instructions() {
+        for dest in inst.get_defined_registers() {
+            values.entry(*dest).or_insert_with(|| {
+                let depends_on = inst.get_used_registers().into_iter().copied().collect();
+                register(dest, RegisterValueLocation::DependsOn(depends_on))
+            });
+        }
+    }
+    values
+}
+
+/// The registers `inst` uses that have to be in a machine register, rather
+/// than have a constant they hold substituted by [`with_constants`]
+fn uses_needing_machine_register(inst: &IR) -> SmallVec<[&RegisterIndex; 2]> {
+    match inst {
+        IR::Add { .. }
+        | IR::Subtract { .. }
+        | IR::Multiply { .. }
+        | IR::Divide { .. }
+        | IR::Remainder { .. }
+        | IR::ShiftLeft { .. }
+        | IR::ShiftRight { .. }
+        | IR::Compare { .. }
+        | IR::Copy { .. }
+        | IR::Phi { .. }
+        | IR::TruncateChecked { .. }
+        | IR::PrintInt { .. }
+        | IR::Assert { .. }
+        | IR::ReturnValue { .. }
+        | IR::ReturnStruct { .. }
+        | IR::Call { .. }
+        | IR::Pin { .. }
+        | IR::MemLoad { .. }
+        | IR::MemStore { .. } => smallvec![],
+        IR::Store {
+            dest_register: Value::Register(r),
+            ..
+        } => smallvec![r],
+        _ => inst.get_used_registers(),
+    }
+}
+
+/// Registers that always hold the same constant and are only used where the
+/// constant can be used instead.  They aren't given a machine register;
+/// the constant is loaded again wherever it's needed.
+fn rematerialized_constants(
+    bbm: &BasicBlockManager,
+    types: &BTreeMap<RegisterIndex, PrimitiveValue>,
+) -> BTreeMap<RegisterIndex, Value> {
+    let mut constants: BTreeMap<RegisterIndex, Value> = compute_register_values(bbm, types)
+        .into_iter()
+        .filter_map(|(r, register)| match register.value {
+            RegisterValueLocation::Constant(value) => Some((
+                r,
+                Value::Immediate {
+                    _type: register._type,
+                    value,
+                },
+            )),
+            _ => None,
+        })
+        .collect();
+    for (_, bb) in bbm.iterate_basic_blocks() {
+        for inst in bb.iterate_instructions() {
+            for r in uses_needing_machine_register(inst) {
+                constants.remove(r);
+            }
+        }
+    }
+    constants
+}
+
+/// Allocas whose pointer is only ever the address of a `Load` or `Store`.
+/// They aren't given a machine register; the slot's address is put in rcx
+/// where it's used, so a function can have more slots than registers.
+fn direct_slots(bbm: &BasicBlockManager) -> BTreeSet<RegisterIndex> {
+    let instructions = || {
+        bbm.iterate_basic_blocks()
+            .flat_map(|(_, bb)| bb.iterate_instructions())
+    };
+    let mut slots = instructions()
+        .filter_map(|inst| match inst {
+            IR::Alloca { dest_register, .. } => Some(*dest_register),
+            _ => None,
+        })
+        .collect::<BTreeSet<_>>();
+    for inst in instructions() {
+        match inst {
+            IR::Load { .. } => (),
+            // storing the pointer itself lets it escape
+            IR::Store {
+                src_register: Value::Register(src),
+                ..
+            } => {
+                slots.remove(src);
+            }
+            IR::Store { .. } => (),
+            _ => {
+                for r in inst.get_used_registers() {
+                    slots.remove(r);
+                }
+            }
+        }
+    }
+    slots
+}
+
+/// Registers loaded from a stack slot that are only used by arithmetic in the
+/// same block, before anything could write to the slot.  They aren't given a
+/// machine register; the arithmetic reads the slot as a memory operand
+/// instead.  The values are how far below rbp the slots are.
+fn folded_loads(
+    bbm: &BasicBlockManager,
+    types: &BTreeMap<RegisterIndex, PrimitiveValue>,
+    constants: &BTreeMap<RegisterIndex, Value>,
+) -> BTreeMap<RegisterIndex, i32> {
+    // the arithmetic is done on whole registers, so only 64 bit values can
+    // be read straight from memory
+    let candidates: BTreeMap<RegisterIndex, i32> = compute_register_values(bbm, types)
+        .into_iter()
+        .filter_map(|(r, register)| match register.value {
+            RegisterValueLocation::Memory(offset) if register._type.size() == 8 => {
+                Some((r, offset as i32))
+            }
+            _ => None,
+        })
+        .collect();
+    let mut folded = candidates.clone();
+    let in_register = |v: &Value| match v {
+        Value::Register(r) if !constants.contains_key(r) => Some(*r),
+        _ => None,
+    };
+    for (_, bb) in bbm.iterate_basic_blocks() {
+        // loaded in this block, with nothing written to memory since
+        let mut unchanged = BTreeSet::new();
+        for inst in bb.iterate_instructions() {
+            // the operand that can be read from memory; the other one has to
+            // be in a machine register
+            let foldable = match inst {
+                IR::Add {
+                    src1,
+                    src2,
+                    overflow: Overflow::Wrap | Overflow::Poison,
+                    ..
+                }
+                | IR::Multiply {
+                    src1,
+                    src2,
+                    overflow: Overflow::Wrap | Overflow::Poison,
+                    ..
+                } => match (in_register(src1), in_register(src2)) {
+                    (Some(r1), Some(r2)) if r1 != r2 && candidates.contains_key(&r2) => Some(r2),
+                    (Some(r1), Some(r2)) if r1 != r2 && candidates.contains_key(&r1) => Some(r1),
+                    _ => None,
+                },
+                IR::Subtract {
+                    src1,
+                    src2,
+                    overflow: Overflow::Wrap | Overflow::Poison,
+                    ..
+                } => match (in_register(src1), in_register(src2)) {
+                    (Some(r1), Some(r2)) if r1 != r2 => Some(r2),
+                    _ => None,
+                },
+                _ => None,
+            };
+            // loads count what they define as used
+            let defines = inst.get_defined_registers();
+            for r in inst.get_used_registers() {
+                if defines.contains(&r) {
+                    continue;
+                }
+                if foldable != Some(*r) || !unchanged.contains(r) {
+                    folded.remove(r);
+                }
+            }
+            if schedule::memory_effect(inst) == schedule::MemoryEffect::Write {
+                unchanged.clear();
+            }
+            for r in defines {
+                if candidates.contains_key(r) {
+                    unchanged.insert(*r);
+                }
+            }
+        }
+    }
+    folded
+}
+
+/// `inst` with the registers in `constants` replaced by their values
+fn with_constants(inst: &IR, constants: &BTreeMap<RegisterIndex, Value>) -> IR {
+    let replace = |v: &mut Value| {
+        if let Value::Register(r) = v {
+            if let Some(c) = constants.get(r) {
+                *v = *c;
+            }
+        }
+    };
+    let mut inst = inst.clone();
+    match inst {
+        IR::Add {
+            ref mut src1,
+            ref mut src2,
+            ..
+        }
+        | IR::Subtract {
+            ref mut src1,
+            ref mut src2,
+            ..
+        }
+        | IR::Multiply {
+            ref mut src1,
+            ref mut src2,
+            ..
+        }
+        | IR::Divide {
+            ref mut src1,
+            ref mut src2,
+            ..
+        }
+        | IR::Remainder {
+            ref mut src1,
+            ref mut src2,
+            ..
+        }
+        | IR::ShiftLeft {
+            ref mut src1,
+            ref mut src2,
+            ..
+        }
+        | IR::ShiftRight {
+            ref mut src1,
+            ref mut src2,
+            ..
+        }
+        | IR::Compare {
+            ref mut src1,
+            ref mut src2,
+            ..
+        }
+        | IR::MemStore {
+            offset: ref mut src1,
+            src: ref mut src2,
+        } => {
+            replace(src1);
+            replace(src2);
+        }
+        IR::Copy { ref mut src, .. }
+        | IR::TruncateChecked { ref mut src, .. }
+        | IR::PrintInt { ref mut src, .. }
+        | IR::Assert {
+            cond: ref mut src, ..
+        }
+        | IR::ReturnValue { value: ref mut src }
+        | IR::Pin {
+            value: ref mut src, ..
+        }
+        | IR::MemLoad {
+            offset: ref mut src,
+            ..
+        }
+        | IR::Store {
+            src_register: ref mut src,
+            ..
+        } => replace(src),
+        IR::ReturnStruct { ref mut values } => values.iter_mut().for_each(replace),
+        IR::Call { ref mut args, .. } => args.iter_mut().for_each(replace),
+        _ => (),
+    }
+    inst
+}
+
 #[derive(Debug)]
 pub struct CodeGenError {
     /// Which IR instruction the error happened at
-    location: usize,
-    reason: CodeGenErrorReason,
+    pub(crate) location: usize,
+    pub(crate) reason: CodeGenErrorReason,
+}
+
+impl CodeGenError {
+    pub fn location(&self) -> usize {
+        self.location
+    }
+
+    pub fn reason(&self) -> &CodeGenErrorReason {
+        &self.reason
+    }
+}
+
+/// Requirements on the machine registers picked by [`compute_register_map`]
+#[derive(Debug, Clone, Default)]
+pub struct RegisterConstraints {
+    fixed: BTreeMap<RegisterIndex, MachineRegister>,
+    /// Registers that each register must not share a machine register with
+    distinct: BTreeMap<RegisterIndex, BTreeSet<RegisterIndex>>,
+    /// Machine registers to use if they're free, which aren't requirements
+    preferred: BTreeMap<RegisterIndex, MachineRegister>,
+}
+
+impl RegisterConstraints {
+    /// `reg` must be assigned `machine_reg`, which is then only used for
+    /// registers with this constraint.  Two registers fixed to the same
+    /// machine register can only share it if the first is last used by the
+    /// instruction defining the second.
+    pub fn fix(&mut self, reg: RegisterIndex, machine_reg: MachineRegister) -> &mut Self {
+        self.fixed.insert(reg, machine_reg);
+        self
+    }
+
+    /// `reg` should be assigned `machine_reg` if it's free, which other
+    /// registers avoid taking while `reg` might still want it
+    pub fn prefer(&mut self, reg: RegisterIndex, machine_reg: MachineRegister) -> &mut Self {
+        self.preferred.insert(reg, machine_reg);
+        self
+    }
+
+    /// `a` and `b` must not share a machine register
+    pub fn must_not_alias(&mut self, a: RegisterIndex, b: RegisterIndex) -> &mut Self {
+        self.distinct.entry(a).or_default().insert(b);
+        self.distinct.entry(b).or_default().insert(a);
+        self
+    }
+}
+
+/// Assign a machine register to every register defined in the IR, or to the
+/// low half of 128 bit ones
+pub fn compute_register_map(
+    bbm: &BasicBlockManager,
+    options: &CodeGenOptions,
+) -> BTreeMap<RegisterIndex, MachineRegister> {
+    compute_register_pairs(bbm, options).0
+}
+
+/// Like [`compute_register_map`], also giving the machine register for the
+/// high half of each 128 bit register in the second map.
+///
+/// Panics if more registers are live at once than there are machine
+/// registers; generating code spills some of them to the stack first.
+pub fn compute_register_pairs(
+    bbm: &BasicBlockManager,
+    options: &CodeGenOptions,
+) -> (
+    BTreeMap<RegisterIndex, MachineRegister>,
+    BTreeMap<RegisterIndex, MachineRegister>,
+) {
+    let constraints = &options.register_constraints;
+    allocate_registers(bbm, options, constraints, &BTreeSet::new())
+        .unwrap_or_else(|e| panic!("Ran out of machine registers: {:?}", e))
 }
 
-// does not handle register spilling right now
-// TODO: handle register spilling
-fn compute_register_map(bbm: &BasicBlockManager) -> BTreeMap<RegisterIndex, MachineRegister> {
+/// The machine registers from [`compute_register_pairs`]
+type RegisterPairs = (
+    BTreeMap<RegisterIndex, MachineRegister>,
+    BTreeMap<RegisterIndex, MachineRegister>,
+);
+
+/// Where allocation ran out of machine registers: defining the registers of
+/// instruction `index` of `block` while every register in `live` had one
+#[derive(Debug)]
+struct OutOfRegisters {
+    block: BasicBlockIndex,
+    index: usize,
+    live: Vec<RegisterIndex>,
+}
+
+/// [`compute_register_pairs`] with `constraints` instead of the ones in
+/// `options`.  The stack slots in `slots` are accessed directly, so they
+/// don't need a register holding their address.
+fn allocate_registers(
+    bbm: &BasicBlockManager,
+    options: &CodeGenOptions,
+    constraints: &RegisterConstraints,
+    slots: &BTreeSet<RegisterIndex>,
+) -> Result<RegisterPairs, OutOfRegisters> {
     let mut available_registers = VecDeque::new();
     available_registers.push_back(MachineRegister::Rdx);
     available_registers.push_back(MachineRegister::Rbx);
@@ -39,8 +475,41 @@ fn compute_register_map(bbm: &BasicBlockManager) -> BTreeMap<RegisterIndex, Mach
     available_registers.push_back(MachineRegister::R13);
     available_registers.push_back(MachineRegister::R14);
     available_registers.push_back(MachineRegister::R15);
+    if options.omit_frame_pointer {
+        available_registers.push_back(MachineRegister::Rbp);
+    }
+    for machine_reg in constraints.fixed.values() {
+        assert!(
+            available_registers.contains(machine_reg),
+            "{:?} can't be allocated",
+            machine_reg
+        );
+    }
+    // fixed and pinned registers are kept out of the pool so they're free
+    // when needed
+    let pinned = bbm
+        .iterate_basic_blocks()
+        .flat_map(|(_, bb)| bb.iterate_instructions())
+        .filter_map(|inst| match inst {
+            IR::Pin { register, .. } => Some(*register),
+            _ => None,
+        })
+        .collect::<BTreeSet<_>>();
+    available_registers
+        .retain(|mr| !constraints.fixed.values().any(|f| f == mr) && !pinned.contains(mr));
+    let types = bbm.compute_register_types();
+    let constants = rematerialized_constants(bbm, &types);
+    let folded = folded_loads(bbm, &types, &constants);
+    let unallocated: BTreeSet<RegisterIndex> = constants
+        .keys()
+        .chain(folded.keys())
+        .chain(slots)
+        .chain(&direct_slots(bbm))
+        .copied()
+        .collect();
     let current_mapping: BTreeMap<RegisterIndex, MachineRegister> = BTreeMap::new();
     let mut out: BTreeMap<RegisterIndex, MachineRegister> = BTreeMap::new();
+    let mut high_halves: BTreeMap<RegisterIndex, MachineRegister> = BTreeMap::new();
     let gd = reg_alloc::compute_graph(bbm);
     let gq = reg_alloc::GraphQuery::new(gd, bbm);
     let mut seen = BTreeSet::new();
@@ -49,78 +518,330 @@ fn compute_register_map(bbm: &BasicBlockManager) -> BTreeMap<RegisterIndex, Mach
         &gq,
         bbm.start,
         &mut out,
+        &mut high_halves,
         current_mapping,
-        available_registers,
+        available_registers.clone(),
+        constraints,
+        &types,
+        &unallocated,
         &mut seen,
-    );
+    )?;
+    // code is still generated for blocks that can't be reached, so their
+    // registers need somewhere to go too; it doesn't matter where
+    for (idx, _) in bbm.iterate_basic_blocks() {
+        build_register_map_inner(
+            bbm,
+            &gq,
+            idx,
+            &mut out,
+            &mut high_halves,
+            BTreeMap::new(),
+            available_registers.clone(),
+            constraints,
+            &types,
+            &unallocated,
+            &mut seen,
+        )?;
+    }
+
+    Ok((out, high_halves))
+}
 
-    out
+/// The machine registers allocated for a program
+struct Allocation {
+    /// The program with registers spilled, if there weren't enough machine
+    /// registers for it as it was
+    spilled: Option<reg_alloc::spill::Spilled>,
+    register_map: BTreeMap<RegisterIndex, MachineRegister>,
+    high_halves: BTreeMap<RegisterIndex, MachineRegister>,
 }
 
+/// Allocate registers for `ctx`, spilling registers until there are enough
+/// machine registers for the rest.
+///
+/// Where it runs out, the register that's live there and used furthest
+/// away is spilled.  It can't be one the instruction there needs, or one
+/// fixed to a machine register.
+fn allocate_spilling(
+    ctx: &Context,
+    options: &CodeGenOptions,
+    constraints: &RegisterConstraints,
+) -> Allocation {
+    let mut spiller: Option<reg_alloc::spill::Spiller> = None;
+    loop {
+        let (bbm, slots) = match &spiller {
+            Some(spiller) => (&spiller.ctx().basic_blocks, spiller.slots()),
+            None => (&ctx.basic_blocks, BTreeSet::new()),
+        };
+        let out = match allocate_registers(bbm, options, constraints, &slots) {
+            Ok((register_map, high_halves)) => {
+                return match spiller {
+                    None => Allocation {
+                        spilled: None,
+                        register_map,
+                        high_halves,
+                    },
+                    Some(spiller) => {
+                        let spilled = spiller.finish();
+                        let slots = spilled.spills.iter().map(|s| s.slot).collect();
+                        let bbm = &spilled.ctx.basic_blocks;
+                        // sharing slots doesn't change what's in registers
+                        let (register_map, high_halves) =
+                            allocate_registers(bbm, options, constraints, &slots)
+                                .unwrap_or_else(|e| panic!("Ran out after spilling: {:?}", e));
+                        Allocation {
+                            spilled: Some(spilled),
+                            register_map,
+                            high_halves,
+                        }
+                    }
+                };
+            }
+            Err(out) => out,
+        };
+        let spiller = spiller.get_or_insert_with(|| reg_alloc::spill::Spiller::new(ctx));
+        let bbm = &spiller.ctx().basic_blocks;
+        let bb = bbm.get(out.block).unwrap();
+        let code = bb.iterate_instructions().collect::<Vec<_>>();
+        // the phis and parameters at the top all need theirs at once
+        let leading = reg_alloc::spill::leading_definitions(bb);
+        let needing = if out.index < leading {
+            0..leading
+        } else {
+            out.index..out.index + 1
+        };
+        let needed = code[needing.clone()]
+            .iter()
+            .flat_map(|inst| {
+                inst.get_used_registers()
+                    .into_iter()
+                    .chain(inst.get_defined_registers())
+            })
+            .copied()
+            .collect::<BTreeSet<_>>();
+        let victim = out
+            .live
+            .iter()
+            .copied()
+            .filter(|r| {
+                !needed.contains(r) && !constraints.fixed.contains_key(r) && spiller.can_spill(*r)
+            })
+            .max_by_key(|r| {
+                let next_use = reg_alloc::spill::next_use(bbm, *r, out.block, needing.end - 1);
+                (next_use.unwrap_or(usize::MAX), *r)
+            })
+            .unwrap_or_else(|| {
+                panic!(
+                    "Ran out of machine registers, and none of the live ones can be spilled: {:?}",
+                    out
+                )
+            });
+        spiller.spill(victim);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn build_register_map_inner(
     bbm: &BasicBlockManager,
     gq: &reg_alloc::GraphQuery,
     cur_idx: BasicBlockIndex,
     reg_map: &mut BTreeMap<RegisterIndex, MachineRegister>,
+    high_halves: &mut BTreeMap<RegisterIndex, MachineRegister>,
     mut current_map: BTreeMap<RegisterIndex, MachineRegister>,
     mut available_registers: VecDeque<MachineRegister>,
+    constraints: &RegisterConstraints,
+    types: &BTreeMap<RegisterIndex, PrimitiveValue>,
+    // rematerialized constants, folded loads, and stack slots accessed
+    // directly
+    unallocated: &BTreeSet<RegisterIndex>,
     seen: &mut BTreeSet<BasicBlockIndex>,
-) {
+) -> Result<(), OutOfRegisters> {
+    let is_fixed = |mr: MachineRegister| constraints.fixed.values().any(|f| *f == mr);
     if seen.contains(&cur_idx) {
-        return;
+        return Ok(());
     } else {
         seen.insert(cur_idx);
     }
+    let free = |k: RegisterIndex,
+                current_map: &mut BTreeMap<RegisterIndex, MachineRegister>,
+                available_registers: &mut VecDeque<MachineRegister>,
+                high_halves: &BTreeMap<RegisterIndex, MachineRegister>| {
+        let machine_reg = current_map.remove(&k).unwrap();
+        if !is_fixed(machine_reg) {
+            available_registers.push_back(machine_reg);
+        }
+        available_registers.extend(high_halves.get(&k));
+    };
 
     // =====================================================
-    // free registers that are not used on this path
+    // free registers that are not live coming into this block
     // TODO: optimize [this can probably avoid the clone AND also only be done
     // in cases where the parent has multiple paths]
     let cm_copy = current_map.clone();
     for (k, _) in cm_copy {
         if !gq.is_live_in(k, cur_idx) {
-            let machine_reg = current_map.remove(&k).unwrap();
-            available_registers.push_back(machine_reg);
+            free(k, &mut current_map, &mut available_registers, high_halves);
         }
     }
 
-    // TODO: generate liveness info from inside basic blocks too to reduce register pressure
-    // this should cause basic tests to fail in the short-term so should be implemented
-    // very soon
-    for declared_reg in bbm.get(cur_idx).unwrap().iter_defined_registers() {
-        let machine_reg = available_registers
-            .pop_front()
-            .expect("Ran out of machine registers! Need to implement register spilling");
-        let existing_reg = current_map.insert(*declared_reg, machine_reg);
-        assert!(existing_reg.is_none());
-        let existing_reg = reg_map.insert(*declared_reg, machine_reg);
-        assert!(existing_reg.is_none());
-    }
+    let bb = bbm.get(cur_idx).unwrap();
+    // the phis and parameters at the top all get their values at once, so
+    // none of their registers are freed until they all have one
+    let leading = reg_alloc::spill::leading_definitions(bb);
+    let last_uses = last_uses(bbm, cur_idx);
+    for (index, inst) in bb.iterate_instructions().enumerate() {
+        for declared_reg in inst.get_defined_registers() {
+            if unallocated.contains(declared_reg) {
+                continue;
+            }
+            let out_of_registers = |current_map: &BTreeMap<_, _>| OutOfRegisters {
+                block: cur_idx,
+                index,
+                live: current_map.keys().copied().collect(),
+            };
+            let machine_reg = if let Some(fixed) = constraints.fixed.get(declared_reg) {
+                if let Some((other, _)) = current_map.iter().find(|(_, mr)| *mr == fixed) {
+                    let other = *other;
+                    if gq.is_live_out(other, cur_idx) || !dies_by(bb, other, *declared_reg) {
+                        panic!(
+                            "{:?} and {:?} both need {:?} at the same time",
+                            declared_reg, other, fixed
+                        );
+                    }
+                    current_map.remove(&other);
+                }
+                *fixed
+            } else {
+                // registers that are done with may have left their machine
+                // register to something else, so all of them are avoided
+                let avoid = constraints
+                    .distinct
+                    .get(declared_reg)
+                    .into_iter()
+                    .flatten()
+                    .flat_map(|r| reg_map.get(r).into_iter().chain(high_halves.get(r)))
+                    .collect::<Vec<_>>();
+                // registers still to be defined may want some of them
+                let wanted = constraints
+                    .preferred
+                    .iter()
+                    .filter(|(r, _)| *r != declared_reg && !reg_map.contains_key(r))
+                    .map(|(_, mr)| mr)
+                    .collect::<Vec<_>>();
+                let preferred = constraints.preferred.get(declared_reg);
+                let position = available_registers
+                    .iter()
+                    .position(|mr| Some(mr) == preferred && !avoid.contains(&mr))
+                    .or_else(|| {
+                        available_registers
+                            .iter()
+                            .position(|mr| !avoid.contains(&mr) && !wanted.contains(&mr))
+                    })
+                    .or_else(|| {
+                        available_registers
+                            .iter()
+                            .position(|mr| !avoid.contains(&mr))
+                    })
+                    .ok_or_else(|| out_of_registers(&current_map))?;
+                available_registers.remove(position).unwrap()
+            };
+            // the high half of a 128 bit value gets a register of its own,
+            // never a fixed one
+            if types.get(declared_reg).is_some_and(|t| t.size() > 8) {
+                let high = available_registers
+                    .pop_front()
+                    .ok_or_else(|| out_of_registers(&current_map))?;
+                high_halves.insert(*declared_reg, high);
+            }
+            let existing_reg = current_map.insert(*declared_reg, machine_reg);
+            assert!(existing_reg.is_none());
+            let existing_reg = reg_map.insert(*declared_reg, machine_reg);
+            assert!(existing_reg.is_none());
+        }
 
-    // =====================================================
-    // free registers that are not used on any path after
-    // TODO: optimize
-    let cm_copy = current_map.clone();
-    for (k, _) in cm_copy {
-        if !gq.is_live_out(k, cur_idx) {
-            let machine_reg = current_map.remove(&k).unwrap();
-            available_registers.push_back(machine_reg);
+        // =====================================================
+        // free registers that aren't used after this instruction, here or
+        // on any path after
+        if index + 1 < leading {
+            continue;
+        }
+        let cm_copy = current_map.clone();
+        for (k, _) in cm_copy {
+            let used_later = last_uses.get(&k).is_some_and(|last| *last > index);
+            if !used_later && !gq.is_live_out(k, cur_idx) {
+                free(k, &mut current_map, &mut available_registers, high_halves);
+            }
         }
     }
-    for exit in bbm.get(cur_idx).unwrap().iter_exits() {
+
+    for exit in bb.iter_exits() {
         build_register_map_inner(
             bbm,
             gq,
             *exit,
             reg_map,
+            high_halves,
             current_map.clone(),
             available_registers.clone(),
+            constraints,
+            types,
+            unallocated,
             seen,
-        );
+        )?;
     }
+    Ok(())
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Where each register is last used in block `idx`, with the phis of its
+/// successors using what they take from it after its last instruction.  The
+/// phis in the block itself use theirs on the way in, so they aren't counted.
+fn last_uses(bbm: &BasicBlockManager, idx: BasicBlockIndex) -> BTreeMap<RegisterIndex, usize> {
+    let bb = bbm.get(idx).unwrap();
+    let mut last_uses = BTreeMap::new();
+    for (index, inst) in bb.iterate_instructions().enumerate() {
+        if matches!(inst, IR::Phi { .. }) {
+            continue;
+        }
+        for v in inst.get_used_values() {
+            if let Value::Register(r) = v {
+                last_uses.insert(r, index);
+            }
+        }
+    }
+    let end = bb.iterate_instructions().count();
+    for succ in bbm.successors(idx) {
+        for inst in bbm.get(succ).unwrap().iterate_instructions() {
+            if let IR::Phi { incoming, .. } = inst {
+                for (_, v) in incoming.iter().filter(|(pred, _)| *pred == idx) {
+                    if let Value::Register(r) = v {
+                        last_uses.insert(*r, end);
+                    }
+                }
+            }
+        }
+    }
+    last_uses
+}
+
+/// Whether `reg` isn't used in `bb` after the instruction defining `def`,
+/// so `def` can take its machine register
+fn dies_by(bb: &BasicBlock, reg: RegisterIndex, def: RegisterIndex) -> bool {
+    let code = bb.iterate_instructions().collect::<Vec<_>>();
+    let defined_at = code
+        .iter()
+        .position(|inst| inst.get_defined_registers().contains(&&def));
+    let last_use = code.iter().rposition(|inst| {
+        inst.get_used_values()
+            .iter()
+            .any(|v| matches!(v, Value::Register(r) if *r == reg))
+    });
+    match (defined_at, last_use) {
+        (Some(defined_at), Some(last_use)) => last_use <= defined_at,
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
 pub enum MachineRegister {
     Rax = 0,
     Rcx = 1,
@@ -140,152 +861,2801 @@ pub enum MachineRegister {
     R15 = 15,
 }
 
-pub extern "C" fn guest_print(buffer: *const u8, len: u64) {
+/// A callback for [`CodeGenOptions::print_handler`], called with what
+/// generated code prints
+#[derive(Clone)]
+pub struct PrintHandler(Arc<PrintHandlerFn>);
+
+type PrintHandlerFn = dyn Fn(&[u8]) + Send + Sync;
+
+impl PrintHandler {
+    pub fn new(handler: impl Fn(&[u8]) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(handler))
+    }
+}
+
+impl std::fmt::Debug for PrintHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("PrintHandler")
+    }
+}
+
+/// Generated code passes the print functions a pointer to the handler it was
+/// generated with, which the code owns, or null to write to stdout
+unsafe fn write_output(handler: *const PrintHandler, output: &[u8]) {
     use std::io::Write;
-    std::io::stdout()
-        .write_all(unsafe { std::slice::from_raw_parts(buffer, len as usize) })
-        .unwrap()
+    match handler.as_ref() {
+        Some(handler) => (handler.0)(output),
+        None => std::io::stdout().write_all(output).unwrap(),
+    }
 }
 
-fn emit_mov_imm(ops: &mut Assembler, dest: MachineRegister, imm: usize, _type: PrimitiveValue) {
-    match _type {
-        PrimitiveValue::U8 | PrimitiveValue::I8 => {
-            let val = imm as u8 as i32;
-            // 32bit
-            dynasm!(ops
-                    ; xor Rd(dest as u8), Rd(dest as u8)
-                    ; mov Rd(dest as u8), BYTE val
-            );
-        }
-        PrimitiveValue::U16 | PrimitiveValue::I16 => {
-            let val = imm as u16 as i32;
-            // 32bit
-            dynasm!(ops
-                    ; xor Rd(dest as u8), Rd(dest as u8)
-                    ; mov Ra(dest as u8), WORD val
-            );
-        }
-        PrimitiveValue::U32 | PrimitiveValue::I32 => {
-            let val = imm as i32;
-            // 32bit
-            dynasm!(ops
-                    ; xor Rd(dest as u8), Rd(dest as u8)
-                    ; mov Ra(dest as u8), DWORD val
-            );
-        }
-        PrimitiveValue::U64 | PrimitiveValue::I64 => {
-            let val = imm as i64;
-            // 64bit
-            dynasm!(ops
-                    ; mov Ra(dest as u8), QWORD val
+/// # Safety
+///
+/// `buffer` must point to `len` readable bytes, and `handler` must be null or
+/// point to a live `PrintHandler`.
+#[export_name = "shiba_jit_guest_print"]
+pub unsafe extern "C" fn guest_print(buffer: *const u8, len: u64, handler: *const PrintHandler) {
+    write_output(handler, std::slice::from_raw_parts(buffer, len as usize))
+}
+
+/// # Safety
+///
+/// `handler` must be null or point to a live `PrintHandler`.
+#[export_name = "shiba_jit_guest_print_signed"]
+pub unsafe extern "C" fn guest_print_signed(value: i64, handler: *const PrintHandler) {
+    write_output(handler, format!("{}\n", value).as_bytes());
+}
+
+/// # Safety
+///
+/// `handler` must be null or point to a live `PrintHandler`.
+#[export_name = "shiba_jit_guest_print_unsigned"]
+pub unsafe extern "C" fn guest_print_unsigned(value: u64, handler: *const PrintHandler) {
+    write_output(handler, format!("{}\n", value).as_bytes());
+}
+
+/// Load the pointer to `handler` that's passed to the print functions into
+/// `dest`
+fn emit_print_handler(ops: &mut Assembler, dest: MachineRegister, handler: Option<&PrintHandler>) {
+    let d = dest as u8;
+    match handler {
+        Some(handler) => dynasm!(ops ; mov Rq(d), QWORD handler as *const _ as i64),
+        None => dynasm!(ops ; xor Rd(d), Rd(d)),
+    }
+}
+
+/// Called with the message of an assertion that failed
+pub type AssertHandler = fn(&[u8]);
+
+lazy_static! {
+    static ref ASSERT_HANDLER: Mutex<Option<AssertHandler>> = Mutex::new(None);
+}
+
+/// Have failed assertions in generated code call `handler` with their
+/// message instead of aborting the process.
+///
+/// The generated code carries on after the handler returns.
+pub fn set_assert_handler(handler: Option<AssertHandler>) {
+    *ASSERT_HANDLER.lock().unwrap() = handler;
+}
+
+/// Called by `IR::Assert` when its condition is zero
+///
+/// # Safety
+///
+/// `buffer` must point to `len` readable bytes.
+#[export_name = "shiba_jit_guest_assert_failed"]
+pub unsafe extern "C" fn guest_assert_failed(buffer: *const u8, len: u64) {
+    let message = std::slice::from_raw_parts(buffer, len as usize);
+    let handler = *ASSERT_HANDLER.lock().unwrap();
+    match handler {
+        Some(handler) => handler(message),
+        None => {
+            eprintln!(
+                "guest assertion failed: {}",
+                String::from_utf8_lossy(message)
             );
+            std::process::abort();
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash)]
-pub enum RegisterEvent {
-    Acquire(usize),
-    Release(usize),
+lazy_static! {
+    static ref TRAP_HANDLER: Mutex<Option<fn(u64)>> = Mutex::new(None);
 }
 
-#[derive(Debug)]
-pub enum CodeGenErrorReason {
-    RegisterValueTaken(usize),
-    RegisterNotFound(usize),
-    TypeMismatch(PrimitiveValue, PrimitiveValue),
-    CodeGenFailure,
+/// Have traps in generated code call `handler` with the trap's code instead
+/// of aborting the process.
+///
+/// The generated function returns to its caller after the handler does.
+pub fn set_trap_handler(handler: Option<fn(u64)>) {
+    *TRAP_HANDLER.lock().unwrap() = handler;
 }
 
-pub fn set_up_constants(
-    ctx: &Context,
-    ops: &mut Assembler,
-) -> BTreeMap<ConstantIndex, DynamicLabel> {
-    let mut constant_map: BTreeMap<ConstantIndex, DynamicLabel> = BTreeMap::new();
-    for (i, constant) in ctx.constants.iter().enumerate() {
-        // TODO: investigate dynamic vs global labels
-        let dyn_lab = ops.new_dynamic_label();
+/// Called by `IR::Trap`
+#[export_name = "shiba_jit_guest_abort"]
+pub extern "C" fn guest_abort(code: u64) {
+    let handler = *TRAP_HANDLER.lock().unwrap();
+    match handler {
+        Some(handler) => handler(code),
+        None => {
+            eprintln!("guest aborted with code {}", code);
+            std::process::abort();
+        }
+    }
+}
+
+/// Load `imm`, truncated to the width of `_type`, into `dest`.  The rest of
+/// the register is zeroed.
+fn emit_mov_imm(ops: &mut Assembler, dest: MachineRegister, imm: usize, _type: PrimitiveValue) {
+    let val = truncate(imm, _type);
+    if val <= u32::MAX as usize {
+        // writing the low 32 bits zeroes the upper 32
         dynasm!(ops
-                ; => dyn_lab
-                ; .bytes constant.as_slice()
+                ; mov Rd(dest as u8), DWORD val as u32 as i32
+        );
+    } else {
+        dynasm!(ops
+                ; mov Ra(dest as u8), QWORD val as i64
         );
-        constant_map.insert(ConstantIndex::new(i as _), dyn_lab);
     }
-    constant_map
 }
 
-pub fn generate_code(ctx: &Context) -> Result<(ExecutableBuffer, AssemblyOffset), CodeGenError> {
-    let mut ops = Assembler::new().unwrap();
+/// The low bits of `value` that a `_type` holds
+fn truncate(value: usize, _type: PrimitiveValue) -> usize {
+    let bits = _type.size() * 8;
+    if bits < 64 {
+        value & ((1 << bits) - 1)
+    } else {
+        value
+    }
+}
 
+/// Call the host function at `function`, which is exported as `symbol`.
+/// Clobbers rax.
+///
+/// With [`CodeGenOptions::position_independent`] the address isn't used:
+/// it's a relative `call` with the displacement left for the linker.
+fn emit_host_call(
+    ops: &mut Assembler,
+    relocations: &mut Vec<Relocation>,
+    function: usize,
+    symbol: &'static str,
+    options: &CodeGenOptions,
+) {
+    if options.position_independent {
+        // call rel32
+        dynasm!(ops
+                ; .bytes [0xe8, 0, 0, 0, 0].iter()
+        );
+        relocations.push(Relocation {
+            offset: AssemblyOffset(ops.offset().0 - 4),
+            target: RelocationTarget::HostFunctionCall(symbol),
+        });
+        return;
+    }
     dynasm!(ops
-            ; .arch x64
+            ; mov rax, QWORD function as _
     );
+    relocations.push(Relocation {
+        offset: AssemblyOffset(ops.offset().0 - 8),
+        target: RelocationTarget::HostFunction(symbol),
+    });
+    dynasm!(ops
+            ; call rax
+    );
+}
 
-    let start_offset;
+/// Where the System V ABI passes the first six integer arguments
+const ARGUMENT_REGISTERS: [MachineRegister; 6] = [
+    MachineRegister::Rdi,
+    MachineRegister::Rsi,
+    MachineRegister::Rdx,
+    MachineRegister::Rcx,
+    MachineRegister::R8,
+    MachineRegister::R9,
+];
 
-    // =================================================================
-    // set up the constants
+/// Where the System V ABI passes an argument
+#[derive(Debug, Clone, Copy)]
+enum ArgumentLocation {
+    /// The index of its first argument register
+    Register(usize),
+    /// How far above the first argument on the stack it is
+    Stack(i32),
+}
 
-    let constant_map = set_up_constants(ctx, &mut ops);
+/// Where each argument is passed, given their types.  128 bit arguments take
+/// two registers, or 16 aligned bytes of the stack if there aren't two
+/// left; the registers they skip are still used by later arguments.
+fn argument_locations(types: &[PrimitiveValue]) -> Vec<ArgumentLocation> {
+    let mut next_register = 0;
+    let mut stack = 0;
+    types
+        .iter()
+        .map(|_type| {
+            let registers = if _type.size() > 8 { 2 } else { 1 };
+            if next_register + registers <= ARGUMENT_REGISTERS.len() {
+                next_register += registers;
+                ArgumentLocation::Register(next_register - registers)
+            } else {
+                if registers == 2 {
+                    stack = (stack + 15) / 16 * 16;
+                }
+                stack += 8 * registers as i32;
+                ArgumentLocation::Stack(stack - 8 * registers as i32)
+            }
+        })
+        .collect()
+}
 
-    // =================================================================
-    // generate some machine code
-    start_offset = ops.offset();
+/// The register each parameter is read into, its type, and where it's
+/// passed
+fn parameter_locations(ctx: &Context) -> Vec<(RegisterIndex, PrimitiveValue, ArgumentLocation)> {
+    let entry = ctx.basic_blocks.get(ctx.entry()).unwrap();
+    // the pointer to return a big struct through comes first
+    let hidden = returns_through_pointer(ctx) as usize;
+    let parameters = entry
+        .iterate_instructions()
+        .filter_map(|inst| match *inst {
+            IR::Parameter {
+                dest_register,
+                _type,
+                index,
+            } => Some((dest_register, _type, index + hidden)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    // arguments that aren't read can't be wider than a register
+    let count = parameters.iter().map(|(_, _, index)| index + 1).max();
+    let mut types = vec![PrimitiveValue::U64; count.unwrap_or(0)];
+    for &(_, _type, index) in &parameters {
+        types[index] = _type;
+    }
+    let locations = argument_locations(&types);
+    parameters
+        .into_iter()
+        .map(|(dest, _type, index)| (dest, _type, locations[index]))
+        .collect()
+}
 
-    let register_map = compute_register_map(&ctx.basic_blocks);
-    dynasm!(ops
-            ; push rbp
-            ; mov rbp, rsp
-            ; sub rsp, 0x8
-            ; push rbx
-            ; push rdi
-            ; push rsi
-    );
+/// Move the parameters to their registers.  This has to come straight after
+/// the prologue, while the argument registers still hold the arguments.
+fn emit_load_parameters(
+    ops: &mut Assembler,
+    ctx: &Context,
+    register_map: &BTreeMap<RegisterIndex, MachineRegister>,
+    high_halves: &BTreeMap<RegisterIndex, MachineRegister>,
+    options: &CodeGenOptions,
+    frame: &StackFrame,
+) {
+    let parameters = parameter_locations(ctx);
+    // the argument registers may be allocated to other parameters
+    let mut moves = vec![];
+    for &(dest, _, location) in &parameters {
+        if let ArgumentLocation::Register(k) = location {
+            let source = MoveSource::Register(ARGUMENT_REGISTERS[k]);
+            moves.push((register_map[&dest], source));
+            if let Some(high) = high_halves.get(&dest) {
+                let source = MoveSource::Register(ARGUMENT_REGISTERS[k + 1]);
+                moves.push((*high, source));
+            }
+        }
+    }
+    emit_machine_moves(ops, moves);
+    for &(dest, _type, location) in &parameters {
+        let mdest = register_map[&dest];
+        let halves = std::iter::once(mdest).chain(high_halves.get(&dest).copied());
+        if let ArgumentLocation::Stack(offset) = location {
+            for (k, mdest) in halves.enumerate() {
+                let offset = offset + 8 * k as i32;
+                // the rest are above the return address, in order
+                if options.omit_frame_pointer {
+                    // the pushed registers, the frame, and rbp are below the
+                    // return address
+                    dynasm!(ops
+                            ; mov Ra(mdest as u8), [rsp + frame.pushed() + frame.size + 0x10 + offset]
+                    );
+                } else {
+                    dynasm!(ops
+                            ; mov Ra(mdest as u8), [rbp + 0x10 + offset]
+                    );
+                }
+            }
+        }
+        // only the low bits of narrower arguments are defined
+        if _type.size() <= 8 {
+            emit_extend(ops, mdest, _type);
+        }
+    }
+}
+
+/// The callee-saved registers that are only saved if they're used; rbx and
+/// rbp always are
+const CALLEE_SAVED: [MachineRegister; 4] = [
+    MachineRegister::R12,
+    MachineRegister::R13,
+    MachineRegister::R14,
+    MachineRegister::R15,
+];
+
+/// The stack slots allocas point to, which are below where rbp points, and
+/// the registers pushed below them
+struct StackFrame {
+    /// How far below rbp each alloca's slot is
+    slots: BTreeMap<RegisterIndex, i32>,
+    /// How much rsp is moved down to make room for the slots.  If the
+    /// function calls anything, rsp stays aligned for calls after the
+    /// registers are pushed.
+    size: i32,
+    /// The callee-saved registers pushed after rbx, in order
+    saved: Vec<MachineRegister>,
+}
+
+/// Whether the code for `ctx` calls a host function anywhere, so rsp has to
+/// be 16 byte aligned
+fn makes_calls(ctx: &Context, options: &CodeGenOptions) -> bool {
+    // out of bounds accesses call the handler
+    ctx.memory_bounds.is_some()
+        || ctx.iter_instructions().any(|(_, _, inst)| match inst {
+            IR::Call { .. } | IR::PrintInt { .. } | IR::PrintConstant { .. } => true,
+            IR::Assert { .. } => options.debug_assertions,
+            _ => false,
+        })
+}
+
+/// How far below rbp the slot each alloca points to is
+fn stack_slot_offsets(bbm: &BasicBlockManager) -> BTreeMap<RegisterIndex, i32> {
+    let mut slots = BTreeMap::new();
+    let mut used = 0;
+    for (_, bb) in bbm.iterate_basic_blocks() {
+        for inst in bb.iterate_instructions() {
+            if let IR::Alloca {
+                dest_register,
+                _type,
+                alignment,
+            } = inst
+            {
+                // rbp is 16 byte aligned, so alignments up to that hold
+                let alignment = (*alignment as i32).max(1);
+                used += _type.size() as i32;
+                used = (used + alignment - 1) / alignment * alignment;
+                slots.insert(*dest_register, used);
+            }
+        }
+    }
+    slots
+}
+
+impl StackFrame {
+    fn new(
+        ctx: &Context,
+        register_map: &BTreeMap<RegisterIndex, MachineRegister>,
+        high_halves: &BTreeMap<RegisterIndex, MachineRegister>,
+        options: &CodeGenOptions,
+    ) -> Self {
+        let pinned = ctx
+            .iter_instructions()
+            .filter_map(|(_, _, inst)| match inst {
+                IR::Pin { register, .. } => Some(*register),
+                _ => None,
+            });
+        let used: BTreeSet<MachineRegister> = register_map
+            .values()
+            .chain(high_halves.values())
+            .copied()
+            .chain(pinned)
+            .collect();
+        let saved: Vec<MachineRegister> = CALLEE_SAVED
+            .iter()
+            .copied()
+            .filter(|r| used.contains(r))
+            .collect();
+
+        let slots = stack_slot_offsets(&ctx.basic_blocks);
+        // each slot is further down than the ones before it
+        let used = slots.values().max().copied().unwrap_or(0);
+        let pushed = 8 * (3 + saved.len() as i32);
+        // a leaf function only has to keep rsp aligned for its pushes
+        let size = if makes_calls(ctx, options) {
+            (used + pushed + 15) / 16 * 16 - pushed
+        } else {
+            (used + 7) / 8 * 8
+        };
+        Self { slots, size, saved }
+    }
+
+    /// How many bytes are pushed below the slots: rbx, the saved registers,
+    /// rdi, and rsi
+    fn pushed(&self) -> i32 {
+        8 * (3 + self.saved.len() as i32)
+    }
+
+    fn layout(&self, options: &CodeGenOptions) -> FrameLayout {
+        // relative to rsp on entry; rbp is pushed first, and then the slots
+        // are below it
+        let below_slots = -8 - self.size;
+        let mut saved_registers = vec![
+            (MachineRegister::Rbp, -8),
+            (MachineRegister::Rbx, below_slots - 8),
+        ];
+        let rest = self
+            .saved
+            .iter()
+            .chain(&[MachineRegister::Rdi, MachineRegister::Rsi]);
+        for (k, r) in rest.enumerate() {
+            saved_registers.push((*r, below_slots - 16 - 8 * k as i32));
+        }
+        FrameLayout {
+            saved_registers,
+            frame_pointer: !options.omit_frame_pointer,
+            frame_size: self.size as usize,
+            slots: self
+                .slots
+                .iter()
+                .map(|(r, offset)| (*r, -8 - offset))
+                .collect(),
+        }
+    }
+}
+
+/// Put the address of the stack slot `offset` below rbp in `mr`
+fn emit_slot_address(
+    ops: &mut Assembler,
+    mr: MachineRegister,
+    offset: i32,
+    options: &CodeGenOptions,
+    frame: &StackFrame,
+) {
+    // the displacements aren't known when the macro is expanded, so they're
+    // always 32 bits
+    if options.omit_frame_pointer {
+        // the pushed registers and the frame are below where rbp would point
+        dynasm!(ops
+                ; lea Ra(mr as u8), [rsp + frame.pushed() + frame.size - offset]
+        );
+    } else {
+        dynasm!(ops
+                ; lea Ra(mr as u8), [rbp - offset]
+        );
+    }
+}
+
+/// The machine register holding the pointer `r`.  Stack slots accessed
+/// directly don't have one, so their address is put in rcx.
+fn emit_pointer(
+    ops: &mut Assembler,
+    r: RegisterIndex,
+    register_map: &BTreeMap<RegisterIndex, MachineRegister>,
+    options: &CodeGenOptions,
+    frame: &StackFrame,
+) -> MachineRegister {
+    match register_map.get(&r) {
+        Some(mr) => *mr,
+        None => {
+            emit_slot_address(ops, MachineRegister::Rcx, frame.slots[&r], options, frame);
+            MachineRegister::Rcx
+        }
+    }
+}
+
+/// `dest = other op [slot]` for an add, subtract, or multiply with one
+/// operand loaded from the stack slot `offset` below rbp, as found by
+/// [`folded_loads`].  Only the subtrahend is ever folded.
+fn emit_folded_arithmetic(
+    ops: &mut Assembler,
+    inst: &IR,
+    mdest: MachineRegister,
+    mother: MachineRegister,
+    offset: i32,
+    options: &CodeGenOptions,
+    frame: &StackFrame,
+) {
+    let (d, o) = (mdest as u8, mother as u8);
+    if mdest != mother {
+        dynasm!(ops
+                ; mov Rq(d), Rq(o)
+        );
+    }
+    // without a frame pointer the slot is above the pushed registers, like
+    // for allocas
+    let below_rsp = frame.pushed() + frame.size - offset;
+    match (inst, options.omit_frame_pointer) {
+        (IR::Add { .. }, false) => dynasm!(ops ; add Rq(d), QWORD [rbp - offset]),
+        (IR::Add { .. }, true) => dynasm!(ops ; add Rq(d), QWORD [rsp + below_rsp]),
+        (IR::Subtract { .. }, false) => dynasm!(ops ; sub Rq(d), QWORD [rbp - offset]),
+        (IR::Subtract { .. }, true) => dynasm!(ops ; sub Rq(d), QWORD [rsp + below_rsp]),
+        (IR::Multiply { .. }, false) => dynasm!(ops ; imul Rq(d), QWORD [rbp - offset]),
+        (IR::Multiply { .. }, true) => dynasm!(ops ; imul Rq(d), QWORD [rsp + below_rsp]),
+        _ => unreachable!("{:?} can't read an operand from memory", inst),
+    }
+}
+
+/// Restore the callee-saved registers and return
+fn emit_epilogue(ops: &mut Assembler, options: &CodeGenOptions, frame: &StackFrame) {
+    dynasm!(ops
+            ; pop rsi
+            ; pop rdi
+    );
+    for r in frame.saved.iter().rev() {
+        dynasm!(ops
+                ; pop Rq(*r as u8)
+        );
+    }
+    dynasm!(ops
+            ; pop rbx
+    );
+    if frame.size != 0 {
+        dynasm!(ops
+                ; add rsp, frame.size
+        );
+    }
+    if !options.omit_frame_pointer {
+        dynasm!(ops
+                ; mov rsp, rbp
+        );
+    }
+    dynasm!(ops
+            ; pop rbp
+            ; ret
+    );
+}
+
+/// Where each field of a `#[repr(C)]` struct with fields of these types
+/// goes, and how big the struct is
+fn struct_layout(fields: &[PrimitiveValue]) -> (Vec<usize>, usize) {
+    let mut offsets = Vec::with_capacity(fields.len());
+    let mut size: usize = 0;
+    for field in fields {
+        // integers are aligned to their size
+        size = size.div_ceil(field.size()) * field.size();
+        offsets.push(size);
+        size += field.size();
+    }
+    let alignment = fields.iter().map(|f| f.size()).max().unwrap_or(1);
+    (offsets, size.div_ceil(alignment) * alignment)
+}
+
+/// The System V ABI returns structs bigger than 16 bytes through a pointer
+/// the caller passes as a hidden first argument
+fn returns_through_pointer(ctx: &Context) -> bool {
+    ctx.return_struct
+        .as_ref()
+        .is_some_and(|fields| struct_layout(fields).1 > 16)
+}
+
+/// Put the fields of the returned struct where the caller expects them and
+/// return.  Clobbers rax, rcx, and rdx.
+fn emit_return_struct(
+    ops: &mut Assembler,
+    ctx: &Context,
+    values: &[Value],
+    register_map: &BTreeMap<RegisterIndex, MachineRegister>,
+    options: &CodeGenOptions,
+    frame: &StackFrame,
+) {
+    let fields = ctx.return_struct.as_deref().unwrap_or(&[]);
+    let (offsets, _) = struct_layout(fields);
+    if returns_through_pointer(ctx) {
+        // rdi was pushed by the prologue, just before rsi
+        dynasm!(ops
+                ; mov rax, [rsp + 8]
+        );
+        for ((value, _type), offset) in values.iter().zip(fields).zip(offsets) {
+            emit_mov_value(ops, MachineRegister::Rcx, *value, register_map);
+            let offset = offset as i32;
+            match _type.size() {
+                1 => dynasm!(ops ; mov [rax + offset], cl),
+                2 => dynasm!(ops ; mov [rax + offset], cx),
+                4 => dynasm!(ops ; mov [rax + offset], ecx),
+                _ => dynasm!(ops ; mov [rax + offset], rcx),
+            }
+        }
+    } else {
+        // each eightbyte is packed in rax, and rdx may hold a field of the
+        // first, so the second waits on the stack until the first is done
+        for eightbyte in (0..2).rev() {
+            dynasm!(ops
+                    ; xor eax, eax
+            );
+            let in_eightbyte = values
+                .iter()
+                .zip(fields)
+                .zip(&offsets)
+                .filter(|(_, offset)| **offset / 8 == eightbyte);
+            for ((value, _type), offset) in in_eightbyte {
+                emit_mov_value(ops, MachineRegister::Rcx, *value, register_map);
+                // only the field's own bits, so they don't spill into the next
+                let bits = match _type.size() {
+                    1 => PrimitiveValue::U8,
+                    2 => PrimitiveValue::U16,
+                    4 => PrimitiveValue::U32,
+                    _ => PrimitiveValue::U64,
+                };
+                emit_extend(ops, MachineRegister::Rcx, bits);
+                let shift = (offset % 8 * 8) as i8;
+                if shift != 0 {
+                    dynasm!(ops
+                            ; shl rcx, shift
+                    );
+                }
+                dynasm!(ops
+                        ; or rax, rcx
+                );
+            }
+            if eightbyte == 1 {
+                dynasm!(ops
+                        ; push rax
+                );
+            }
+        }
+        dynasm!(ops
+                ; pop rdx
+        );
+    }
+    emit_epilogue(ops, options, frame);
+}
+
+/// Make sure `size` bytes at `ptr` are in bounds, otherwise call the handler
+/// and return.  Clobbers rax.
+fn emit_bounds_check(
+    ops: &mut Assembler,
+    bounds: &MemoryBounds,
+    ptr: MachineRegister,
+    size: usize,
+    options: &CodeGenOptions,
+    frame: &StackFrame,
+) {
+    let in_bounds = ops.new_dynamic_label();
+    let out_of_bounds = ops.new_dynamic_label();
+    let lowest = bounds.base as i64;
+    let highest = (bounds.base + bounds.len).saturating_sub(size) as i64;
+    dynasm!(ops
+            ; mov rax, QWORD lowest
+            ; cmp Ra(ptr as u8), rax
+            ; jb => out_of_bounds
+            ; mov rax, QWORD highest
+            ; cmp Ra(ptr as u8), rax
+            ; jbe => in_bounds
+            ; => out_of_bounds
+            ; mov rdi, Ra(ptr as u8)
+            ; mov rax, QWORD bounds.on_out_of_bounds as usize as _
+            ; call rax
+    );
+    emit_epilogue(ops, options, frame);
+    dynasm!(ops
+            ; => in_bounds
+    );
+}
+
+/// Put the address of `offset` into the linear memory in rcx
+fn emit_linear_memory_address(
+    ops: &mut Assembler,
+    memory: &LinearMemory,
+    offset: Value,
+    register_map: &BTreeMap<RegisterIndex, MachineRegister>,
+) {
+    let base = match memory.base() {
+        Value::Immediate { value, .. } => value,
+        Value::Register(_) => unreachable!("linear memory base is always known"),
+    };
+    match offset {
+        Value::Register(r) => {
+            let mr = register_map[&r];
+            // writing the 32 bit register zero-extends the offset
+            dynasm!(ops
+                    ; mov ecx, Rd(mr as u8)
+                    ; mov rax, QWORD base as i64
+                    ; add rcx, rax
+            );
+        }
+        Value::Immediate { value, .. } => {
+            let address = base + (value as u32 as usize);
+            dynasm!(ops
+                    ; mov rcx, QWORD address as i64
+            );
+        }
+    }
+}
+
+/// Values to copy into phi destinations, as (destination, value)
+type PhiMoves = Vec<(RegisterIndex, Value)>;
+
+/// The moves into `succ`'s phis needed when control comes from `pred`
+fn phi_moves(
+    ctx: &Context,
+    constants: &BTreeMap<RegisterIndex, Value>,
+    pred: BasicBlockIndex,
+    succ: BasicBlockIndex,
+) -> PhiMoves {
+    ctx.basic_blocks
+        .get(succ)
+        .unwrap()
+        .iterate_instructions()
+        .filter_map(|inst| match inst {
+            IR::Phi {
+                dest_register,
+                incoming,
+            } => incoming
+                .iter()
+                .find(|(b, _)| *b == pred)
+                .map(|(_, v)| match v {
+                    Value::Register(r) => (*dest_register, *constants.get(r).unwrap_or(v)),
+                    Value::Immediate { .. } => (*dest_register, *v),
+                }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The label to branch to for the edge from `pred` to `succ`, which is a stub
+/// setting up the phis in `succ` if it has any
+fn edge_label(
+    ops: &mut Assembler,
+    ctx: &Context,
+    constants: &BTreeMap<RegisterIndex, Value>,
+    bb_map: &mut BTreeMap<BasicBlockIndex, DynamicLabel>,
+    edge_stubs: &mut Vec<(DynamicLabel, PhiMoves, BasicBlockIndex)>,
+    pred: BasicBlockIndex,
+    succ: BasicBlockIndex,
+) -> DynamicLabel {
+    let moves = phi_moves(ctx, constants, pred, succ);
+    if moves.is_empty() {
+        *bb_map
+            .entry(succ)
+            .or_insert_with(|| ops.new_dynamic_label())
+    } else {
+        let stub = ops.new_dynamic_label();
+        edge_stubs.push((stub, moves, succ));
+        stub
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MoveSource {
+    Register(MachineRegister),
+    Immediate(usize, PrimitiveValue),
+}
+
+/// Emit the moves as if they all happened at once, using rax to break cycles
+fn emit_parallel_moves(
+    ops: &mut Assembler,
+    moves: PhiMoves,
+    register_map: &BTreeMap<RegisterIndex, MachineRegister>,
+) {
+    let moves = moves
+        .into_iter()
+        .map(|(dest, v)| {
+            let src = match v {
+                Value::Register(r) => MoveSource::Register(register_map[&r]),
+                Value::Immediate { _type, value } => MoveSource::Immediate(value, _type),
+            };
+            (register_map[&dest], src)
+        })
+        .collect();
+    emit_machine_moves(ops, moves);
+}
+
+/// [`emit_parallel_moves`] between machine registers
+fn emit_machine_moves(ops: &mut Assembler, moves: Vec<(MachineRegister, MoveSource)>) {
+    let mut pending = moves
+        .into_iter()
+        .filter(|(dest, src)| *src != MoveSource::Register(*dest))
+        .collect::<Vec<_>>();
+
+    while !pending.is_empty() {
+        // a move is safe once nothing else still needs its destination
+        let ready = pending
+            .iter()
+            .position(|(dest, _)| !pending.iter().any(|(_, s)| *s == MoveSource::Register(*dest)));
+        match ready {
+            Some(pos) => {
+                let (dest, src) = pending.remove(pos);
+                match src {
+                    MoveSource::Register(mr) => dynasm!(ops
+                            ; mov Ra(dest as u8), Ra(mr as u8)
+                    ),
+                    MoveSource::Immediate(value, _type) => emit_mov_imm(ops, dest, value, _type),
+                }
+            }
+            None => {
+                // only cycles are left, save one destination so it can be overwritten
+                let dest = pending[0].0;
+                dynasm!(ops
+                        ; mov rax, Ra(dest as u8)
+                );
+                for (_, src) in pending.iter_mut() {
+                    if *src == MoveSource::Register(dest) {
+                        *src = MoveSource::Register(MachineRegister::Rax);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Move a value into a specific machine register
+fn emit_mov_value(
+    ops: &mut Assembler,
+    dest: MachineRegister,
+    v: Value,
+    register_map: &BTreeMap<RegisterIndex, MachineRegister>,
+) {
+    match v {
+        Value::Register(r) => {
+            let mr = register_map[&r];
+            if mr != dest {
+                dynasm!(ops
+                        ; mov Ra(dest as u8), Ra(mr as u8)
+                );
+            }
+        }
+        Value::Immediate { _type, value } => emit_mov_imm(ops, dest, value, _type),
+    }
+}
+
+/// The immediate as a sign extended 32 bit operand, if it can be encoded as one
+fn imm32(value: usize, _type: PrimitiveValue) -> Option<i32> {
+    match _type {
+        // only the low bits are significant
+        PrimitiveValue::U64 | PrimitiveValue::I64 => {
+            if value as i64 == value as i32 as i64 {
+                Some(value as i32)
+            } else {
+                None
+            }
+        }
+        _ => Some(value as i32),
+    }
+}
+
+/// Multiply `src` by a constant, using shifts and `lea` where that's cheaper
+/// than `imul`
+fn emit_multiply_by_constant(
+    ops: &mut Assembler,
+    dest: MachineRegister,
+    src: MachineRegister,
+    constant: usize,
+) {
+    let (d, s) = (dest as u8, src as u8);
+    if constant == 0 {
+        dynasm!(ops ; xor Rd(d), Rd(d));
+        return;
+    }
+    let shift = constant.trailing_zeros() as i8;
+    match constant >> shift {
+        1 => {
+            if dest != src {
+                dynasm!(ops ; mov Ra(d), Ra(s));
+            }
+        }
+        3 => dynasm!(ops ; lea Ra(d), [Ra(s) + Ra(s) * 2]),
+        5 => dynasm!(ops ; lea Ra(d), [Ra(s) + Ra(s) * 4]),
+        9 => dynasm!(ops ; lea Ra(d), [Ra(s) + Ra(s) * 8]),
+        _ => {
+            if constant as i64 == constant as i32 as i64 {
+                dynasm!(ops ; imul Ra(d), Ra(s), constant as i32);
+            } else {
+                dynasm!(ops
+                        ; mov rax, QWORD constant as i64
+                        ; imul rax, Ra(s)
+                        ; mov Ra(d), rax
+                );
+            }
+            return;
+        }
+    }
+    if shift != 0 {
+        dynasm!(ops ; shl Ra(d), shift);
+    }
+}
+
+/// Save the registers a host function may clobber, leaving the stack 16 byte
+/// aligned for the call
+fn emit_save_caller_saved(ops: &mut Assembler) {
+    dynasm!(ops
+            ; push rax
+            ; push rcx
+            ; push rdx
+            ; push rsi
+            ; push rdi
+            ; push r8
+            ; push r9
+            ; push r10
+            ; push r11
+            ; sub rsp, 0x8
+    );
+}
+
+/// The registers [`emit_save_caller_saved`] pushes, from the top of the stack
+/// down, after the padding
+const CALLER_SAVED: [MachineRegister; 9] = [
+    MachineRegister::R11,
+    MachineRegister::R10,
+    MachineRegister::R9,
+    MachineRegister::R8,
+    MachineRegister::Rdi,
+    MachineRegister::Rsi,
+    MachineRegister::Rdx,
+    MachineRegister::Rcx,
+    MachineRegister::Rax,
+];
+
+/// Put what a host function returned in rax in `mdest`, between the call and
+/// [`emit_restore_caller_saved`].  If `mdest` is one of the registers that
+/// restores, the result is written over where it was saved instead, so it's
+/// what's popped.
+fn emit_save_call_result(ops: &mut Assembler, mdest: MachineRegister, _type: PrimitiveValue) {
+    // only the low bits of narrower results are defined
+    emit_extend(ops, MachineRegister::Rax, _type);
+    match CALLER_SAVED.iter().position(|r| *r == mdest) {
+        Some(k) => dynasm!(ops
+                ; mov [rsp + 8 + 8 * k as i32], rax
+        ),
+        None => dynasm!(ops
+                ; mov Rq(mdest as u8), rax
+        ),
+    }
+}
+
+fn emit_restore_caller_saved(ops: &mut Assembler) {
+    dynasm!(ops
+            ; add rsp, 0x8
+            ; pop r11
+            ; pop r10
+            ; pop r9
+            ; pop r8
+            ; pop rdi
+            ; pop rsi
+            ; pop rdx
+            ; pop rcx
+            ; pop rax
+    );
+}
+
+/// Sign or zero extend the low `_type` sized part of `reg` to the whole register
+fn emit_extend(ops: &mut Assembler, reg: MachineRegister, _type: PrimitiveValue) {
+    let r = reg as u8;
+    match _type {
+        PrimitiveValue::U8 => dynasm!(ops ; movzx Rd(r), Rb(r)),
+        PrimitiveValue::I8 => dynasm!(ops ; movsx Rq(r), Rb(r)),
+        PrimitiveValue::U16 => dynasm!(ops ; movzx Rd(r), Rw(r)),
+        PrimitiveValue::I16 => dynasm!(ops ; movsx Rq(r), Rw(r)),
+        PrimitiveValue::U32 => dynasm!(ops ; mov Rd(r), Rd(r)),
+        PrimitiveValue::I32 => dynasm!(ops ; movsxd Rq(r), Rd(r)),
+        PrimitiveValue::U64 | PrimitiveValue::I64 => (),
+        PrimitiveValue::U128 | PrimitiveValue::I128 => {
+            unreachable!("128 bit values are in register pairs")
+        }
+    }
+}
+
+/// Divide two constants the way [`emit_divide`] would at runtime, or `None`
+/// if the division would fault
+fn fold_divide(v1: usize, v2: usize, _type: PrimitiveValue, remainder: bool) -> Option<usize> {
+    let (v1, v2) = (truncate(v1, _type), truncate(v2, _type));
+    if v2 == 0 {
+        return None;
+    }
+    let result = if _type.is_signed() {
+        let shift = 64 - _type.size() * 8;
+        let extend = |v: usize| ((v << shift) as i64) >> shift;
+        let (v1, v2) = (extend(v1), extend(v2));
+        // i8 and i16 are divided in 32 bits, so only the wider types overflow
+        if shift <= 32 && v2 == -1 && v1 == i64::MIN >> shift {
+            return None;
+        }
+        if remainder {
+            v1.wrapping_rem(v2) as usize
+        } else {
+            v1.wrapping_div(v2) as usize
+        }
+    } else if remainder {
+        v1 % v2
+    } else {
+        v1 / v2
+    };
+    Some(truncate(result, _type))
+}
+
+/// Emit a division, putting either the quotient or the remainder in `dest`.
+///
+/// `div` and `idiv` need rax and rdx; rax isn't allocated but rdx is, so
+/// it's saved around the division.  Dividing by zero raises SIGFPE.
+fn emit_divide(
+    ops: &mut Assembler,
+    dest: MachineRegister,
+    _type: PrimitiveValue,
+    src1: Value,
+    src2: Value,
+    register_map: &BTreeMap<RegisterIndex, MachineRegister>,
+    remainder: bool,
+) {
+    if let (Value::Immediate { value: v1, .. }, Value::Immediate { value: v2, .. }) = (src1, src2) {
+        if let Some(result) = fold_divide(v1, v2, _type, remainder) {
+            emit_mov_imm(ops, dest, result, _type);
+            return;
+        }
+    }
+    // read both operands before rdx is clobbered
+    emit_mov_value(ops, MachineRegister::Rcx, src2, register_map);
+    emit_mov_value(ops, MachineRegister::Rax, src1, register_map);
+    dynasm!(ops
+            ; push rdx
+    );
+    match _type {
+        PrimitiveValue::I8 => {
+            dynasm!(ops
+                    ; movsx eax, al
+                    ; movsx ecx, cl
+                    ; cdq
+                    ; idiv ecx
+            );
+        }
+        PrimitiveValue::I16 => {
+            dynasm!(ops
+                    ; movsx eax, ax
+                    ; movsx ecx, cx
+                    ; cdq
+                    ; idiv ecx
+            );
+        }
+        PrimitiveValue::I32 => {
+            dynasm!(ops
+                    ; cdq
+                    ; idiv ecx
+            );
+        }
+        PrimitiveValue::I64 => {
+            dynasm!(ops
+                    ; cqo
+                    ; idiv rcx
+            );
+        }
+        PrimitiveValue::U8 => {
+            dynasm!(ops
+                    ; movzx eax, al
+                    ; movzx ecx, cl
+                    ; xor edx, edx
+                    ; div ecx
+            );
+        }
+        PrimitiveValue::U16 => {
+            dynasm!(ops
+                    ; movzx eax, ax
+                    ; movzx ecx, cx
+                    ; xor edx, edx
+                    ; div ecx
+            );
+        }
+        PrimitiveValue::U32 => {
+            dynasm!(ops
+                    ; xor edx, edx
+                    ; div ecx
+            );
+        }
+        PrimitiveValue::U64 => {
+            dynasm!(ops
+                    ; xor edx, edx
+                    ; div rcx
+            );
+        }
+        // rejected by `check_supported`
+        PrimitiveValue::U128 | PrimitiveValue::I128 => unreachable!("128 bit division"),
+    }
+    if remainder {
+        dynasm!(ops
+                ; mov rax, rdx
+        );
+    }
+    // restore rdx before writing the result in case it's the destination
+    dynasm!(ops
+            ; pop rdx
+            ; mov Ra(dest as u8), rax
+    );
+}
+
+/// Half of a 128 bit value
+#[derive(Clone, Copy)]
+enum Half {
+    Register(MachineRegister),
+    Constant(u64),
+}
+
+/// The low and high halves of a 128 bit value.  Immediates are 64 bits,
+/// extended the way their type is.
+fn halves(
+    value: Value,
+    register_map: &BTreeMap<RegisterIndex, MachineRegister>,
+    high_halves: &BTreeMap<RegisterIndex, MachineRegister>,
+) -> (Half, Half) {
+    match value {
+        Value::Register(r) => (
+            Half::Register(register_map[&r]),
+            Half::Register(high_halves[&r]),
+        ),
+        Value::Immediate { _type, value } => {
+            let negative = _type.is_signed() && (value as i64) < 0;
+            (
+                Half::Constant(value as u64),
+                Half::Constant(if negative { u64::MAX } else { 0 }),
+            )
+        }
+    }
+}
+
+fn emit_mov_half(ops: &mut Assembler, dest: MachineRegister, half: Half) {
+    match half {
+        Half::Register(r) if r == dest => (),
+        Half::Register(r) => dynasm!(ops ; mov Rq(dest as u8), Rq(r as u8)),
+        Half::Constant(c) => emit_mov_imm(ops, dest, c as usize, PrimitiveValue::U64),
+    }
+}
+
+/// Clobbers rax
+fn emit_push_half(ops: &mut Assembler, half: Half) {
+    match half {
+        Half::Register(r) => dynasm!(ops ; push Rq(r as u8)),
+        // pushed immediates are sign extended
+        Half::Constant(c) if c as i64 == c as i32 as i64 => {
+            dynasm!(ops ; push c as i32)
+        }
+        Half::Constant(c) => dynasm!(ops ; mov rax, QWORD c as i64 ; push rax),
+    }
+}
+
+/// Add, subtract, or multiply 128 bit values, keeping the low 128 bits.  The
+/// result is built in rax and rcx before it's moved to `dest`'s pair, so the
+/// operands can be in any registers.
+fn emit_wide_arithmetic(
+    ops: &mut Assembler,
+    inst: &IR,
+    register_map: &BTreeMap<RegisterIndex, MachineRegister>,
+    high_halves: &BTreeMap<RegisterIndex, MachineRegister>,
+) {
+    let (dest, src1, src2) = match *inst {
+        IR::Add {
+            dest_register,
+            src1,
+            src2,
+            ..
+        }
+        | IR::Subtract {
+            dest_register,
+            src1,
+            src2,
+            ..
+        }
+        | IR::Multiply {
+            dest_register,
+            src1,
+            src2,
+            ..
+        } => (dest_register, src1, src2),
+        _ => unreachable!("not 128 bit arithmetic: {:?}", inst),
+    };
+    let (a, b) = (
+        halves(src1, register_map, high_halves),
+        halves(src2, register_map, high_halves),
+    );
+    let (dest_low, dest_high) = (register_map[&dest], high_halves[&dest]);
+    if let ((Half::Constant(a0), Half::Constant(a1)), (Half::Constant(b0), Half::Constant(b1))) =
+        (a, b)
+    {
+        let a = (a1 as u128) << 64 | a0 as u128;
+        let b = (b1 as u128) << 64 | b0 as u128;
+        let result = match inst {
+            IR::Add { .. } => a.wrapping_add(b),
+            IR::Subtract { .. } => a.wrapping_sub(b),
+            _ => a.wrapping_mul(b),
+        };
+        emit_mov_half(ops, dest_low, Half::Constant(result as u64));
+        emit_mov_half(ops, dest_high, Half::Constant((result >> 64) as u64));
+        return;
+    }
+    match inst {
+        IR::Multiply { .. } => {
+            // (a1 * 2^64 + a0) * (b1 * 2^64 + b0) is a0 * b0 plus the cross
+            // terms in the high half.  mul needs rdx, and rax and rcx are all
+            // that's free, so the operands are read from the stack.
+            dynasm!(ops
+                    ; push rdx
+            );
+            emit_push_half(ops, a.1);
+            emit_push_half(ops, a.0);
+            emit_push_half(ops, b.1);
+            emit_push_half(ops, b.0);
+            dynasm!(ops
+                    ; mov rax, [rsp + 24]
+                    ; imul rax, [rsp]
+                    ; mov rcx, [rsp + 16]
+                    ; imul rcx, [rsp + 8]
+                    ; add rcx, rax
+                    ; mov rax, [rsp + 16]
+                    ; mul QWORD [rsp]
+                    ; add rcx, rdx
+                    ; add rsp, 32
+                    // restore rdx before writing the result in case it's
+                    // part of the destination
+                    ; pop rdx
+            );
+        }
+        _ => {
+            // subtracting a constant is adding its negation, and a constant
+            // is added to rather than added, so b ends up in registers
+            let (a, b, subtract) = match (inst, b) {
+                (IR::Subtract { .. }, (Half::Constant(b0), Half::Constant(b1))) => {
+                    let negated = ((b1 as u128) << 64 | b0 as u128).wrapping_neg();
+                    let negated = (
+                        Half::Constant(negated as u64),
+                        Half::Constant((negated >> 64) as u64),
+                    );
+                    (negated, a, false)
+                }
+                (IR::Subtract { .. }, _) => (a, b, true),
+                (_, (Half::Constant(_), _)) => (b, a, false),
+                _ => (a, b, false),
+            };
+            let (b0, b1) = match b {
+                (Half::Register(b0), Half::Register(b1)) => (b0 as u8, b1 as u8),
+                _ => unreachable!("constants are handled above"),
+            };
+            emit_mov_half(ops, MachineRegister::Rax, a.0);
+            emit_mov_half(ops, MachineRegister::Rcx, a.1);
+            // the carry from the low halves goes into the high halves, so
+            // nothing that changes the flags can come between them
+            if subtract {
+                dynasm!(ops
+                        ; sub rax, Rq(b0)
+                        ; sbb rcx, Rq(b1)
+                );
+            } else {
+                dynasm!(ops
+                        ; add rax, Rq(b0)
+                        ; adc rcx, Rq(b1)
+                );
+            }
+        }
+    }
+    dynasm!(ops
+            ; mov Rq(dest_low as u8), rax
+            ; mov Rq(dest_high as u8), rcx
+    );
+}
+
+/// What [`emit_checked_arithmetic`] does about a result that doesn't fit
+#[derive(Clone, Copy)]
+enum OnOverflow {
+    /// Branch to the label
+    Trap(DynamicLabel),
+    /// Set the register to 1 if it doesn't fit, and 0 if it does
+    Flag(MachineRegister),
+}
+
+/// Which flag says the result of checked arithmetic didn't fit
+#[derive(Clone, Copy)]
+enum OverflowCondition {
+    NotEqual,
+    Overflow,
+    Carry,
+}
+
+/// Add, subtract, or multiply, doing `on_overflow` if the result doesn't fit
+/// in `_type`.  Clobbers rax and rcx.
+fn emit_checked_arithmetic(
+    ops: &mut Assembler,
+    inst: &IR,
+    _type: PrimitiveValue,
+    register_map: &BTreeMap<RegisterIndex, MachineRegister>,
+    on_overflow: OnOverflow,
+) {
+    let (dest_register, src1, src2) = match *inst {
+        IR::Add {
+            dest_register,
+            src1,
+            src2,
+            ..
+        }
+        | IR::Subtract {
+            dest_register,
+            src1,
+            src2,
+            ..
+        }
+        | IR::Multiply {
+            dest_register,
+            src1,
+            src2,
+            ..
+        } => (dest_register, src1, src2),
+        _ => unreachable!("not arithmetic that can overflow: {:?}", inst),
+    };
+    let mdest = register_map[&dest_register];
+    emit_mov_value(ops, MachineRegister::Rax, src1, register_map);
+    emit_mov_value(ops, MachineRegister::Rcx, src2, register_map);
+    let condition = if _type.size() < 8 {
+        // the exact result fits in 64 bits, so it fits in the type if
+        // narrowing it and extending it back gives the same thing
+        emit_extend(ops, MachineRegister::Rax, _type);
+        emit_extend(ops, MachineRegister::Rcx, _type);
+        match inst {
+            IR::Add { .. } => dynasm!(ops ; add rax, rcx),
+            IR::Subtract { .. } => dynasm!(ops ; sub rax, rcx),
+            _ => dynasm!(ops ; imul rax, rcx),
+        }
+        dynasm!(ops
+                ; mov rcx, rax
+        );
+        emit_extend(ops, MachineRegister::Rcx, _type);
+        dynasm!(ops
+                ; cmp rcx, rax
+        );
+        OverflowCondition::NotEqual
+    } else if _type.is_signed() {
+        match inst {
+            IR::Add { .. } => dynasm!(ops ; add rax, rcx),
+            IR::Subtract { .. } => dynasm!(ops ; sub rax, rcx),
+            _ => dynasm!(ops ; imul rax, rcx),
+        }
+        OverflowCondition::Overflow
+    } else {
+        match inst {
+            IR::Add { .. } => dynasm!(ops ; add rax, rcx),
+            IR::Subtract { .. } => dynasm!(ops ; sub rax, rcx),
+            // only the one operand form of mul sets the carry flag on
+            // unsigned overflow, and it writes the high half to rdx
+            _ => dynasm!(ops
+                         ; push rdx
+                         ; mul rcx
+                         ; pop rdx
+            ),
+        }
+        OverflowCondition::Carry
+    };
+    match (on_overflow, condition) {
+        (OnOverflow::Trap(trap), OverflowCondition::NotEqual) => dynasm!(ops ; jne => trap),
+        (OnOverflow::Trap(trap), OverflowCondition::Overflow) => dynasm!(ops ; jo => trap),
+        (OnOverflow::Trap(trap), OverflowCondition::Carry) => dynasm!(ops ; jc => trap),
+        (OnOverflow::Flag(mflag), _) => {
+            // rcx isn't needed anymore, and nothing in between touches the
+            // flags
+            match condition {
+                OverflowCondition::NotEqual => dynasm!(ops ; setne cl),
+                OverflowCondition::Overflow => dynasm!(ops ; seto cl),
+                OverflowCondition::Carry => dynasm!(ops ; setc cl),
+            }
+            dynasm!(ops
+                    ; movzx Rd(mflag as u8), cl
+            );
+        }
+    }
+    dynasm!(ops
+            ; mov Ra(mdest as u8), rax
+    );
+}
+
+/// Compare `src1` to `src2` as values of `_type`, setting the flags.
+/// Clobbers rax and rcx.
+fn emit_compare(
+    ops: &mut Assembler,
+    _type: PrimitiveValue,
+    src1: Value,
+    src2: Value,
+    register_map: &BTreeMap<RegisterIndex, MachineRegister>,
+) {
+    // extending both to 64 bits keeps their order, signed or not
+    emit_mov_value(ops, MachineRegister::Rax, src1, register_map);
+    emit_mov_value(ops, MachineRegister::Rcx, src2, register_map);
+    emit_extend(ops, MachineRegister::Rax, _type);
+    emit_extend(ops, MachineRegister::Rcx, _type);
+    dynasm!(ops
+            ; cmp rax, rcx
+    );
+}
+
+/// Jump to `target` if the flags from a `cmp` say `comparison` holds
+fn emit_jcc(ops: &mut Assembler, comparison: Comparison, signed: bool, target: DynamicLabel) {
+    match (comparison, signed) {
+        (Comparison::Equal, _) => dynasm!(ops ; je => target),
+        (Comparison::NotEqual, _) => dynasm!(ops ; jne => target),
+        (Comparison::Less, true) => dynasm!(ops ; jl => target),
+        (Comparison::Less, false) => dynasm!(ops ; jb => target),
+        (Comparison::LessOrEqual, true) => dynasm!(ops ; jle => target),
+        (Comparison::LessOrEqual, false) => dynasm!(ops ; jbe => target),
+        (Comparison::Greater, true) => dynasm!(ops ; jg => target),
+        (Comparison::Greater, false) => dynasm!(ops ; ja => target),
+        (Comparison::GreaterOrEqual, true) => dynasm!(ops ; jge => target),
+        (Comparison::GreaterOrEqual, false) => dynasm!(ops ; jae => target),
+    }
+}
+
+/// Set al to 1 if the flags from a `cmp` say `comparison` holds, otherwise 0
+fn emit_setcc(ops: &mut Assembler, comparison: Comparison, signed: bool) {
+    match (comparison, signed) {
+        (Comparison::Equal, _) => dynasm!(ops ; sete al),
+        (Comparison::NotEqual, _) => dynasm!(ops ; setne al),
+        (Comparison::Less, true) => dynasm!(ops ; setl al),
+        (Comparison::Less, false) => dynasm!(ops ; setb al),
+        (Comparison::LessOrEqual, true) => dynasm!(ops ; setle al),
+        (Comparison::LessOrEqual, false) => dynasm!(ops ; setbe al),
+        (Comparison::Greater, true) => dynasm!(ops ; setg al),
+        (Comparison::Greater, false) => dynasm!(ops ; seta al),
+        (Comparison::GreaterOrEqual, true) => dynasm!(ops ; setge al),
+        (Comparison::GreaterOrEqual, false) => dynasm!(ops ; setae al),
+    }
+}
+
+/// Shift a constant the way [`emit_shift`] would at runtime
+fn fold_shift(value: usize, count: usize, _type: PrimitiveValue, right: bool) -> usize {
+    let bits = _type.size() * 8;
+    let count = count % bits;
+    let result = if !right {
+        value << count
+    } else if _type.is_signed() {
+        let shift = 64 - bits;
+        ((((value << shift) as i64) >> shift) >> count) as usize
+    } else {
+        truncate(value, _type) >> count
+    };
+    truncate(result, _type)
+}
+
+/// Emit a shift of `src1` by `src2` bits.
+///
+/// A constant count is encoded in the instruction; otherwise it has to be in
+/// cl, so rcx is clobbered.
+#[allow(clippy::too_many_arguments)]
+fn emit_shift(
+    ops: &mut Assembler,
+    dest: MachineRegister,
+    _type: PrimitiveValue,
+    src1: Value,
+    src2: Value,
+    register_map: &BTreeMap<RegisterIndex, MachineRegister>,
+    right: bool,
+    features: CpuFeatures,
+) {
+    let bits = _type.size() * 8;
+    // BMI2 shifts take the count in any register and leave the source alone,
+    // masking the count to 6 bits, which is only right for 64 bit types
+    if let (true, 64, Value::Register(s), Value::Register(c)) = (features.bmi2, bits, src1, src2) {
+        let (d, s, c) = (dest as u8, register_map[&s] as u8, register_map[&c] as u8);
+        match (right, _type.is_signed()) {
+            (false, _) => dynasm!(ops ; shlx Rq(d), Rq(s), Rq(c)),
+            (true, false) => dynasm!(ops ; shrx Rq(d), Rq(s), Rq(c)),
+            (true, true) => dynasm!(ops ; sarx Rq(d), Rq(s), Rq(c)),
+        }
+        return;
+    }
+    let count = match src2 {
+        Value::Immediate { value: count, .. } => {
+            if let Value::Immediate { value, .. } = src1 {
+                emit_mov_imm(ops, dest, fold_shift(value, count, _type, right), _type);
+                return;
+            }
+            Some((count % bits) as i8)
+        }
+        Value::Register(r) => {
+            // loaded first, dest may be the count's register
+            dynasm!(ops
+                    ; mov rcx, Ra(register_map[&r] as u8)
+            );
+            // the instruction only masks the count to 6 bits
+            if bits < 64 {
+                dynasm!(ops
+                        ; and ecx, (bits - 1) as i32
+                );
+            }
+            None
+        }
+    };
+    emit_mov_value(ops, dest, src1, register_map);
+    if right {
+        // the bits above the type's width are shifted down into it
+        emit_extend(ops, dest, _type);
+    }
+    let d = dest as u8;
+    match (count, right, _type.is_signed()) {
+        (Some(0), _, _) => (),
+        (Some(count), false, _) => dynasm!(ops ; shl Ra(d), count),
+        (Some(count), true, false) => dynasm!(ops ; shr Ra(d), count),
+        (Some(count), true, true) => dynasm!(ops ; sar Ra(d), count),
+        (None, false, _) => dynasm!(ops ; shl Ra(d), cl),
+        (None, true, false) => dynasm!(ops ; shr Ra(d), cl),
+        (None, true, true) => dynasm!(ops ; sar Ra(d), cl),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub enum RegisterEvent {
+    Acquire(usize),
+    Release(usize),
+}
+
+#[derive(Debug)]
+pub enum CodeGenErrorReason {
+    RegisterValueTaken(usize),
+    RegisterNotFound(usize),
+    TypeMismatch(PrimitiveValue, PrimitiveValue),
+    CodeGenFailure,
+    /// The [`CodeGenOptions`] can't be used together
+    IncompatibleOptions(&'static str),
+    /// One of the [`CodeGenOptions`] has a value that can't be used
+    UnsupportedOptions(&'static str),
+    /// Two functions given to [`generate_entry_points`] have the same name
+    DuplicateEntryPoint(String),
+    /// Executable memory for the code couldn't be allocated
+    OutOfMemory(std::io::Error),
+    /// The code couldn't be written to [`CodeGenOptions::dump_code_to`]
+    DumpCode(std::io::Error),
+    /// The code is bigger than [`CodeGenOptions::max_code_size`]
+    CodeTooLarge { size: usize, limit: usize },
+    /// A jump or label reference couldn't be resolved
+    Relocation(dynasmrt::DynasmError),
+    /// The code refers to something at a fixed address in this process, so
+    /// it can't be written out as an object file
+    NotRelocatable(&'static str),
+    /// Inline code can only take and produce values in rax and rcx
+    TooManyInlineOperands,
+    /// A jump to a block that isn't in the [`Context`]
+    UnknownBlock(BasicBlockIndex),
+    /// Values can't be pinned to the stack or frame pointer, or to registers
+    /// that are fixed by the [`RegisterConstraints`]
+    CantPin(MachineRegister),
+    /// A `ReturnStruct` whose values don't match the fields given to
+    /// [`Context::set_return_struct`], or that there aren't any
+    ReturnStructMismatch,
+    /// A `Call` with more arguments than fit in the argument registers
+    TooManyCallArguments,
+    /// The new code for a block passed to [`recompile_block`] is `size`
+    /// bytes, but there's only `room` for it
+    PatchTooLarge { size: usize, room: usize },
+    /// The block can't be recompiled on its own because something outside of
+    /// it changed too
+    CantPatchBlock(&'static str),
+    /// The [`Context`] was changed after [`Context::finalize`] was last
+    /// called, or it never was
+    NotFinalized,
+    /// A `U128` or `I128` value used by something other than a `Parameter`,
+    /// `Copy`, `ReturnValue`, or an `Add`, `Subtract`, or `Multiply` that
+    /// wraps
+    Unsupported128Bit,
+}
+
+pub fn set_up_constants(
+    ctx: &Context,
+    ops: &mut Assembler,
+) -> BTreeMap<ConstantIndex, DynamicLabel> {
+    let mut constant_map: BTreeMap<ConstantIndex, DynamicLabel> = BTreeMap::new();
+    for (i, constant) in ctx.constants.iter().enumerate() {
+        // TODO: investigate dynamic vs global labels
+        let dyn_lab = ops.new_dynamic_label();
+        dynasm!(ops
+                ; => dyn_lab
+                ; .bytes constant.as_slice()
+        );
+        constant_map.insert(ConstantIndex::new(i as _), dyn_lab);
+    }
+    constant_map
+}
+
+/// Like [`set_up_constants`] for several `Context`s at once.  Constants with
+/// the same bytes are only emitted once and share a label, even if they're
+/// in different `Context`s.  The maps are in the same order as `ctxs`.
+pub fn set_up_shared_constants(
+    ctxs: &[&Context],
+    ops: &mut Assembler,
+) -> Vec<BTreeMap<ConstantIndex, DynamicLabel>> {
+    let mut emitted: BTreeMap<&[u8], DynamicLabel> = BTreeMap::new();
+    let mut constant_maps = vec![];
+    for ctx in ctxs {
+        let mut constant_map: BTreeMap<ConstantIndex, DynamicLabel> = BTreeMap::new();
+        for (i, constant) in ctx.constants.iter().enumerate() {
+            let dyn_lab = *emitted.entry(constant.as_slice()).or_insert_with(|| {
+                let dyn_lab = ops.new_dynamic_label();
+                dynasm!(ops
+                        ; => dyn_lab
+                        ; .bytes constant.as_slice()
+                );
+                dyn_lab
+            });
+            constant_map.insert(ConstantIndex::new(i as _), dyn_lab);
+        }
+        constant_maps.push(constant_map);
+    }
+    constant_maps
+}
+
+/// A callback for [`CodeGenOptions::on_lower_instruction`].  It's given the
+/// block, the index of the instruction in the block, the instruction, and the
+/// offset in the buffer that its code starts at.
+#[derive(Clone)]
+pub struct InstructionHook(Arc<InstructionHookFn>);
+
+type InstructionHookFn = dyn Fn(BasicBlockIndex, usize, &IR, AssemblyOffset) + Send + Sync;
+
+impl InstructionHook {
+    pub fn new(
+        hook: impl Fn(BasicBlockIndex, usize, &IR, AssemblyOffset) + Send + Sync + 'static,
+    ) -> Self {
+        Self(Arc::new(hook))
+    }
+}
+
+impl std::fmt::Debug for InstructionHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("InstructionHook")
+    }
+}
+
+/// Knobs for [`generate_code_with_options`]
+#[derive(Debug, Clone)]
+pub struct CodeGenOptions {
+    /// Register unwind information for the generated function with the
+    /// platform unwinder so backtraces can walk through JITted frames
+    pub emit_unwind_info: bool,
+    /// Don't set up rbp as a frame pointer, addressing the stack relative to
+    /// rsp instead and letting the register allocator use rbp.
+    ///
+    /// All stack allocations are currently fixed size, so this is always
+    /// possible.  The unwind info assumes a frame pointer, so this can't be
+    /// combined with `emit_unwind_info`.
+    pub omit_frame_pointer: bool,
+    /// The CPU features lowerings are allowed to use, or `None` to use
+    /// whatever the host supports
+    pub cpu_features: Option<CpuFeatures>,
+    /// Write the raw machine code of the function to this file, useful for
+    /// disassembling the output
+    pub dump_code_to: Option<std::path::PathBuf>,
+    /// Functions start at a multiple of this many bytes, padded with nops.
+    /// Must be a power of 2, or generating code fails with
+    /// [`CodeGenErrorReason::UnsupportedOptions`].
+    pub function_alignment: usize,
+    /// What the padding before functions is filled with.  It's always
+    /// written out, so the same `Context` and options give the same bytes.
+    pub padding: Padding,
+    /// Fail with [`CodeGenErrorReason::CodeTooLarge`] rather than map more
+    /// than this many bytes of code and constants
+    pub max_code_size: Option<usize>,
+    /// Requirements on which machine registers IR registers get
+    pub register_constraints: RegisterConstraints,
+    /// Called as each instruction is lowered, in the order the code is laid
+    /// out.  Useful for mapping instructions to the machine code they became.
+    pub on_lower_instruction: Option<InstructionHook>,
+    /// Fill in [`GeneratedCode::instruction_offsets`]
+    pub record_instruction_offsets: bool,
+    /// Check `IR::Assert`s, which are left out otherwise
+    pub debug_assertions: bool,
+    /// Leave this many bytes of nops after each basic block, so a version of
+    /// it that's grown still fits when it's passed to [`recompile_block`]
+    pub block_patch_room: usize,
+    /// Call host functions with a relative `call` whose displacement is
+    /// filled in through a [`RelocationTarget::HostFunctionCall`], rather
+    /// than loading their absolute address.  This is for code that's linked,
+    /// like [`crate::codegen::elf::emit_elf`]'s; the displacements are left
+    /// as 0, so generating code to run with it set fails with
+    /// [`CodeGenErrorReason::UnsupportedOptions`].
+    pub position_independent: bool,
+    /// Pass what the code prints to this instead of writing it to stdout
+    pub print_handler: Option<PrintHandler>,
+}
+
+impl Default for CodeGenOptions {
+    fn default() -> Self {
+        Self {
+            emit_unwind_info: false,
+            omit_frame_pointer: false,
+            cpu_features: None,
+            dump_code_to: None,
+            function_alignment: 16,
+            padding: Padding::Nops,
+            max_code_size: None,
+            register_constraints: RegisterConstraints::default(),
+            on_lower_instruction: None,
+            record_instruction_offsets: false,
+            debug_assertions: false,
+            block_patch_room: 0,
+            position_independent: false,
+            print_handler: None,
+        }
+    }
+}
+
+/// How gaps in the code are filled, see [`CodeGenOptions::padding`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+    /// As few nops as possible
+    Nops,
+    /// Every byte is this one, like 0xCC to trap if it's ever run
+    Byte(u8),
+}
+
+/// The recommended nop encodings, from 1 to 9 bytes long; see the Intel
+/// optimization manual, section 3.5.1.9 "Using NOPs"
+const MULTI_BYTE_NOPS: [&[u8]; 9] = [
+    &[0x90],
+    &[0x66, 0x90],
+    &[0x0F, 0x1F, 0x00],
+    &[0x0F, 0x1F, 0x40, 0x00],
+    &[0x0F, 0x1F, 0x44, 0x00, 0x00],
+    &[0x66, 0x0F, 0x1F, 0x44, 0x00, 0x00],
+    &[0x0F, 0x1F, 0x80, 0x00, 0x00, 0x00, 0x00],
+    &[0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    &[0x66, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+];
+
+/// Pad up to the next multiple of `alignment`
+fn emit_padding(ops: &mut Assembler, alignment: usize, padding: Padding) {
+    assert!(
+        alignment.is_power_of_two(),
+        "Alignment must be a power of 2"
+    );
+    let remaining = (alignment - ops.offset().0 % alignment) % alignment;
+    if let Padding::Byte(byte) = padding {
+        dynasm!(ops
+                ; .bytes vec![byte; remaining]
+        );
+        return;
+    }
+    emit_nops(ops, remaining);
+}
+
+/// Emit `count` bytes of nops
+fn emit_nops(ops: &mut Assembler, mut remaining: usize) {
+    while remaining > 0 {
+        let len = remaining.min(MULTI_BYTE_NOPS.len());
+        dynasm!(ops
+                ; .bytes MULTI_BYTE_NOPS[len - 1]
+        );
+        remaining -= len;
+    }
+}
+
+/// The output of code generation.
+#[derive(Debug)]
+pub struct GeneratedCode {
+    // NOTE: declared first so the unwinder forgets about the code before it's unmapped
+    pub unwind_info: Option<UnwindRegistration>,
+    pub buffer: ExecutableBuffer,
+    /// Where the generated function starts in `buffer`
+    pub start: AssemblyOffset,
+    /// The CPU features the code was generated for
+    pub cpu_features: CpuFeatures,
+    /// The machine register each IR register was assigned, or the low half
+    /// of 128 bit ones
+    pub register_map: BTreeMap<RegisterIndex, MachineRegister>,
+    /// The machine register the high half of each 128 bit register was
+    /// assigned
+    pub high_halves: BTreeMap<RegisterIndex, MachineRegister>,
+    /// The addresses in the code that depend on where it's loaded
+    pub relocations: Vec<Relocation>,
+    /// Where the code for each instruction starts, in the order the code is
+    /// laid out, if [`CodeGenOptions::record_instruction_offsets`] was set.
+    /// Instructions that don't need any code start where the next one does.
+    pub instruction_offsets: Option<Vec<(BasicBlockIndex, usize, AssemblyOffset)>>,
+    /// Where the code for each basic block starts and ends in `buffer`,
+    /// including the room left by [`CodeGenOptions::block_patch_room`].
+    /// The moves for phis on some edges are put after all of the blocks and
+    /// aren't included.
+    pub block_ranges: BTreeMap<BasicBlockIndex, (AssemblyOffset, AssemblyOffset)>,
+    /// The stack frame the function sets up
+    pub frame_layout: FrameLayout,
+    pub spill_report: SpillReport,
+    /// The linear memory the code accesses, kept alive for as long as the
+    /// code is
+    linear_memory: Option<Arc<MemoryAllocation>>,
+    /// The [`CodeGenOptions::print_handler`] the code passes a pointer to,
+    /// boxed so it stays put
+    print_handler: Option<Box<PrintHandler>>,
+}
+
+/// The stack frame set up by the prologue of a generated function.
+///
+/// Offsets are in bytes from the stack pointer on entry, which points at the
+/// return address, so they're all negative.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameLayout {
+    /// The registers the prologue pushes, in order, and where each is saved.
+    /// rbp and rbx always are, then the other callee-saved registers the
+    /// function uses, then rdi and rsi.
+    pub saved_registers: Vec<(MachineRegister, i32)>,
+    /// Whether rbp is set up to point at where its old value is saved
+    pub frame_pointer: bool,
+    /// How many bytes are reserved right below the saved rbp for stack slots
+    pub frame_size: usize,
+    /// Where the slot each `Alloca` points to starts, including the ones
+    /// added for registers spilled when there weren't enough machine
+    /// registers.
+    pub slots: BTreeMap<RegisterIndex, i32>,
+}
+
+/// The registers that were spilled to the stack because there weren't
+/// enough machine registers, to help restructure code that needs too many
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SpillReport {
+    pub spills: Vec<SpilledRegister>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpilledRegister {
+    pub register: RegisterIndex,
+    /// Where its stack slot starts, like the offsets in [`FrameLayout`].
+    /// Registers that are never live at the same time can share a slot.
+    pub slot: i32,
+    /// The instructions it's loaded back from the stack for, by block and
+    /// index.  A load at the end of a block is for the phis of a successor
+    /// and is counted as part of the block's last instruction.
+    pub reloads: Vec<(BasicBlockIndex, usize)>,
+}
+
+/// A field in the generated code holding an address, which would have to be
+/// patched to move the code somewhere else
+#[derive(Debug, Clone)]
+pub struct Relocation {
+    /// Where the field starts in the buffer
+    pub offset: AssemblyOffset,
+    pub target: RelocationTarget,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RelocationTarget {
+    /// A 4 byte displacement to the constant, relative to the end of the field
+    Constant(ConstantIndex),
+    /// The 8 byte address of the host function exported as this symbol
+    HostFunction(&'static str),
+    /// A 4 byte displacement to the host function exported as this symbol,
+    /// relative to the end of the field, in a `call`.  See
+    /// [`CodeGenOptions::position_independent`].
+    HostFunctionCall(&'static str),
+}
+
+impl GeneratedCode {
+    /// The machine register `reg` was assigned, if it was used
+    pub fn machine_register(&self, reg: RegisterIndex) -> Option<MachineRegister> {
+        self.register_map.get(&reg).copied()
+    }
+
+    /// The machine code of the generated function
+    pub fn code(&self) -> &[u8] {
+        &self.buffer[self.start.0..]
+    }
+
+    /// Make the generated function callable as an `F`; see [`JitFunction`]
+    ///
+    /// # Safety
+    ///
+    /// Same as [`JitFunction::new`].
+    pub unsafe fn into_function<F: Copy>(self) -> JitFunction<F> {
+        JitFunction::new(self)
+    }
+}
+
+/// A generated function together with the code it's in, so the code can't be
+/// freed while the function can still be called.
+///
+/// `F` is the function's type, an `extern "C" fn` with the parameters added
+/// with [`Context::add_parameter`], and `call` takes the same arguments.
+pub struct JitFunction<F> {
+    code: GeneratedCode,
+    function: F,
+}
+
+impl<F: Copy> JitFunction<F> {
+    /// # Safety
+    ///
+    /// `F` must be an `extern "C" fn` type matching the parameters and return
+    /// value of the generated function.
+    pub unsafe fn new(code: GeneratedCode) -> Self {
+        assert_eq!(
+            std::mem::size_of::<F>(),
+            std::mem::size_of::<*const u8>(),
+            "JitFunction needs a function pointer type"
+        );
+        let ptr = code.buffer.ptr(code.start);
+        let function = std::mem::transmute_copy(&ptr);
+        Self { code, function }
+    }
+
+    pub fn code(&self) -> &GeneratedCode {
+        &self.code
+    }
+
+    /// Patch in new code for `block`; see [`recompile_block`].  The code
+    /// stays where it is, so the function doesn't change.
+    pub fn recompile_block(
+        &mut self,
+        ctx: &Context,
+        block: BasicBlockIndex,
+        options: &CodeGenOptions,
+    ) -> Result<(), CodeGenError> {
+        recompile_block(ctx, block, &mut self.code, options)
+    }
+}
+
+impl<F> std::fmt::Debug for JitFunction<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("JitFunction")
+            .field("start", &self.code.buffer.ptr(self.code.start))
+            .finish()
+    }
+}
+
+macro_rules! impl_jit_function_call {
+    ($($arg:ident),*) => {
+        impl<R, $($arg),*> JitFunction<extern "C" fn($($arg),*) -> R> {
+            /// Call the generated function
+            #[allow(non_snake_case, clippy::too_many_arguments)]
+            pub fn call(&self, $($arg: $arg),*) -> R {
+                (self.function)($($arg),*)
+            }
+        }
+    };
+}
+
+impl_jit_function_call!();
+impl_jit_function_call!(A);
+impl_jit_function_call!(A, B);
+impl_jit_function_call!(A, B, C);
+impl_jit_function_call!(A, B, C, D);
+impl_jit_function_call!(A, B, C, D, E);
+impl_jit_function_call!(A, B, C, D, E, G);
+impl_jit_function_call!(A, B, C, D, E, G, H);
+impl_jit_function_call!(A, B, C, D, E, G, H, I);
+
+pub fn generate_code(ctx: &Context) -> Result<(ExecutableBuffer, AssemblyOffset), CodeGenError> {
+    generate_code_with_options(ctx, &CodeGenOptions::default()).map(|gc| (gc.buffer, gc.start))
+}
+
+/// Generate code for `ctx` with the default options and copy out just the
+/// bytes of the function, for tools that want to look at it rather than run it
+pub fn compiled_bytes(ctx: &Context) -> Result<Vec<u8>, CodeGenError> {
+    generate_code_with_options(ctx, &CodeGenOptions::default()).map(|gc| gc.code().to_vec())
+}
+
+pub fn generate_code_with_options(
+    ctx: &Context,
+    options: &CodeGenOptions,
+) -> Result<GeneratedCode, CodeGenError> {
+    check_runnable(options)?;
+    generate_code_to_link(ctx, options)
+}
+
+/// Position independent code can't be called until it's linked, so it's
+/// only made for [`crate::codegen::elf::emit_elf`]
+fn check_runnable(options: &CodeGenOptions) -> Result<(), CodeGenError> {
+    if options.position_independent {
+        return Err(CodeGenError {
+            location: 0,
+            reason: CodeGenErrorReason::UnsupportedOptions(
+                "position independent code has to be linked with emit_elf",
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Like [`generate_code_with_options`], but the code may be
+/// [`CodeGenOptions::position_independent`], to be written out and linked
+/// rather than run
+pub(crate) fn generate_code_to_link(
+    ctx: &Context,
+    options: &CodeGenOptions,
+) -> Result<GeneratedCode, CodeGenError> {
+    if options.emit_unwind_info && options.omit_frame_pointer {
+        return Err(CodeGenError {
+            location: 0,
+            reason: CodeGenErrorReason::IncompatibleOptions(
+                "unwind info requires a frame pointer",
+            ),
+        });
+    }
+
+    check_supported(ctx, options)?;
+
+    let features = options.cpu_features.unwrap_or_else(cpu_features);
+    let mut ops = Assembler::new().map_err(|e| CodeGenError {
+        location: 0,
+        reason: CodeGenErrorReason::OutOfMemory(e),
+    })?;
+
+    dynasm!(ops
+            ; .arch x64
+    );
+
+    // =================================================================
+    // set up the constants
+
+    let constant_map = set_up_constants(ctx, &mut ops);
+    let print_handler = options.print_handler.clone().map(Box::new);
+
+    // =================================================================
+    // generate some machine code
+    let EmittedFunction {
+        start: start_offset,
+        register_map,
+        high_halves,
+        relocations,
+        instruction_offsets,
+        prologue_layout,
+        block_ranges,
+        frame_layout,
+        spill_report,
+        ..
+    } = emit_function(
+        ctx,
+        options,
+        &mut ops,
+        &constant_map,
+        None,
+        print_handler.as_deref(),
+    );
+
+    let buffer = finish_code(ops, options)?;
+    let unwind_info = if options.emit_unwind_info {
+        let code_len = buffer.len() - start_offset.0;
+        let eh_frame = unwind::build_eh_frame(buffer.ptr(start_offset), code_len, prologue_layout);
+        Some(UnwindRegistration::new(eh_frame))
+    } else {
+        None
+    };
+    let generated = GeneratedCode {
+        unwind_info,
+        buffer,
+        start: start_offset,
+        cpu_features: features,
+        register_map,
+        high_halves,
+        relocations,
+        instruction_offsets,
+        block_ranges,
+        frame_layout,
+        spill_report,
+        linear_memory: ctx.linear_memory.as_ref().map(LinearMemory::allocation),
+        print_handler,
+    };
+    if let Some(path) = &options.dump_code_to {
+        dump_code(path, generated.code())?;
+    }
+    Ok(generated)
+}
+
+/// Write `code` out for [`CodeGenOptions::dump_code_to`]
+fn dump_code(path: &std::path::Path, code: &[u8]) -> Result<(), CodeGenError> {
+    std::fs::write(path, code).map_err(|e| CodeGenError {
+        location: 0,
+        reason: CodeGenErrorReason::DumpCode(e),
+    })
+}
+
+/// Generate the code for `block` again and patch it over its old code in
+/// `code`, leaving the rest of the function as it is.
+///
+/// `code` must have been generated from `ctx` with `options`, and only
+/// `block` may have changed since.  The registers in it must get the same
+/// machine registers as before, and the new code has to fit where the old
+/// code was; see [`CodeGenOptions::block_patch_room`].
+pub fn recompile_block(
+    ctx: &Context,
+    block: BasicBlockIndex,
+    code: &mut GeneratedCode,
+    options: &CodeGenOptions,
+) -> Result<(), CodeGenError> {
+    let cant_patch = |reason| CodeGenError {
+        location: 0,
+        reason: CodeGenErrorReason::CantPatchBlock(reason),
+    };
+    check_supported(ctx, options)?;
+    let (old_start, old_end) = *code
+        .block_ranges
+        .get(&block)
+        .ok_or_else(|| cant_patch("the block wasn't in the generated code"))?;
+
+    let mut ops = Assembler::new().map_err(|e| CodeGenError {
+        location: 0,
+        reason: CodeGenErrorReason::OutOfMemory(e),
+    })?;
+    dynasm!(ops
+            ; .arch x64
+    );
+    let constant_map = set_up_constants(ctx, &mut ops);
+    let patch = BlockPatch {
+        block,
+        block_ranges: &code.block_ranges,
+    };
+    let linear_memory = ctx.linear_memory.as_ref().map(LinearMemory::allocation);
+    let same_memory = match (&linear_memory, &code.linear_memory) {
+        (Some(new), Some(old)) => Arc::ptr_eq(new, old),
+        (new, old) => new.is_none() && old.is_none(),
+    };
+    if !same_memory {
+        return Err(cant_patch("the linear memory changed"));
+    }
+    let same_handler = match (&options.print_handler, &code.print_handler) {
+        (Some(new), Some(old)) => Arc::ptr_eq(&new.0, &old.0),
+        (new, old) => new.is_none() && old.is_none(),
+    };
+    if !same_handler {
+        return Err(cant_patch("the print handler changed"));
+    }
+    let emitted = emit_function(
+        ctx,
+        options,
+        &mut ops,
+        &constant_map,
+        Some(&patch),
+        code.print_handler.as_deref(),
+    );
+    // the registers added by spilling are new every time, so they'd never
+    // match the old ones
+    if !emitted.spill_report.spills.is_empty() {
+        return Err(cant_patch("registers were spilled"));
+    }
+    if emitted.start != code.start {
+        return Err(cant_patch("the constants changed"));
+    }
+    if emitted.register_map != code.register_map || emitted.high_halves != code.high_halves {
+        return Err(cant_patch("the registers were allocated differently"));
+    }
+    if emitted.frame_layout != code.frame_layout {
+        return Err(cant_patch("the stack frame changed"));
+    }
+    let (new_start, new_end) = *emitted
+        .block_ranges
+        .get(&block)
+        .ok_or_else(|| cant_patch("the block isn't laid out anymore"))?;
+    if new_start != old_start {
+        return Err(cant_patch("the code before the block changed"));
+    }
+    if new_end > old_end {
+        return Err(CodeGenError {
+            location: 0,
+            reason: CodeGenErrorReason::PatchTooLarge {
+                size: new_end.0 - new_start.0,
+                room: old_end.0 - old_start.0,
+            },
+        });
+    }
+    // the block may fall through to the next one
+    emit_nops(&mut ops, old_end.0 - new_end.0);
+    let new_code = finish_code(ops, options)?;
+    // padding before the function can absorb constants changing size, and
+    // only the block is copied over, so the bytes have to match too
+    if new_code[..code.start.0] != code.buffer[..code.start.0] {
+        return Err(cant_patch("the constants changed"));
+    }
+
+    // changing the protection unmaps the code if it fails, and callers still
+    // hold pointers into it, so there's nothing to return to
+    let mut buffer = std::mem::take(&mut code.buffer)
+        .make_mut()
+        .unwrap_or_else(|e| panic!("Couldn't make the code writable to patch it: {}", e));
+    buffer[old_start.0..old_end.0].copy_from_slice(&new_code[old_start.0..old_end.0]);
+    code.buffer = buffer
+        .make_exec()
+        .unwrap_or_else(|e| panic!("Couldn't make the patched code executable: {}", e));
+
+    let in_block = |r: &Relocation| (old_start.0..old_end.0).contains(&r.offset.0);
+    code.relocations.retain(|r| !in_block(r));
+    code.relocations
+        .extend(emitted.relocations.into_iter().filter(|r| in_block(r)));
+    if let (Some(offsets), Some(new_offsets)) =
+        (&mut code.instruction_offsets, emitted.instruction_offsets)
+    {
+        let at = offsets.iter().position(|(b, _, _)| *b == block);
+        offsets.retain(|(b, _, _)| *b != block);
+        let at = at.unwrap_or(offsets.len());
+        offsets.splice(at..at, new_offsets);
+    }
+    // the block's already been patched even if this fails
+    if let Some(path) = &options.dump_code_to {
+        dump_code(path, code.code())?;
+    }
+    Ok(())
+}
+
+/// Several generated functions in one buffer, from [`generate_entry_points`]
+#[derive(Debug)]
+pub struct GeneratedEntryPoints {
+    pub buffer: ExecutableBuffer,
+    /// Where each function starts in `buffer`, by name
+    pub entries: BTreeMap<String, AssemblyOffset>,
+    /// The CPU features the code was generated for
+    pub cpu_features: CpuFeatures,
+    /// The linear memories the functions access, kept alive for as long as
+    /// the code is
+    _linear_memories: Vec<Arc<MemoryAllocation>>,
+    /// The [`CodeGenOptions::print_handler`] the code passes a pointer to
+    _print_handler: Option<Box<PrintHandler>>,
+}
+
+impl GeneratedEntryPoints {
+    /// The function named `name` as an `F`, if there is one
+    ///
+    /// # Safety
+    ///
+    /// Same as [`JitFunction::new`], and the function mustn't be called after
+    /// `self` is dropped.
+    pub unsafe fn function<F: Copy>(&self, name: &str) -> Option<F> {
+        assert_eq!(
+            std::mem::size_of::<F>(),
+            std::mem::size_of::<*const u8>(),
+            "entry points need a function pointer type"
+        );
+        let ptr = self.buffer.ptr(*self.entries.get(name)?);
+        Some(std::mem::transmute_copy(&ptr))
+    }
+}
+
+/// Generate code for several functions, each named by the `&str` it's paired
+/// with, into one buffer.  Their constants are emitted once for all of them,
+/// so functions built with the same constants share them.
+///
+/// Errors are located by the instruction in the function they're about.
+/// Unwind info can only be emitted for a single function.
+pub fn generate_entry_points(
+    functions: &[(&str, &Context)],
+    options: &CodeGenOptions,
+) -> Result<GeneratedEntryPoints, CodeGenError> {
+    check_runnable(options)?;
+    if options.emit_unwind_info {
+        return Err(CodeGenError {
+            location: 0,
+            reason: CodeGenErrorReason::IncompatibleOptions(
+                "unwind info is only emitted for a single function",
+            ),
+        });
+    }
+    let mut names = BTreeSet::new();
+    for (name, ctx) in functions {
+        if !names.insert(*name) {
+            return Err(CodeGenError {
+                location: 0,
+                reason: CodeGenErrorReason::DuplicateEntryPoint(name.to_string()),
+            });
+        }
+        check_supported(ctx, options)?;
+    }
+
+    let features = options.cpu_features.unwrap_or_else(cpu_features);
+    let mut ops = Assembler::new().map_err(|e| CodeGenError {
+        location: 0,
+        reason: CodeGenErrorReason::OutOfMemory(e),
+    })?;
+
+    dynasm!(ops
+            ; .arch x64
+    );
+
+    let ctxs: Vec<&Context> = functions.iter().map(|(_, ctx)| *ctx).collect();
+    let constant_maps = set_up_shared_constants(&ctxs, &mut ops);
+
+    let print_handler = options.print_handler.clone().map(Box::new);
+    let mut entries = BTreeMap::new();
+    for ((name, ctx), constant_map) in functions.iter().zip(&constant_maps) {
+        let emitted = emit_function(
+            ctx,
+            options,
+            &mut ops,
+            constant_map,
+            None,
+            print_handler.as_deref(),
+        );
+        entries.insert(name.to_string(), emitted.start);
+    }
+
+    let buffer = finish_code(ops, options)?;
+    if let Some(path) = &options.dump_code_to {
+        dump_code(path, &buffer[..])?;
+    }
+    Ok(GeneratedEntryPoints {
+        buffer,
+        entries,
+        cpu_features: features,
+        _linear_memories: functions
+            .iter()
+            .filter_map(|(_, ctx)| ctx.linear_memory.as_ref())
+            .map(LinearMemory::allocation)
+            .collect(),
+        _print_handler: print_handler,
+    })
+}
+
+/// Check that code can be generated for `ctx` with `options`
+fn check_supported(ctx: &Context, options: &CodeGenOptions) -> Result<(), CodeGenError> {
+    if !options.function_alignment.is_power_of_two() {
+        return Err(CodeGenError {
+            location: 0,
+            reason: CodeGenErrorReason::UnsupportedOptions(
+                "the function alignment must be a power of 2",
+            ),
+        });
+    }
+    // the parents of blocks aren't known until then, so liveness would be wrong
+    if !ctx.finalized {
+        return Err(CodeGenError {
+            location: 0,
+            reason: CodeGenErrorReason::NotFinalized,
+        });
+    }
+    for (location, (_, _, inst)) in ctx.iter_instructions().enumerate() {
+        if let Some((left, right)) = crate::validate::operand_type_mismatch(ctx, inst) {
+            return Err(CodeGenError {
+                location,
+                reason: CodeGenErrorReason::TypeMismatch(left, right),
+            });
+        }
+        if let IR::InlineBytes { defines, uses, .. } = inst {
+            if defines.len() > 2 || uses.len() > 2 {
+                return Err(CodeGenError {
+                    location,
+                    reason: CodeGenErrorReason::TooManyInlineOperands,
+                });
+            }
+        }
+        if let IR::Pin { register, .. } = *inst {
+            let frame_pointer = !options.omit_frame_pointer && register == MachineRegister::Rbp;
+            let fixed = options
+                .register_constraints
+                .fixed
+                .values()
+                .any(|f| *f == register);
+            if register == MachineRegister::Rsp || frame_pointer || fixed {
+                return Err(CodeGenError {
+                    location,
+                    reason: CodeGenErrorReason::CantPin(register),
+                });
+            }
+        }
+        let wide = |v: Value| ctx.value_type(v).is_some_and(|t| t.size() > 8);
+        let defines_wide = inst
+            .get_defined_registers()
+            .into_iter()
+            .any(|r| wide(Value::Register(*r)));
+        if defines_wide || inst.get_used_values().into_iter().any(wide) {
+            let supported = match inst {
+                IR::Add { overflow, .. }
+                | IR::Subtract { overflow, .. }
+                | IR::Multiply { overflow, .. } => {
+                    matches!(overflow, Overflow::Wrap | Overflow::Poison)
+                }
+                IR::Copy { .. } | IR::ReturnValue { .. } | IR::Parameter { .. } => true,
+                _ => false,
+            };
+            if !supported {
+                return Err(CodeGenError {
+                    location,
+                    reason: CodeGenErrorReason::Unsupported128Bit,
+                });
+            }
+        }
+        if let IR::Call { args, .. } = inst {
+            if args.len() > ARGUMENT_REGISTERS.len() {
+                return Err(CodeGenError {
+                    location,
+                    reason: CodeGenErrorReason::TooManyCallArguments,
+                });
+            }
+        }
+        if let IR::ReturnStruct { values } = inst {
+            let types = values.iter().map(|v| ctx.value_type(*v));
+            let matches = ctx
+                .return_struct()
+                .is_some_and(|fields| types.eq(fields.iter().map(|f| Some(*f))));
+            if !matches {
+                return Err(CodeGenError {
+                    location,
+                    reason: CodeGenErrorReason::ReturnStructMismatch,
+                });
+            }
+        }
+        // checked before allocating registers, which follows the jumps
+        if let Some(target) = inst
+            .branch_targets()
+            .into_iter()
+            .find(|t| ctx.basic_blocks.get(*t).is_none())
+        {
+            return Err(CodeGenError {
+                location,
+                reason: CodeGenErrorReason::UnknownBlock(target),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// What [`emit_function`] emitted
+struct EmittedFunction {
+    start: AssemblyOffset,
+    register_map: BTreeMap<RegisterIndex, MachineRegister>,
+    high_halves: BTreeMap<RegisterIndex, MachineRegister>,
+    relocations: Vec<Relocation>,
+    instruction_offsets: Option<Vec<(BasicBlockIndex, usize, AssemblyOffset)>>,
+    prologue_layout: PrologueLayout,
+    block_ranges: BTreeMap<BasicBlockIndex, (AssemblyOffset, AssemblyOffset)>,
+    frame_layout: FrameLayout,
+    spill_report: SpillReport,
+}
+
+/// The block [`recompile_block`] is generating again
+struct BlockPatch<'a> {
+    block: BasicBlockIndex,
+    /// Where the code for every block already is
+    block_ranges: &'a BTreeMap<BasicBlockIndex, (AssemblyOffset, AssemblyOffset)>,
+}
+
+/// Emit the function in `ctx` after what's already in `ops`, referring to
+/// its constants through `constant_map`.
+///
+/// With a `patch`, only that block is emitted, at the same offset as before,
+/// and jumps to the other blocks go to where they already are.  The edges
+/// from it that need phi moves are emitted right after it.
+///
+/// `print_handler` is the copy of [`CodeGenOptions::print_handler`] the code
+/// will own, whose address is passed to the print functions.
+fn emit_function(
+    ctx: &Context,
+    options: &CodeGenOptions,
+    ops: &mut Assembler,
+    constant_map: &BTreeMap<ConstantIndex, DynamicLabel>,
+    patch: Option<&BlockPatch>,
+    print_handler: Option<&PrintHandler>,
+) -> EmittedFunction {
+    emit_padding(ops, options.function_alignment, options.padding);
+    let start_offset = ops.offset();
+    let features = options.cpu_features.unwrap_or_else(cpu_features);
+
+    // parameters are left where they're passed if nothing needs the register
+    let mut constraints = options.register_constraints.clone();
+    for (dest, _, location) in parameter_locations(ctx) {
+        if let ArgumentLocation::Register(k) = location {
+            constraints
+                .preferred
+                .entry(dest)
+                .or_insert(ARGUMENT_REGISTERS[k]);
+        }
+    }
+    let Allocation {
+        spilled,
+        register_map,
+        high_halves,
+    } = allocate_spilling(ctx, options, &constraints);
+    // code is generated for the program with the spill code in it, but
+    // instructions are still numbered the way they were
+    let original = ctx;
+    let ctx = spilled.as_ref().map_or(ctx, |spilled| &spilled.ctx);
+    let origins = spilled.as_ref().map(|spilled| &spilled.origins);
+    // registers holding constants that weren't given a machine register
+    let constants = rematerialized_constants(&ctx.basic_blocks, ctx.register_types());
+    // loads from stack slots that arithmetic reads from the slot instead
+    let folded = folded_loads(&ctx.basic_blocks, ctx.register_types(), &constants);
+    // offsets are recorded for the unwind info
+    // rbp is callee-saved so it's pushed even if it's not used as the frame pointer
+    dynasm!(ops
+            ; push rbp
+    );
+    let push_rbp = ops.offset().0 - start_offset.0;
+    if !options.omit_frame_pointer {
+        dynasm!(ops
+                ; mov rbp, rsp
+        );
+    }
+    let set_rbp = ops.offset().0 - start_offset.0;
+    let frame = StackFrame::new(ctx, &register_map, &high_halves, options);
+    if frame.size != 0 {
+        dynasm!(ops
+                ; sub rsp, frame.size
+        );
+    }
+    dynasm!(ops
+            ; push rbx
+    );
+    let push_rbx = ops.offset().0 - start_offset.0;
+    for r in &frame.saved {
+        dynasm!(ops
+                ; push Rq(*r as u8)
+        );
+    }
+    let push_saved = ops.offset().0 - start_offset.0;
+    dynasm!(ops
+            ; push rdi
+            ; push rsi
+    );
+    let prologue_layout = PrologueLayout {
+        push_rbp,
+        set_rbp,
+        push_rbx,
+        frame_size: frame.size as usize,
+        push_saved,
+        // r8 to r15 have the same DWARF numbers as encodings
+        saved: frame.saved.iter().map(|r| *r as u8).collect(),
+    };
+    emit_load_parameters(ops, ctx, &register_map, &high_halves, options, &frame);
+
+    let register_types = ctx.register_types();
+    // pointers to stack slots don't need bounds checks, and neither do loads
+    // from constants.  Stores to constants are caught by the check.
+    let stack_slots: BTreeMap<RegisterIndex, PrimitiveValue> = ctx
+        .iter_instructions()
+        .filter_map(|(_, _, inst)| match inst {
+            IR::Alloca {
+                dest_register,
+                _type,
+                ..
+            } => Some((*dest_register, *_type)),
+            _ => None,
+        })
+        .collect();
+    let constant_addrs: BTreeSet<RegisterIndex> = ctx
+        .iter_instructions()
+        .filter_map(|(_, _, inst)| match inst {
+            IR::ConstantAddr { dest_register, .. } => Some(*dest_register),
+            _ => None,
+        })
+        .collect();
+    // how wide a load through each pointer is
+    let pointee_types = ctx.basic_blocks.pointee_types();
+    let bounds_to_check = |r: &RegisterIndex, store: bool| match ctx.memory_bounds {
+        Some(ref bounds)
+            if !stack_slots.contains_key(r) && (store || !constant_addrs.contains(r)) =>
+        {
+            Some(bounds)
+        }
+        _ => None,
+    };
 
     // TODO: investigate the different types of labels
     let mut bb_map: BTreeMap<BasicBlockIndex, DynamicLabel> = BTreeMap::new();
-    for (i, basic_block) in ctx.iterate_basic_blocks() {
+    // edges that need to set up phis before getting to their target, emitted
+    // after all of the blocks
+    let mut edge_stubs: Vec<(DynamicLabel, PhiMoves, BasicBlockIndex)> = vec![];
+    let mut relocations: Vec<Relocation> = vec![];
+    let mut instruction_offsets = if options.record_instruction_offsets {
+        Some(vec![])
+    } else {
+        None
+    };
+    // how many instructions use each register, to tell if a comparison's
+    // result is only used by the branch after it
+    let mut use_counts: BTreeMap<RegisterIndex, usize> = BTreeMap::new();
+    for (_, _, inst) in ctx.iter_instructions() {
+        for used in inst.get_used_registers() {
+            *use_counts.entry(*used).or_insert(0) += 1;
+        }
+    }
+    // set by a comparison fused with the branch after it
+    let mut fused_comparison: Option<(Comparison, bool)> = None;
+    let mut block_ranges = BTreeMap::new();
+    if let Some(patch) = patch {
+        for (&b, &(start, _)) in patch.block_ranges {
+            if b != patch.block {
+                let label = ops.new_dynamic_label();
+                ops.labels_mut().define_dynamic(label, start).unwrap();
+                bb_map.insert(b, label);
+            }
+        }
+    }
+    let layout = ctx.basic_blocks.layout_order();
+    for (position, &i) in layout.iter().enumerate() {
+        if let Some(patch) = patch {
+            if i != patch.block {
+                continue;
+            }
+            let (start, _) = patch.block_ranges[&i];
+            dynasm!(ops
+                    ; .bytes vec![0; start.0.saturating_sub(ops.offset().0)]
+            );
+        }
+        let basic_block = ctx.basic_blocks.get(i).unwrap();
+        // jumps to this can be left out
+        let next_in_layout = layout.get(position + 1).copied();
+        let block_start = ops.offset();
         let ent = bb_map.entry(i).or_insert_with(|| ops.new_dynamic_label());
         dynasm!(ops
                 ; => *ent);
-        for inst in basic_block.iterate_instructions() {
+        let original_code = original
+            .basic_blocks
+            .get(i)
+            .unwrap()
+            .iterate_instructions()
+            .collect::<Vec<_>>();
+        for (index, inst) in basic_block.iterate_instructions().enumerate() {
+            // spill code is part of the instruction it was added for
+            let (original_index, first) = match origins {
+                Some(origins) => {
+                    let block_origins = &origins[&i];
+                    let first = index == 0 || block_origins[index - 1] != block_origins[index];
+                    (block_origins[index], first)
+                }
+                None => (index, true),
+            };
+            if first {
+                if let Some(ref hook) = options.on_lower_instruction {
+                    let original_inst = original_code[original_index];
+                    (hook.0)(i, original_index, original_inst, ops.offset());
+                }
+                if let Some(ref mut offsets) = instruction_offsets {
+                    offsets.push((i, original_index, ops.offset()));
+                }
+            }
+            // the constant is put wherever the register is used instead
+            let defines = inst.get_defined_registers();
+            if defines
+                .iter()
+                .any(|r| constants.contains_key(r) || folded.contains_key(r))
+            {
+                continue;
+            }
+            let substituted;
+            let inst = if inst
+                .get_used_registers()
+                .iter()
+                .any(|r| constants.contains_key(r))
+            {
+                substituted = with_constants(inst, &constants);
+                &substituted
+            } else {
+                inst
+            };
             match *inst {
                 IR::PrintConstant { ref constant_ref } => {
                     let const_loc = constant_map[constant_ref];
                     let len = ctx.get_constant(*constant_ref).unwrap().len();
+                    emit_save_caller_saved(ops);
+                    dynasm!(ops
+                                ; lea rdi, [=>const_loc]
+                    );
+                    relocations.push(Relocation {
+                        offset: AssemblyOffset(ops.offset().0 - 4),
+                        target: RelocationTarget::Constant(*constant_ref),
+                    });
+                    dynasm!(ops
+                                ; xor esi, esi
+                                ; mov si, BYTE len as _
+                    );
+                    emit_print_handler(ops, MachineRegister::Rdx, print_handler);
+                    let print: unsafe extern "C" fn(*const u8, u64, *const PrintHandler) =
+                        guest_print;
+                    emit_host_call(
+                        ops,
+                        &mut relocations,
+                        print as usize,
+                        "shiba_jit_guest_print",
+                        options,
+                    );
+                    emit_restore_caller_saved(ops);
+                }
+                IR::Assert {
+                    cond,
+                    message_constant,
+                } => {
+                    let always_holds = match cond {
+                        Value::Immediate { _type, value } => truncate(value, _type) != 0,
+                        Value::Register(_) => false,
+                    };
+                    if options.debug_assertions && !always_holds {
+                        let passed = ops.new_dynamic_label();
+                        if let Value::Register(r) = cond {
+                            // only the bits of the type count
+                            let _type = ctx.value_type(cond).unwrap_or(PrimitiveValue::U64);
+                            let r = register_map[&r] as u8;
+                            match _type.size() {
+                                1 => dynasm!(ops ; test Rb(r), Rb(r)),
+                                2 => dynasm!(ops ; test Rw(r), Rw(r)),
+                                4 => dynasm!(ops ; test Rd(r), Rd(r)),
+                                _ => dynasm!(ops ; test Rq(r), Rq(r)),
+                            }
+                            dynasm!(ops
+                                    ; jnz => passed
+                            );
+                        }
+                        let const_loc = constant_map[&message_constant];
+                        let len = ctx.get_constant(message_constant).unwrap().len();
+                        emit_save_caller_saved(ops);
+                        dynasm!(ops
+                                ; lea rdi, [=>const_loc]
+                        );
+                        relocations.push(Relocation {
+                            offset: AssemblyOffset(ops.offset().0 - 4),
+                            target: RelocationTarget::Constant(message_constant),
+                        });
+                        dynasm!(ops
+                                ; mov esi, len as i32
+                        );
+                        let failed: unsafe extern "C" fn(*const u8, u64) = guest_assert_failed;
+                        emit_host_call(
+                            ops,
+                            &mut relocations,
+                            failed as usize,
+                            "shiba_jit_guest_assert_failed",
+                            options,
+                        );
+                        emit_restore_caller_saved(ops);
+                        dynasm!(ops
+                                ; => passed
+                        );
+                    }
+                }
+                IR::Pin { value, register } => {
+                    emit_mov_value(ops, register, value, &register_map);
+                }
+                IR::ConstantAddr {
+                    dest_register,
+                    constant_ref,
+                } => {
+                    let mdest = register_map[&dest_register];
                     dynasm!(ops
-                                ; push rax
-                                ; push rcx
-                                ; push rdx
-                                ; push rsi
-                                ; push rdi
-                                ; push r8
-                                ; push r9
-                                ; push r10
-                                ; push r11
-                                ; lea rdi, [=>const_loc]
-                                ; xor esi, esi
-                                ; mov si, BYTE len as _
-                                ; mov rax, QWORD guest_print as _
-                                ; call rax
-                                ; pop r11
-                                ; pop r10
-                                ; pop r9
-                                ; pop r8
-                                ; pop rdi
-                                ; pop rsi
-                                ; pop rdx
-                                ; pop rcx
-                                ; pop rax
+                            ; lea Ra(mdest as u8), [=>constant_map[&constant_ref]]
                     );
+                    relocations.push(Relocation {
+                        offset: AssemblyOffset(ops.offset().0 - 4),
+                        target: RelocationTarget::Constant(constant_ref),
+                    });
+                }
+                IR::PrintInt { src, _type } => {
+                    let (print, symbol) = if _type.is_signed() {
+                        let print: unsafe extern "C" fn(i64, *const PrintHandler) =
+                            guest_print_signed;
+                        (print as usize, "shiba_jit_guest_print_signed")
+                    } else {
+                        let print: unsafe extern "C" fn(u64, *const PrintHandler) =
+                            guest_print_unsigned;
+                        (print as usize, "shiba_jit_guest_print_unsigned")
+                    };
+                    emit_save_caller_saved(ops);
+                    emit_mov_value(ops, MachineRegister::Rdi, src, &register_map);
+                    emit_extend(ops, MachineRegister::Rdi, _type);
+                    emit_print_handler(ops, MachineRegister::Rsi, print_handler);
+                    emit_host_call(ops, &mut relocations, print, symbol, options);
+                    emit_restore_caller_saved(ops);
+                }
+                IR::Call {
+                    function,
+                    symbol,
+                    ref args,
+                    dest,
+                } => {
+                    emit_save_caller_saved(ops);
+                    // the arguments may already be in argument registers
+                    let moves = args
+                        .iter()
+                        .zip(&ARGUMENT_REGISTERS)
+                        .map(|(arg, mdest)| match *arg {
+                            Value::Register(r) => (*mdest, MoveSource::Register(register_map[&r])),
+                            Value::Immediate { _type, value } => {
+                                (*mdest, MoveSource::Immediate(value, _type))
+                            }
+                        })
+                        .collect();
+                    emit_machine_moves(ops, moves);
+                    for (arg, mdest) in args.iter().zip(&ARGUMENT_REGISTERS) {
+                        if let Some(_type) = ctx.value_type(*arg) {
+                            emit_extend(ops, *mdest, _type);
+                        }
+                    }
+                    emit_host_call(ops, &mut relocations, function, symbol, options);
+                    if let Some((dest_register, _type)) = dest {
+                        emit_save_call_result(ops, register_map[&dest_register], _type);
+                    }
+                    emit_restore_caller_saved(ops);
+                }
+                IR::Phi { .. } => {
+                    // handled by the blocks jumping here
+                }
+                IR::Parameter { .. } => {
+                    // loaded by the prologue
                 }
+                IR::Nop => (),
                 IR::Jump { bb_idx } => {
+                    emit_parallel_moves(ops, phi_moves(ctx, &constants, i, bb_idx), &register_map);
                     let j_ent = bb_map
                         .entry(bb_idx)
                         .or_insert_with(|| ops.new_dynamic_label());
-                    dynasm!(ops
-                        ; jmp => *j_ent
-                    );
+                    if next_in_layout != Some(bb_idx) {
+                        dynasm!(ops
+                            ; jmp => *j_ent
+                        );
+                    }
                 }
                 IR::JumpIfEqual {
                     src_register,
@@ -293,53 +3663,186 @@ pub fn generate_code(ctx: &Context) -> Result<(ExecutableBuffer, AssemblyOffset)
                     false_bb_idx,
                 } => {
                     // TODO: evaluate IR in the context of this instruction: seems suboptimal
-                    let true_ent = bb_map
-                        .entry(true_bb_idx)
-                        .or_insert_with(|| ops.new_dynamic_label())
-                        .clone();
-                    let false_ent = bb_map
-                        .entry(false_bb_idx)
-                        .or_insert_with(|| ops.new_dynamic_label());
-                    match src_register {
-                        Value::Register(r1) => {
-                            let mr1 = register_map[&r1];
-                            dynasm!(ops
-                                    ; cmp Ra(mr1 as u8), DWORD 0
-                                    ; je => true_ent
-                                    ; jmp => *false_ent
-                            )
+                    let mut edge_label = |target| {
+                        let moves = phi_moves(ctx, &constants, i, target);
+                        if moves.is_empty() {
+                            (*bb_map.entry(target).or_insert_with(|| ops.new_dynamic_label()), true)
+                        } else {
+                            let stub = ops.new_dynamic_label();
+                            edge_stubs.push((stub, moves, target));
+                            (stub, false)
+                        }
+                    };
+                    let (true_ent, true_direct) = edge_label(true_bb_idx);
+                    let (false_ent, false_direct) = edge_label(false_bb_idx);
+                    // the flags are already set if this is testing the result
+                    // of the comparison right before it
+                    let (taken_when, signed) = match fused_comparison.take() {
+                        Some((comparison, signed)) => (comparison.negate(), signed),
+                        None => {
+                            let r1 = match src_register {
+                                Value::Register(r1) => r1,
+                                _ => unimplemented!("Conditional jumps on immediate values"),
+                            };
+                            // only the bits of the type count; the rest may
+                            // be left over from wider arithmetic
+                            let r = register_map[&r1] as u8;
+                            let _type = ctx.value_type(src_register).unwrap_or(PrimitiveValue::U64);
+                            match _type.size() {
+                                1 => dynasm!(ops ; test Rb(r), Rb(r)),
+                                2 => dynasm!(ops ; test Rw(r), Rw(r)),
+                                4 => dynasm!(ops ; test Rd(r), Rd(r)),
+                                _ => dynasm!(ops ; test Rq(r), Rq(r)),
+                            }
+                            (Comparison::Equal, false)
                         }
-                        _ => unimplemented!("Conditional jumps on immediate values"),
+                    };
+                    // fall through to whichever target comes next
+                    if false_direct && next_in_layout == Some(false_bb_idx) {
+                        emit_jcc(ops, taken_when, signed, true_ent);
+                    } else if true_direct && next_in_layout == Some(true_bb_idx) {
+                        emit_jcc(ops, taken_when.negate(), signed, false_ent);
+                    } else {
+                        emit_jcc(ops, taken_when, signed, true_ent);
+                        dynasm!(ops
+                                ; jmp => false_ent
+                        );
                     }
                 }
+                IR::Add { dest_register, .. }
+                | IR::Subtract { dest_register, .. }
+                | IR::Multiply { dest_register, .. }
+                    if high_halves.contains_key(&dest_register) =>
+                {
+                    emit_wide_arithmetic(ops, inst, &register_map, &high_halves);
+                }
+                IR::Add {
+                    dest_register,
+                    overflow: Overflow::Trap(trap),
+                    ..
+                }
+                | IR::Subtract {
+                    dest_register,
+                    overflow: Overflow::Trap(trap),
+                    ..
+                }
+                | IR::Multiply {
+                    dest_register,
+                    overflow: Overflow::Trap(trap),
+                    ..
+                } => {
+                    let _type = register_types[&dest_register];
+                    let trap_ent =
+                        edge_label(ops, ctx, &constants, &mut bb_map, &mut edge_stubs, i, trap);
+                    emit_checked_arithmetic(
+                        ops,
+                        inst,
+                        _type,
+                        &register_map,
+                        OnOverflow::Trap(trap_ent),
+                    );
+                }
+                IR::Add {
+                    dest_register,
+                    overflow: Overflow::Flag(flag),
+                    ..
+                }
+                | IR::Subtract {
+                    dest_register,
+                    overflow: Overflow::Flag(flag),
+                    ..
+                }
+                | IR::Multiply {
+                    dest_register,
+                    overflow: Overflow::Flag(flag),
+                    ..
+                } => {
+                    let _type = register_types[&dest_register];
+                    let on_overflow = OnOverflow::Flag(register_map[&flag]);
+                    emit_checked_arithmetic(ops, inst, _type, &register_map, on_overflow);
+                }
+                IR::Add {
+                    dest_register,
+                    src1: Value::Register(r1),
+                    src2: Value::Register(r2),
+                    ..
+                }
+                | IR::Subtract {
+                    dest_register,
+                    src1: Value::Register(r1),
+                    src2: Value::Register(r2),
+                    ..
+                }
+                | IR::Multiply {
+                    dest_register,
+                    src1: Value::Register(r1),
+                    src2: Value::Register(r2),
+                    ..
+                } if folded.contains_key(&r1) || folded.contains_key(&r2) => {
+                    let mdest = register_map[&dest_register];
+                    let (other, offset) = match folded.get(&r2) {
+                        Some(offset) => (r1, *offset),
+                        None => (r2, folded[&r1]),
+                    };
+                    emit_folded_arithmetic(
+                        ops,
+                        inst,
+                        mdest,
+                        register_map[&other],
+                        offset,
+                        options,
+                        &frame,
+                    );
+                }
                 IR::Add {
                     dest_register,
                     src1,
                     src2,
+                    ..
                 } => {
                     let mdest = register_map[&dest_register];
                     match (src1, src2) {
                         (Value::Register(r1), Value::Register(r2)) => {
                             let mr1 = register_map[&r1];
                             let mr2 = register_map[&r2];
-                            dynasm!(ops
-                                     ; mov Ra(mdest as u8), Ra(mr1 as u8)
-                                     ; add Ra(mdest as u8), Ra(mr2 as u8)
-                            );
+                            if mdest != mr1 && mdest != mr2 {
+                                // one instruction instead of a mov and an add
+                                dynasm!(ops
+                                         ; lea Ra(mdest as u8), [Ra(mr1 as u8) + Ra(mr2 as u8)]
+                                );
+                            } else {
+                                // dest shares a register with one of the operands
+                                let other = if mdest == mr1 { mr2 } else { mr1 };
+                                dynasm!(ops
+                                         ; add Ra(mdest as u8), Ra(other as u8)
+                                );
+                            }
                         }
                         (Value::Register(r1), Value::Immediate { _type, value })
                         | (Value::Immediate { _type, value }, Value::Register(r1)) => {
                             let mr1 = register_map[&r1];
-                            emit_mov_imm(&mut ops, mdest, value, _type);
-                            dynasm!(ops
-                                   ; add Ra(mdest as u8), Ra(mr1 as u8)
-                            );
+                            if mdest != mr1 {
+                                dynasm!(ops
+                                        ; mov Ra(mdest as u8), Ra(mr1 as u8)
+                                );
+                            }
+                            if let Some(imm) = imm32(value, _type) {
+                                dynasm!(ops
+                                       ; add Ra(mdest as u8), imm
+                                );
+                            } else {
+                                emit_mov_imm(ops, MachineRegister::Rax, value, _type);
+                                dynasm!(ops
+                                       ; add Ra(mdest as u8), rax
+                                );
+                            }
                         }
                         (
                             Value::Immediate { _type, value: v1 },
                             Value::Immediate { value: v2, .. },
                         ) => {
-                            emit_mov_imm(&mut ops, mdest, v1 + v2, _type);
+                            // emit_mov_imm truncates to the width of the type, like the instruction would
+                            emit_mov_imm(ops, mdest, v1.wrapping_add(v2), _type);
                         }
                     }
                 }
@@ -347,71 +3850,275 @@ pub fn generate_code(ctx: &Context) -> Result<(ExecutableBuffer, AssemblyOffset)
                     dest_register,
                     src1,
                     src2,
+                    ..
                 } => {
                     let mdest = register_map[&dest_register];
                     match (src1, src2) {
                         (Value::Register(r1), Value::Register(r2)) => {
                             let mr1 = register_map[&r1];
                             let mr2 = register_map[&r2];
-                            dynasm!(ops
-                                     ; mov Ra(mdest as u8), Ra(mr1 as u8)
-                                     ; sub Ra(mdest as u8), Ra(mr2 as u8)
-                            );
+                            if mdest == mr2 && mdest != mr1 {
+                                // the subtrahend would be overwritten by the minuend
+                                dynasm!(ops
+                                         ; mov rax, Ra(mr1 as u8)
+                                         ; sub rax, Ra(mr2 as u8)
+                                         ; mov Ra(mdest as u8), rax
+                                );
+                            } else {
+                                dynasm!(ops
+                                         ; mov Ra(mdest as u8), Ra(mr1 as u8)
+                                         ; sub Ra(mdest as u8), Ra(mr2 as u8)
+                                );
+                            }
                         }
-                        (Value::Register(_), Value::Immediate { .. }) => {
-                            // emit_mov_imm is insufficient hee
-                            todo!("Implement this by updating the core abstraction");
-                            /*let mr1 = register_map[&r1];
-                            dynasm!(ops
-                                    ; mov Ra(mdest as u8), Ra(mr1 as u8));
-                            emit_mov_imm(&mut ops, mdest, value, _type);
-                            dynasm!(ops
-                                   ; sub Ra(mdest as u8), Ra(mr1 as u8)
-                            );*/
+                        (Value::Register(r1), Value::Immediate { _type, value }) => {
+                            let mr1 = register_map[&r1];
+                            if mdest != mr1 {
+                                dynasm!(ops
+                                        ; mov Ra(mdest as u8), Ra(mr1 as u8)
+                                );
+                            }
+                            if let Some(imm) = imm32(value, _type) {
+                                dynasm!(ops
+                                       ; sub Ra(mdest as u8), imm
+                                );
+                            } else {
+                                emit_mov_imm(ops, MachineRegister::Rax, value, _type);
+                                dynasm!(ops
+                                       ; sub Ra(mdest as u8), rax
+                                );
+                            }
                         }
                         (Value::Immediate { _type, value }, Value::Register(r2)) => {
                             let mr2 = register_map[&r2];
-                            emit_mov_imm(&mut ops, mdest, value, _type);
+                            // build the result in rax in case dest is the subtrahend
+                            emit_mov_imm(ops, MachineRegister::Rax, value, _type);
                             dynasm!(ops
-                                   ; sub Ra(mdest as u8), Ra(mr2 as u8)
+                                   ; sub rax, Ra(mr2 as u8)
+                                   ; mov Ra(mdest as u8), rax
                             );
                         }
                         (
                             Value::Immediate { _type, value: v1 },
                             Value::Immediate { value: v2, .. },
                         ) => {
-                            emit_mov_imm(&mut ops, mdest, v1 - v2, _type);
+                            emit_mov_imm(ops, mdest, v1.wrapping_sub(v2), _type);
                         }
                     }
                 }
-                IR::Alloca {
+                IR::Multiply {
                     dest_register,
-                    _type,
+                    src1,
+                    src2,
                     ..
                 } => {
                     let mdest = register_map[&dest_register];
-                    match _type {
-                        PrimitiveValue::I32 | PrimitiveValue::U32 => {
-                            dynasm!(ops
-                                    ; lea Ra(mdest as u8), [rbp - 4]
-                            );
+                    match (src1, src2) {
+                        (Value::Register(r1), Value::Register(r2)) => {
+                            let mr1 = register_map[&r1];
+                            let mr2 = register_map[&r2];
+                            if mdest == mr2 {
+                                dynasm!(ops
+                                         ; imul Ra(mdest as u8), Ra(mr1 as u8)
+                                );
+                            } else {
+                                dynasm!(ops
+                                         ; mov Ra(mdest as u8), Ra(mr1 as u8)
+                                         ; imul Ra(mdest as u8), Ra(mr2 as u8)
+                                );
+                            }
+                        }
+                        (Value::Register(r1), Value::Immediate { value, .. })
+                        | (Value::Immediate { value, .. }, Value::Register(r1)) => {
+                            let mr1 = register_map[&r1];
+                            emit_multiply_by_constant(ops, mdest, mr1, value);
+                        }
+                        (
+                            Value::Immediate { _type, value: v1 },
+                            Value::Immediate { value: v2, .. },
+                        ) => {
+                            emit_mov_imm(ops, mdest, v1.wrapping_mul(v2), _type);
+                        }
+                    }
+                }
+                IR::Divide {
+                    dest_register,
+                    src1,
+                    src2,
+                } => {
+                    let mdest = register_map[&dest_register];
+                    let _type = register_types[&dest_register];
+                    emit_divide(ops, mdest, _type, src1, src2, &register_map, false);
+                }
+                IR::Remainder {
+                    dest_register,
+                    src1,
+                    src2,
+                } => {
+                    let mdest = register_map[&dest_register];
+                    let _type = register_types[&dest_register];
+                    emit_divide(ops, mdest, _type, src1, src2, &register_map, true);
+                }
+                IR::ShiftLeft {
+                    dest_register,
+                    src1,
+                    src2,
+                } => {
+                    let mdest = register_map[&dest_register];
+                    let _type = register_types[&dest_register];
+                    emit_shift(
+                        ops,
+                        mdest,
+                        _type,
+                        src1,
+                        src2,
+                        &register_map,
+                        false,
+                        features,
+                    );
+                }
+                IR::ShiftRight {
+                    dest_register,
+                    src1,
+                    src2,
+                } => {
+                    let mdest = register_map[&dest_register];
+                    let _type = register_types[&dest_register];
+                    emit_shift(ops, mdest, _type, src1, src2, &register_map, true, features);
+                }
+                IR::Compare {
+                    dest_register,
+                    comparison,
+                    src1,
+                    src2,
+                } => {
+                    let _type = ctx
+                        .value_type(src1)
+                        .or_else(|| ctx.value_type(src2))
+                        .unwrap_or(PrimitiveValue::U32);
+                    emit_compare(ops, _type, src1, src2, &register_map);
+                    // a branch on the result that comes right after can use
+                    // the flags instead
+                    let next = basic_block
+                        .iterate_instructions()
+                        .skip(index + 1)
+                        .find(|inst| !matches!(inst, IR::Nop));
+                    let fuse = match next {
+                        Some(IR::JumpIfEqual {
+                            src_register: Value::Register(r),
+                            ..
+                        }) => *r == dest_register && use_counts[&dest_register] == 1,
+                        _ => false,
+                    };
+                    if fuse {
+                        fused_comparison = Some((comparison, _type.is_signed()));
+                    } else {
+                        let mdest = register_map[&dest_register];
+                        emit_setcc(ops, comparison, _type.is_signed());
+                        dynasm!(ops
+                                ; movzx Rd(mdest as u8), al
+                        );
+                    }
+                }
+                IR::Copy { dest_register, src } if high_halves.contains_key(&dest_register) => {
+                    // through rax and rcx in case the pairs overlap
+                    let (low, high) = halves(src, &register_map, &high_halves);
+                    emit_mov_half(ops, MachineRegister::Rax, low);
+                    emit_mov_half(ops, MachineRegister::Rcx, high);
+                    dynasm!(ops
+                            ; mov Rq(register_map[&dest_register] as u8), rax
+                            ; mov Rq(high_halves[&dest_register] as u8), rcx
+                    );
+                }
+                IR::Copy { dest_register, src } => {
+                    let mdest = register_map[&dest_register];
+                    match src {
+                        Value::Register(r1) => {
+                            let mr1 = register_map[&r1];
+                            if mdest != mr1 {
+                                dynasm!(ops
+                                        ; mov Ra(mdest as u8), Ra(mr1 as u8)
+                                );
+                            }
                         }
-                        _ => {
-                            unimplemented!("should probably rewrite allocas and not implement this")
+                        Value::Immediate { _type, value } => {
+                            emit_mov_imm(ops, mdest, value, _type);
                         }
                     }
                 }
+                IR::TruncateChecked {
+                    dest_register,
+                    dest_type,
+                    src,
+                    trap,
+                } => {
+                    let mdest = register_map[&dest_register];
+                    let src_type = ctx.value_type(src).unwrap_or(PrimitiveValue::U32);
+                    let trap_ent =
+                        edge_label(ops, ctx, &constants, &mut bb_map, &mut edge_stubs, i, trap);
+                    // the value fits if narrowing it and extending it back
+                    // gives the same thing
+                    emit_mov_value(ops, MachineRegister::Rax, src, &register_map);
+                    emit_extend(ops, MachineRegister::Rax, src_type);
+                    dynasm!(ops
+                            ; mov rcx, rax
+                    );
+                    emit_extend(ops, MachineRegister::Rcx, dest_type);
+                    dynasm!(ops
+                            ; cmp rcx, rax
+                            ; jne => trap_ent
+                    );
+                    // that misses a change of sign when the top bit is set
+                    if src_type.is_signed() != dest_type.is_signed() {
+                        dynasm!(ops
+                                ; test rax, rax
+                                ; js => trap_ent
+                        );
+                    }
+                    dynasm!(ops
+                            ; mov Ra(mdest as u8), rcx
+                    );
+                }
+                IR::Alloca { dest_register, .. } => {
+                    // slots for spilled registers, and ones only loaded
+                    // from and stored to, are accessed directly
+                    if let Some(mdest) = register_map.get(&dest_register) {
+                        let offset = frame.slots[&dest_register];
+                        emit_slot_address(ops, *mdest, offset, options, &frame);
+                    }
+                }
                 IR::Load {
                     dest_register,
                     src_register,
+                    ..
                 } => {
                     let mdest = register_map[&dest_register];
                     match src_register {
                         Value::Register(src) => {
-                            let msrc = register_map[&src];
-                            dynasm!(ops
-                                    ; mov Rd(mdest as u8), [Ra(msrc as u8)]
-                            );
+                            let msrc = emit_pointer(ops, src, &register_map, options, &frame);
+                            // what's in memory, which may be narrower than
+                            // the result
+                            let size = pointee_types
+                                .get(&src)
+                                .copied()
+                                .unwrap_or(PrimitiveValue::U32)
+                                .size();
+                            if let Some(bounds) = bounds_to_check(&src, false) {
+                                emit_bounds_check(ops, bounds, msrc, size, options, &frame);
+                            }
+                            // read only as many bytes as the value has,
+                            // extending it the way the result's type is
+                            let signed = register_types[&dest_register].is_signed();
+                            let (d, s) = (mdest as u8, msrc as u8);
+                            match (size, signed) {
+                                (1, false) => dynasm!(ops ; movzx Rd(d), BYTE [Ra(s)]),
+                                (1, true) => dynasm!(ops ; movsx Rq(d), BYTE [Ra(s)]),
+                                (2, false) => dynasm!(ops ; movzx Rd(d), WORD [Ra(s)]),
+                                (2, true) => dynasm!(ops ; movsx Rq(d), WORD [Ra(s)]),
+                                (4, false) => dynasm!(ops ; mov Rd(d), [Ra(s)]),
+                                (4, true) => dynasm!(ops ; movsxd Rq(d), DWORD [Ra(s)]),
+                                _ => dynasm!(ops ; mov Rq(d), [Ra(s)]),
+                            }
                         }
                         Value::Immediate { .. } => {
                             todo!("deref raw pointers");
@@ -427,43 +4134,218 @@ pub fn generate_code(ctx: &Context) -> Result<(ExecutableBuffer, AssemblyOffset)
                     src_register,
                 } => match (dest_register, src_register) {
                     (Value::Register(dest), Value::Register(src)) => {
-                        let mdest = register_map[&dest];
+                        let mdest = emit_pointer(ops, dest, &register_map, options, &frame);
                         let msrc = register_map[&src];
+                        // only write as many bytes as the value has
+                        let _type = register_types[&src];
+                        if let Some(bounds) = bounds_to_check(&dest, true) {
+                            emit_bounds_check(ops, bounds, mdest, _type.size(), options, &frame);
+                        }
 
-                        dynasm!(ops
-                                ; mov [Ra(mdest as u8)], Ra(msrc as u8)
-                        );
+                        match _type.size() {
+                            1 => dynasm!(ops
+                                    ; mov [Ra(mdest as u8)], Rb(msrc as u8)
+                            ),
+                            2 => dynasm!(ops
+                                    ; mov [Ra(mdest as u8)], Rw(msrc as u8)
+                            ),
+                            4 => dynasm!(ops
+                                    ; mov [Ra(mdest as u8)], Rd(msrc as u8)
+                            ),
+                            _ => dynasm!(ops
+                                    ; mov [Ra(mdest as u8)], Ra(msrc as u8)
+                            ),
+                        }
                     }
                     (Value::Register(dest), Value::Immediate { _type, value }) => {
-                        let mdest = register_map[&dest];
+                        let mdest = emit_pointer(ops, dest, &register_map, options, &frame);
+                        if let Some(bounds) = bounds_to_check(&dest, true) {
+                            emit_bounds_check(ops, bounds, mdest, _type.size(), options, &frame);
+                        }
 
-                        match _type {
-                            PrimitiveValue::U32 => {
-                                dynasm!(ops
-                                        ; mov eax, DWORD value as i32
-                                        ; mov [Ra(mdest as u8)], eax
-                                );
-                            }
-                            _ => unimplemented!("storing anything but a u32"),
+                        match _type.size() {
+                            1 => dynasm!(ops
+                                    ; mov BYTE [Ra(mdest as u8)], value as i8
+                            ),
+                            2 => dynasm!(ops
+                                    ; mov WORD [Ra(mdest as u8)], value as i16
+                            ),
+                            4 => dynasm!(ops
+                                    ; mov DWORD [Ra(mdest as u8)], value as i32
+                            ),
+                            // the immediate is sign extended to 64 bits
+                            _ if value as i64 == value as i32 as i64 => dynasm!(ops
+                                    ; mov QWORD [Ra(mdest as u8)], value as i32
+                            ),
+                            _ => dynasm!(ops
+                                    ; mov rax, QWORD value as i64
+                                    ; mov [Ra(mdest as u8)], rax
+                            ),
                         }
                     }
                     _ => unimplemented!("Store for constant destinations"),
                 },
+                IR::MemLoad {
+                    dest_register,
+                    offset,
+                } => {
+                    let memory = ctx
+                        .linear_memory
+                        .as_ref()
+                        .expect("MemLoad requires a linear memory");
+                    let mdest = register_map[&dest_register];
+                    emit_linear_memory_address(ops, memory, offset, &register_map);
+                    if let Some(ref bounds) = ctx.memory_bounds {
+                        emit_bounds_check(ops, bounds, MachineRegister::Rcx, 4, options, &frame);
+                    }
+                    dynasm!(ops
+                            ; mov Rd(mdest as u8), [rcx]
+                    );
+                }
+                IR::MemStore { offset, src } => {
+                    let memory = ctx
+                        .linear_memory
+                        .as_ref()
+                        .expect("MemStore requires a linear memory");
+                    emit_linear_memory_address(ops, memory, offset, &register_map);
+                    if let Some(ref bounds) = ctx.memory_bounds {
+                        emit_bounds_check(ops, bounds, MachineRegister::Rcx, 4, options, &frame);
+                    }
+                    match src {
+                        Value::Register(r) => {
+                            let mr = register_map[&r];
+                            dynasm!(ops
+                                    ; mov [rcx], Rd(mr as u8)
+                            );
+                        }
+                        Value::Immediate { value, .. } => {
+                            dynasm!(ops
+                                    ; mov DWORD [rcx], value as i32
+                            );
+                        }
+                    }
+                }
                 IR::Return => {
+                    emit_epilogue(ops, options, &frame);
+                }
+                IR::ReturnValue { value } => {
+                    if ctx.value_type(value).is_some_and(|t| t.size() > 8) {
+                        // in rdx:rax, like the System V ABI returns __int128
+                        let (low, high) = halves(value, &register_map, &high_halves);
+                        emit_mov_half(ops, MachineRegister::Rax, low);
+                        emit_mov_half(ops, MachineRegister::Rdx, high);
+                    } else {
+                        emit_mov_value(ops, MachineRegister::Rax, value, &register_map);
+                    }
+                    emit_epilogue(ops, options, &frame);
+                }
+                IR::ReturnStruct { ref values } => {
+                    emit_return_struct(ops, ctx, values, &register_map, options, &frame);
+                }
+                IR::Trap { code } => {
+                    let abort: extern "C" fn(u64) = guest_abort;
+                    dynasm!(ops
+                            ; mov rdi, QWORD code as i64
+                    );
+                    emit_host_call(
+                        ops,
+                        &mut relocations,
+                        abort as usize,
+                        "shiba_jit_guest_abort",
+                        options,
+                    );
+                    emit_epilogue(ops, options, &frame);
+                }
+                IR::Unreachable => {
+                    // nothing's needed if control can't get here anyway
+                    let after_terminator = basic_block
+                        .iterate_instructions()
+                        .take(index)
+                        .filter(|inst| !matches!(inst, IR::Nop))
+                        .last()
+                        .is_some_and(IR::is_terminator);
+                    if !after_terminator {
+                        dynasm!(ops
+                                ; ud2
+                        );
+                    }
+                }
+                IR::InlineBytes {
+                    ref bytes,
+                    ref defines,
+                    ref uses,
+                } => {
+                    let passing = [MachineRegister::Rax, MachineRegister::Rcx];
+                    // allocated registers are never rax or rcx, so these
+                    // can't overwrite each other
+                    for (r, mr) in uses.iter().zip(passing.iter()) {
+                        dynasm!(ops
+                                ; mov Ra(*mr as u8), Ra(register_map[r] as u8)
+                        );
+                    }
                     dynasm!(ops
-                            ; pop rsi
-                            ; pop rdi
-                            ; pop rbx
-                            ; add rsp, 0x8
-                            ; mov rsp, rbp
-                            ; pop rbp
-                            ; ret
+                            ; .bytes bytes.iter()
                     );
+                    for (r, mr) in defines.iter().zip(passing.iter()) {
+                        dynasm!(ops
+                                ; mov Ra(register_map[r] as u8), Ra(*mr as u8)
+                        );
+                    }
                 }
                 _ => unimplemented!("not yet"),
             }
         }
+        // the block it falls through to may have been moved by the layout
+        if !basic_block.is_terminated() {
+            if let Some(target) = ctx.basic_blocks.fall_through_target(i) {
+                emit_parallel_moves(ops, phi_moves(ctx, &constants, i, target), &register_map);
+                if next_in_layout != Some(target) {
+                    let f_ent = bb_map
+                        .entry(target)
+                        .or_insert_with(|| ops.new_dynamic_label());
+                    dynasm!(ops
+                        ; jmp => *f_ent
+                    );
+                }
+            }
+        }
+        if patch.is_none() {
+            emit_nops(ops, options.block_patch_room);
+        }
+        block_ranges.insert(i, (block_start, ops.offset()));
+    }
+    if let Some(patch) = patch {
+        // the stubs go right after the block, so it mustn't fall into them
+        let next = layout.iter().skip_while(|b| **b != patch.block).nth(1);
+        match next {
+            Some(next) if !edge_stubs.is_empty() => dynasm!(ops
+                    ; jmp => bb_map[next]
+            ),
+            _ => (),
+        }
     }
+    for (stub, moves, target) in edge_stubs {
+        let t_ent = *bb_map
+            .entry(target)
+            .or_insert_with(|| ops.new_dynamic_label());
+        dynasm!(ops
+                ; => stub
+        );
+        emit_parallel_moves(ops, moves, &register_map);
+        dynasm!(ops
+                ; jmp => t_ent
+        );
+    }
+    if let Some(patch) = patch {
+        if let Some(range) = block_ranges.get_mut(&patch.block) {
+            range.1 = ops.offset();
+        }
+    }
+    // blocks are all laid out, so every label jumped to has been placed
+    assert!(
+        bb_map.keys().all(|b| ctx.basic_blocks.get(*b).is_some()),
+        "jump to a block that wasn't emitted"
+    );
 
     /*
 
@@ -601,19 +4483,53 @@ pub fn generate_code(ctx: &Context) -> Result<(ExecutableBuffer, AssemblyOffset)
     }
         */
 
-    ops.finalize()
-        .map_err(|_| CodeGenError {
-            location: 0,
-            reason: CodeGenErrorReason::CodeGenFailure,
-        })
-        .map(|r| {
-            use std::io::Write;
-            let mut f = std::fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open("out")
-                .unwrap();
-            f.write_all(&r[start_offset.0..]).unwrap();
-            (r, start_offset)
-        })
+    let frame_layout = frame.layout(options);
+    let spill_report = SpillReport {
+        spills: spilled
+            .map(|spilled| spilled.spills)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|spill| SpilledRegister {
+                register: spill.register,
+                slot: frame_layout.slots[&spill.slot],
+                reloads: spill.reloads,
+            })
+            .collect(),
+    };
+    EmittedFunction {
+        start: start_offset,
+        register_map,
+        high_halves,
+        relocations,
+        instruction_offsets,
+        prologue_layout,
+        block_ranges,
+        frame_layout,
+        spill_report,
+    }
+}
+
+/// Check the size of the code in `ops` and make it executable
+fn finish_code(
+    mut ops: Assembler,
+    options: &CodeGenOptions,
+) -> Result<ExecutableBuffer, CodeGenError> {
+    // checked before committing, which is when the executable memory grows
+    let size = ops.offset().0;
+    if let Some(limit) = options.max_code_size {
+        if size > limit {
+            return Err(CodeGenError {
+                location: 0,
+                reason: CodeGenErrorReason::CodeTooLarge { size, limit },
+            });
+        }
+    }
+    ops.commit().map_err(|e| CodeGenError {
+        location: 0,
+        reason: CodeGenErrorReason::Relocation(e),
+    })?;
+    ops.finalize().map_err(|_| CodeGenError {
+        location: 0,
+        reason: CodeGenErrorReason::CodeGenFailure,
+    })
 }
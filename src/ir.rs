@@ -1,7 +1,16 @@
+pub mod dce;
+pub mod interp;
+pub mod licm;
+pub mod optimize;
+pub mod ssa;
+
+use crate::codegen::x86_64::MachineRegister;
 use smallvec::SmallVec;
-use std::sync::{mpsc, Mutex};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{mpsc, Arc};
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub enum PrimitiveValue {
     U8,
     I8,
@@ -11,6 +20,58 @@ pub enum PrimitiveValue {
     I32,
     U64,
     I64,
+    /// Kept in a pair of machine registers, which only some instructions
+    /// handle
+    U128,
+    I128,
+}
+
+impl PrimitiveValue {
+    /// Width in bytes
+    pub fn size(self) -> usize {
+        match self {
+            PrimitiveValue::U8 | PrimitiveValue::I8 => 1,
+            PrimitiveValue::U16 | PrimitiveValue::I16 => 2,
+            PrimitiveValue::U32 | PrimitiveValue::I32 => 4,
+            PrimitiveValue::U64 | PrimitiveValue::I64 => 8,
+            PrimitiveValue::U128 | PrimitiveValue::I128 => 16,
+        }
+    }
+
+    pub fn is_signed(self) -> bool {
+        matches!(
+            self,
+            PrimitiveValue::I8
+                | PrimitiveValue::I16
+                | PrimitiveValue::I32
+                | PrimitiveValue::I64
+                | PrimitiveValue::I128
+        )
+    }
+
+    /// The smallest and largest values of the type.  The largest `U128` is
+    /// more than an `i128` holds, so it's clamped to `i128::MAX`.
+    pub fn range(self) -> (i128, i128) {
+        let bits = self.size() * 8;
+        if self == PrimitiveValue::U128 {
+            (0, i128::MAX)
+        } else if bits == 128 {
+            (i128::MIN, i128::MAX)
+        } else if self.is_signed() {
+            (-(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+        } else {
+            (0, (1 << bits) - 1)
+        }
+    }
+
+    /// Whether `value` is a valid immediate of this type: the bits above the
+    /// width of the type are all 0, or for signed types all copies of its
+    /// sign bit, as [`Value::i32`] and the like make them.  Immediates of
+    /// 128 bit types are 64 bits, extended the way the type is.
+    pub fn fits(self, value: usize) -> bool {
+        let bits = self.size() * 8;
+        bits >= 64 || value >> bits == 0 || (self.is_signed() && (value as i64) >> (bits - 1) == -1)
+    }
 }
 
 #[derive(Debug)]
@@ -18,22 +79,140 @@ pub struct Register {
     _type: PrimitiveValue,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Hash)]
 pub enum Value {
     Register(RegisterIndex),
     Immediate { _type: PrimitiveValue, value: usize },
 }
 
 impl Value {
+    /// An immediate of `_type`, or `None` if `value` doesn't fit in it
+    pub fn immediate(_type: PrimitiveValue, value: i128) -> Option<Self> {
+        // only 64 bits are stored, see `PrimitiveValue::fits`
+        let (min, max) = match _type {
+            PrimitiveValue::U128 => PrimitiveValue::U64.range(),
+            PrimitiveValue::I128 => PrimitiveValue::I64.range(),
+            _ => _type.range(),
+        };
+        if value < min || value > max {
+            return None;
+        }
+        Some(Value::Immediate {
+            _type,
+            value: value as _,
+        })
+    }
+
+    pub fn u8(v: u8) -> Self {
+        Value::Immediate {
+            _type: PrimitiveValue::U8,
+            value: v as _,
+        }
+    }
+
+    pub fn i8(v: i8) -> Self {
+        Value::Immediate {
+            _type: PrimitiveValue::I8,
+            value: v as _,
+        }
+    }
+
+    pub fn u16(v: u16) -> Self {
+        Value::Immediate {
+            _type: PrimitiveValue::U16,
+            value: v as _,
+        }
+    }
+
+    pub fn i16(v: i16) -> Self {
+        Value::Immediate {
+            _type: PrimitiveValue::I16,
+            value: v as _,
+        }
+    }
+
     pub fn u32(v: u32) -> Self {
         Value::Immediate {
             _type: PrimitiveValue::U32,
             value: v as _,
         }
     }
+
+    pub fn i32(v: i32) -> Self {
+        Value::Immediate {
+            _type: PrimitiveValue::I32,
+            value: v as _,
+        }
+    }
+
+    pub fn u64(v: u64) -> Self {
+        Value::Immediate {
+            _type: PrimitiveValue::U64,
+            value: v as _,
+        }
+    }
+
+    pub fn i64(v: i64) -> Self {
+        Value::Immediate {
+            _type: PrimitiveValue::I64,
+            value: v as _,
+        }
+    }
 }
 
-#[derive(Debug)]
+/// What arithmetic does when the result doesn't fit in its type
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub enum Overflow {
+    /// Keep the low bits, like the hardware does
+    #[default]
+    Wrap,
+    /// Branch to the block instead of producing a value
+    Trap(BasicBlockIndex),
+    /// Keep the low bits like `Wrap`, and also define the register as 1 if
+    /// the result didn't fit and 0 if it did: the carry for unsigned types
+    /// and the overflow for signed ones
+    Flag(RegisterIndex),
+    /// The front end promises it doesn't happen, so the result can be
+    /// anything.  Optimizations may assume the result fits, but the code is
+    /// the same as for `Wrap`.
+    Poison,
+}
+
+/// How [`IR::Compare`] compares its operands.  Orderings are signed or
+/// unsigned depending on the type of the operands.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    /// The comparison that's true exactly when this one is false
+    pub fn negate(self) -> Self {
+        match self {
+            Comparison::Equal => Comparison::NotEqual,
+            Comparison::NotEqual => Comparison::Equal,
+            Comparison::Less => Comparison::GreaterOrEqual,
+            Comparison::LessOrEqual => Comparison::Greater,
+            Comparison::Greater => Comparison::LessOrEqual,
+            Comparison::GreaterOrEqual => Comparison::Less,
+        }
+    }
+}
+
+/// Which way a conditional jump is expected to go, so the likely target can
+/// be laid out right after the jump
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum BranchHint {
+    TrueLikely,
+    FalseLikely,
+}
+
+#[derive(Debug, Clone, Hash)]
 pub enum IR {
     Alloca {
         dest_register: RegisterIndex,
@@ -44,32 +223,96 @@ pub enum IR {
         dest_register: RegisterIndex,
         src1: Value,
         src2: Value,
+        overflow: Overflow,
     },
     Subtract {
         dest_register: RegisterIndex,
         src1: Value,
         src2: Value,
+        overflow: Overflow,
     },
     Multiply {
         dest_register: RegisterIndex,
         src1: Value,
         src2: Value,
+        overflow: Overflow,
     },
+    /// Division rounding toward zero, as in Rust and C
     Divide {
         dest_register: RegisterIndex,
         src1: Value,
         src2: Value,
     },
+    /// The remainder of `Divide`, taking the sign of `src1`
+    Remainder {
+        dest_register: RegisterIndex,
+        src1: Value,
+        src2: Value,
+    },
+    /// Shifts `src1` left by `src2` bits, modulo the width of its type
+    ShiftLeft {
+        dest_register: RegisterIndex,
+        src1: Value,
+        src2: Value,
+    },
+    /// Shifts `src1` right by `src2` bits, modulo the width of its type.
+    /// Signed types shift in copies of the sign bit, unsigned types zeros.
+    ShiftRight {
+        dest_register: RegisterIndex,
+        src1: Value,
+        src2: Value,
+    },
+    /// 1 if `src1` and `src2` compare as `comparison`, otherwise 0.  The
+    /// result is a `U32`.
+    Compare {
+        dest_register: RegisterIndex,
+        comparison: Comparison,
+        src1: Value,
+        src2: Value,
+    },
+    /// Takes the value from `incoming` for the block that control came from.
+    ///
+    /// Phis come before the other instructions in a block.
+    Phi {
+        dest_register: RegisterIndex,
+        incoming: Vec<(BasicBlockIndex, Value)>,
+    },
+    /// Copies `src` into a new register
+    Copy {
+        dest_register: RegisterIndex,
+        src: Value,
+    },
+    /// Narrows `src` to `dest_type`, branching to `trap` instead if the value
+    /// doesn't fit
+    TruncateChecked {
+        dest_register: RegisterIndex,
+        dest_type: PrimitiveValue,
+        src: Value,
+        trap: BasicBlockIndex,
+    },
     /// Src is a pointer that's  dereffed
     Load {
         dest_register: RegisterIndex,
         src_register: Value,
+        /// The type of the result if it's wider than what's in memory.  It's
+        /// sign extended if this type is signed and zero extended otherwise.
+        extend_to: Option<PrimitiveValue>,
     },
     /// Dest is a pointer that's dereffed
     Store {
         dest_register: Value,
         src_register: Value,
     },
+    /// Load 32 bits from the [`LinearMemory`] at a 32 bit offset
+    MemLoad {
+        dest_register: RegisterIndex,
+        offset: Value,
+    },
+    /// Store 32 bits into the [`LinearMemory`] at a 32 bit offset
+    MemStore {
+        offset: Value,
+        src: Value,
+    },
     JumpIfEqual {
         src_register: Value,
         true_bb_idx: BasicBlockIndex,
@@ -86,7 +329,88 @@ pub enum IR {
     PrintConstant {
         constant_ref: ConstantIndex,
     },
+    /// The address of a constant, which is read only
+    ConstantAddr {
+        dest_register: RegisterIndex,
+        constant_ref: ConstantIndex,
+    },
+    /// Print `src` in decimal, as signed or unsigned depending on `_type`
+    PrintInt {
+        src: Value,
+        _type: PrimitiveValue,
+    },
+    /// Check that `cond` isn't zero, for catching mistakes in the program.
+    /// Only code generated with `debug_assertions` set in the
+    /// [`crate::codegen::x86_64::CodeGenOptions`] checks it: if it's zero,
+    /// the message in the constant is passed to
+    /// [`crate::codegen::x86_64::guest_assert_failed`] and the program
+    /// carries on after that returns.
+    Assert {
+        cond: Value,
+        message_constant: ConstantIndex,
+    },
+    /// Call the host function at `function` with `args` in the System V
+    /// argument registers, so there can be at most 6.  `symbol` names the
+    /// function in relocations.
+    ///
+    /// If there's a `dest`, the integer the function returns in rax is put
+    /// in the register, as the type with it.
+    Call {
+        function: usize,
+        symbol: &'static str,
+        args: SmallVec<[Value; 4]>,
+        dest: Option<(RegisterIndex, PrimitiveValue)>,
+    },
     Return,
+    /// Return `value` to the caller, in rax
+    ReturnValue {
+        value: Value,
+    },
+    /// Return a struct with `values` as its fields, laid out as given to
+    /// [`Context::set_return_struct`].  Structs of up to 16 bytes are
+    /// returned in rax and rdx, bigger ones through the pointer the caller
+    /// passes in rdi.
+    ReturnStruct {
+        values: SmallVec<[Value; 2]>,
+    },
+    /// Does nothing; a placeholder for removed instructions so passes can
+    /// rewrite blocks in place.  See [`Context::strip_nops`].
+    Nop,
+    /// Stop running the guest, reporting `code` to the host through
+    /// [`crate::codegen::x86_64::guest_abort`]
+    Trap {
+        code: u64,
+    },
+    /// Marks the end of a block that control never reaches, like what comes
+    /// after a `Trap`.  Reaching it anyway is a fatal error.
+    Unreachable,
+    /// The `index`th argument the function was called with, see
+    /// [`Context::add_parameter`]
+    Parameter {
+        dest_register: RegisterIndex,
+        _type: PrimitiveValue,
+        index: usize,
+    },
+    /// Puts `value` in `register`, for glue like `InlineBytes` that expects
+    /// values in particular registers.  Pinned registers aren't given to any
+    /// other register, so the value stays there until something overwrites
+    /// it.  Most instructions use rax and rcx as scratch space, so values
+    /// pinned to them should be used by the next instruction.
+    Pin {
+        value: Value,
+        register: MachineRegister,
+    },
+    /// Machine code emitted as is, for things the IR can't express.
+    ///
+    /// The registers in `uses` are in rax and rcx, in that order, when the
+    /// code starts, and the registers in `defines` are taken from rax and
+    /// rcx when it ends.  It may change rax, rcx, and the flags; every other
+    /// register and the stack must be as they were.
+    InlineBytes {
+        bytes: Vec<u8>,
+        defines: SmallVec<[RegisterIndex; 2]>,
+        uses: SmallVec<[RegisterIndex; 2]>,
+    },
 }
 
 impl IR {
@@ -96,7 +420,11 @@ impl IR {
             IR::Add { src1, src2, .. }
             | IR::Subtract { src1, src2, .. }
             | IR::Multiply { src1, src2, .. }
-            | IR::Divide { src1, src2, .. } => {
+            | IR::Divide { src1, src2, .. }
+            | IR::Remainder { src1, src2, .. }
+            | IR::ShiftLeft { src1, src2, .. }
+            | IR::ShiftRight { src1, src2, .. }
+            | IR::Compare { src1, src2, .. } => {
                 if let Value::Register(r1) = src1 {
                     out.push(r1);
                 }
@@ -107,6 +435,7 @@ impl IR {
             IR::Load {
                 dest_register,
                 src_register,
+                ..
             } => {
                 out.push(dest_register);
                 if let Value::Register(r2) = src_register {
@@ -124,70 +453,857 @@ impl IR {
                     out.push(r2);
                 }
             }
+            IR::Copy { src: v1, .. }
+            | IR::Pin { value: v1, .. }
+            | IR::MemLoad { offset: v1, .. }
+            | IR::TruncateChecked { src: v1, .. }
+            | IR::PrintInt { src: v1, .. }
+            | IR::Assert { cond: v1, .. }
+            | IR::ReturnValue { value: v1 } => {
+                if let Value::Register(r1) = v1 {
+                    out.push(r1);
+                }
+            }
+            IR::MemStore { offset, src } => {
+                if let Value::Register(r1) = offset {
+                    out.push(r1);
+                }
+                if let Value::Register(r2) = src {
+                    out.push(r2);
+                }
+            }
+            IR::Phi { incoming, .. } => {
+                for (_, v) in incoming {
+                    if let Value::Register(r) = v {
+                        out.push(r);
+                    }
+                }
+            }
             IR::JumpIfEqual { src_register, .. } | IR::JumpIfNotEqual { src_register, .. } => {
                 if let Value::Register(r1) = src_register {
                     out.push(r1);
                 }
             }
-            IR::Jump { .. } | IR::PrintConstant { .. } | IR::Alloca { .. } | IR::Return => (),
+            IR::InlineBytes { uses, .. } => out.extend(uses.iter()),
+            IR::ReturnStruct { values } => {
+                out.extend(values.iter().filter_map(|v| match v {
+                    Value::Register(r) => Some(r),
+                    Value::Immediate { .. } => None,
+                }));
+            }
+            IR::Call { args, .. } => {
+                out.extend(args.iter().filter_map(|v| match v {
+                    Value::Register(r) => Some(r),
+                    Value::Immediate { .. } => None,
+                }));
+            }
+            IR::Jump { .. }
+            | IR::PrintConstant { .. }
+            | IR::ConstantAddr { .. }
+            | IR::Alloca { .. }
+            | IR::Parameter { .. }
+            | IR::Return
+            | IR::Nop
+            | IR::Trap { .. }
+            | IR::Unreachable => (),
         }
         out
     }
+
+    /// The operands of the instruction, including immediates
+    pub fn get_used_values(&self) -> SmallVec<[Value; 2]> {
+        match self {
+            IR::Add { src1, src2, .. }
+            | IR::Subtract { src1, src2, .. }
+            | IR::Multiply { src1, src2, .. }
+            | IR::Divide { src1, src2, .. }
+            | IR::Remainder { src1, src2, .. }
+            | IR::ShiftLeft { src1, src2, .. }
+            | IR::ShiftRight { src1, src2, .. }
+            | IR::Compare { src1, src2, .. }
+            | IR::Store {
+                dest_register: src1,
+                src_register: src2,
+            }
+            | IR::MemStore {
+                offset: src1,
+                src: src2,
+            } => smallvec![*src1, *src2],
+            IR::Copy { src: v1, .. }
+            | IR::Pin { value: v1, .. }
+            | IR::MemLoad { offset: v1, .. }
+            | IR::TruncateChecked { src: v1, .. }
+            | IR::PrintInt { src: v1, .. }
+            | IR::Assert { cond: v1, .. }
+            | IR::ReturnValue { value: v1 }
+            | IR::Load {
+                src_register: v1, ..
+            }
+            | IR::JumpIfEqual {
+                src_register: v1, ..
+            }
+            | IR::JumpIfNotEqual {
+                src_register: v1, ..
+            } => smallvec![*v1],
+            IR::Phi { incoming, .. } => incoming.iter().map(|(_, v)| *v).collect(),
+            IR::ReturnStruct { values } => values.iter().copied().collect(),
+            IR::Call { args, .. } => args.iter().copied().collect(),
+            IR::InlineBytes { uses, .. } => uses.iter().map(|r| Value::Register(*r)).collect(),
+            IR::Jump { .. }
+            | IR::PrintConstant { .. }
+            | IR::ConstantAddr { .. }
+            | IR::Alloca { .. }
+            | IR::Parameter { .. }
+            | IR::Return
+            | IR::Nop
+            | IR::Trap { .. }
+            | IR::Unreachable => smallvec![],
+        }
+    }
+
+    /// Use `new` wherever the instruction used `old`, for passes renaming
+    /// registers
+    pub(crate) fn replace_uses(&mut self, old: RegisterIndex, new: RegisterIndex) {
+        let values: SmallVec<[&mut Value; 2]> = match self {
+            IR::Add { src1, src2, .. }
+            | IR::Subtract { src1, src2, .. }
+            | IR::Multiply { src1, src2, .. }
+            | IR::Divide { src1, src2, .. }
+            | IR::Remainder { src1, src2, .. }
+            | IR::ShiftLeft { src1, src2, .. }
+            | IR::ShiftRight { src1, src2, .. }
+            | IR::Compare { src1, src2, .. }
+            | IR::Store {
+                dest_register: src1,
+                src_register: src2,
+            }
+            | IR::MemStore {
+                offset: src1,
+                src: src2,
+            } => smallvec![src1, src2],
+            IR::Copy { src: v1, .. }
+            | IR::Pin { value: v1, .. }
+            | IR::MemLoad { offset: v1, .. }
+            | IR::TruncateChecked { src: v1, .. }
+            | IR::PrintInt { src: v1, .. }
+            | IR::Assert { cond: v1, .. }
+            | IR::ReturnValue { value: v1 }
+            | IR::Load {
+                src_register: v1, ..
+            }
+            | IR::JumpIfEqual {
+                src_register: v1, ..
+            }
+            | IR::JumpIfNotEqual {
+                src_register: v1, ..
+            } => smallvec![v1],
+            IR::Phi { incoming, .. } => incoming.iter_mut().map(|(_, v)| v).collect(),
+            IR::ReturnStruct { values } => values.iter_mut().collect(),
+            IR::Call { args, .. } => args.iter_mut().collect(),
+            IR::InlineBytes { uses, .. } => {
+                for r in uses.iter_mut().filter(|r| **r == old) {
+                    *r = new;
+                }
+                smallvec![]
+            }
+            _ => smallvec![],
+        };
+        for v in values {
+            if let Value::Register(r) = v {
+                if *r == old {
+                    *r = new;
+                }
+            }
+        }
+    }
+
+    pub fn get_defined_registers(&self) -> SmallVec<[&RegisterIndex; 2]> {
+        match self {
+            IR::Add {
+                dest_register,
+                overflow: Overflow::Flag(flag),
+                ..
+            }
+            | IR::Subtract {
+                dest_register,
+                overflow: Overflow::Flag(flag),
+                ..
+            }
+            | IR::Multiply {
+                dest_register,
+                overflow: Overflow::Flag(flag),
+                ..
+            } => smallvec![dest_register, flag],
+            IR::Alloca { dest_register, .. }
+            | IR::Add { dest_register, .. }
+            | IR::Subtract { dest_register, .. }
+            | IR::Multiply { dest_register, .. }
+            | IR::Load { dest_register, .. }
+            | IR::Divide { dest_register, .. }
+            | IR::Remainder { dest_register, .. }
+            | IR::ShiftLeft { dest_register, .. }
+            | IR::ShiftRight { dest_register, .. }
+            | IR::Compare { dest_register, .. }
+            | IR::Copy { dest_register, .. }
+            | IR::Phi { dest_register, .. }
+            | IR::TruncateChecked { dest_register, .. }
+            | IR::MemLoad { dest_register, .. }
+            | IR::ConstantAddr { dest_register, .. }
+            | IR::Parameter { dest_register, .. } => smallvec![dest_register],
+            IR::Call {
+                dest: Some((dest_register, _)),
+                ..
+            } => smallvec![dest_register],
+            IR::InlineBytes { defines, .. } => defines.iter().collect(),
+            _ => smallvec![],
+        }
+    }
+
+    /// The register arithmetic sets to whether it overflowed, see
+    /// [`Overflow::Flag`]
+    pub fn overflow_flag(&self) -> Option<RegisterIndex> {
+        match self {
+            IR::Add {
+                overflow: Overflow::Flag(flag),
+                ..
+            }
+            | IR::Subtract {
+                overflow: Overflow::Flag(flag),
+                ..
+            }
+            | IR::Multiply {
+                overflow: Overflow::Flag(flag),
+                ..
+            } => Some(*flag),
+            _ => None,
+        }
+    }
+
+    /// The blocks this instruction may transfer control to
+    pub fn branch_targets(&self) -> SmallVec<[BasicBlockIndex; 2]> {
+        match self {
+            IR::Jump { bb_idx } => smallvec![*bb_idx],
+            IR::JumpIfEqual {
+                true_bb_idx,
+                false_bb_idx,
+                ..
+            }
+            | IR::JumpIfNotEqual {
+                true_bb_idx,
+                false_bb_idx,
+                ..
+            } => smallvec![*true_bb_idx, *false_bb_idx],
+            IR::TruncateChecked { trap, .. }
+            | IR::Add {
+                overflow: Overflow::Trap(trap),
+                ..
+            }
+            | IR::Subtract {
+                overflow: Overflow::Trap(trap),
+                ..
+            }
+            | IR::Multiply {
+                overflow: Overflow::Trap(trap),
+                ..
+            } => smallvec![*trap],
+            _ => smallvec![],
+        }
+    }
+
+    /// [`IR::branch_targets`], for passes rewriting where control goes
+    pub(crate) fn branch_targets_mut(&mut self) -> SmallVec<[&mut BasicBlockIndex; 2]> {
+        match self {
+            IR::Jump { bb_idx } => smallvec![bb_idx],
+            IR::JumpIfEqual {
+                true_bb_idx,
+                false_bb_idx,
+                ..
+            }
+            | IR::JumpIfNotEqual {
+                true_bb_idx,
+                false_bb_idx,
+                ..
+            } => smallvec![true_bb_idx, false_bb_idx],
+            IR::TruncateChecked { trap, .. }
+            | IR::Add {
+                overflow: Overflow::Trap(trap),
+                ..
+            }
+            | IR::Subtract {
+                overflow: Overflow::Trap(trap),
+                ..
+            }
+            | IR::Multiply {
+                overflow: Overflow::Trap(trap),
+                ..
+            } => smallvec![trap],
+            _ => smallvec![],
+        }
+    }
+
+    /// Whether this instruction ends a basic block
+    pub fn is_terminator(&self) -> bool {
+        matches!(
+            self,
+            IR::Jump { .. }
+                | IR::JumpIfEqual { .. }
+                | IR::JumpIfNotEqual { .. }
+                | IR::Return
+                | IR::ReturnValue { .. }
+                | IR::ReturnStruct { .. }
+                | IR::Trap { .. }
+                | IR::Unreachable
+        )
+    }
 }
 
-/// Top level type to generate IR with
+/// A region of memory that loads and stores are restricted to, for sandboxing
+#[derive(Debug, Clone, Copy, Hash)]
+pub struct MemoryBounds {
+    pub base: usize,
+    pub len: usize,
+    /// Called with the faulting address on an out of bounds access; the
+    /// generated function returns immediately afterwards
+    pub on_out_of_bounds: extern "C" fn(u64),
+}
+
+/// A zero initialized region of host memory, accessed by offset like a
+/// WebAssembly linear memory.
 #[derive(Debug)]
+pub struct LinearMemory {
+    allocation: Arc<MemoryAllocation>,
+}
+
+/// The memory behind a [`LinearMemory`].  Generated code that uses the
+/// memory holds on to it too, so it's only freed once nothing can access it.
+#[derive(Debug)]
+pub(crate) struct MemoryAllocation {
+    base: *mut u8,
+    pages: usize,
+}
+
+// the memory is only accessed through the `LinearMemory`, which uniquely owns
+// it like a `Box<[u8]>`, and by generated code
+unsafe impl Send for MemoryAllocation {}
+unsafe impl Sync for MemoryAllocation {}
+
+impl Drop for MemoryAllocation {
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.base, LinearMemory::layout(self.pages)) };
+    }
+}
+
+impl std::hash::Hash for LinearMemory {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.allocation.base.hash(state);
+        self.allocation.pages.hash(state);
+    }
+}
+
+impl LinearMemory {
+    /// Same as a WebAssembly page
+    pub const PAGE_SIZE: usize = 0x10000;
+
+    pub fn new(pages: usize) -> Self {
+        assert!(pages > 0, "Linear memory must have at least one page");
+        let base = unsafe { std::alloc::alloc_zeroed(Self::layout(pages)) };
+        assert!(!base.is_null(), "Failed to allocate linear memory");
+        Self {
+            allocation: Arc::new(MemoryAllocation { base, pages }),
+        }
+    }
+
+    fn layout(pages: usize) -> std::alloc::Layout {
+        std::alloc::Layout::from_size_align(pages * Self::PAGE_SIZE, Self::PAGE_SIZE).unwrap()
+    }
+
+    /// Keep the memory around for as long as code referring to it
+    pub(crate) fn allocation(&self) -> Arc<MemoryAllocation> {
+        Arc::clone(&self.allocation)
+    }
+
+    /// Another handle to the same memory, for [`Context::fork`]
+    fn share(&self) -> Self {
+        Self {
+            allocation: self.allocation(),
+        }
+    }
+
+    /// The base pointer, for use in computations
+    pub fn base(&self) -> Value {
+        Value::Immediate {
+            _type: PrimitiveValue::U64,
+            value: self.allocation.base as usize,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.allocation.pages * Self::PAGE_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.allocation.base, self.len()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.allocation.base, self.len()) }
+    }
+
+    /// Bounds covering the whole memory, to pass to [`Context::set_memory_bounds`]
+    pub fn bounds(&self, on_out_of_bounds: extern "C" fn(u64)) -> MemoryBounds {
+        MemoryBounds {
+            base: self.allocation.base as usize,
+            len: self.len(),
+            on_out_of_bounds,
+        }
+    }
+}
+
+/// Top level type to generate IR with
+#[derive(Debug, Hash)]
 pub struct Context {
     /// Global constants
     pub(crate) constants: Vec<Vec<u8>>,
+    /// If set, every `Load` and `Store` through a pointer is checked against this
+    pub(crate) memory_bounds: Option<MemoryBounds>,
+    /// The type of every register, computed by `finalize`
+    pub(crate) register_types: BTreeMap<RegisterIndex, PrimitiveValue>,
+    /// Memory accessed by `MemLoad` and `MemStore`.
+    ///
+    /// Generated code refers to it directly, so it keeps the memory alive
+    /// even after the `Context` is dropped or given a new memory.
+    pub(crate) linear_memory: Option<LinearMemory>,
     // TODO: add global variables here
     /// The basic block / CFG
     pub(crate) basic_blocks: BasicBlockManager,
+    /// The types of the fields of the struct `ReturnStruct` returns
+    pub(crate) return_struct: Option<Vec<PrimitiveValue>>,
+    /// Whether `finalize` has been called since the blocks were last changed
+    pub(crate) finalized: bool,
 }
 
 impl Context {
     pub fn new() -> Context {
         Self {
             constants: vec![],
+            memory_bounds: None,
+            register_types: BTreeMap::new(),
+            linear_memory: None,
             basic_blocks: BasicBlockManager::new(),
+            return_struct: None,
+            finalized: false,
         }
     }
 
+    /// Empty the `Context` so it can be used to build another program, keeping
+    /// the memory it's already allocated.
+    ///
+    /// The linear memory and memory bounds go too, so code generated from the
+    /// old program mustn't be run after this.
+    pub fn clear(&mut self) {
+        self.constants.clear();
+        self.memory_bounds = None;
+        self.register_types.clear();
+        self.linear_memory = None;
+        self.basic_blocks.clear();
+        self.return_struct = None;
+        self.finalized = false;
+    }
+
+    /// A copy of the program for changes made just to generate code for it,
+    /// like spilling registers.  It accesses the same linear memory, and
+    /// registers added to either are numbered together so they never clash.
+    pub(crate) fn fork(&self) -> Context {
+        Self {
+            constants: self.constants.clone(),
+            memory_bounds: self.memory_bounds,
+            register_types: self.register_types.clone(),
+            linear_memory: self.linear_memory.as_ref().map(LinearMemory::share),
+            basic_blocks: self.basic_blocks.fork(),
+            return_struct: self.return_struct.clone(),
+            finalized: self.finalized,
+        }
+    }
+
+    /// Make the function return a struct with fields of these types, in
+    /// order, laid out like a `#[repr(C)]` struct.  See [`IR::ReturnStruct`].
+    pub fn set_return_struct(&mut self, fields: Vec<PrimitiveValue>) {
+        self.return_struct = Some(fields);
+    }
+
+    pub fn return_struct(&self) -> Option<&[PrimitiveValue]> {
+        self.return_struct.as_deref()
+    }
+
     pub fn add_constant(&mut self, constant: &[u8]) -> ConstantIndex {
         self.constants.push(constant.to_vec());
         ConstantIndex(self.constants.len() as u32 - 1)
     }
 
+    /// A constant holding `v` in the target's byte order, so loading through
+    /// its address gives back `v`
+    pub fn add_u32_constant(&mut self, v: u32) -> ConstantIndex {
+        // x86_64 is little endian
+        let ci = self.add_constant(&v.to_le_bytes());
+        self.basic_blocks
+            .constant_types
+            .insert(ci, PrimitiveValue::U32);
+        ci
+    }
+
+    /// A constant holding `v` in the target's byte order, so loading through
+    /// its address gives back `v`
+    pub fn add_u64_constant(&mut self, v: u64) -> ConstantIndex {
+        let ci = self.add_constant(&v.to_le_bytes());
+        self.basic_blocks
+            .constant_types
+            .insert(ci, PrimitiveValue::U64);
+        ci
+    }
+
     // TODO: revisit types
     pub fn get_constant(&self, ci: ConstantIndex) -> Option<&Vec<u8>> {
         self.constants.get(ci.0 as usize)
     }
 
     pub fn new_basic_block(&mut self) -> BasicBlockIndex {
+        self.finalized = false;
         self.basic_blocks.new_basic_block()
     }
 
+    /// Start running the program at `block` instead of the first block.
+    ///
+    /// The entry block may still be jumped to from other blocks.
+    pub fn set_entry(&mut self, block: BasicBlockIndex) {
+        assert!(
+            self.basic_blocks.get(block).is_some(),
+            "entry block {:?} doesn't exist",
+            block
+        );
+        self.finalized = false;
+        self.basic_blocks.start = block;
+    }
+
+    pub fn entry(&self) -> BasicBlockIndex {
+        self.basic_blocks.start
+    }
+
+    /// Add an argument to the function, passed in the way the System V ABI
+    /// passes integers: the first six in registers and the rest on the
+    /// stack.  `U128` and `I128` arguments take two registers.
+    ///
+    /// It's defined at the top of the entry block, so the entry has to be
+    /// created (and set, if it's not the first block) first.
+    ///
+    /// The parameter is the argument after the highest one read so far, so
+    /// after [`Context::argument`] reads argument 5 the next one added is
+    /// argument 6, whichever arguments before it were read.
+    pub fn add_parameter(&mut self, _type: PrimitiveValue) -> Value {
+        let index = self
+            .basic_blocks
+            .get(self.basic_blocks.start)
+            .expect("create the entry block before adding parameters")
+            .iterate_instructions()
+            .filter_map(|inst| match inst {
+                IR::Parameter { index, .. } => Some(index + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        self.argument(index, _type)
+    }
+
+    /// Read the `index`th argument the function was called with, like
+    /// [`Context::add_parameter`] but for any argument, in any order.  Each
+    /// read gets its own register, all of them loaded before anything else
+    /// runs.
+    pub fn argument(&mut self, index: usize, _type: PrimitiveValue) -> Value {
+        self.finalized = false;
+        let dest_register = self.basic_blocks.new_register();
+        let entry = self.basic_blocks.start;
+        let code = &mut self
+            .basic_blocks
+            .get_mut(entry)
+            .expect("create the entry block before reading arguments")
+            .code;
+        // after the phis and the parameters already added
+        let position = code
+            .iter()
+            .position(|inst| !matches!(inst, IR::Phi { .. } | IR::Parameter { .. }))
+            .unwrap_or(code.len());
+        code.insert(
+            position,
+            IR::Parameter {
+                dest_register,
+                _type,
+                index,
+            },
+        );
+        Value::Register(dest_register)
+    }
+
+    /// Give the program a linear memory of `pages` pages, replacing any existing one
+    pub fn add_linear_memory(&mut self, pages: usize) -> &mut LinearMemory {
+        self.linear_memory = Some(LinearMemory::new(pages));
+        self.linear_memory.as_mut().unwrap()
+    }
+
+    pub fn linear_memory(&self) -> Option<&LinearMemory> {
+        self.linear_memory.as_ref()
+    }
+
+    pub fn linear_memory_mut(&mut self) -> Option<&mut LinearMemory> {
+        self.linear_memory.as_mut()
+    }
+
+    /// Check that every `Load` and `Store` through a pointer stays within
+    /// `bounds`.
+    ///
+    /// Pointers to stack allocations made with `alloca` aren't checked.
+    pub fn set_memory_bounds(&mut self, bounds: MemoryBounds) {
+        self.memory_bounds = Some(bounds);
+    }
+
     pub fn build_basic_block(&mut self, bi: BasicBlockIndex) -> &mut BasicBlock {
+        self.finalized = false;
         self.basic_blocks.get_mut(bi).unwrap()
     }
 
+    /// Branch from the end of `from` to a new "then" or "else" block depending
+    /// on `cond`, returning the block both of them join back up in.
+    ///
+    /// As in C, `then` is taken if `cond` is non-zero.  If either of the
+    /// builders terminates its block itself (for example with a `ret`), it
+    /// won't jump to the join block.
+    pub fn build_if(
+        &mut self,
+        from: BasicBlockIndex,
+        cond: Value,
+        then: impl FnOnce(&mut BasicBlock),
+        else_: impl FnOnce(&mut BasicBlock),
+    ) -> BasicBlockIndex {
+        let then_idx = self.new_basic_block();
+        let else_idx = self.new_basic_block();
+        let join_idx = self.new_basic_block();
+
+        let from_bb = self.build_basic_block(from);
+        assert!(
+            !from_bb.is_terminated(),
+            "Can't branch out of a basic block that's already been terminated"
+        );
+        from_bb.jump_if_equal(cond, else_idx, then_idx);
+
+        self.build_branch_arm(then_idx, join_idx, then);
+        self.build_branch_arm(else_idx, join_idx, else_);
+
+        join_idx
+    }
+
+    /// Loop from the end of `from` for as long as the value returned by `cond`
+    /// is non-zero, returning the block the loop exits to.
+    ///
+    /// `cond` builds the loop header, which is evaluated before every
+    /// iteration, and `body` builds the loop body, which jumps back to the
+    /// header unless it terminates its block itself.
+    pub fn build_while(
+        &mut self,
+        from: BasicBlockIndex,
+        cond: impl FnOnce(&mut BasicBlock) -> Value,
+        body: impl FnOnce(&mut BasicBlock),
+    ) -> BasicBlockIndex {
+        let header_idx = self.new_basic_block();
+        let body_idx = self.new_basic_block();
+        let exit_idx = self.new_basic_block();
+
+        let from_bb = self.build_basic_block(from);
+        assert!(
+            !from_bb.is_terminated(),
+            "Can't loop out of a basic block that's already been terminated"
+        );
+        from_bb.jump(header_idx);
+
+        let header_bb = self.build_basic_block(header_idx);
+        let cond_value = cond(header_bb);
+        header_bb.jump_if_equal(cond_value, exit_idx, body_idx);
+
+        self.build_branch_arm(body_idx, header_idx, body);
+
+        exit_idx
+    }
+
+    fn build_branch_arm(
+        &mut self,
+        idx: BasicBlockIndex,
+        join_idx: BasicBlockIndex,
+        builder: impl FnOnce(&mut BasicBlock),
+    ) {
+        let bb = self.build_basic_block(idx);
+        builder(bb);
+        if !bb.is_terminated() {
+            bb.jump(join_idx);
+        }
+    }
+
+    /// Apply `f` to every instruction in the program, then rebuild the CFG in
+    /// case any branches were changed
+    pub fn map_instructions(&mut self, mut f: impl FnMut(&mut IR)) {
+        for block in self.basic_blocks.iter_basic_blocks_mut() {
+            block.instructions_mut().iter_mut().for_each(&mut f);
+        }
+        self.rebuild_cfg();
+        self.register_types = self.compute_register_types();
+    }
+
+    /// Recompute the parents and exits of every block from the instructions
+    pub fn rebuild_cfg(&mut self) {
+        self.basic_blocks.rebuild_cfg();
+    }
+
+    /// Check the IR for mistakes; see [`crate::validate`]
+    pub fn validate(&self) -> Result<(), Vec<crate::validate::ValidationError>> {
+        crate::validate::validate(self)
+    }
+
+    /// Remove every `Nop`
+    pub fn strip_nops(&mut self) {
+        for block in self.basic_blocks.iter_basic_blocks_mut() {
+            block.instructions_mut().retain(|inst| !matches!(inst, IR::Nop));
+        }
+    }
+
+    /// Reorder the instructions in each basic block to overlap latencies.
+    ///
+    /// See [`crate::schedule`].
+    pub fn schedule_instructions(&mut self) {
+        for block in self.basic_blocks.iter_basic_blocks_mut() {
+            crate::schedule::schedule_basic_block(block);
+        }
+    }
+
+    /// Remove instructions whose results are never used and that have no
+    /// other effect, returning how many were removed.
+    ///
+    /// See [`crate::ir::dce`].
+    pub fn eliminate_dead_code(&mut self) -> usize {
+        dce::eliminate_dead_code(self)
+    }
+
+    /// Compute the address of each constant once, outside of any loops.
+    ///
+    /// See [`crate::ir::licm`].
+    pub fn hoist_constant_addrs(&mut self) {
+        licm::hoist_constant_addrs(self);
+    }
+
+    /// Thread jumps, merge blocks, and remove empty and unreachable blocks
+    /// until the CFG stops changing.
+    ///
+    /// See [`crate::ir::optimize::simplify_cfg`].
+    pub fn simplify_cfg(&mut self) {
+        optimize::simplify_cfg(self);
+    }
+
+    /// Blocks that were created but never had anything added to them, and
+    /// aren't marked with [`BasicBlock::set_placeholder`].  These are usually
+    /// a forgotten [`Context::build_basic_block`], and control would fall
+    /// through them into whatever block comes next.
+    pub fn unbuilt_blocks(&self) -> Vec<BasicBlockIndex> {
+        self.iterate_basic_blocks()
+            .filter(|(_, bb)| bb.code.is_empty() && !bb.placeholder)
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    /// Get the `Context` ready for code generation.  This has to be called
+    /// again after any of the blocks are changed.
+    ///
+    /// Blocks that were never built are reported by [`Context::validate`];
+    /// see [`Context::unbuilt_blocks`].
     pub fn finalize(&mut self) {
         self.basic_blocks.finalize();
+        self.register_types = self.compute_register_types();
         crate::reg_alloc::compute_graph(&self.basic_blocks);
+        self.finalized = true;
+    }
+
+    /// Whether [`Context::finalize`] has been called since the blocks were
+    /// last changed
+    pub fn is_finalized(&self) -> bool {
+        self.finalized
+    }
+
+    /// Generate the code for the basic block `idx` again after changing it,
+    /// and patch it into `code` in place.
+    ///
+    /// See [`crate::codegen::x86_64::recompile_block`].
+    pub fn recompile_block(
+        &self,
+        idx: BasicBlockIndex,
+        code: &mut crate::codegen::x86_64::GeneratedCode,
+        options: &crate::codegen::x86_64::CodeGenOptions,
+    ) -> Result<(), crate::codegen::x86_64::CodeGenError> {
+        crate::codegen::x86_64::recompile_block(self, idx, code, options)
+    }
+
+    pub(crate) fn iterate_basic_blocks(
+        &self,
+    ) -> impl Iterator<Item = (BasicBlockIndex, &BasicBlock)> {
+        self.basic_blocks.iterate_basic_blocks()
+    }
+
+    /// The type of every register, as of the last call to `finalize`
+    pub fn register_types(&self) -> &BTreeMap<RegisterIndex, PrimitiveValue> {
+        &self.register_types
+    }
+
+    /// The type of a value, if it's known
+    pub fn value_type(&self, v: Value) -> Option<PrimitiveValue> {
+        match v {
+            Value::Register(r) => self.register_types.get(&r).copied(),
+            Value::Immediate { _type, .. } => Some(_type),
+        }
+    }
+
+    fn compute_register_types(&self) -> BTreeMap<RegisterIndex, PrimitiveValue> {
+        self.basic_blocks.compute_register_types()
+    }
+
+    /// Every instruction in the program along with its position, in block order
+    pub fn iter_instructions(&self) -> impl Iterator<Item = (BasicBlockIndex, usize, &IR)> {
+        self.iterate_basic_blocks().flat_map(|(bb_idx, bb)| {
+            bb.iterate_instructions()
+                .enumerate()
+                .map(move |(i, inst)| (bb_idx, i, inst))
+        })
+    }
+
+    pub fn basic_blocks(&self) -> &BasicBlockManager {
+        &self.basic_blocks
+    }
+
+    /// The blocks `block` can jump or fall through to.
+    ///
+    /// These come from the instructions, so they're up to date even if the
+    /// CFG hasn't been rebuilt after rewriting them.
+    pub fn successors(&self, block: BasicBlockIndex) -> impl Iterator<Item = BasicBlockIndex> {
+        self.basic_blocks.successors(block).into_iter()
     }
 
-    pub(crate) fn iterate_basic_blocks(
+    /// The blocks that can jump or fall through to `block`, in block order
+    pub fn predecessors(
         &self,
-    ) -> impl Iterator<Item = (BasicBlockIndex, &BasicBlock)> {
-        self.basic_blocks.iterate_basic_blocks()
+        block: BasicBlockIndex,
+    ) -> impl Iterator<Item = BasicBlockIndex> + '_ {
+        self.iterate_basic_blocks()
+            .map(|(idx, _)| idx)
+            .filter(move |idx| self.basic_blocks.successors(*idx).contains(&block))
     }
 }
 
-// TODO: maybe use an atomic here or think about data flow and avoid a global
-lazy_static! {
-    static ref LAST_REGISTER: Mutex<u32> = Mutex::new(0);
-}
-
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum BasicBlockMessage {
     /// A Jump from the first index to the second occured.
@@ -207,6 +1323,12 @@ pub struct BasicBlock {
     /// TODO: use fancier types here
     exits: SmallVec<[BasicBlockIndex; 2]>,
     code: Vec<IR>,
+    /// Rarely executed, so it should be kept out of the way of the hot path
+    cold: bool,
+    /// Left empty on purpose, so [`Context::finalize`] doesn't complain
+    placeholder: bool,
+    /// The exit most likely to be taken, laid out right after this block
+    likely_exit: Option<BasicBlockIndex>,
     /// Its own index, used due to [`BasicBlockMessage`]
     self_idx: BasicBlockIndex,
     /// A bit of a hack to allow things like `jump` to exist on `BasicBlock`:
@@ -216,6 +1338,20 @@ pub struct BasicBlock {
     /// because it will help keep the public API simple.  This should be reevaluated
     /// later though.
     manager_chan: mpsc::Sender<BasicBlockMessage>,
+    /// The last register allocated in the [`Context`], shared by all of its
+    /// blocks
+    last_register: Arc<AtomicU32>,
+}
+
+/// Hashes the contents of the block but not its connection to the manager
+impl std::hash::Hash for BasicBlock {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.parents.hash(state);
+        self.exits.hash(state);
+        self.code.hash(state);
+        self.cold.hash(state);
+        self.likely_exit.hash(state);
+    }
 }
 
 impl BasicBlock {
@@ -229,6 +1365,39 @@ impl BasicBlock {
         self
     }
 
+    /// Mark the block as unlikely to run, like error handling, so that it's
+    /// emitted after the rest of the code
+    pub fn set_cold(&mut self, cold: bool) -> &mut Self {
+        self.cold = cold;
+        self
+    }
+
+    pub fn is_cold(&self) -> bool {
+        self.cold
+    }
+
+    /// Mark the block as meant to be empty, like one a pass will fill in
+    /// later, so [`Context::validate`] doesn't report it while it's empty or
+    /// nothing reaches it
+    pub fn set_placeholder(&mut self, placeholder: bool) -> &mut Self {
+        self.placeholder = placeholder;
+        self
+    }
+
+    pub fn is_placeholder(&self) -> bool {
+        self.placeholder
+    }
+
+    /// Whether the last instruction in the block transfers control elsewhere
+    pub fn is_terminated(&self) -> bool {
+        self.code
+            .iter()
+            .rev()
+            .find(|inst| !matches!(inst, IR::Nop))
+            .map(IR::is_terminator)
+            .unwrap_or(false)
+    }
+
     pub(crate) fn iter_parents(&self) -> impl Iterator<Item = &BasicBlockIndex> {
         self.parents.iter()
     }
@@ -236,15 +1405,7 @@ impl BasicBlock {
         self.exits.iter()
     }
     pub(crate) fn iter_defined_registers(&self) -> impl Iterator<Item = &RegisterIndex> {
-        self.code.iter().filter_map(|c| match c {
-            IR::Alloca { dest_register, .. }
-            | IR::Add { dest_register, .. }
-            | IR::Subtract { dest_register, .. }
-            | IR::Multiply { dest_register, .. }
-            | IR::Load { dest_register, .. }
-            | IR::Divide { dest_register, .. } => Some(dest_register),
-            _ => None,
-        })
+        self.code.iter().flat_map(|c| c.get_defined_registers())
     }
     pub(crate) fn iter_used_registers(&self) -> impl Iterator<Item = &RegisterIndex> {
         self.code.iter().flat_map(|c| c.get_used_registers())
@@ -256,13 +1417,19 @@ impl BasicBlock {
         self.code.iter()
     }
 
+    /// Direct access to the instructions, for rewriting passes.
+    ///
+    /// Call [`Context::rebuild_cfg`] afterwards if control flow was changed.
+    pub fn instructions_mut(&mut self) -> &mut Vec<IR> {
+        &mut self.code
+    }
+
+    fn new_register(&self) -> RegisterIndex {
+        RegisterIndex(self.last_register.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
     pub fn alloca(&mut self, _type: PrimitiveValue, alignment: u8) -> Value {
-        let n = {
-            let mut lr = LAST_REGISTER.lock().unwrap();
-            *lr += 1;
-            *lr
-        };
-        let ri = RegisterIndex(n);
+        let ri = self.new_register();
         self.code.push(IR::Alloca {
             dest_register: ri,
             _type,
@@ -275,16 +1442,113 @@ impl BasicBlock {
         self.code.push(IR::Return);
     }
 
+    pub fn ret_value(&mut self, value: Value) {
+        self.code.push(IR::ReturnValue { value });
+    }
+
+    /// Return a struct with the fields given to [`Context::set_return_struct`]
+    pub fn ret_struct(&mut self, values: &[Value]) {
+        self.code.push(IR::ReturnStruct {
+            values: values.iter().copied().collect(),
+        });
+    }
+
+    pub fn print_int(&mut self, src: Value, _type: PrimitiveValue) {
+        self.code.push(IR::PrintInt { src, _type });
+    }
+
+    /// Check that `cond` isn't zero when debug assertions are generated,
+    /// reporting the constant `message` if it is.  See [`IR::Assert`].
+    pub fn assert(&mut self, cond: Value, message: ConstantIndex) {
+        self.code.push(IR::Assert {
+            cond,
+            message_constant: message,
+        });
+    }
+
+    /// Call a host function, like an `extern "C" fn(*mut u64)` cast to
+    /// `usize`, with `args`.  See [`IR::Call`].
+    pub fn call_host(&mut self, function: usize, symbol: &'static str, args: &[Value]) {
+        self.code.push(IR::Call {
+            function,
+            symbol,
+            args: args.iter().copied().collect(),
+            dest: None,
+        });
+    }
+
+    /// [`call_host`](Self::call_host) for a function returning an integer of
+    /// type `_type`, which is what the returned register holds
+    pub fn call_host_with_result(
+        &mut self,
+        function: usize,
+        symbol: &'static str,
+        args: &[Value],
+        _type: PrimitiveValue,
+    ) -> Value {
+        let ri = self.new_register();
+        self.code.push(IR::Call {
+            function,
+            symbol,
+            args: args.iter().copied().collect(),
+            dest: Some((ri, _type)),
+        });
+        Value::Register(ri)
+    }
+
+    /// Abort the guest with an error code for the host
+    pub fn trap(&mut self, code: u64) {
+        self.code.push(IR::Trap { code });
+    }
+
+    /// End the block, promising it's never reached this far
+    pub fn unreachable(&mut self) {
+        self.code.push(IR::Unreachable);
+    }
+
+    /// Emit `bytes` as machine code, with `uses` passed in and `outputs`
+    /// new registers taken out as described in [`IR::InlineBytes`]
+    pub fn inline_bytes(&mut self, bytes: &[u8], uses: &[Value], outputs: usize) -> Vec<Value> {
+        assert!(
+            uses.len() <= 2 && outputs <= 2,
+            "inline code can only take and produce 2 values"
+        );
+        let uses = uses
+            .iter()
+            .map(|v| match v {
+                Value::Register(r) => *r,
+                Value::Immediate { .. } => panic!("inline code can only use registers"),
+            })
+            .collect();
+        let defines: SmallVec<[RegisterIndex; 2]> =
+            (0..outputs).map(|_| self.new_register()).collect();
+        let out = defines.iter().map(|r| Value::Register(*r)).collect();
+        self.code.push(IR::InlineBytes {
+            bytes: bytes.to_vec(),
+            defines,
+            uses,
+        });
+        out
+    }
+
     pub fn load(&mut self, src: Value) -> Value {
-        let n = {
-            let mut lr = LAST_REGISTER.lock().unwrap();
-            *lr += 1;
-            *lr
-        };
-        let ri = RegisterIndex(n);
+        let ri = self.new_register();
         self.code.push(IR::Load {
             dest_register: ri,
             src_register: src,
+            extend_to: None,
+        });
+        Value::Register(ri)
+    }
+
+    /// Load from `src` and extend the value to `_type`, sign extending if
+    /// `_type` is signed and zero extending if not
+    pub fn load_extended(&mut self, src: Value, _type: PrimitiveValue) -> Value {
+        let ri = self.new_register();
+        self.code.push(IR::Load {
+            dest_register: ri,
+            src_register: src,
+            extend_to: Some(_type),
         });
         Value::Register(ri)
     }
@@ -296,36 +1560,198 @@ impl BasicBlock {
         });
     }
 
+    pub fn mem_load(&mut self, offset: Value) -> Value {
+        let ri = self.new_register();
+        self.code.push(IR::MemLoad {
+            dest_register: ri,
+            offset,
+        });
+        Value::Register(ri)
+    }
+
+    pub fn mem_store(&mut self, offset: Value, src: Value) {
+        self.code.push(IR::MemStore { offset, src });
+    }
+
+    /// Arithmetic that traps on overflow can leave the block
+    fn overflow_exit(&mut self, overflow: Overflow) {
+        if let Overflow::Trap(trap) = overflow {
+            self.exits.push(trap);
+            self.manager_chan
+                .send(BasicBlockMessage::Jump(self.self_idx, trap))
+                .unwrap();
+        }
+    }
+
     pub fn add(&mut self, v1: Value, v2: Value) -> Value {
-        let n = {
-            let mut lr = LAST_REGISTER.lock().unwrap();
-            *lr += 1;
-            *lr
-        };
-        let ri = RegisterIndex(n);
+        self.add_with_overflow(v1, v2, Overflow::Wrap)
+    }
+
+    pub fn add_with_overflow(&mut self, v1: Value, v2: Value, overflow: Overflow) -> Value {
+        let ri = self.new_register();
+        self.overflow_exit(overflow);
         self.code.push(IR::Add {
             dest_register: ri,
             src1: v1,
             src2: v2,
+            overflow,
         });
         Value::Register(ri)
     }
 
+    /// Like [`BasicBlock::add`], also returning whether the result overflowed
+    /// as a `U32` of 0 or 1; see [`Overflow::Flag`]
+    pub fn add_with_flag(&mut self, v1: Value, v2: Value) -> (Value, Value) {
+        let flag = self.new_register();
+        let result = self.add_with_overflow(v1, v2, Overflow::Flag(flag));
+        (result, Value::Register(flag))
+    }
+
     pub fn subtract(&mut self, v1: Value, v2: Value) -> Value {
-        let n = {
-            let mut lr = LAST_REGISTER.lock().unwrap();
-            *lr += 1;
-            *lr
-        };
-        let ri = RegisterIndex(n);
+        self.subtract_with_overflow(v1, v2, Overflow::Wrap)
+    }
+
+    pub fn subtract_with_overflow(&mut self, v1: Value, v2: Value, overflow: Overflow) -> Value {
+        let ri = self.new_register();
+        self.overflow_exit(overflow);
         self.code.push(IR::Subtract {
             dest_register: ri,
             src1: v1,
             src2: v2,
+            overflow,
+        });
+        Value::Register(ri)
+    }
+
+    /// Like [`BasicBlock::subtract`], also returning whether the result overflowed
+    /// as a `U32` of 0 or 1; see [`Overflow::Flag`]
+    pub fn subtract_with_flag(&mut self, v1: Value, v2: Value) -> (Value, Value) {
+        let flag = self.new_register();
+        let result = self.subtract_with_overflow(v1, v2, Overflow::Flag(flag));
+        (result, Value::Register(flag))
+    }
+
+    pub fn multiply(&mut self, v1: Value, v2: Value) -> Value {
+        self.multiply_with_overflow(v1, v2, Overflow::Wrap)
+    }
+
+    pub fn multiply_with_overflow(&mut self, v1: Value, v2: Value, overflow: Overflow) -> Value {
+        let ri = self.new_register();
+        self.overflow_exit(overflow);
+        self.code.push(IR::Multiply {
+            dest_register: ri,
+            src1: v1,
+            src2: v2,
+            overflow,
+        });
+        Value::Register(ri)
+    }
+
+    /// Like [`BasicBlock::multiply`], also returning whether the result overflowed
+    /// as a `U32` of 0 or 1; see [`Overflow::Flag`]
+    pub fn multiply_with_flag(&mut self, v1: Value, v2: Value) -> (Value, Value) {
+        let flag = self.new_register();
+        let result = self.multiply_with_overflow(v1, v2, Overflow::Flag(flag));
+        (result, Value::Register(flag))
+    }
+
+    /// 1 if `v1` and `v2` compare as `comparison`, otherwise 0
+    pub fn compare(&mut self, comparison: Comparison, v1: Value, v2: Value) -> Value {
+        let ri = self.new_register();
+        self.code.push(IR::Compare {
+            dest_register: ri,
+            comparison,
+            src1: v1,
+            src2: v2,
+        });
+        Value::Register(ri)
+    }
+
+    /// Put `value` in `register` at this point; see [`IR::Pin`]
+    pub fn pin(&mut self, value: Value, register: MachineRegister) {
+        self.code.push(IR::Pin { value, register });
+    }
+
+    /// A pointer to the bytes of a constant
+    pub fn constant_addr(&mut self, constant_ref: ConstantIndex) -> Value {
+        let ri = self.new_register();
+        self.code.push(IR::ConstantAddr {
+            dest_register: ri,
+            constant_ref,
+        });
+        Value::Register(ri)
+    }
+
+    pub fn copy(&mut self, src: Value) -> Value {
+        let ri = self.new_register();
+        self.code.push(IR::Copy {
+            dest_register: ri,
+            src,
+        });
+        Value::Register(ri)
+    }
+
+    pub fn divide(&mut self, v1: Value, v2: Value) -> Value {
+        let ri = self.new_register();
+        self.code.push(IR::Divide {
+            dest_register: ri,
+            src1: v1,
+            src2: v2,
         });
         Value::Register(ri)
     }
 
+    pub fn remainder(&mut self, v1: Value, v2: Value) -> Value {
+        let ri = self.new_register();
+        self.code.push(IR::Remainder {
+            dest_register: ri,
+            src1: v1,
+            src2: v2,
+        });
+        Value::Register(ri)
+    }
+
+    pub fn shift_left(&mut self, v1: Value, v2: Value) -> Value {
+        let ri = self.new_register();
+        self.code.push(IR::ShiftLeft {
+            dest_register: ri,
+            src1: v1,
+            src2: v2,
+        });
+        Value::Register(ri)
+    }
+
+    pub fn shift_right(&mut self, v1: Value, v2: Value) -> Value {
+        let ri = self.new_register();
+        self.code.push(IR::ShiftRight {
+            dest_register: ri,
+            src1: v1,
+            src2: v2,
+        });
+        Value::Register(ri)
+    }
+
+    /// Narrow `src` to `dest_type`, continuing at `trap` if it's out of range
+    pub fn truncate_checked(
+        &mut self,
+        src: Value,
+        dest_type: PrimitiveValue,
+        trap: BasicBlockIndex,
+    ) -> Value {
+        let ri = self.new_register();
+        self.exits.push(trap);
+        self.code.push(IR::TruncateChecked {
+            dest_register: ri,
+            dest_type,
+            src,
+            trap,
+        });
+        self.manager_chan
+            .send(BasicBlockMessage::Jump(self.self_idx, trap))
+            .unwrap();
+        Value::Register(ri)
+    }
+
     pub fn jump(&mut self, target: BasicBlockIndex) {
         self.exits.push(target);
         self.code.push(IR::Jump { bb_idx: target });
@@ -334,6 +1760,14 @@ impl BasicBlock {
             .unwrap();
     }
 
+    /// Continue at `next` when this block is done.  `next` is laid out right
+    /// after this block when it can be, so control falls through to it
+    /// without a jump.
+    pub fn then(&mut self, next: BasicBlockIndex) {
+        self.jump(next);
+        self.likely_exit = Some(next);
+    }
+
     /// jumps if register is 0
     pub fn jump_if_equal(
         &mut self,
@@ -341,6 +1775,22 @@ impl BasicBlock {
         true_target: BasicBlockIndex,
         false_target: BasicBlockIndex,
     ) {
+        self.jump_if_equal_with_hint(register, true_target, false_target, None)
+    }
+
+    /// [`jump_if_equal`](Self::jump_if_equal), with a hint of which target is
+    /// likely so it can be the one that's fallen through to
+    pub fn jump_if_equal_with_hint(
+        &mut self,
+        register: Value,
+        true_target: BasicBlockIndex,
+        false_target: BasicBlockIndex,
+        hint: Option<BranchHint>,
+    ) {
+        self.likely_exit = hint.map(|hint| match hint {
+            BranchHint::TrueLikely => true_target,
+            BranchHint::FalseLikely => false_target,
+        });
         self.exits.push(true_target);
         self.exits.push(false_target);
         self.code.push(IR::JumpIfEqual {
@@ -376,6 +1826,18 @@ pub struct BasicBlockIndex(u32);
 #[repr(transparent)]
 pub struct RegisterIndex(u32);
 
+impl std::fmt::Display for BasicBlockIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "bb{}", self.0)
+    }
+}
+
+impl std::fmt::Display for RegisterIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "r{}", self.0)
+    }
+}
+
 // TODO: get dominance tree (find blocks that are coupled (i.e. x dominates y if all paths to y include x))
 // DFS on the tree
 // def-use chain (list of uses of variables)
@@ -388,6 +1850,19 @@ pub struct BasicBlockManager {
     message_recv: mpsc::Receiver<BasicBlockMessage>,
     /// only held on to for the `new_basic_block` method
     message_sender: mpsc::Sender<BasicBlockMessage>,
+    /// Registers are numbered from 1 in each `Context`
+    last_register: Arc<AtomicU32>,
+    /// The type held by each constant that was added with one, which is what
+    /// loads through its address read
+    pub(crate) constant_types: BTreeMap<ConstantIndex, PrimitiveValue>,
+}
+
+impl std::hash::Hash for BasicBlockManager {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.start.hash(state);
+        self.blocks.hash(state);
+        self.constant_types.hash(state);
+    }
 }
 
 impl BasicBlockManager {
@@ -398,6 +1873,47 @@ impl BasicBlockManager {
             blocks: vec![],
             message_recv: rx,
             message_sender: tx,
+            last_register: Arc::new(AtomicU32::new(0)),
+            constant_types: BTreeMap::new(),
+        }
+    }
+
+    /// Drop every block, keeping the memory they were in, and start
+    /// numbering blocks and registers from the beginning again
+    pub fn clear(&mut self) {
+        // the blocks that sent these are about to go away
+        self.message_recv.try_iter().for_each(drop);
+        self.blocks.clear();
+        self.constant_types.clear();
+        self.start = BasicBlockIndex(0);
+        self.last_register.store(0, Ordering::Relaxed);
+    }
+
+    /// See [`Context::fork`]
+    fn fork(&self) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let blocks = self
+            .blocks
+            .iter()
+            .map(|bb| BasicBlock {
+                parents: bb.parents.clone(),
+                exits: bb.exits.clone(),
+                code: bb.code.clone(),
+                cold: bb.cold,
+                placeholder: bb.placeholder,
+                likely_exit: bb.likely_exit,
+                self_idx: bb.self_idx,
+                manager_chan: tx.clone(),
+                last_register: Arc::clone(&self.last_register),
+            })
+            .collect();
+        Self {
+            start: self.start,
+            blocks,
+            message_recv: rx,
+            message_sender: tx,
+            last_register: Arc::clone(&self.last_register),
+            constant_types: self.constant_types.clone(),
         }
     }
 
@@ -405,7 +1921,10 @@ impl BasicBlockManager {
         for message in self.message_recv.try_iter() {
             match message {
                 BasicBlockMessage::Jump(src, target) => {
-                    self.blocks[target.0 as usize].add_parent(src);
+                    // jumps to blocks that don't exist are caught by validation
+                    if let Some(block) = self.blocks.get_mut(target.0 as usize) {
+                        block.add_parent(src);
+                    }
                 }
             }
         }
@@ -419,6 +1938,12 @@ impl BasicBlockManager {
         todo!("Check connectivity of basic block");
     }
 
+    /// A register that hasn't been used in the program yet, for passes adding
+    /// instructions
+    pub(crate) fn new_register(&self) -> RegisterIndex {
+        RegisterIndex(self.last_register.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
     pub fn new_basic_block(&mut self) -> BasicBlockIndex {
         self.process_messages();
         let idx = self.blocks.len() as u32;
@@ -426,19 +1951,329 @@ impl BasicBlockManager {
             parents: Default::default(),
             exits: Default::default(),
             code: Default::default(),
+            cold: false,
+            placeholder: false,
+            likely_exit: None,
             self_idx: BasicBlockIndex(idx),
             manager_chan: self.message_sender.clone(),
+            last_register: Arc::clone(&self.last_register),
         });
 
         BasicBlockIndex(idx)
     }
 
+    /// The block that an unterminated block falls through to
+    pub(crate) fn fall_through_target(&self, bi: BasicBlockIndex) -> Option<BasicBlockIndex> {
+        let next = BasicBlockIndex(bi.0 + 1);
+        self.get(next).map(|_| next)
+    }
+
+    /// The blocks `bi` can jump or fall through to, from its instructions,
+    /// without duplicates
+    pub fn successors(&self, bi: BasicBlockIndex) -> SmallVec<[BasicBlockIndex; 2]> {
+        let block = match self.get(bi) {
+            Some(block) => block,
+            None => return SmallVec::new(),
+        };
+        let mut exits: SmallVec<[BasicBlockIndex; 2]> = SmallVec::new();
+        let fall_through = Some(block)
+            .filter(|block| !block.is_terminated())
+            .and_then(|_| self.fall_through_target(bi));
+        for target in block
+            .code
+            .iter()
+            .flat_map(IR::branch_targets)
+            .chain(fall_through)
+        {
+            if !exits.contains(&target) {
+                exits.push(target);
+            }
+        }
+        exits
+    }
+
+    /// Recompute the parents and exits of every block from the instructions.
+    ///
+    /// A block that isn't terminated falls through to the next one.
+    pub fn rebuild_cfg(&mut self) {
+        // anything queued up is about to be recomputed anyway
+        self.message_recv.try_iter().for_each(drop);
+        let num_blocks = self.blocks.len();
+        for i in 0..num_blocks {
+            let exits = self.successors(BasicBlockIndex(i as u32));
+            let block = &mut self.blocks[i];
+            block.parents.clear();
+            block.exits = exits;
+        }
+        for i in 0..num_blocks {
+            for j in 0..self.blocks[i].exits.len() {
+                let exit = self.blocks[i].exits[j];
+                let parents = match self.blocks.get_mut(exit.0 as usize) {
+                    Some(block) => &mut block.parents,
+                    None => continue,
+                };
+                let src = BasicBlockIndex(i as u32);
+                if !parents.contains(&src) {
+                    parents.push(src);
+                }
+            }
+        }
+    }
+
+    /// Remove the blocks in `removed`, numbering the rest from 0 again in the
+    /// same order.  Nothing left may branch or fall through to a removed
+    /// block; phis forget what they took from them.
+    pub(crate) fn remove_blocks(&mut self, removed: &BTreeSet<BasicBlockIndex>) {
+        self.process_messages();
+        let mut renumbered = BTreeMap::new();
+        for (idx, _) in self.iterate_basic_blocks() {
+            if !removed.contains(&idx) {
+                renumbered.insert(idx, BasicBlockIndex(renumbered.len() as u32));
+            }
+        }
+        let old_blocks = std::mem::take(&mut self.blocks);
+        for (i, mut block) in old_blocks.into_iter().enumerate() {
+            let new_idx = match renumbered.get(&BasicBlockIndex(i as u32)) {
+                Some(idx) => *idx,
+                None => continue,
+            };
+            block.self_idx = new_idx;
+            block.likely_exit = block.likely_exit.and_then(|b| renumbered.get(&b).copied());
+            for inst in &mut block.code {
+                for target in inst.branch_targets_mut() {
+                    *target = renumbered[target];
+                }
+                if let IR::Phi { incoming, .. } = inst {
+                    incoming.retain(|(b, _)| !removed.contains(b));
+                    for (b, _) in incoming.iter_mut() {
+                        *b = renumbered[b];
+                    }
+                }
+            }
+            self.blocks.push(block);
+        }
+        self.start = renumbered[&self.start];
+        self.rebuild_cfg();
+    }
+
     // TODO: probably don't expose this
     /// get the manager ready for further processing
     pub fn finalize(&mut self) {
         self.process_messages();
     }
 
+    /// What each pointer register points to: the type held by an `Alloca`'s
+    /// slot or by a typed constant, following copies of the pointer
+    pub(crate) fn pointee_types(&self) -> BTreeMap<RegisterIndex, PrimitiveValue> {
+        let mut pointees = BTreeMap::new();
+        let instructions = || {
+            self.iterate_basic_blocks()
+                .flat_map(|(_, bb)| bb.iterate_instructions())
+        };
+        for inst in instructions() {
+            match inst {
+                IR::Alloca {
+                    dest_register,
+                    _type,
+                    ..
+                } => {
+                    pointees.insert(*dest_register, *_type);
+                }
+                IR::ConstantAddr {
+                    dest_register,
+                    constant_ref,
+                } => {
+                    if let Some(_type) = self.constant_types.get(constant_ref) {
+                        pointees.insert(*dest_register, *_type);
+                    }
+                }
+                _ => (),
+            }
+        }
+        // copies can come before what they copy in block order
+        loop {
+            let mut changed = false;
+            for inst in instructions() {
+                if let IR::Copy {
+                    dest_register,
+                    src: Value::Register(src),
+                } = inst
+                {
+                    if let Some(_type) = pointees.get(src).copied() {
+                        changed |= pointees.insert(*dest_register, _type).is_none();
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        pointees
+    }
+
+    /// Infer the type of every register from the instruction that defines it.
+    ///
+    /// Pointers are `U64`.  Loads through pointers that don't come from an
+    /// `Alloca` or a typed constant are assumed to be `U32`.
+    pub(crate) fn compute_register_types(&self) -> BTreeMap<RegisterIndex, PrimitiveValue> {
+        let mut types: BTreeMap<RegisterIndex, PrimitiveValue> = BTreeMap::new();
+        let pointee_types = self.pointee_types();
+        let value_type = |types: &BTreeMap<RegisterIndex, PrimitiveValue>, v: &Value| match v {
+            Value::Register(r) => types.get(r).copied(),
+            Value::Immediate { _type, .. } => Some(*_type),
+        };
+
+        // blocks aren't necessarily in dominance order, so keep going until
+        // everything that can be resolved has been
+        loop {
+            let mut changed = false;
+            for inst in self
+                .iterate_basic_blocks()
+                .flat_map(|(_, bb)| bb.iterate_instructions())
+            {
+                if let Some(flag) = inst.overflow_flag() {
+                    if types.insert(flag, PrimitiveValue::U32).is_none() {
+                        changed = true;
+                    }
+                }
+                let (dest, _type) = match inst {
+                    IR::Alloca { dest_register, .. } => (dest_register, Some(PrimitiveValue::U64)),
+                    IR::Add {
+                        dest_register,
+                        src1,
+                        src2,
+                        ..
+                    }
+                    | IR::Subtract {
+                        dest_register,
+                        src1,
+                        src2,
+                        ..
+                    }
+                    | IR::Multiply {
+                        dest_register,
+                        src1,
+                        src2,
+                        ..
+                    }
+                    | IR::Divide {
+                        dest_register,
+                        src1,
+                        src2,
+                    }
+                    | IR::Remainder {
+                        dest_register,
+                        src1,
+                        src2,
+                    } => (
+                        dest_register,
+                        value_type(&types, src1).or_else(|| value_type(&types, src2)),
+                    ),
+                    // the shift count can be any type
+                    IR::ShiftLeft {
+                        dest_register,
+                        src1,
+                        ..
+                    }
+                    | IR::ShiftRight {
+                        dest_register,
+                        src1,
+                        ..
+                    } => (dest_register, value_type(&types, src1)),
+                    IR::Compare { dest_register, .. } => (dest_register, Some(PrimitiveValue::U32)),
+                    IR::Copy { dest_register, src } => (dest_register, value_type(&types, src)),
+                    IR::Phi {
+                        dest_register,
+                        incoming,
+                    } => (
+                        dest_register,
+                        incoming.iter().find_map(|(_, v)| value_type(&types, v)),
+                    ),
+                    IR::TruncateChecked {
+                        dest_register,
+                        dest_type,
+                        ..
+                    } => (dest_register, Some(*dest_type)),
+                    IR::Load {
+                        dest_register,
+                        extend_to: Some(_type),
+                        ..
+                    } => (dest_register, Some(*_type)),
+                    IR::Load {
+                        dest_register,
+                        src_register,
+                        extend_to: None,
+                    } => {
+                        let pointee = match src_register {
+                            Value::Register(r) => pointee_types.get(r).copied(),
+                            Value::Immediate { .. } => None,
+                        };
+                        (dest_register, Some(pointee.unwrap_or(PrimitiveValue::U32)))
+                    }
+                    IR::MemLoad { dest_register, .. } => (dest_register, Some(PrimitiveValue::U32)),
+                    IR::ConstantAddr { dest_register, .. } => {
+                        (dest_register, Some(PrimitiveValue::U64))
+                    }
+                    IR::Parameter {
+                        dest_register,
+                        _type,
+                        ..
+                    }
+                    | IR::Call {
+                        dest: Some((dest_register, _type)),
+                        ..
+                    } => (dest_register, Some(*_type)),
+                    IR::InlineBytes { defines, .. } => {
+                        for dest in defines {
+                            if types.insert(*dest, PrimitiveValue::U64).is_none() {
+                                changed = true;
+                            }
+                        }
+                        continue;
+                    }
+                    _ => continue,
+                };
+                if let Some(_type) = _type {
+                    if types.insert(*dest, _type).is_none() {
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        types
+    }
+
+    /// The order blocks are emitted in: the entry block, then the rest of the
+    /// blocks in order with the cold ones last.  A block's likely exit, if it
+    /// has one that isn't placed yet, comes right after it.
+    pub fn layout_order(&self) -> Vec<BasicBlockIndex> {
+        let rest = self
+            .iterate_basic_blocks()
+            .filter(|(i, _)| *i != self.start);
+        let (cold, hot): (Vec<_>, Vec<_>) = rest.partition(|(_, bb)| bb.cold);
+        let mut order = Vec::with_capacity(self.blocks.len());
+        let mut placed = BTreeSet::new();
+        let candidates =
+            std::iter::once(self.start).chain(hot.into_iter().chain(cold).map(|(i, _)| i));
+        for candidate in candidates {
+            let mut next = Some(candidate);
+            while let Some(idx) = next.filter(|idx| placed.insert(*idx)) {
+                order.push(idx);
+                let bb = &self.blocks[idx.0 as usize];
+                next = bb.likely_exit.filter(|likely| {
+                    *likely != self.start
+                        && bb.exits.contains(likely)
+                        && (bb.cold || !self.blocks[likely.0 as usize].cold)
+                });
+            }
+        }
+        order
+    }
+
     pub fn get_mut(&mut self, bi: BasicBlockIndex) -> Option<&mut BasicBlock> {
         self.blocks.get_mut(bi.0 as usize)
     }
@@ -446,7 +2281,7 @@ impl BasicBlockManager {
         self.blocks.get(bi.0 as usize)
     }
 
-    pub(crate) fn iterate_basic_blocks(
+    pub fn iterate_basic_blocks(
         &self,
     ) -> impl Iterator<Item = (BasicBlockIndex, &BasicBlock)> {
         self.blocks
@@ -454,6 +2289,10 @@ impl BasicBlockManager {
             .enumerate()
             .map(|(i, b)| (BasicBlockIndex(i as u32), b))
     }
+
+    pub(crate) fn iter_basic_blocks_mut(&mut self) -> impl Iterator<Item = &mut BasicBlock> {
+        self.blocks.iter_mut()
+    }
 }
 
 /* Register usage detection on basic block:
@@ -594,3 +2433,76 @@ for (location, instruction) in instruction_stream.iter().enumerate() {
         }
     }
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn immediates_must_fit_their_type() {
+        let out_of_range = [
+            (PrimitiveValue::U8, 0x1FF),
+            (PrimitiveValue::U8, -1),
+            (PrimitiveValue::I8, 128),
+            (PrimitiveValue::I8, -129),
+            (PrimitiveValue::U16, 1 << 16),
+            (PrimitiveValue::I16, -(1 << 15) - 1),
+            (PrimitiveValue::U32, 1 << 32),
+            (PrimitiveValue::I32, i32::MAX as i128 + 1),
+            (PrimitiveValue::U64, -1),
+            (PrimitiveValue::I64, u64::MAX as i128),
+        ];
+        for (_type, value) in out_of_range {
+            assert!(
+                Value::immediate(_type, value).is_none(),
+                "{} as {:?}",
+                value,
+                _type
+            );
+        }
+
+        let in_range = [
+            (PrimitiveValue::U8, 0xFF),
+            (PrimitiveValue::I8, -128),
+            (PrimitiveValue::U16, 0xFFFF),
+            (PrimitiveValue::I16, -1),
+            (PrimitiveValue::I32, i32::MIN as i128),
+            (PrimitiveValue::U64, u64::MAX as i128),
+            (PrimitiveValue::I64, i64::MIN as i128),
+        ];
+        for (_type, value) in in_range {
+            match Value::immediate(_type, value) {
+                Some(Value::Immediate { _type: t, value }) => {
+                    assert_eq!(t, _type);
+                    assert!(_type.fits(value), "{:#x} as {:?}", value, _type);
+                }
+                other => panic!("{:?} for {} as {:?}", other, value, _type),
+            }
+        }
+    }
+
+    #[test]
+    fn typed_constructors_give_values_that_fit() {
+        let values = [
+            Value::u8(u8::MAX),
+            Value::i8(i8::MIN),
+            Value::u16(u16::MAX),
+            Value::i16(-1),
+            Value::u32(u32::MAX),
+            Value::i32(i32::MIN),
+            Value::u64(u64::MAX),
+            Value::i64(i64::MIN),
+        ];
+        for v in values {
+            match v {
+                Value::Immediate { _type, value } => {
+                    assert!(_type.fits(value), "{:#x} as {:?}", value, _type)
+                }
+                Value::Register(_) => unreachable!(),
+            }
+        }
+        assert!(!PrimitiveValue::U8.fits(0x1FF));
+        assert!(!PrimitiveValue::I8.fits(0x180));
+        assert!(PrimitiveValue::I8.fits(-0x80i64 as usize));
+    }
+}
@@ -0,0 +1,54 @@
+//! Dead code elimination.
+//!
+//! An instruction is removed if nothing uses the registers it defines and
+//! removing it can't change what the program does: it doesn't touch memory
+//! or the host, and it can't trap.  That can leave what it used dead in
+//! turn, so this repeats until nothing more can be removed.
+
+use super::*;
+use crate::reg_alloc::{compute_graph, GraphQuery};
+
+/// Remove every instruction whose results are never used and that has no
+/// other effect, returning how many were removed
+pub fn eliminate_dead_code(ctx: &mut Context) -> usize {
+    let mut removed = 0;
+    loop {
+        ctx.rebuild_cfg();
+        let gq = GraphQuery::new(compute_graph(&ctx.basic_blocks), &ctx.basic_blocks);
+        let mut removed_now = 0;
+        for block in ctx.basic_blocks.iter_basic_blocks_mut() {
+            let code = block.instructions_mut();
+            let before = code.len();
+            code.retain(|inst| {
+                let defined = inst.get_defined_registers();
+                defined.is_empty() || !is_pure(inst) || !defined.iter().all(|r| gq.is_dead(**r))
+            });
+            removed_now += before - code.len();
+        }
+        if removed_now == 0 {
+            break;
+        }
+        removed += removed_now;
+    }
+    ctx.register_types = ctx.compute_register_types();
+    removed
+}
+
+/// Whether `inst` does nothing but define its registers
+fn is_pure(inst: &IR) -> bool {
+    match inst {
+        IR::Add { overflow, .. }
+        | IR::Subtract { overflow, .. }
+        | IR::Multiply { overflow, .. } => !matches!(overflow, Overflow::Trap(_)),
+        IR::ShiftLeft { .. }
+        | IR::ShiftRight { .. }
+        | IR::Compare { .. }
+        | IR::Copy { .. }
+        | IR::Phi { .. }
+        | IR::Alloca { .. }
+        | IR::ConstantAddr { .. } => true,
+        // division can fault, loads can fail their bounds checks, and
+        // parameters are numbered by how many come before them
+        _ => false,
+    }
+}
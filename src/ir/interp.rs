@@ -0,0 +1,604 @@
+//! An interpreter for the IR, to check generated code against.
+//!
+//! It runs a function the way the generated code would, but on simulated
+//! memory: every `Alloca` gets its own slot, constants can be read through
+//! `ConstantAddr`, and the linear memory is a copy of the `Context`'s.
+//! Pointers to anything else can't be followed.  What's printed is collected
+//! instead of going to stdout.
+//!
+//! Registers hold their value truncated to their type, and are extended
+//! according to the type wherever the bits above it would matter.
+
+use super::*;
+
+/// How many instructions are run before giving up, so loops that never end
+/// are reported instead of hanging
+const STEP_LIMIT: usize = 100_000;
+
+/// Simulated addresses start here, so they aren't mistaken for small integers
+const SLOT_BASE: u64 = 0x1000_0000;
+const CONSTANT_BASE: u64 = 0x2000_0000;
+
+/// How an interpreted function finished
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Execution {
+    /// What `ReturnValue` returned, truncated to its type.  `None` if the
+    /// function returned nothing or trapped.
+    pub return_value: Option<u64>,
+    pub return_type: Option<PrimitiveValue>,
+    /// The fields `ReturnStruct` returned, each truncated to its type
+    pub return_struct: Option<Vec<u64>>,
+    /// The code of the `Trap` that stopped the function
+    pub trap: Option<u64>,
+    /// Everything printed by `PrintConstant` and `PrintInt`
+    pub output: Vec<u8>,
+}
+
+pub type InterpResult = Result<Execution, InterpError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterpError {
+    /// The instruction the error happened at
+    pub block: BasicBlockIndex,
+    pub index: usize,
+    pub reason: InterpErrorReason,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterpErrorReason {
+    /// Dividing by zero, which raises SIGFPE in generated code
+    DivideByZero,
+    /// The smallest `I32` or `I64` divided by -1, which raises SIGFPE too
+    DivideOverflow,
+    /// A pointer that doesn't point into simulated memory
+    BadPointer(u64),
+    /// A register used on a path it isn't defined on
+    UndefinedRegister(RegisterIndex),
+    /// A load from a slot that hasn't been stored to, which is whatever
+    /// was on the stack in generated code
+    UninitializedRead(u64),
+    /// A store to a constant
+    StoreToConstant(u64),
+    /// An access outside of the linear memory
+    OutOfBounds(u64),
+    /// The function has fewer arguments than this parameter's index
+    MissingArgument(usize),
+    /// An `Unreachable` was reached
+    Unreachable,
+    /// An `Assert` whose condition was zero, with its message
+    AssertFailed(ConstantIndex),
+    /// Machine code, which can't be interpreted
+    InlineBytes,
+    /// A host function, which can't be given pointers into simulated memory
+    Call,
+    /// Control ran off the end of the last block
+    FellOffEnd,
+    /// More than [`STEP_LIMIT`] instructions were run
+    TooManySteps,
+    /// A `U128` or `I128` value, which aren't simulated
+    Unsupported128Bit,
+}
+
+/// Interpret the function in `ctx`, which takes no arguments
+pub fn run(ctx: &Context) -> InterpResult {
+    run_with_arguments(ctx, &[])
+}
+
+/// Interpret the function in `ctx`, passing it `args`
+pub fn run_with_arguments(ctx: &Context, args: &[u64]) -> InterpResult {
+    Interpreter::new(ctx, args).run()
+}
+
+/// The low bits of `value` that fit in `_type`
+pub fn truncate(value: u64, _type: PrimitiveValue) -> u64 {
+    let bits = _type.size() * 8;
+    if bits < 64 {
+        value & ((1 << bits) - 1)
+    } else {
+        value
+    }
+}
+
+/// `value` as its type would have it
+fn extend(value: u64, _type: PrimitiveValue) -> i128 {
+    let shift = 64 - _type.size() * 8;
+    if _type.is_signed() {
+        (((value << shift) as i64) >> shift) as i128
+    } else {
+        ((value << shift) >> shift) as i128
+    }
+}
+
+/// Whether `value` can be represented in `_type`
+fn fits(value: i128, _type: PrimitiveValue) -> bool {
+    extend(value as u64, _type) == value
+}
+
+struct Interpreter<'a> {
+    ctx: &'a Context,
+    args: &'a [u64],
+    registers: BTreeMap<RegisterIndex, u64>,
+    /// The address of each `Alloca`'s slot, and the type it holds
+    slots: BTreeMap<RegisterIndex, (u64, PrimitiveValue)>,
+    /// What each pointer points to, which is how much a load through it reads
+    pointee_types: BTreeMap<RegisterIndex, PrimitiveValue>,
+    /// The memory for every slot, starting at `SLOT_BASE`
+    stack: Vec<u8>,
+    /// Which bytes of `stack` have been stored to
+    initialized: Vec<bool>,
+    /// Where each constant starts after `CONSTANT_BASE`
+    constants: Vec<u64>,
+    linear_memory: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl<'a> Interpreter<'a> {
+    fn new(ctx: &'a Context, args: &'a [u64]) -> Self {
+        let mut slots = BTreeMap::new();
+        let mut used = 0;
+        for (_, _, inst) in ctx.iter_instructions() {
+            if let IR::Alloca {
+                dest_register,
+                _type,
+                ..
+            } = inst
+            {
+                slots.insert(*dest_register, (SLOT_BASE + used, *_type));
+                // a little apart, so accesses past the end are caught
+                used += _type.size() as u64 + 8;
+            }
+        }
+        let mut constants = vec![];
+        let mut offset = 0;
+        for constant in &ctx.constants {
+            constants.push(offset);
+            offset += constant.len() as u64 + 8;
+        }
+        Self {
+            ctx,
+            args,
+            registers: BTreeMap::new(),
+            slots,
+            pointee_types: ctx.basic_blocks.pointee_types(),
+            stack: vec![0; used as usize],
+            initialized: vec![false; used as usize],
+            constants,
+            linear_memory: ctx
+                .linear_memory
+                .as_ref()
+                .map_or_else(Vec::new, |memory| memory.as_slice().to_vec()),
+            output: vec![],
+        }
+    }
+
+    fn value(&self, v: Value) -> Result<u64, InterpErrorReason> {
+        match v {
+            Value::Register(r) => self
+                .registers
+                .get(&r)
+                .copied()
+                .ok_or(InterpErrorReason::UndefinedRegister(r)),
+            Value::Immediate { _type, value } => Ok(truncate(value as u64, _type)),
+        }
+    }
+
+    fn value_type(&self, v: Value) -> PrimitiveValue {
+        self.ctx.value_type(v).unwrap_or(PrimitiveValue::U64)
+    }
+
+    fn register_type(&self, r: RegisterIndex) -> PrimitiveValue {
+        self.value_type(Value::Register(r))
+    }
+
+    fn set(&mut self, r: RegisterIndex, value: u64) {
+        let value = truncate(value, self.register_type(r));
+        self.registers.insert(r, value);
+    }
+
+    /// Where `size` bytes at `address` are in the stack, if they are
+    fn stack_range(&self, address: u64, size: usize) -> Option<std::ops::Range<usize>> {
+        let start = address.checked_sub(SLOT_BASE)? as usize;
+        if start + size <= self.stack.len() {
+            Some(start..start + size)
+        } else {
+            None
+        }
+    }
+
+    /// The constant `size` bytes at `address` are in, if they are
+    fn constant_bytes(&self, address: u64, size: usize) -> Option<&[u8]> {
+        let offset = address.checked_sub(CONSTANT_BASE)? as usize;
+        self.constants
+            .iter()
+            .zip(&self.ctx.constants)
+            .find_map(|(start, constant)| {
+                let from = offset.checked_sub(*start as usize)?;
+                constant.get(from..from + size)
+            })
+    }
+
+    fn load(&self, address: u64, size: usize) -> Result<u64, InterpErrorReason> {
+        let bytes = match self.stack_range(address, size) {
+            Some(range) if self.initialized[range.clone()].contains(&false) => {
+                return Err(InterpErrorReason::UninitializedRead(address))
+            }
+            Some(range) => &self.stack[range],
+            None => self
+                .constant_bytes(address, size)
+                .ok_or(InterpErrorReason::BadPointer(address))?,
+        };
+        let mut value = [0; 8];
+        value[..size].copy_from_slice(bytes);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    fn store(&mut self, address: u64, size: usize, value: u64) -> Result<(), InterpErrorReason> {
+        match self.stack_range(address, size) {
+            Some(range) => {
+                self.stack[range.clone()].copy_from_slice(&value.to_le_bytes()[..size]);
+                self.initialized[range].iter_mut().for_each(|b| *b = true);
+                Ok(())
+            }
+            None if self.constant_bytes(address, size).is_some() => {
+                Err(InterpErrorReason::StoreToConstant(address))
+            }
+            None => Err(InterpErrorReason::BadPointer(address)),
+        }
+    }
+
+    /// The 4 bytes of linear memory at `offset`
+    fn linear_memory_range(
+        &self,
+        offset: u64,
+    ) -> Result<std::ops::Range<usize>, InterpErrorReason> {
+        let start = offset as usize;
+        if start + 4 <= self.linear_memory.len() {
+            Ok(start..start + 4)
+        } else {
+            Err(InterpErrorReason::OutOfBounds(offset))
+        }
+    }
+
+    /// The result of `inst` and whether it overflowed
+    fn arithmetic(&self, inst: &IR) -> Result<(u64, bool), InterpErrorReason> {
+        let (dest_register, src1, src2) = match *inst {
+            IR::Add {
+                dest_register,
+                src1,
+                src2,
+                ..
+            }
+            | IR::Subtract {
+                dest_register,
+                src1,
+                src2,
+                ..
+            }
+            | IR::Multiply {
+                dest_register,
+                src1,
+                src2,
+                ..
+            }
+            | IR::Divide {
+                dest_register,
+                src1,
+                src2,
+            }
+            | IR::Remainder {
+                dest_register,
+                src1,
+                src2,
+            }
+            | IR::ShiftLeft {
+                dest_register,
+                src1,
+                src2,
+            }
+            | IR::ShiftRight {
+                dest_register,
+                src1,
+                src2,
+            } => (dest_register, src1, src2),
+            _ => unreachable!("not arithmetic: {:?}", inst),
+        };
+        let _type = self.register_type(dest_register);
+        let (a, b) = (
+            extend(self.value(src1)?, _type),
+            extend(self.value(src2)?, _type),
+        );
+        let bits = _type.size() as u32 * 8;
+        let result = match inst {
+            IR::Add { .. } => a + b,
+            IR::Subtract { .. } => a - b,
+            IR::Multiply { .. } => a.wrapping_mul(b),
+            IR::Divide { .. } | IR::Remainder { .. } => {
+                if b == 0 {
+                    return Err(InterpErrorReason::DivideByZero);
+                }
+                // i8 and i16 are divided as 32 bit values, which can't
+                // overflow
+                if b == -1 && bits >= 32 && !fits(-a, _type) && _type.is_signed() {
+                    return Err(InterpErrorReason::DivideOverflow);
+                }
+                if let IR::Divide { .. } = inst {
+                    a / b
+                } else {
+                    a % b
+                }
+            }
+            IR::ShiftLeft { .. } => a << (b.rem_euclid(bits as i128) as u32),
+            _ => a >> (b.rem_euclid(bits as i128) as u32),
+        };
+        Ok((truncate(result as u64, _type), !fits(result, _type)))
+    }
+
+    fn run(mut self) -> InterpResult {
+        let bbm = &self.ctx.basic_blocks;
+        let mut block = bbm.start;
+        let mut previous: Option<BasicBlockIndex> = None;
+        let mut steps = 0;
+        loop {
+            let bb = bbm.get(block).unwrap();
+            // phis all read their values before any of them are written
+            let mut phis = vec![];
+            for (index, inst) in bb.iterate_instructions().enumerate() {
+                if let IR::Phi {
+                    dest_register,
+                    incoming,
+                } = inst
+                {
+                    if let Some((_, v)) = incoming.iter().find(|(b, _)| Some(*b) == previous) {
+                        let value = self.value(*v).map_err(|reason| InterpError {
+                            block,
+                            index,
+                            reason,
+                        })?;
+                        phis.push((*dest_register, value));
+                    }
+                }
+            }
+            for (dest, value) in phis {
+                self.set(dest, value);
+            }
+
+            let mut next = None;
+            for (index, inst) in bb.iterate_instructions().enumerate() {
+                steps += 1;
+                let error = |reason| InterpError {
+                    block,
+                    index,
+                    reason,
+                };
+                if steps > STEP_LIMIT {
+                    return Err(error(InterpErrorReason::TooManySteps));
+                }
+                // registers are only 64 bits here
+                let wide = |v: Value| self.value_type(v).size() > 8;
+                let defines_wide = inst
+                    .get_defined_registers()
+                    .into_iter()
+                    .any(|r| wide(Value::Register(*r)));
+                if defines_wide || inst.get_used_values().into_iter().any(wide) {
+                    return Err(error(InterpErrorReason::Unsupported128Bit));
+                }
+                match *inst {
+                    IR::Add { .. }
+                    | IR::Subtract { .. }
+                    | IR::Multiply { .. }
+                    | IR::Divide { .. }
+                    | IR::Remainder { .. }
+                    | IR::ShiftLeft { .. }
+                    | IR::ShiftRight { .. } => {
+                        let (value, overflowed) = self.arithmetic(inst).map_err(error)?;
+                        match (inst.branch_targets().first(), inst.overflow_flag()) {
+                            (Some(trap_to), _) if overflowed => {
+                                next = Some(*trap_to);
+                                break;
+                            }
+                            (_, Some(flag)) => self.set(flag, overflowed as u64),
+                            _ => (),
+                        }
+                        let dest = inst.get_defined_registers()[0];
+                        self.set(*dest, value);
+                    }
+                    IR::Compare {
+                        dest_register,
+                        comparison,
+                        src1,
+                        src2,
+                    } => {
+                        let _type = self
+                            .ctx
+                            .value_type(src1)
+                            .or_else(|| self.ctx.value_type(src2))
+                            .unwrap_or(PrimitiveValue::U32);
+                        let (a, b) = (
+                            extend(self.value(src1).map_err(error)?, _type),
+                            extend(self.value(src2).map_err(error)?, _type),
+                        );
+                        let result = match comparison {
+                            Comparison::Equal => a == b,
+                            Comparison::NotEqual => a != b,
+                            Comparison::Less => a < b,
+                            Comparison::LessOrEqual => a <= b,
+                            Comparison::Greater => a > b,
+                            Comparison::GreaterOrEqual => a >= b,
+                        };
+                        self.set(dest_register, result as u64);
+                    }
+                    IR::Copy { dest_register, src } => {
+                        let value = self.value(src).map_err(error)?;
+                        self.set(dest_register, value);
+                    }
+                    IR::TruncateChecked {
+                        dest_register,
+                        dest_type,
+                        src,
+                        trap: trap_to,
+                    } => {
+                        let value = extend(self.value(src).map_err(error)?, self.value_type(src));
+                        if !fits(value, dest_type) {
+                            next = Some(trap_to);
+                            break;
+                        }
+                        self.set(dest_register, value as u64);
+                    }
+                    IR::Parameter {
+                        dest_register,
+                        index: arg,
+                        ..
+                    } => {
+                        let value = *self
+                            .args
+                            .get(arg)
+                            .ok_or_else(|| error(InterpErrorReason::MissingArgument(arg)))?;
+                        self.set(dest_register, value);
+                    }
+                    IR::Alloca { dest_register, .. } => {
+                        let address = self.slots[&dest_register].0;
+                        self.set(dest_register, address);
+                    }
+                    IR::ConstantAddr {
+                        dest_register,
+                        constant_ref,
+                    } => {
+                        let address = CONSTANT_BASE + self.constants[constant_ref.0 as usize];
+                        self.set(dest_register, address);
+                    }
+                    IR::Load {
+                        dest_register,
+                        src_register,
+                        ..
+                    } => {
+                        // as wide as what the pointer points to
+                        let size = match src_register {
+                            Value::Register(r) => self.pointee_types.get(&r).copied(),
+                            Value::Immediate { .. } => None,
+                        }
+                        .unwrap_or(PrimitiveValue::U32)
+                        .size();
+                        let address = self.value(src_register).map_err(error)?;
+                        let loaded = self.load(address, size).map_err(error)?;
+                        // extended by the type of the destination
+                        let shift = 64 - size * 8;
+                        let value = if self.register_type(dest_register).is_signed() {
+                            (((loaded << shift) as i64) >> shift) as u64
+                        } else {
+                            loaded
+                        };
+                        self.set(dest_register, value);
+                    }
+                    IR::Store {
+                        dest_register,
+                        src_register,
+                    } => {
+                        let size = self.value_type(src_register).size();
+                        let (address, value) = (
+                            self.value(dest_register).map_err(error)?,
+                            self.value(src_register).map_err(error)?,
+                        );
+                        self.store(address, size, value).map_err(error)?;
+                    }
+                    IR::MemLoad {
+                        dest_register,
+                        offset,
+                    } => {
+                        let range = self
+                            .linear_memory_range(self.value(offset).map_err(error)? & 0xffff_ffff)
+                            .map_err(error)?;
+                        let mut bytes = [0; 4];
+                        bytes.copy_from_slice(&self.linear_memory[range]);
+                        self.set(dest_register, u32::from_le_bytes(bytes) as u64);
+                    }
+                    IR::MemStore { offset, src } => {
+                        let range = self
+                            .linear_memory_range(self.value(offset).map_err(error)? & 0xffff_ffff)
+                            .map_err(error)?;
+                        let value = self.value(src).map_err(error)? as u32;
+                        self.linear_memory[range].copy_from_slice(&value.to_le_bytes());
+                    }
+                    IR::PrintConstant { constant_ref } => {
+                        let constant = &self.ctx.constants[constant_ref.0 as usize];
+                        self.output.extend_from_slice(constant);
+                    }
+                    IR::PrintInt { src, _type } => {
+                        let value = extend(self.value(src).map_err(error)?, _type);
+                        self.output.extend(format!("{}\n", value).bytes());
+                    }
+                    IR::Assert {
+                        cond,
+                        message_constant,
+                    } => {
+                        if self.value(cond).map_err(error)? == 0 {
+                            return Err(error(InterpErrorReason::AssertFailed(message_constant)));
+                        }
+                    }
+                    IR::Jump { bb_idx } => {
+                        next = Some(bb_idx);
+                        break;
+                    }
+                    IR::JumpIfEqual {
+                        src_register,
+                        true_bb_idx,
+                        false_bb_idx,
+                    }
+                    | IR::JumpIfNotEqual {
+                        src_register,
+                        true_bb_idx,
+                        false_bb_idx,
+                    } => {
+                        let zero = self.value(src_register).map_err(error)? == 0;
+                        let taken = matches!(inst, IR::JumpIfEqual { .. }) == zero;
+                        next = Some(if taken { true_bb_idx } else { false_bb_idx });
+                        break;
+                    }
+                    IR::Return => return Ok(self.finish(None, None)),
+                    IR::ReturnValue { value } => {
+                        let _type = self.value_type(value);
+                        let value = self.value(value).map_err(error)?;
+                        return Ok(self.finish(Some((value, _type)), None));
+                    }
+                    IR::ReturnStruct { ref values } => {
+                        let fields = values
+                            .iter()
+                            .map(|v| Ok(truncate(self.value(*v)?, self.value_type(*v))))
+                            .collect::<Result<Vec<_>, _>>()
+                            .map_err(error)?;
+                        let mut execution = self.finish(None, None);
+                        execution.return_struct = Some(fields);
+                        return Ok(execution);
+                    }
+                    IR::Trap { code } => return Ok(self.finish(None, Some(code))),
+                    IR::Unreachable => return Err(error(InterpErrorReason::Unreachable)),
+                    IR::InlineBytes { .. } => return Err(error(InterpErrorReason::InlineBytes)),
+                    IR::Call { .. } => return Err(error(InterpErrorReason::Call)),
+                    // only which machine register a value is in changes
+                    IR::Pin { .. } | IR::Phi { .. } | IR::Nop => (),
+                }
+            }
+
+            previous = Some(block);
+            block = match next.or_else(|| bbm.fall_through_target(block)) {
+                Some(next) => next,
+                None => {
+                    return Err(InterpError {
+                        block,
+                        index: bb.iterate_instructions().count(),
+                        reason: InterpErrorReason::FellOffEnd,
+                    })
+                }
+            };
+        }
+    }
+
+    fn finish(self, returned: Option<(u64, PrimitiveValue)>, trap: Option<u64>) -> Execution {
+        Execution {
+            return_value: returned.map(|(value, _)| value),
+            return_type: returned.map(|(_, _type)| _type),
+            trap,
+            return_struct: None,
+            output: self.output,
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Loop-invariant code motion for the addresses of constants.
+//!
+//! A `ConstantAddr` gives the same value wherever it is, so one in a loop is
+//! moved to the closest block dominating it that isn't in any loop, where it
+//! runs once.  Then each `ConstantAddr` dominated by another of the same
+//! constant becomes a copy of that one, so the address is only computed once
+//! on any path.
+
+use super::*;
+use crate::reg_alloc::{compute_graph, GraphQuery};
+use std::collections::*;
+
+/// Where a `ConstantAddr` is: its block and index in the block, then the
+/// constant and the register it defines
+type Site = (BasicBlockIndex, usize, ConstantIndex, RegisterIndex);
+
+/// Hoist every `ConstantAddr` out of the loops it's in and de-duplicate them
+pub fn hoist_constant_addrs(ctx: &mut Context) {
+    ctx.rebuild_cfg();
+    let gq = GraphQuery::new(compute_graph(&ctx.basic_blocks), &ctx.basic_blocks);
+
+    // =====================================================
+    // hoist
+    let mut hoisted: BTreeMap<BasicBlockIndex, Vec<IR>> = BTreeMap::new();
+    for block in ctx.basic_blocks.iter_basic_blocks_mut() {
+        let idx = block.self_idx;
+        let target = match outside_loops(&gq, idx) {
+            Some(target) if target != idx => target,
+            _ => continue,
+        };
+        let code = block.instructions_mut();
+        let (addrs, rest) = std::mem::take(code)
+            .into_iter()
+            .partition(|inst| matches!(inst, IR::ConstantAddr { .. }));
+        *code = rest;
+        hoisted.entry(target).or_default().extend(addrs);
+    }
+    for (target, addrs) in hoisted {
+        let code = ctx.basic_blocks.get_mut(target).unwrap().instructions_mut();
+        // before the jump out of the block
+        let at = code
+            .iter()
+            .rposition(IR::is_terminator)
+            .unwrap_or(code.len());
+        code.splice(at..at, addrs);
+    }
+
+    // =====================================================
+    // de-duplicate
+    let addrs: Vec<Site> = ctx
+        .iter_instructions()
+        .filter_map(|(block, index, inst)| match inst {
+            IR::ConstantAddr {
+                dest_register,
+                constant_ref,
+            } => Some((block, index, *constant_ref, *dest_register)),
+            _ => None,
+        })
+        .collect();
+    let dominates = |a: &Site, b: &Site| {
+        if a.0 == b.0 {
+            return a.1 < b.1;
+        }
+        let mut block = b.0;
+        while let Some(idom) = gq.immediate_dominator(block) {
+            if idom == a.0 {
+                return true;
+            }
+            block = idom;
+        }
+        false
+    };
+    for addr in &addrs {
+        // one that isn't dominated by any other, so it's kept
+        let first = addrs.iter().find(|other| {
+            other.2 == addr.2
+                && dominates(other, addr)
+                && !addrs
+                    .iter()
+                    .any(|third| third.2 == addr.2 && dominates(third, other))
+        });
+        if let Some(first) = first {
+            let (block, index, _, dest_register) = *addr;
+            ctx.basic_blocks.get_mut(block).unwrap().instructions_mut()[index] = IR::Copy {
+                dest_register,
+                src: Value::Register(first.3),
+            };
+        }
+    }
+
+    ctx.register_types = ctx.compute_register_types();
+}
+
+/// The closest block dominating `block` that isn't in a loop, `None` if the
+/// entry is in one
+fn outside_loops(gq: &GraphQuery, mut block: BasicBlockIndex) -> Option<BasicBlockIndex> {
+    while gq.loop_depth(block) > 0 {
+        block = gq.immediate_dominator(block)?;
+    }
+    Some(block)
+}
@@ -0,0 +1,358 @@
+//! Cleaning up the control flow graph.
+//!
+//! Other transforms tend to leave debris behind: blocks that do nothing but
+//! jump somewhere else, straight line code split over several blocks, and
+//! blocks nothing reaches anymore.  [`simplify_cfg`] removes all of it.
+
+use super::*;
+
+/// Simplify the CFG until it stops changing:
+///
+/// - branches to a block that only jumps somewhere else go straight there
+/// - a block that's only reached by a jump from one other block is merged
+///   into that block
+/// - blocks that can't be reached from the entry are removed
+///
+/// Empty blocks are removed by threading jumps through them, which leaves
+/// them unreachable.  Running this on a CFG it's already simplified does
+/// nothing.
+pub fn simplify_cfg(ctx: &mut Context) {
+    ctx.strip_nops();
+    loop {
+        let threaded = thread_jumps(ctx);
+        let merged = merge_blocks(ctx);
+        let removed = remove_unreachable_blocks(ctx);
+        if !(threaded || merged || removed) {
+            break;
+        }
+    }
+    ctx.rebuild_cfg();
+    ctx.register_types = ctx.compute_register_types();
+    ctx.finalized = false;
+}
+
+/// Where control goes after `block` if that's all the block does: it's only
+/// a `Jump`, or it's empty and falls through
+fn forwards_to(bbm: &BasicBlockManager, block: BasicBlockIndex) -> Option<BasicBlockIndex> {
+    let target = match bbm.get(block)?.code.as_slice() {
+        [IR::Jump { bb_idx }] => Some(*bb_idx),
+        [] => bbm.fall_through_target(block),
+        _ => None,
+    };
+    target.filter(|target| *target != block)
+}
+
+/// Make `block` go to `to` wherever it went to `from`
+fn retarget(
+    bbm: &mut BasicBlockManager,
+    block: BasicBlockIndex,
+    from: BasicBlockIndex,
+    to: BasicBlockIndex,
+) {
+    let falls_through =
+        !bbm.get(block).unwrap().is_terminated() && bbm.fall_through_target(block) == Some(from);
+    let bb = bbm.get_mut(block).unwrap();
+    for inst in &mut bb.code {
+        for target in inst.branch_targets_mut() {
+            if *target == from {
+                *target = to;
+            }
+        }
+    }
+    if falls_through {
+        bb.code.push(IR::Jump { bb_idx: to });
+    }
+    if bb.likely_exit == Some(from) {
+        bb.likely_exit = Some(to);
+    }
+}
+
+/// The value each phi at the top of `block` takes when coming from `pred`,
+/// or `None` if one of them doesn't say
+fn phi_values(bb: &BasicBlock, pred: BasicBlockIndex) -> Option<Vec<(RegisterIndex, Value)>> {
+    bb.code
+        .iter()
+        .map_while(|inst| match inst {
+            IR::Phi {
+                dest_register,
+                incoming,
+            } => Some((*dest_register, incoming)),
+            _ => None,
+        })
+        .map(|(dest, incoming)| {
+            let (_, value) = incoming.iter().find(|(b, _)| *b == pred)?;
+            Some((dest, *value))
+        })
+        .collect()
+}
+
+/// Branch past blocks that only jump somewhere else, returning whether
+/// anything changed
+fn thread_jumps(ctx: &mut Context) -> bool {
+    let bbm = &mut ctx.basic_blocks;
+    let blocks = bbm
+        .iterate_basic_blocks()
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+    let mut changed = false;
+    for block in blocks {
+        for via in bbm.successors(block) {
+            let mut target = match forwards_to(bbm, via) {
+                Some(target) => target,
+                None => continue,
+            };
+            // follow the whole chain, which may loop
+            let mut last = via;
+            let mut seen = BTreeSet::new();
+            seen.insert(via);
+            while let Some(next) = forwards_to(bbm, target) {
+                if !seen.insert(target) {
+                    break;
+                }
+                last = target;
+                target = next;
+            }
+            if seen.contains(&target) {
+                continue;
+            }
+            // the phis in `target` take what they took from the end of the
+            // chain from `block` instead, which they can't if `block`
+            // already goes there
+            let target_bb = bbm.get(target).unwrap();
+            let values = match phi_values(target_bb, last) {
+                Some(values) => values,
+                None => continue,
+            };
+            if !values.is_empty() && bbm.successors(block).contains(&target) {
+                continue;
+            }
+            retarget(bbm, block, via, target);
+            for inst in &mut bbm.get_mut(target).unwrap().code {
+                if let IR::Phi {
+                    dest_register,
+                    incoming,
+                } = inst
+                {
+                    let value = values
+                        .iter()
+                        .find(|(dest, _)| dest == dest_register)
+                        .unwrap()
+                        .1;
+                    incoming.push((block, value));
+                }
+            }
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Merge each block into the block before it if that's the only way to get
+/// there, returning whether anything changed
+fn merge_blocks(ctx: &mut Context) -> bool {
+    let entry = ctx.entry();
+    let bbm = &mut ctx.basic_blocks;
+    let blocks = bbm
+        .iterate_basic_blocks()
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+    let mut changed = false;
+    for block in blocks {
+        let next = match bbm.get(block).unwrap().code.last() {
+            Some(IR::Jump { bb_idx }) => *bb_idx,
+            _ => continue,
+        };
+        let only_way_in = bbm
+            .iterate_basic_blocks()
+            .filter(|(idx, _)| bbm.successors(*idx).contains(&next))
+            .map(|(idx, _)| idx)
+            .eq(std::iter::once(block));
+        // it mustn't also be a trap target of the block
+        let jumps = bbm
+            .get(block)
+            .unwrap()
+            .code
+            .iter()
+            .flat_map(IR::branch_targets)
+            .filter(|target| *target == next)
+            .count();
+        if next == block || next == entry || !only_way_in || jumps != 1 {
+            continue;
+        }
+        let values = match phi_values(bbm.get(next).unwrap(), block) {
+            Some(values) => values,
+            None => continue,
+        };
+        let falls_through = !bbm.get(next).unwrap().is_terminated();
+        let fall_through = bbm.fall_through_target(next).filter(|_| falls_through);
+        let next_bb = bbm.get_mut(next).unwrap();
+        // left behind so it doesn't fall through into anything
+        let mut moved = std::mem::replace(&mut next_bb.code, vec![IR::Unreachable]);
+        let likely_exit = next_bb.likely_exit.take();
+        for (inst, (dest_register, src)) in moved.iter_mut().zip(values) {
+            *inst = IR::Copy { dest_register, src };
+        }
+        moved.extend(fall_through.map(|bb_idx| IR::Jump { bb_idx }));
+        let bb = bbm.get_mut(block).unwrap();
+        bb.code.pop();
+        bb.code.append(&mut moved);
+        bb.likely_exit = likely_exit;
+        // what came from `next` comes from `block` now
+        for succ in bbm.successors(block) {
+            for inst in &mut bbm.get_mut(succ).unwrap().code {
+                if let IR::Phi { incoming, .. } = inst {
+                    for (pred, _) in incoming.iter_mut().filter(|(pred, _)| *pred == next) {
+                        *pred = block;
+                    }
+                }
+            }
+        }
+        changed = true;
+    }
+    changed
+}
+
+/// Remove the blocks that can't be reached from the entry, returning whether
+/// there were any
+fn remove_unreachable_blocks(ctx: &mut Context) -> bool {
+    let bbm = &mut ctx.basic_blocks;
+    let mut reachable = BTreeSet::new();
+    let mut stack = vec![bbm.start];
+    while let Some(block) = stack.pop() {
+        if reachable.insert(block) {
+            stack.extend(bbm.successors(block));
+        }
+    }
+    let unreachable = bbm
+        .iterate_basic_blocks()
+        .map(|(idx, _)| idx)
+        .filter(|idx| !reachable.contains(idx))
+        .collect::<BTreeSet<_>>();
+    if unreachable.is_empty() {
+        return false;
+    }
+    bbm.remove_blocks(&unreachable);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::interp;
+
+    /// The instructions of each block, in order
+    fn blocks(ctx: &Context) -> Vec<Vec<String>> {
+        ctx.basic_blocks()
+            .iterate_basic_blocks()
+            .map(|(_, bb)| bb.code.iter().map(|inst| format!("{:?}", inst)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn messy_cfg_collapses_in_one_call() {
+        // entry -> a -> b -> c -> d, where a and b only jump and d is only
+        // reached from c, then d branches to two returns.  The last two
+        // blocks can't be reached.
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let a = ctx.new_basic_block();
+        let b = ctx.new_basic_block();
+        let c = ctx.new_basic_block();
+        let d = ctx.new_basic_block();
+        let zero = ctx.new_basic_block();
+        let other = ctx.new_basic_block();
+        let dead = ctx.new_basic_block();
+        let dead_too = ctx.new_basic_block();
+        let x = ctx.add_parameter(PrimitiveValue::U64);
+        ctx.build_basic_block(entry).jump(a);
+        ctx.build_basic_block(a).jump(b);
+        ctx.build_basic_block(b).jump(c);
+        let bb = ctx.build_basic_block(c);
+        let y = bb.add(x, Value::u64(1));
+        bb.jump(d);
+        ctx.build_basic_block(d).jump_if_equal(y, zero, other);
+        ctx.build_basic_block(zero).ret_value(Value::u64(7));
+        let bb = ctx.build_basic_block(other);
+        let doubled = bb.add(y, y);
+        bb.ret_value(doubled);
+        ctx.build_basic_block(dead).jump(dead_too);
+        ctx.build_basic_block(dead_too).ret_value(Value::u64(99));
+        ctx.finalize();
+        let run = |ctx: &Context, x: u64| {
+            interp::run_with_arguments(ctx, &[x])
+                .unwrap()
+                .return_value
+                .unwrap()
+        };
+        let before = [run(&ctx, u64::MAX), run(&ctx, 5)];
+        assert_eq!(before, [7, 12]);
+
+        simplify_cfg(&mut ctx);
+        ctx.finalize();
+
+        // the entry does everything up to the branch, and the two returns
+        // are all that's left
+        let code = blocks(&ctx);
+        assert_eq!(code.len(), 3, "{:#?}", code);
+        let entry_code = ctx
+            .basic_blocks()
+            .get(ctx.entry())
+            .unwrap()
+            .code
+            .iter()
+            .collect::<Vec<_>>();
+        assert!(
+            matches!(
+                entry_code[..],
+                [IR::Parameter { .. }, IR::Add { .. }, IR::JumpIfEqual { .. }]
+            ),
+            "{:#?}",
+            entry_code
+        );
+        assert_eq!(ctx.successors(ctx.entry()).count(), 2);
+        assert!(ctx.validate().is_ok());
+        assert_eq!([run(&ctx, u64::MAX), run(&ctx, 5)], before);
+
+        // and it's already as simple as it gets
+        simplify_cfg(&mut ctx);
+        ctx.finalize();
+        assert_eq!(blocks(&ctx), code);
+    }
+
+    #[test]
+    fn loops_are_kept() {
+        // a loop through a block that only jumps back can't be threaded away
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let x = ctx.add_parameter(PrimitiveValue::U32);
+        let bb = ctx.build_basic_block(entry);
+        let i = bb.alloca(PrimitiveValue::U32, 4);
+        bb.store(i, x);
+        let exit = ctx.build_while(
+            entry,
+            |header| {
+                let i = header.load(i);
+                header.compare(Comparison::Less, i, Value::u32(10))
+            },
+            |body| {
+                let value = body.load(i);
+                let next = body.add(value, Value::u32(1));
+                body.store(i, next);
+            },
+        );
+        let bb = ctx.build_basic_block(exit);
+        let counted = bb.load(i);
+        bb.ret_value(counted);
+        ctx.finalize();
+
+        simplify_cfg(&mut ctx);
+        ctx.finalize();
+        assert!(ctx.validate().is_ok());
+        let result = interp::run_with_arguments(&ctx, &[3]).unwrap();
+        assert_eq!(result.return_value, Some(10));
+        let code = blocks(&ctx);
+        simplify_cfg(&mut ctx);
+        ctx.finalize();
+        assert_eq!(blocks(&ctx), code);
+    }
+}
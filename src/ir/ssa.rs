@@ -0,0 +1,232 @@
+//! Construction of SSA form from variables kept in stack slots.
+//!
+//! Front-ends can give every variable an `alloca` and `load`/`store` it
+//! freely; this promotes those slots to registers, inserting phis where
+//! different values of a variable meet.  It follows "Efficiently Computing
+//! Static Single Assignment Form and the Control Dependence Graph" by Cytron,
+//! Ferrante, Rosen, Wegman, and Zadeck: phis go on the iterated dominance
+//! frontier of the blocks storing to a slot, then loads are renamed to the
+//! value reaching them by walking the dominator tree.
+
+use super::*;
+use crate::reg_alloc::{compute_graph, GraphQuery};
+use std::collections::*;
+
+/// Promote every `alloca` that's only loaded from and stored to into
+/// registers.
+///
+/// Loads become copies of the value stored last, and the allocas and stores
+/// are removed.  Loads that no store reaches read 0.
+pub fn construct(ctx: &mut Context) {
+    ctx.rebuild_cfg();
+    let gq = GraphQuery::new(compute_graph(&ctx.basic_blocks), &ctx.basic_blocks);
+
+    let slots = promotable_slots(ctx);
+    if slots.is_empty() {
+        return;
+    }
+
+    // =====================================================
+    // place phis
+    let mut phis: BTreeMap<BasicBlockIndex, Vec<(RegisterIndex, RegisterIndex)>> = BTreeMap::new();
+    for slot in slots.keys() {
+        let mut worklist = ctx
+            .iterate_basic_blocks()
+            .filter(|(_, bb)| {
+                bb.iterate_instructions().any(|inst| {
+                    matches!(inst, IR::Store { dest_register: Value::Register(r), .. } if r == slot)
+                })
+            })
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+        let mut has_phi = BTreeSet::new();
+        while let Some(block) = worklist.pop() {
+            for frontier in gq.dominance_frontier(block) {
+                if has_phi.insert(frontier) {
+                    phis.entry(frontier)
+                        .or_default()
+                        .push((*slot, ctx.basic_blocks.new_register()));
+                    worklist.push(frontier);
+                }
+            }
+        }
+    }
+
+    // =====================================================
+    // rename, visiting each block after its dominator
+    let mut children: BTreeMap<BasicBlockIndex, Vec<BasicBlockIndex>> = BTreeMap::new();
+    for (idx, _) in ctx.iterate_basic_blocks() {
+        if let Some(idom) = gq.immediate_dominator(idx) {
+            children.entry(idom).or_default().push(idx);
+        }
+    }
+    let mut renamer = Renamer {
+        slots: &slots,
+        phis: &phis,
+        incoming: BTreeMap::new(),
+    };
+    let mut visited = BTreeSet::new();
+    let mut stack = vec![(ctx.basic_blocks.start, BTreeMap::new())];
+    while let Some((block, mut current)) = stack.pop() {
+        visited.insert(block);
+        renamer.rename_block(ctx, block, &mut current);
+        for child in children.get(&block).into_iter().flatten() {
+            stack.push((*child, current.clone()));
+        }
+    }
+    // unreachable blocks still need their uses of the slots removed
+    let unvisited = ctx
+        .iterate_basic_blocks()
+        .map(|(idx, _)| idx)
+        .filter(|idx| !visited.contains(idx))
+        .collect::<Vec<_>>();
+    for block in unvisited {
+        renamer.rename_block(ctx, block, &mut BTreeMap::new());
+    }
+
+    // =====================================================
+    // add the phis that turned out to be needed
+    let mut incoming = renamer.incoming;
+    let mut used: BTreeSet<RegisterIndex> = ctx
+        .basic_blocks
+        .iterate_basic_blocks()
+        .flat_map(|(_, bb)| bb.iter_used_registers().copied().collect::<Vec<_>>())
+        .collect();
+    // a phi is only needed if something other than the phis it feeds reads it
+    let mut live_phis: BTreeSet<RegisterIndex> = BTreeSet::new();
+    loop {
+        let newly_live = incoming
+            .keys()
+            .filter(|dest| used.contains(*dest) && !live_phis.contains(*dest))
+            .copied()
+            .collect::<Vec<_>>();
+        if newly_live.is_empty() {
+            break;
+        }
+        for dest in newly_live {
+            live_phis.insert(dest);
+            for (_, v) in &incoming[&dest] {
+                if let Value::Register(r) = v {
+                    used.insert(*r);
+                }
+            }
+        }
+    }
+    for (block, block_phis) in &phis {
+        let code = ctx.basic_blocks.get_mut(*block).unwrap().instructions_mut();
+        let new_phis = block_phis
+            .iter()
+            .filter(|(_, dest)| live_phis.contains(dest))
+            .map(|(_, dest)| IR::Phi {
+                dest_register: *dest,
+                incoming: incoming.remove(dest).unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+        code.splice(0..0, new_phis);
+    }
+
+    ctx.register_types = ctx.compute_register_types();
+}
+
+/// Allocas whose address is never used except to load from or store to, and
+/// the type they hold
+fn promotable_slots(ctx: &Context) -> BTreeMap<RegisterIndex, PrimitiveValue> {
+    let mut slots = BTreeMap::new();
+    for (_, _, inst) in ctx.iter_instructions() {
+        if let IR::Alloca {
+            dest_register,
+            _type,
+            ..
+        } = inst
+        {
+            slots.insert(*dest_register, *_type);
+        }
+    }
+    for (_, _, inst) in ctx.iter_instructions() {
+        match inst {
+            // there's nothing to extend a promoted value with
+            IR::Load {
+                extend_to: None, ..
+            }
+            | IR::Alloca { .. } => (),
+            // storing the address itself lets it escape
+            IR::Store {
+                src_register: Value::Register(r),
+                ..
+            } => {
+                slots.remove(r);
+            }
+            IR::Store { .. } => (),
+            _ => {
+                for r in inst.get_used_registers() {
+                    slots.remove(r);
+                }
+            }
+        }
+    }
+    slots
+}
+
+struct Renamer<'a> {
+    slots: &'a BTreeMap<RegisterIndex, PrimitiveValue>,
+    /// The (slot, phi destination) pairs placed in each block
+    phis: &'a BTreeMap<BasicBlockIndex, Vec<(RegisterIndex, RegisterIndex)>>,
+    /// The incoming values of each phi, by destination
+    incoming: BTreeMap<RegisterIndex, Vec<(BasicBlockIndex, Value)>>,
+}
+
+impl<'a> Renamer<'a> {
+    /// Rewrite the uses of the slots in `block`, given the value of each slot
+    /// coming into it, and record what flows into the phis of its successors
+    fn rename_block(
+        &mut self,
+        ctx: &mut Context,
+        block: BasicBlockIndex,
+        current: &mut BTreeMap<RegisterIndex, Value>,
+    ) {
+        for (slot, dest) in self.phis.get(&block).into_iter().flatten() {
+            current.insert(*slot, Value::Register(*dest));
+        }
+
+        let slots = self.slots;
+        let value_of = |current: &BTreeMap<RegisterIndex, Value>, slot: &RegisterIndex| {
+            current.get(slot).copied().unwrap_or(Value::Immediate {
+                _type: slots[slot],
+                value: 0,
+            })
+        };
+        let bb = ctx.basic_blocks.get_mut(block).unwrap();
+        let code = std::mem::take(bb.instructions_mut());
+        let mut out = Vec::with_capacity(code.len());
+        for inst in code {
+            match inst {
+                IR::Alloca { dest_register, .. } if slots.contains_key(&dest_register) => (),
+                IR::Load {
+                    dest_register,
+                    src_register: Value::Register(slot),
+                    extend_to: None,
+                } if slots.contains_key(&slot) => out.push(IR::Copy {
+                    dest_register,
+                    src: value_of(current, &slot),
+                }),
+                IR::Store {
+                    dest_register: Value::Register(slot),
+                    src_register,
+                } if slots.contains_key(&slot) => {
+                    current.insert(slot, src_register);
+                }
+                inst => out.push(inst),
+            }
+        }
+        *bb.instructions_mut() = out;
+
+        for exit in bb.iter_exits() {
+            for (slot, dest) in self.phis.get(exit).into_iter().flatten() {
+                let incoming = self.incoming.entry(*dest).or_default();
+                if !incoming.iter().any(|(b, _)| *b == block) {
+                    incoming.push((block, value_of(current, slot)));
+                }
+            }
+        }
+    }
+}
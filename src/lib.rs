@@ -10,3 +10,5 @@ extern crate smallvec;
 pub mod codegen;
 pub mod ir;
 pub mod reg_alloc;
+pub mod schedule;
+pub mod validate;
@@ -9,15 +9,21 @@
 //!
 //! [paper]: https://dl.acm.org/doi/10.1145/1356058.1356064
 
+mod range;
+pub(crate) mod spill;
+
+pub use range::Range;
+
 use crate::ir::*;
 use petgraph::{
     algo::dominators::{simple_fast, Dominators},
     graph::NodeIndex,
     stable_graph::StableGraph,
-    visit::{depth_first_search, DfsEvent},
-    Directed,
+    visit::{depth_first_search, DfsEvent, EdgeRef, IntoEdgeReferences},
+    Directed, Direction,
 };
 use std::collections::*;
+use std::fmt::Write;
 
 pub struct GraphData {
     pub index_map: BTreeMap<BasicBlockIndex, NodeIndex>,
@@ -28,15 +34,29 @@ pub struct GraphData {
     pub root: NodeIndex,
 }
 
+/// The registers an instruction defines and the ones it reads
+type InstructionRegisters = (Vec<RegisterIndex>, Vec<RegisterIndex>);
+
 pub struct GraphQuery {
     graph_data: GraphData,
     dominators: Dominators<NodeIndex>,
     reduced_reachability: BTreeMap<NodeIndex, BTreeSet<NodeIndex>>,
+    /// For each node, the targets of the back-edges reachable from it
     back_edges: BTreeMap<NodeIndex, BTreeSet<NodeIndex>>,
+    /// Edges whose target dominates their source, as (source, target)
+    loop_back_edges: Vec<(NodeIndex, NodeIndex)>,
     /// Map showing where a register is used
     use_map: BTreeMap<RegisterIndex, BTreeSet<NodeIndex>>,
     /// Map showing where a register was defined
     define_map: BTreeMap<RegisterIndex, NodeIndex>,
+    /// How many natural loops each node is in; nodes in no loops are absent
+    loop_depths: BTreeMap<NodeIndex, u32>,
+    /// Nodes in the dominance frontier of each node; empty frontiers are absent
+    dominance_frontiers: BTreeMap<NodeIndex, BTreeSet<NodeIndex>>,
+    value_ranges: range::ValueRanges,
+    /// The registers each instruction in each node defines and reads, in
+    /// order; phis read on the edges into the node so their reads are left out
+    instruction_registers: BTreeMap<NodeIndex, Vec<InstructionRegisters>>,
 }
 
 impl GraphQuery {
@@ -44,8 +64,13 @@ impl GraphQuery {
         let (reduced_reachability, back_edges) =
             graph_data.compute_reduced_reachability_and_back_edges();
         let dominators = simple_fast(&graph_data.graph, graph_data.root);
+        let loop_back_edges = find_back_edges(&graph_data.graph, &dominators);
+        let loop_depths = compute_loop_depths(&graph_data.graph, &loop_back_edges);
+        let dominance_frontiers = compute_dominance_frontiers(&graph_data.graph, &dominators);
+        let value_ranges = range::ValueRanges::new(bbm);
         let mut use_map: BTreeMap<RegisterIndex, BTreeSet<NodeIndex>> = BTreeMap::new();
         let mut define_map: BTreeMap<RegisterIndex, NodeIndex> = BTreeMap::new();
+        let mut instruction_registers = BTreeMap::new();
         for (idx, block) in bbm.iterate_basic_blocks() {
             let ni = graph_data.index_map[&idx];
             for reg_idx in block.iter_used_registers() {
@@ -56,75 +81,231 @@ impl GraphQuery {
                 let result = define_map.insert(*reg_idx, ni);
                 assert_eq!(result, None);
             }
+            let registers = block
+                .iterate_instructions()
+                .map(|inst| {
+                    let defs = inst.get_defined_registers();
+                    let uses = match inst {
+                        IR::Phi { .. } => vec![],
+                        _ => inst
+                            .get_used_registers()
+                            .into_iter()
+                            .filter(|r| !defs.contains(r))
+                            .copied()
+                            .collect(),
+                    };
+                    (defs.into_iter().copied().collect(), uses)
+                })
+                .collect();
+            instruction_registers.insert(ni, registers);
         }
         Self {
             graph_data,
             dominators,
             reduced_reachability,
             back_edges,
+            loop_back_edges,
             use_map,
             define_map,
+            loop_depths,
+            dominance_frontiers,
+            value_ranges,
+            instruction_registers,
         }
     }
 
-    /// Register is live coming into this basic block
+    /// How many loops the block is nested in, 0 if it's not in a loop
+    pub fn loop_depth(&self, node: BasicBlockIndex) -> u32 {
+        let ni = self.graph_data.index_map[&node];
+        self.loop_depths.get(&ni).copied().unwrap_or(0)
+    }
+
+    /// The closest block that every path from the entry to `node` goes
+    /// through, `None` for the entry and unreachable blocks
+    pub fn immediate_dominator(&self, node: BasicBlockIndex) -> Option<BasicBlockIndex> {
+        let ni = self.graph_data.index_map[&node];
+        self.dominators
+            .immediate_dominator(ni)
+            .map(|d| self.graph_data.graph[d])
+    }
+
+    /// The blocks where `node`'s dominance ends: those with a predecessor that
+    /// `node` dominates without strictly dominating the block itself.
+    ///
+    /// This is where phis for values defined in `node` are needed.
+    pub fn dominance_frontier(&self, node: BasicBlockIndex) -> BTreeSet<BasicBlockIndex> {
+        let ni = self.graph_data.index_map[&node];
+        self.dominance_frontiers
+            .get(&ni)
+            .map(|df| df.iter().map(|n| self.graph_data.graph[*n]).collect())
+            .unwrap_or_default()
+    }
+
+    /// The values register `idx` can have in `node`, as far as can be told
+    /// from how it's computed and from the conditional jumps taken to get
+    /// to `node`.
+    ///
+    /// A register tested by `JumpIfEqual` is known to be 0 in the true
+    /// target and nonzero in the false one, so a range that's a single
+    /// value lets a later test on it be folded.
+    pub fn value_range(&self, idx: RegisterIndex, node: BasicBlockIndex) -> Range {
+        self.value_ranges.get(idx, node)
+    }
+
+    /// The back-edges of the CFG as (source, target) pairs.
+    ///
+    /// The target of a back-edge dominates its source, so it's the header of
+    /// a loop that the source is the end of.
+    pub fn back_edges(&self) -> impl Iterator<Item = (BasicBlockIndex, BasicBlockIndex)> + '_ {
+        let graph = &self.graph_data.graph;
+        self.loop_back_edges
+            .iter()
+            .map(move |(s, t)| (graph[*s], graph[*t]))
+    }
+
+    /// Register `idx` is defined but never used.  Registers that aren't
+    /// defined anywhere aren't dead.
+    pub fn is_dead(&self, idx: RegisterIndex) -> bool {
+        self.define_map.contains_key(&idx) && !self.use_map.contains_key(&idx)
+    }
+
+    /// Register `idx` is live coming into `node`: its value may still be
+    /// read on some path starting at the top of `node`.
+    ///
+    /// `node` can be any block, not just the one defining `idx`; a register
+    /// is never live-in at its own definition.
     pub fn is_live_in(&self, idx: RegisterIndex, node: BasicBlockIndex) -> bool {
-        let ni = self.define_map[&idx];
+        let def = self.define_map[&idx];
         let node_ni = self.graph_data.index_map[&node];
-        let strict_dominators = self
-            .dominators
-            .strict_dominators(ni)
-            .unwrap()
-            .collect::<BTreeSet<_>>();
-        let uses_set = &self.use_map[&idx];
-        self.back_edges[&node_ni]
-            .intersection(&strict_dominators)
-            .any(|t| {
-                self.reduced_reachability[&t]
-                    .intersection(&uses_set)
-                    .count()
-                    != 0
-            })
-    }
-
-    /// Register is live coming out of this basic block
+        let uses = match self.use_map.get(&idx) {
+            Some(uses) => uses,
+            None => return false,
+        };
+        if !self.strictly_dominates(def, node_ni) {
+            return false;
+        }
+        self.loop_targets(def, node_ni)
+            .iter()
+            .chain(Some(&node_ni))
+            .any(|t| !self.reduced_reachability[t].is_disjoint(uses))
+    }
+
+    /// Register `idx` is live coming out of `node`: its value may still be
+    /// read on some path starting at the bottom of `node`.
     pub fn is_live_out(&self, idx: RegisterIndex, node: BasicBlockIndex) -> bool {
-        let ni = self.define_map[&idx];
+        let def = self.define_map[&idx];
         let node_ni = self.graph_data.index_map[&node];
-        if self.define_map[&idx] == node_ni {
-            // handle defined but never used variables
-            return self.use_map.get(&idx).is_some()
-                && self.use_map[&idx].iter().filter(|n| **n != node_ni).count() != 0;
-        }
-        // can avoid allocation here
-        let registers_node_dominates_node = self
-            .dominators
-            .strict_dominators(node_ni)
-            .unwrap()
-            .any(|e| e == ni);
-        if registers_node_dominates_node {
-            let strict_dominators = self
-                .dominators
-                .strict_dominators(ni)
-                .unwrap()
+        let uses = match self.use_map.get(&idx) {
+            Some(uses) => uses,
+            None => return false,
+        };
+        if def == node_ni {
+            // every use is dominated by the definition, so any use elsewhere
+            // is reachable from here
+            return uses.iter().any(|n| *n != node_ni);
+        }
+        if !self.strictly_dominates(def, node_ni) {
+            return false;
+        }
+        let targets = self.loop_targets(def, node_ni);
+        // a use in this block only counts if control can come back to it
+        let loops_back = targets.contains(&node_ni);
+        targets.iter().chain(Some(&node_ni)).any(|t| {
+            self.reduced_reachability[t]
+                .iter()
+                .any(|n| uses.contains(n) && (*n != node_ni || *t != node_ni || loops_back))
+        })
+    }
+
+    /// The most registers live at the same time anywhere in the function.
+    ///
+    /// A register needs a machine register from its definition to its last
+    /// use, so if this is more than the allocator has (10 on x86_64, 11 when
+    /// the frame pointer is omitted) some of them would have to be spilled.
+    pub fn max_register_pressure(&self) -> usize {
+        let mut max = 0;
+        for (node, block) in &self.graph_data.index_map {
+            let mut live = self
+                .define_map
+                .keys()
+                .filter(|r| self.is_live_out(**r, *node))
+                .copied()
                 .collect::<BTreeSet<_>>();
-            for t in self.back_edges[&ni].intersection(&strict_dominators) {
-                let mut u = self.use_map[&idx].clone();
-                if *t == node_ni && !self.back_edges[&node_ni].contains(&node_ni) {
-                    u.remove(&node_ni);
+            max = max.max(live.len());
+            for (defs, uses) in self.instruction_registers[block].iter().rev() {
+                // a definition needs a register even if it's never read
+                max = max.max(live.union(&defs.iter().copied().collect()).count());
+                for def in defs {
+                    live.remove(def);
                 }
-                if self.reduced_reachability[&t].intersection(&u).count() != 0 {
-                    return true;
+                live.extend(uses);
+                max = max.max(live.len());
+            }
+        }
+        max
+    }
+
+    /// The registers live coming into and out of each block, a line per
+    /// block, for debugging allocation:
+    ///
+    /// ```text
+    /// bb1: in [r1] out [r1, r2]
+    /// ```
+    pub fn dump_liveness(&self) -> String {
+        let mut out = String::new();
+        for node in self.graph_data.index_map.keys() {
+            let live = |is_live: fn(&Self, RegisterIndex, BasicBlockIndex) -> bool| {
+                self.define_map
+                    .keys()
+                    .filter(|r| is_live(self, **r, *node))
+                    .map(|r| r.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            writeln!(
+                out,
+                "{}: in [{}] out [{}]",
+                node,
+                live(Self::is_live_in),
+                live(Self::is_live_out)
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    /// The back-edge targets that can be reached from `node` without going
+    /// through `def`, following back-edges as many times as needed.
+    ///
+    /// Everything on a path from `node` to a use that doesn't go through
+    /// `def` is strictly dominated by `def`, so the other targets are skipped.
+    fn loop_targets(&self, def: NodeIndex, node: NodeIndex) -> BTreeSet<NodeIndex> {
+        let mut targets = BTreeSet::new();
+        let mut stack = vec![node];
+        while let Some(n) = stack.pop() {
+            for t in &self.back_edges[&n] {
+                if self.strictly_dominates(def, *t) && targets.insert(*t) {
+                    stack.push(*t);
                 }
             }
         }
-        false
+        targets
+    }
+
+    fn strictly_dominates(&self, a: NodeIndex, b: NodeIndex) -> bool {
+        a != b
+            && self
+                .dominators
+                .dominators(b)
+                .map(|mut ds| ds.any(|d| d == a))
+                .unwrap_or(false)
     }
 }
 
 impl GraphData {
     /// Returns the "transitive closure" of reachibilty on the reduced graph,
-    /// that is the set of all nodes that are reachable without back-edges
+    /// that is the set of all nodes that are reachable without back-edges,
+    /// and for each node the targets of the back-edges it can reach that way.
     pub fn compute_reduced_reachability_and_back_edges(
         &self,
     ) -> (
@@ -132,33 +313,156 @@ impl GraphData {
         BTreeMap<NodeIndex, BTreeSet<NodeIndex>>,
     ) {
         let mut rr_out = BTreeMap::new();
-        let mut back_edge_out = BTreeMap::new();
-
         for node_idx in self.reduced_graph.node_indices() {
             let mut connected_nodes: BTreeSet<NodeIndex> = BTreeSet::new();
-            let mut back_edge_targets: BTreeSet<NodeIndex> = BTreeSet::new();
-            depth_first_search(&self.reduced_graph, Some(node_idx), |event| match event {
-                // TODO: it looks like DfsEvents will let us avoid computing the reduced graph
-                DfsEvent::Discover(n, _) => {
+            depth_first_search(&self.reduced_graph, Some(node_idx), |event| {
+                if let DfsEvent::Discover(n, _) = event {
                     connected_nodes.insert(n);
                 }
-                DfsEvent::CrossForwardEdge(s, d)
-                | DfsEvent::TreeEdge(s, d)
-                | DfsEvent::BackEdge(s, d) => {
-                    // manually check back-edges, this back edge is relative to
-                    // the start of the dfs which if not the root, is wrong
-                    if self.depth_map[&s] > self.depth_map[&d] {
-                        back_edge_targets.insert(d);
-                    }
-                }
-                _ => (),
             });
             rr_out.insert(node_idx, connected_nodes);
-            back_edge_out.insert(node_idx, back_edge_targets);
+        }
+
+        let back_edges = self.back_edges();
+        let mut back_edge_out = BTreeMap::new();
+        for (node_idx, reachable) in &rr_out {
+            let back_edge_targets = back_edges
+                .iter()
+                .filter(|(s, _)| reachable.contains(s))
+                .map(|(_, t)| *t)
+                .collect::<BTreeSet<_>>();
+            back_edge_out.insert(*node_idx, back_edge_targets);
         }
 
         (rr_out, back_edge_out)
     }
+
+    /// The edges that were removed to make the reduced graph, as (source,
+    /// target)
+    fn back_edges(&self) -> Vec<(NodeIndex, NodeIndex)> {
+        self.graph
+            .edge_references()
+            .map(|edge| (edge.source(), edge.target()))
+            .filter(|(s, t)| self.reduced_graph.find_edge(*s, *t).is_none())
+            .collect()
+    }
+
+    /// Renders the full graph in the dot format, with the back-edges dashed
+    /// and red and the blocks they go to labeled as loop headers:
+    ///
+    /// ```text
+    /// digraph {
+    ///     bb1 [label="bb1 (loop header)"];
+    ///     bb2 -> bb1 [style=dashed, color=red];
+    /// }
+    /// ```
+    pub fn to_dot_annotated(&self) -> String {
+        let back_edges = self.back_edges();
+        let headers = back_edges.iter().map(|(_, t)| *t).collect::<BTreeSet<_>>();
+        let mut out = String::from("digraph {\n");
+        for node in self.graph.node_indices() {
+            let block = self.graph[node];
+            if headers.contains(&node) {
+                writeln!(out, "    {} [label=\"{} (loop header)\"];", block, block).unwrap();
+            } else {
+                writeln!(out, "    {};", block).unwrap();
+            }
+        }
+        for edge in self.graph.edge_references() {
+            let (s, t) = (edge.source(), edge.target());
+            write!(out, "    {} -> {}", self.graph[s], self.graph[t]).unwrap();
+            if back_edges.contains(&(s, t)) {
+                out.push_str(" [style=dashed, color=red]");
+            }
+            out.push_str(";\n");
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+/// Finds the edges to a node that dominates their source
+fn find_back_edges(
+    graph: &StableGraph<BasicBlockIndex, (), Directed>,
+    dominators: &Dominators<NodeIndex>,
+) -> Vec<(NodeIndex, NodeIndex)> {
+    graph
+        .edge_references()
+        .map(|edge| (edge.source(), edge.target()))
+        .filter(|(tail, header)| {
+            dominators
+                .dominators(*tail)
+                .map(|mut ds| ds.any(|d| d == *header))
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+/// Finds the natural loops of the graph and counts how many contain each node.
+///
+/// The target of a back-edge is the loop header and the loop is everything
+/// that can reach the source without going through the header.  Loops with
+/// the same header are merged.
+fn compute_loop_depths(
+    graph: &StableGraph<BasicBlockIndex, (), Directed>,
+    back_edges: &[(NodeIndex, NodeIndex)],
+) -> BTreeMap<NodeIndex, u32> {
+    let mut loops: BTreeMap<NodeIndex, BTreeSet<NodeIndex>> = BTreeMap::new();
+    for &(tail, header) in back_edges {
+        let body = loops.entry(header).or_insert_with(|| {
+            let mut body = BTreeSet::new();
+            body.insert(header);
+            body
+        });
+        let mut stack = vec![tail];
+        while let Some(n) = stack.pop() {
+            if body.insert(n) {
+                stack.extend(graph.neighbors_directed(n, Direction::Incoming));
+            }
+        }
+    }
+
+    let mut depths = BTreeMap::new();
+    for body in loops.values() {
+        for n in body {
+            *depths.entry(*n).or_insert(0) += 1;
+        }
+    }
+    depths
+}
+
+/// Dominance frontiers from "A Simple, Fast Dominance Algorithm" by Cooper,
+/// Harvey, and Kennedy: walk up the dominator tree from each predecessor of a
+/// join point until reaching the join point's immediate dominator.
+fn compute_dominance_frontiers(
+    graph: &StableGraph<BasicBlockIndex, (), Directed>,
+    dominators: &Dominators<NodeIndex>,
+) -> BTreeMap<NodeIndex, BTreeSet<NodeIndex>> {
+    let mut frontiers: BTreeMap<NodeIndex, BTreeSet<NodeIndex>> = BTreeMap::new();
+    for node in graph.node_indices() {
+        let preds = graph
+            .neighbors_directed(node, Direction::Incoming)
+            .collect::<BTreeSet<_>>();
+        if preds.len() < 2 {
+            continue;
+        }
+        let idom = dominators.immediate_dominator(node);
+        for pred in preds {
+            // unreachable blocks aren't dominated by anything
+            if dominators.dominators(pred).is_none() {
+                continue;
+            }
+            let mut runner = pred;
+            while Some(runner) != idom {
+                frontiers.entry(runner).or_default().insert(node);
+                match dominators.immediate_dominator(runner) {
+                    Some(next) => runner = next,
+                    None => break,
+                }
+            }
+        }
+    }
+    frontiers
 }
 
 pub fn compute_graph(bbm: &BasicBlockManager) -> GraphData {
@@ -175,19 +479,17 @@ pub fn compute_graph(bbm: &BasicBlockManager) -> GraphData {
             // update to avoid duplicates
             graph.update_edge(parent_ni, ni, ());
         }
-        for exit in bb.iter_exits() {
+        // exits to blocks that don't exist are left out
+        for exit in bb.iter_exits().filter(|e| node_lookup.contains_key(e)) {
             let exit_ni = node_lookup[exit];
             // update to avoid duplicates
             graph.update_edge(ni, exit_ni, ());
         }
     }
-    println!("{:?}", petgraph::dot::Dot::new(&graph));
 
     let start_ni = node_lookup[&bbm.start];
     let (reduced_graph, depth_map) = compute_reduced_graph_and_depth_map(&graph, start_ni);
 
-    println!("{:?}", petgraph::dot::Dot::new(&reduced_graph));
-
     GraphData {
         index_map: node_lookup,
         depth_map,
@@ -197,7 +499,12 @@ pub fn compute_graph(bbm: &BasicBlockManager) -> GraphData {
     }
 }
 
-/// Creates a copy of the graph with the back-edges removed
+/// Creates a copy of the graph with the back-edges removed, along with the
+/// depth of each node in the DFS tree.
+///
+/// Back-edges are the edges to an ancestor in a depth first search from
+/// `start`, i.e. to a node that's still being visited.  Edges out of nodes
+/// that can't be reached from `start` are kept.
 pub fn compute_reduced_graph_and_depth_map(
     graph: &StableGraph<BasicBlockIndex, (), Directed>,
     start: NodeIndex,
@@ -206,25 +513,296 @@ pub fn compute_reduced_graph_and_depth_map(
     BTreeMap<NodeIndex, u32>,
 ) {
     let mut reduced_graph = graph.clone();
-    let mut stack = VecDeque::new();
-    let mut seen: BTreeMap<NodeIndex, u32> = BTreeMap::new();
-    seen.insert(start, 0);
-    // second num is generation, we use this to detect back-references
-    stack.push_back((start, 0));
-
-    while let Some((node, generation)) = stack.pop_front() {
-        for neigh in graph.neighbors(node) {
-            if seen.contains_key(&neigh) {
-                if seen[&neigh] < generation {
-                    let edge = reduced_graph.find_edge(node, neigh).unwrap();
-                    reduced_graph.remove_edge(edge);
-                }
-            } else {
-                seen.insert(neigh, generation);
-                stack.push_back((neigh, generation + 1))
-            }
+    let mut depth_map: BTreeMap<NodeIndex, u32> = BTreeMap::new();
+    depth_map.insert(start, 0);
+
+    depth_first_search(graph, Some(start), |event| match event {
+        DfsEvent::TreeEdge(s, d) => {
+            let depth = depth_map[&s] + 1;
+            depth_map.insert(d, depth);
+        }
+        DfsEvent::BackEdge(s, d) => {
+            let edge = reduced_graph.find_edge(s, d).unwrap();
+            reduced_graph.remove_edge(edge);
+        }
+        _ => (),
+    });
+
+    (reduced_graph, depth_map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph_query(ctx: &mut Context) -> GraphQuery {
+        ctx.finalize();
+        let bbm = ctx.basic_blocks();
+        GraphQuery::new(compute_graph(bbm), bbm)
+    }
+
+    #[test]
+    fn nested_loop_depths() {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let x = ctx.add_parameter(PrimitiveValue::U64);
+        let y = ctx.add_parameter(PrimitiveValue::U64);
+        let outer = ctx.new_basic_block();
+        let inner = ctx.new_basic_block();
+        let latch = ctx.new_basic_block();
+        let exit = ctx.new_basic_block();
+        ctx.build_basic_block(entry).jump(outer);
+        ctx.build_basic_block(outer).jump_if_equal(x, exit, inner);
+        ctx.build_basic_block(inner).jump_if_equal(y, latch, inner);
+        ctx.build_basic_block(latch).jump(outer);
+        ctx.build_basic_block(exit).ret();
+
+        let gq = graph_query(&mut ctx);
+        assert_eq!(gq.loop_depth(entry), 0);
+        assert_eq!(gq.loop_depth(outer), 1);
+        assert_eq!(gq.loop_depth(inner), 2);
+        assert_eq!(gq.loop_depth(latch), 1);
+        assert_eq!(gq.loop_depth(exit), 0);
+    }
+
+    #[test]
+    fn diamond_branches_have_the_join_as_their_frontier() {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let x = ctx.add_parameter(PrimitiveValue::U64);
+        let left = ctx.new_basic_block();
+        let right = ctx.new_basic_block();
+        let join = ctx.new_basic_block();
+        ctx.build_basic_block(entry).jump_if_equal(x, left, right);
+        ctx.build_basic_block(left).jump(join);
+        ctx.build_basic_block(right).jump(join);
+        ctx.build_basic_block(join).ret();
+
+        let gq = graph_query(&mut ctx);
+        let frontier = |blocks: &[BasicBlockIndex]| blocks.iter().copied().collect::<BTreeSet<_>>();
+        assert_eq!(gq.dominance_frontier(left), frontier(&[join]));
+        assert_eq!(gq.dominance_frontier(right), frontier(&[join]));
+        assert_eq!(gq.dominance_frontier(entry), frontier(&[]));
+        assert_eq!(gq.dominance_frontier(join), frontier(&[]));
+    }
+
+    #[test]
+    fn liveness_through_a_diamond_into_a_loop() {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let x = ctx.add_parameter(PrimitiveValue::U64);
+        let left = ctx.new_basic_block();
+        let right = ctx.new_basic_block();
+        let header = ctx.new_basic_block();
+        let body = ctx.new_basic_block();
+        let exit = ctx.new_basic_block();
+        let bb = ctx.build_basic_block(entry);
+        let y = match bb.add(x, Value::u64(1)) {
+            Value::Register(r) => r,
+            Value::Immediate { .. } => unreachable!(),
+        };
+        bb.jump_if_equal(x, left, right);
+        ctx.build_basic_block(left).jump(header);
+        ctx.build_basic_block(right).jump(header);
+        ctx.build_basic_block(header).jump_if_equal(x, exit, body);
+        let bb = ctx.build_basic_block(body);
+        bb.print_int(Value::Register(y), PrimitiveValue::U64);
+        bb.jump(header);
+        ctx.build_basic_block(exit).ret();
+
+        let gq = graph_query(&mut ctx);
+        // the second edge into the join isn't a back-edge
+        assert_eq!(gq.back_edges().collect::<Vec<_>>(), [(body, header)]);
+        for block in [left, right, header, body] {
+            assert!(gq.is_live_in(y, block), "not live into {}", block);
         }
+        for block in [entry, left, right, header, body] {
+            assert!(gq.is_live_out(y, block), "not live out of {}", block);
+        }
+        assert!(!gq.is_live_in(y, entry));
+        assert!(!gq.is_live_in(y, exit));
+        assert!(!gq.is_live_out(y, exit));
     }
 
-    (reduced_graph, seen)
+    #[test]
+    fn live_in_at_the_loop_body_but_not_the_entry() {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let x = ctx.add_parameter(PrimitiveValue::U64);
+        let preheader = ctx.new_basic_block();
+        let header = ctx.new_basic_block();
+        let body = ctx.new_basic_block();
+        let exit = ctx.new_basic_block();
+        ctx.build_basic_block(entry).jump(preheader);
+        let bb = ctx.build_basic_block(preheader);
+        let y = match bb.multiply(x, Value::u64(3)) {
+            Value::Register(r) => r,
+            Value::Immediate { .. } => unreachable!(),
+        };
+        bb.jump(header);
+        ctx.build_basic_block(header).jump_if_equal(x, exit, body);
+        let bb = ctx.build_basic_block(body);
+        bb.print_int(Value::Register(y), PrimitiveValue::U64);
+        bb.jump(header);
+        ctx.build_basic_block(exit).ret();
+
+        let gq = graph_query(&mut ctx);
+        assert!(gq.is_live_in(y, body));
+        assert!(gq.is_live_in(y, header));
+        assert!(!gq.is_live_in(y, entry));
+        assert!(!gq.is_live_in(y, preheader));
+        assert!(!gq.is_live_in(y, exit));
+    }
+
+    #[test]
+    fn narrowed_range_decides_a_later_branch() {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let x = ctx.add_parameter(PrimitiveValue::U32);
+        let zero = ctx.new_basic_block();
+        let nonzero = ctx.new_basic_block();
+        let never = ctx.new_basic_block();
+        let always = ctx.new_basic_block();
+        let x_reg = match x {
+            Value::Register(r) => r,
+            Value::Immediate { .. } => unreachable!(),
+        };
+        ctx.build_basic_block(entry).jump_if_equal(x, zero, nonzero);
+        let bb = ctx.build_basic_block(zero);
+        let y = match bb.add(x, Value::u32(5)) {
+            Value::Register(r) => r,
+            Value::Immediate { .. } => unreachable!(),
+        };
+        bb.jump_if_equal(Value::Register(y), never, always);
+        ctx.build_basic_block(nonzero).ret_value(Value::u32(1));
+        ctx.build_basic_block(never).ret_value(Value::u32(2));
+        ctx.build_basic_block(always).ret_value(Value::u32(3));
+
+        let gq = graph_query(&mut ctx);
+        assert_eq!(
+            gq.value_range(x_reg, entry),
+            Range::full(PrimitiveValue::U32)
+        );
+        assert_eq!(gq.value_range(x_reg, zero), Range::constant(0));
+        assert_eq!(
+            gq.value_range(x_reg, nonzero),
+            Range::new(1, u32::MAX as i128)
+        );
+        // so `y` is always 5 there, and the branch on it always goes to `always`
+        let y_range = gq.value_range(y, zero);
+        assert_eq!(y_range.as_constant(), Some(5));
+        assert!(!y_range.contains(0));
+    }
+
+    /// The program from `examples/conditional_print.rs`, with its blocks and
+    /// the registers for the counter's pointer and what's left to count
+    fn the_example() -> (Context, [BasicBlockIndex; 4], RegisterIndex, RegisterIndex) {
+        let mut ctx = Context::new();
+        let hello = ctx.add_constant(b"Hello, world\n");
+        let goodbye = ctx.add_constant(b"Goodbye, world\n");
+        let start = ctx.new_basic_block();
+        let loop_inner = ctx.new_basic_block();
+        let loop_outer = ctx.new_basic_block();
+        let loop_exit = ctx.new_basic_block();
+        let bb = ctx.build_basic_block(start);
+        let counter = bb.alloca(PrimitiveValue::U32, 4);
+        bb.store(counter, Value::u32(0));
+        bb.then(loop_inner);
+        let bb = ctx.build_basic_block(loop_inner);
+        bb.push_instruction(IR::PrintConstant {
+            constant_ref: hello,
+        });
+        let loaded = bb.load(counter);
+        let incremented = bb.add(loaded, Value::u32(1));
+        bb.store(counter, incremented);
+        let remaining = bb.subtract(Value::u32(4), incremented);
+        ctx.build_basic_block(loop_outer)
+            .add_parent(loop_inner)
+            .jump_if_equal(remaining, loop_exit, loop_inner);
+        let bb = ctx.build_basic_block(loop_exit);
+        bb.add_parent(loop_outer)
+            .push_instruction(IR::PrintConstant {
+                constant_ref: goodbye,
+            });
+        bb.ret();
+        let register = |v| match v {
+            Value::Register(r) => r,
+            Value::Immediate { .. } => unreachable!(),
+        };
+        (
+            ctx,
+            [start, loop_inner, loop_outer, loop_exit],
+            register(counter),
+            register(remaining),
+        )
+    }
+
+    #[test]
+    fn liveness_dump_of_the_example() {
+        let (mut ctx, [start, loop_inner, loop_outer, loop_exit], counter, remaining) =
+            the_example();
+        let gq = graph_query(&mut ctx);
+        // the counter's pointer is live around the loop, and what's left to
+        // count is only live into the branch on it
+        let expected = format!(
+            "{start}: in [] out [{c}]\n\
+             {inner}: in [{c}] out [{c}, {r}]\n\
+             {outer}: in [{c}, {r}] out [{c}]\n\
+             {exit}: in [] out []\n",
+            start = start,
+            inner = loop_inner,
+            outer = loop_outer,
+            exit = loop_exit,
+            c = counter,
+            r = remaining,
+        );
+        assert_eq!(gq.dump_liveness(), expected);
+    }
+
+    #[test]
+    fn annotated_dot_of_the_example() {
+        let (mut ctx, [start, loop_inner, loop_outer, loop_exit], _, _) = the_example();
+        ctx.finalize();
+        let dot = compute_graph(ctx.basic_blocks()).to_dot_annotated();
+        // only the edge back to the inner block is dashed
+        let expected = format!(
+            "digraph {{\n    \
+             {start};\n    \
+             {inner} [label=\"{inner} (loop header)\"];\n    \
+             {outer};\n    \
+             {exit};\n    \
+             {start} -> {inner};\n    \
+             {outer} -> {inner} [style=dashed, color=red];\n    \
+             {inner} -> {outer};\n    \
+             {outer} -> {exit};\n\
+             }}\n",
+            start = start,
+            inner = loop_inner,
+            outer = loop_outer,
+            exit = loop_exit,
+        );
+        assert_eq!(dot, expected);
+    }
+
+    #[test]
+    fn register_pressure() {
+        let (mut ctx, ..) = the_example();
+        let gq = graph_query(&mut ctx);
+        // the counter's pointer, and one value computed from it at a time
+        assert_eq!(gq.max_register_pressure(), 2);
+
+        // `x` and twelve values made from it, all live before they're summed
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let x = ctx.add_parameter(PrimitiveValue::U64);
+        let bb = ctx.build_basic_block(entry);
+        let values = (1..=12)
+            .map(|i| bb.add(x, Value::u64(i)))
+            .collect::<Vec<_>>();
+        let sum = values.into_iter().fold(x, |sum, v| bb.add(sum, v));
+        bb.ret_value(sum);
+        let gq = graph_query(&mut ctx);
+        // more than the ten machine registers there are to allocate
+        assert_eq!(gq.max_register_pressure(), 13);
+    }
 }
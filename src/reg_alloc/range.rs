@@ -0,0 +1,382 @@
+//! Value range analysis: the interval of integers each register can hold.
+//!
+//! Every register gets a range from its definition alone.  That's narrowed
+//! per block by what the conditional jumps on the way to the block say: after
+//! `JumpIfEqual` on `x`, the true edge knows `x` is 0 and the false edge knows
+//! it isn't.  Ranges that keep growing, like loop counters, are widened to
+//! every value of their type so the analysis finishes quickly.
+
+use crate::ir::*;
+use std::collections::*;
+
+/// How many times a range may grow before giving up on it
+const MAX_GROWTH: u32 = 4;
+
+/// An inclusive range of integers.  Registers of signed types are
+/// interpreted as signed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Range {
+    pub min: i128,
+    pub max: i128,
+}
+
+impl Range {
+    pub fn new(min: i128, max: i128) -> Self {
+        assert!(min <= max, "empty range {}..={}", min, max);
+        Self { min, max }
+    }
+
+    pub fn constant(value: i128) -> Self {
+        Self::new(value, value)
+    }
+
+    /// Every value of `_type`, as far as a `Range` can hold them
+    pub fn full(_type: PrimitiveValue) -> Self {
+        let bits = _type.size() * 8;
+        if bits == 128 {
+            let (min, max) = _type.range();
+            Self::new(min, max)
+        } else if _type.is_signed() {
+            Self::new(-(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+        } else {
+            Self::new(0, (1 << bits) - 1)
+        }
+    }
+
+    /// The only value in the range, if there's just one
+    pub fn as_constant(self) -> Option<i128> {
+        if self.min == self.max {
+            Some(self.min)
+        } else {
+            None
+        }
+    }
+
+    pub fn contains(self, value: i128) -> bool {
+        self.min <= value && value <= self.max
+    }
+
+    /// The smallest range containing both
+    pub fn union(self, other: Self) -> Self {
+        Self::new(self.min.min(other.min), self.max.max(other.max))
+    }
+
+    /// The values in both, `None` if there aren't any
+    pub fn intersect(self, other: Self) -> Option<Self> {
+        let (min, max) = (self.min.max(other.min), self.max.min(other.max));
+        if min <= max {
+            Some(Self::new(min, max))
+        } else {
+            None
+        }
+    }
+
+    /// The range with `value` taken out, where that can be expressed as a
+    /// range.  `None` if nothing is left.
+    fn without(self, value: i128) -> Option<Self> {
+        if self.as_constant() == Some(value) {
+            None
+        } else if self.min == value {
+            Some(Self::new(value + 1, self.max))
+        } else if self.max == value {
+            Some(Self::new(self.min, value - 1))
+        } else {
+            Some(self)
+        }
+    }
+}
+
+/// What's known about the value of registers
+type Facts = BTreeMap<RegisterIndex, Range>;
+
+pub(crate) struct ValueRanges {
+    types: BTreeMap<RegisterIndex, PrimitiveValue>,
+    /// The range of each register from its definition alone
+    base: Facts,
+    /// Narrower ranges that hold in each block
+    narrowed: BTreeMap<BasicBlockIndex, Facts>,
+}
+
+impl ValueRanges {
+    pub(crate) fn new(bbm: &BasicBlockManager) -> Self {
+        let types = bbm.compute_register_types();
+        let base = compute_base_ranges(bbm, &types);
+        let narrowed = compute_narrowed_ranges(bbm, &types, &base);
+        Self {
+            types,
+            base,
+            narrowed,
+        }
+    }
+
+    pub(crate) fn get(&self, idx: RegisterIndex, block: BasicBlockIndex) -> Range {
+        self.narrowed
+            .get(&block)
+            .and_then(|facts| facts.get(&idx))
+            .or_else(|| self.base.get(&idx))
+            .copied()
+            .unwrap_or_else(|| full_range(&self.types, idx))
+    }
+}
+
+fn full_range(types: &BTreeMap<RegisterIndex, PrimitiveValue>, idx: RegisterIndex) -> Range {
+    Range::full(types.get(&idx).copied().unwrap_or(PrimitiveValue::U64))
+}
+
+fn immediate_range(_type: PrimitiveValue, value: usize) -> Range {
+    // immediates of 128 bit types are extended from 64 bits
+    let shift = 64usize.saturating_sub(_type.size() * 8);
+    let value = if _type.is_signed() {
+        (((value << shift) as i64) >> shift) as i128
+    } else {
+        ((value << shift) >> shift) as i128
+    };
+    Range::constant(value)
+}
+
+/// The range of the value `inst` defines in `dest` given the ranges of its
+/// operands, or `None` if an operand's range isn't known yet.  Anything that
+/// might wrap around gets the `full` range of the destination's type.
+fn evaluate(
+    inst: &IR,
+    dest: RegisterIndex,
+    full: Range,
+    operand: impl Fn(&Value) -> Option<Range>,
+) -> Option<Range> {
+    if inst.overflow_flag() == Some(dest) {
+        return Some(Range::new(0, 1));
+    }
+    // 128 bit arithmetic on ranges could overflow, and nothing needs to
+    // know about them
+    if full.max > u64::MAX as i128 {
+        return Some(full);
+    }
+    let fit = |r: Range| {
+        if full.min <= r.min && r.max <= full.max {
+            r
+        } else {
+            full
+        }
+    };
+    let range = match inst {
+        IR::Copy { src, .. } => operand(src)?,
+        IR::Phi { incoming, .. } => incoming
+            .iter()
+            .filter_map(|(_, v)| operand(v))
+            .fold(None, |acc: Option<Range>, r| {
+                Some(acc.map_or(r, |acc| acc.union(r)))
+            })?,
+        IR::Compare { .. } => Range::new(0, 1),
+        // it traps instead of producing a value that doesn't fit
+        IR::TruncateChecked { src, .. } => operand(src)?.intersect(full).unwrap_or(full),
+        IR::Add { src1, src2, .. }
+        | IR::Subtract { src1, src2, .. }
+        | IR::Multiply { src1, src2, .. }
+        | IR::Divide { src1, src2, .. }
+        | IR::Remainder { src1, src2, .. } => {
+            let (a, b) = (operand(src1)?, operand(src2)?);
+            let overflow = match inst {
+                IR::Add { overflow, .. }
+                | IR::Subtract { overflow, .. }
+                | IR::Multiply { overflow, .. } => *overflow,
+                _ => Overflow::Wrap,
+            };
+            // unless it wraps, a result that doesn't fit traps or can't happen
+            let fit = |r: Range| match overflow {
+                Overflow::Wrap | Overflow::Flag(_) => fit(r),
+                Overflow::Trap(_) | Overflow::Poison => r.intersect(full).unwrap_or(full),
+            };
+            match inst {
+                IR::Add { .. } => fit(Range::new(a.min + b.min, a.max + b.max)),
+                IR::Subtract { .. } => fit(Range::new(a.min - b.max, a.max - b.min)),
+                IR::Multiply { .. } => {
+                    let products = [
+                        a.min.checked_mul(b.min),
+                        a.min.checked_mul(b.max),
+                        a.max.checked_mul(b.min),
+                        a.max.checked_mul(b.max),
+                    ];
+                    if products.iter().all(Option::is_some) {
+                        let products = products.iter().map(|p| p.unwrap());
+                        fit(Range::new(
+                            products.clone().min().unwrap(),
+                            products.max().unwrap(),
+                        ))
+                    } else {
+                        full
+                    }
+                }
+                IR::Divide { .. } if a.min >= 0 && b.min > 0 => {
+                    Range::new(a.min / b.max, a.max / b.min)
+                }
+                IR::Remainder { .. } if a.min >= 0 && b.min > 0 => {
+                    Range::new(0, a.max.min(b.max - 1))
+                }
+                _ => full,
+            }
+        }
+        _ => full,
+    };
+    Some(range)
+}
+
+/// Find the range of every register from its definition, going around until
+/// nothing changes since phis can refer to registers defined later
+fn compute_base_ranges(
+    bbm: &BasicBlockManager,
+    types: &BTreeMap<RegisterIndex, PrimitiveValue>,
+) -> Facts {
+    let mut ranges: Facts = BTreeMap::new();
+    let mut growth: BTreeMap<RegisterIndex, u32> = BTreeMap::new();
+    loop {
+        let mut changed = false;
+        for (_, bb) in bbm.iterate_basic_blocks() {
+            for inst in bb.iterate_instructions() {
+                for dest in inst.get_defined_registers() {
+                    let full = full_range(types, *dest);
+                    let operand = |v: &Value| match v {
+                        Value::Register(r) => ranges.get(r).copied(),
+                        Value::Immediate { _type, value } => Some(immediate_range(*_type, *value)),
+                    };
+                    let range = match evaluate(inst, *dest, full, operand) {
+                        Some(range) => range,
+                        None => continue,
+                    };
+                    let old = ranges.get(dest).copied();
+                    let merged = old.map_or(range, |old| old.union(range));
+                    if old != Some(merged) {
+                        let grown = growth.entry(*dest).or_insert(0);
+                        *grown += 1;
+                        let merged = if *grown > MAX_GROWTH { full } else { merged };
+                        ranges.insert(*dest, merged);
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    ranges
+}
+
+/// The register a conditional jump tests and whether it's 0 when going to
+/// `target`, if that's known
+fn edge_condition(inst: Option<&IR>, target: BasicBlockIndex) -> Option<(RegisterIndex, bool)> {
+    let (reg, true_bb_idx, false_bb_idx, zero_when_true) = match inst? {
+        IR::JumpIfEqual {
+            src_register: Value::Register(r),
+            true_bb_idx,
+            false_bb_idx,
+        } => (*r, *true_bb_idx, *false_bb_idx, true),
+        IR::JumpIfNotEqual {
+            src_register: Value::Register(r),
+            true_bb_idx,
+            false_bb_idx,
+        } => (*r, *true_bb_idx, *false_bb_idx, false),
+        _ => return None,
+    };
+    if true_bb_idx == false_bb_idx {
+        None
+    } else if target == true_bb_idx {
+        Some((reg, zero_when_true))
+    } else {
+        Some((reg, !zero_when_true))
+    }
+}
+
+/// Walk the CFG from the entry, narrowing ranges on the edges of conditional
+/// jumps.  A block only keeps what's known on every edge into it.
+fn compute_narrowed_ranges(
+    bbm: &BasicBlockManager,
+    types: &BTreeMap<RegisterIndex, PrimitiveValue>,
+    base: &Facts,
+) -> BTreeMap<BasicBlockIndex, Facts> {
+    // blocks that haven't been reached yet are missing
+    let mut entry_facts: BTreeMap<BasicBlockIndex, Facts> = BTreeMap::new();
+    let mut growth: BTreeMap<(BasicBlockIndex, RegisterIndex), u32> = BTreeMap::new();
+    let mut narrowed = BTreeMap::new();
+    entry_facts.insert(bbm.start, BTreeMap::new());
+    let mut worklist = vec![bbm.start];
+
+    while let Some(block) = worklist.pop() {
+        let bb = match bbm.get(block) {
+            Some(bb) => bb,
+            // a jump to a block that isn't there, which validation reports
+            None => continue,
+        };
+        let mut facts = entry_facts[&block].clone();
+        // values computed here may be narrower because their operands are
+        for inst in bb.iterate_instructions() {
+            for dest in inst.get_defined_registers() {
+                facts.remove(dest);
+                let operand = |v: &Value| match v {
+                    Value::Register(r) => facts.get(r).or_else(|| base.get(r)).copied(),
+                    Value::Immediate { _type, value } => Some(immediate_range(*_type, *value)),
+                };
+                let range = evaluate(inst, *dest, full_range(types, *dest), operand);
+                if let (Some(range), Some(old)) = (range, base.get(dest)) {
+                    match range.intersect(*old) {
+                        Some(range) if range != *old => {
+                            facts.insert(*dest, range);
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+
+        let terminator = bb
+            .iterate_instructions()
+            .filter(|i| !matches!(i, IR::Nop))
+            .last();
+        for exit in bb.iter_exits() {
+            let mut edge_facts = facts.clone();
+            if let Some((reg, is_zero)) = edge_condition(terminator, *exit) {
+                if let Some(current) = edge_facts.get(&reg).or_else(|| base.get(&reg)) {
+                    let range = if is_zero {
+                        current.intersect(Range::constant(0))
+                    } else {
+                        current.without(0)
+                    };
+                    match range {
+                        Some(range) => {
+                            edge_facts.insert(reg, range);
+                        }
+                        // this edge is never taken
+                        None => continue,
+                    }
+                }
+            }
+            let merged = match entry_facts.get(exit) {
+                None => edge_facts,
+                Some(old) => {
+                    let mut merged = Facts::new();
+                    for (r, old_range) in old {
+                        let range = match edge_facts.get(r) {
+                            Some(range) => old_range.union(*range),
+                            None => continue,
+                        };
+                        if range != *old_range {
+                            let grown = growth.entry((*exit, *r)).or_insert(0);
+                            *grown += 1;
+                            if *grown > MAX_GROWTH {
+                                continue;
+                            }
+                        }
+                        merged.insert(*r, range);
+                    }
+                    merged
+                }
+            };
+            if entry_facts.get(exit) != Some(&merged) {
+                entry_facts.insert(*exit, merged);
+                worklist.push(*exit);
+            }
+        }
+        narrowed.insert(block, facts);
+    }
+
+    narrowed
+}
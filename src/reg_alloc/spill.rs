@@ -0,0 +1,436 @@
+//! Spilling registers to the stack when there aren't enough machine
+//! registers for everything that's live at once.
+//!
+//! A spilled register is stored to a stack slot right after it's defined and
+//! loaded back into a new register right before each use, so it only needs a
+//! machine register for a moment at each end.  That's done by adding
+//! `Alloca`, `Store`, and `Load` instructions to a copy of the program, which
+//! the allocator then runs on again.  Spilled registers that are never live
+//! at the same time share a slot, the same way they'd share a machine
+//! register.
+
+use super::{compute_graph, GraphQuery};
+use crate::ir::*;
+use std::collections::*;
+
+/// A register that was spilled
+#[derive(Debug, Clone)]
+pub(crate) struct Spill {
+    pub register: RegisterIndex,
+    /// The `Alloca` for its stack slot
+    pub slot: RegisterIndex,
+    /// The instructions of the original program it's loaded back for, by
+    /// block and index
+    pub reloads: Vec<(BasicBlockIndex, usize)>,
+}
+
+/// A program with some of its registers spilled
+pub(crate) struct Spilled {
+    pub ctx: Context,
+    /// The instruction of the original program each instruction of `ctx`
+    /// belongs to, by block.  Loads belong to the instruction they're for,
+    /// and stores to the one defining what they store.
+    pub origins: BTreeMap<BasicBlockIndex, Vec<usize>>,
+    pub spills: Vec<Spill>,
+}
+
+/// Spills registers of `original` one at a time
+pub(crate) struct Spiller<'a> {
+    original: &'a Context,
+    spilled: Spilled,
+}
+
+/// An instruction to add to a block, before the instruction at `position`
+struct Insertion {
+    position: usize,
+    /// Stores go before loads at the same position, since a value can be
+    /// used right after it's defined
+    order: u8,
+    inst: IR,
+    /// Whether it belongs to the instruction before it rather than the one
+    /// after
+    belongs_before: bool,
+}
+
+/// How many instructions at the top of `bb` are phis or parameters, which
+/// all get their values at once on the way into the block
+pub(crate) fn leading_definitions(bb: &BasicBlock) -> usize {
+    bb.iterate_instructions()
+        .take_while(|inst| matches!(inst, IR::Phi { .. } | IR::Parameter { .. }))
+        .count()
+}
+
+/// Whether `inst` reads `reg`, other than as an incoming value of a phi
+fn reads(inst: &IR, reg: RegisterIndex) -> bool {
+    !matches!(inst, IR::Phi { .. })
+        && inst
+            .get_used_values()
+            .iter()
+            .any(|v| matches!(v, Value::Register(r) if *r == reg))
+}
+
+/// Where in `block` the first instruction after `after` reading `reg` is,
+/// with the phis of its successors reading what they take from it after its
+/// last instruction
+pub(crate) fn next_use(
+    bbm: &BasicBlockManager,
+    reg: RegisterIndex,
+    block: BasicBlockIndex,
+    after: usize,
+) -> Option<usize> {
+    let code = bbm
+        .get(block)
+        .unwrap()
+        .iterate_instructions()
+        .collect::<Vec<_>>();
+    code.iter()
+        .enumerate()
+        .skip(after + 1)
+        .find(|(_, inst)| reads(inst, reg))
+        .map(|(index, _)| index)
+        .or_else(|| {
+            let taken_by_phi = bbm
+                .successors(block)
+                .into_iter()
+                .any(|succ| phi_values_from(bbm.get(succ).unwrap(), block).any(|r| r == reg));
+            taken_by_phi.then_some(code.len())
+        })
+}
+
+/// The registers the phis of `bb` take when coming from `pred`
+fn phi_values_from(
+    bb: &BasicBlock,
+    pred: BasicBlockIndex,
+) -> impl Iterator<Item = RegisterIndex> + '_ {
+    bb.iterate_instructions()
+        .filter_map(move |inst| match inst {
+            IR::Phi { incoming, .. } => Some(incoming.iter().filter(move |(b, _)| *b == pred)),
+            _ => None,
+        })
+        .flatten()
+        .filter_map(|(_, v)| match v {
+            Value::Register(r) => Some(*r),
+            Value::Immediate { .. } => None,
+        })
+}
+
+/// Where to put a load for the instruction at `position` in `code`, which
+/// is before anything that has to stay right before it: the pins it expects
+/// values in, and the comparison a conditional jump is fused with unless
+/// that's what's being loaded
+fn load_position(code: &[IR], position: usize, reg: RegisterIndex) -> usize {
+    let mut at = position;
+    while at > 0 {
+        match (&code[at - 1], code.get(position)) {
+            (IR::Pin { .. }, _) => at -= 1,
+            (
+                IR::Compare { dest_register, .. },
+                Some(IR::JumpIfEqual {
+                    src_register: Value::Register(cond),
+                    ..
+                }),
+            )
+            | (
+                IR::Compare { dest_register, .. },
+                Some(IR::JumpIfNotEqual {
+                    src_register: Value::Register(cond),
+                    ..
+                }),
+            ) if at == position && dest_register == cond && *dest_register != reg => at -= 1,
+            _ => break,
+        }
+    }
+    at
+}
+
+impl<'a> Spiller<'a> {
+    pub(crate) fn new(original: &'a Context) -> Self {
+        let origins = original
+            .basic_blocks
+            .iterate_basic_blocks()
+            .map(|(idx, bb)| (idx, (0..bb.iterate_instructions().count()).collect()))
+            .collect();
+        Self {
+            original,
+            spilled: Spilled {
+                ctx: original.fork(),
+                origins,
+                spills: vec![],
+            },
+        }
+    }
+
+    /// The program with what's been spilled so far
+    pub(crate) fn ctx(&self) -> &Context {
+        &self.spilled.ctx
+    }
+
+    /// The `Alloca`s added for stack slots, which are read and written
+    /// directly rather than through a register holding their address
+    pub(crate) fn slots(&self) -> BTreeSet<RegisterIndex> {
+        self.spilled.spills.iter().map(|s| s.slot).collect()
+    }
+
+    /// Whether `reg` can be spilled.  It has to be a register of the original
+    /// program that isn't spilled yet and fits in a machine register.
+    /// Pointers to stack slots and constants aren't, since the type of what
+    /// they point to would be lost, and neither are values phis take along
+    /// edges leaving the middle of a block, which have nowhere to be loaded.
+    pub(crate) fn can_spill(&self, reg: RegisterIndex) -> bool {
+        let fits = self
+            .original
+            .register_types()
+            .get(&reg)
+            .is_some_and(|t| t.size() <= 8);
+        let bbm = &self.spilled.ctx.basic_blocks;
+        let edges_ok = bbm.iterate_basic_blocks().all(|(idx, bb)| {
+            bbm.iterate_basic_blocks().all(|(pred, pred_bb)| {
+                if phi_values_from(bb, pred).all(|r| r != reg) {
+                    return true;
+                }
+                let code = pred_bb.iterate_instructions().collect::<Vec<_>>();
+                let body = match code.split_last() {
+                    Some((last, body)) if last.is_terminator() => body,
+                    Some(_) => &code[..],
+                    None => return false,
+                };
+                !body.iter().any(|inst| inst.branch_targets().contains(&idx))
+            })
+        });
+        fits && edges_ok
+            && !self
+                .original
+                .basic_blocks
+                .pointee_types()
+                .contains_key(&reg)
+            && !self.spilled.spills.iter().any(|s| s.register == reg)
+    }
+
+    /// Store `reg` to a new stack slot and load it back before every use
+    pub(crate) fn spill(&mut self, reg: RegisterIndex) {
+        let _type = self.original.register_types()[&reg];
+        let bbm = &self.spilled.ctx.basic_blocks;
+        let slot = bbm.new_register();
+        let mut insertions: BTreeMap<BasicBlockIndex, Vec<Insertion>> = BTreeMap::new();
+        let mut renames = vec![];
+        let mut phi_renames = vec![];
+        let mut reloads = vec![];
+        let load = |dest_register| IR::Load {
+            dest_register,
+            src_register: Value::Register(slot),
+            extend_to: None,
+        };
+
+        let entry = bbm.start;
+        insertions.entry(entry).or_default().push(Insertion {
+            position: leading_definitions(bbm.get(entry).unwrap()),
+            order: 0,
+            inst: IR::Alloca {
+                dest_register: slot,
+                _type,
+                alignment: _type.size() as u8,
+            },
+            belongs_before: true,
+        });
+        for (idx, bb) in bbm.iterate_basic_blocks() {
+            let code = bb.iterate_instructions().cloned().collect::<Vec<_>>();
+            let leading = leading_definitions(bb);
+            for (index, inst) in code.iter().enumerate() {
+                if inst.get_defined_registers().contains(&&reg) {
+                    insertions.entry(idx).or_default().push(Insertion {
+                        position: (index + 1).max(leading),
+                        order: 1,
+                        inst: IR::Store {
+                            dest_register: Value::Register(slot),
+                            src_register: Value::Register(reg),
+                        },
+                        belongs_before: true,
+                    });
+                }
+                if reads(inst, reg) {
+                    let reload = bbm.new_register();
+                    renames.push((idx, index, reload));
+                    insertions.entry(idx).or_default().push(Insertion {
+                        position: load_position(&code, index, reg),
+                        order: 2,
+                        inst: load(reload),
+                        belongs_before: false,
+                    });
+                }
+            }
+            // what the phis of the successors take from here is loaded at
+            // the end
+            for succ in bbm.successors(idx) {
+                let succ_bb = bbm.get(succ).unwrap();
+                for (index, inst) in succ_bb.iterate_instructions().enumerate() {
+                    let takes_reg = match inst {
+                        IR::Phi { incoming, .. } => incoming.iter().any(|(b, v)| {
+                            *b == idx && matches!(v, Value::Register(r) if *r == reg)
+                        }),
+                        _ => false,
+                    };
+                    if !takes_reg {
+                        continue;
+                    }
+                    let end = match code.last() {
+                        Some(last) if last.is_terminator() => code.len() - 1,
+                        _ => code.len(),
+                    };
+                    let reload = bbm.new_register();
+                    phi_renames.push((succ, index, idx, reload));
+                    insertions.entry(idx).or_default().push(Insertion {
+                        position: load_position(&code, end, reg),
+                        order: 2,
+                        inst: load(reload),
+                        belongs_before: end == code.len(),
+                    });
+                }
+            }
+        }
+
+        let bbm = &mut self.spilled.ctx.basic_blocks;
+        for (block, index, reload) in renames {
+            bbm.get_mut(block).unwrap().instructions_mut()[index].replace_uses(reg, reload);
+        }
+        for (block, index, pred, reload) in phi_renames {
+            if let IR::Phi { incoming, .. } =
+                &mut bbm.get_mut(block).unwrap().instructions_mut()[index]
+            {
+                for (_, v) in incoming.iter_mut().filter(|(b, _)| *b == pred) {
+                    *v = Value::Register(reload);
+                }
+            }
+        }
+        for (block, mut insertions) in insertions {
+            insertions.sort_by_key(|insertion| (insertion.position, insertion.order));
+            let origins = self.spilled.origins.get_mut(&block).unwrap();
+            let code = bbm.get_mut(block).unwrap().instructions_mut();
+            let old_code = std::mem::take(code);
+            let old_origins = std::mem::take(origins);
+            let mut insertions = insertions.into_iter().peekable();
+            for position in 0..=old_code.len() {
+                while let Some(insertion) = insertions.next_if(|i| i.position == position) {
+                    let before = position.checked_sub(1).and_then(|p| old_origins.get(p));
+                    let after = old_origins.get(position);
+                    let origin = if insertion.belongs_before {
+                        before.or(after)
+                    } else {
+                        after.or(before)
+                    };
+                    let origin = origin.copied().unwrap_or(0);
+                    if matches!(insertion.inst, IR::Load { .. }) {
+                        reloads.push((block, origin));
+                    }
+                    code.push(insertion.inst);
+                    origins.push(origin);
+                }
+                if let Some(inst) = old_code.get(position) {
+                    code.push(inst.clone());
+                    origins.push(old_origins[position]);
+                }
+            }
+        }
+        self.spilled.ctx.register_types = self.spilled.ctx.basic_blocks.compute_register_types();
+        reloads.sort_unstable();
+        self.spilled.spills.push(Spill {
+            register: reg,
+            slot,
+            reloads,
+        });
+    }
+
+    /// Share stack slots between spilled registers of the same type that are
+    /// never live at the same time, and hand over the result
+    pub(crate) fn finish(mut self) -> Spilled {
+        let bbm = &self.original.basic_blocks;
+        let gq = GraphQuery::new(compute_graph(bbm), bbm);
+        let types = self.original.register_types();
+        // the first register in each slot, and the others sharing it
+        let mut shared: Vec<(RegisterIndex, Vec<RegisterIndex>)> = vec![];
+        let mut replaced: BTreeMap<RegisterIndex, RegisterIndex> = BTreeMap::new();
+        for spill in &mut self.spilled.spills {
+            let reg = spill.register;
+            let slot = shared.iter_mut().find(|(_, regs)| {
+                types[&regs[0]] == types[&reg]
+                    && regs.iter().all(|other| !interfere(bbm, &gq, *other, reg))
+            });
+            match slot {
+                Some((slot, regs)) => {
+                    regs.push(reg);
+                    replaced.insert(spill.slot, *slot);
+                    spill.slot = *slot;
+                }
+                None => shared.push((spill.slot, vec![reg])),
+            }
+        }
+
+        let ctx = &mut self.spilled.ctx;
+        for (idx, origins) in &mut self.spilled.origins {
+            let code = ctx.basic_blocks.get_mut(*idx).unwrap().instructions_mut();
+            let mut index = 0;
+            code.retain(|inst| {
+                let keep = !matches!(inst, IR::Alloca { dest_register, .. } if replaced.contains_key(dest_register));
+                if !keep {
+                    origins.remove(index);
+                } else {
+                    index += 1;
+                }
+                keep
+            });
+            for inst in code {
+                match inst {
+                    IR::Load {
+                        src_register: Value::Register(r),
+                        ..
+                    }
+                    | IR::Store {
+                        dest_register: Value::Register(r),
+                        ..
+                    } => {
+                        if let Some(slot) = replaced.get(r) {
+                            *r = *slot;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        ctx.register_types = ctx.basic_blocks.compute_register_types();
+        self.spilled
+    }
+}
+
+/// Where `reg` gets its value in `bbm`: the block, and the index of the last
+/// instruction that has to run first.  Phis and parameters all get theirs
+/// once the last of them has.
+fn definition(bbm: &BasicBlockManager, reg: RegisterIndex) -> (BasicBlockIndex, usize) {
+    bbm.iterate_basic_blocks()
+        .find_map(|(idx, bb)| {
+            let index = bb
+                .iterate_instructions()
+                .position(|inst| inst.get_defined_registers().contains(&&reg))?;
+            Some((idx, index.max(leading_definitions(bb).saturating_sub(1))))
+        })
+        .unwrap()
+}
+
+/// Whether `reg` is still needed after instruction `index` of `block`
+fn live_after(
+    bbm: &BasicBlockManager,
+    gq: &GraphQuery,
+    reg: RegisterIndex,
+    block: BasicBlockIndex,
+    index: usize,
+) -> bool {
+    let (def_block, def_index) = definition(bbm, reg);
+    let defined = (def_block == block && def_index <= index) || gq.is_live_in(reg, block);
+    defined && (next_use(bbm, reg, block, index).is_some() || gq.is_live_out(reg, block))
+}
+
+/// Whether `a` and `b` need their values at the same time, so they can't
+/// share a slot: one of them is still needed when the other is defined
+fn interfere(bbm: &BasicBlockManager, gq: &GraphQuery, a: RegisterIndex, b: RegisterIndex) -> bool {
+    let (a_block, a_index) = definition(bbm, a);
+    let (b_block, b_index) = definition(bbm, b);
+    live_after(bbm, gq, a, b_block, b_index) || live_after(bbm, gq, b, a_block, a_index)
+}
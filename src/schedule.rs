@@ -0,0 +1,408 @@
+//! Instruction scheduling.
+//!
+//! A simple list scheduler over the dependency DAG of a single basic block.
+//! Instructions are issued in order of readiness, preferring the ones on the
+//! longest latency path to the end of the block, so that independent work
+//! can fill in the gaps while slow instructions (like loads) complete.
+//!
+//! Memory ordering is preserved: stores are never reordered with other
+//! memory operations, and anything that calls out of the generated code is
+//! treated as reading and writing memory, as is anything that may fault and
+//! leave the block, like dividing by a register.  Phis stay at the top of the block
+//! and terminators stay at the end, along with a comparison they branch on so
+//! the two can still be fused.
+
+use crate::ir::*;
+use std::collections::*;
+
+/// Rough latency, in cycles, before the result of an instruction is available
+fn latency(inst: &IR) -> u32 {
+    match inst {
+        IR::Load { .. } | IR::MemLoad { .. } => 4,
+        IR::Multiply { .. } => 3,
+        IR::Divide { .. } | IR::Remainder { .. } => 20,
+        _ => 1,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MemoryEffect {
+    None,
+    Read,
+    Write,
+}
+
+pub(crate) fn memory_effect(inst: &IR) -> MemoryEffect {
+    match inst {
+        IR::Load { .. } | IR::MemLoad { .. } => MemoryEffect::Read,
+        // the host function may do anything
+        IR::Store { .. }
+        | IR::MemStore { .. }
+        | IR::PrintConstant { .. }
+        | IR::PrintInt { .. }
+        | IR::Assert { .. }
+        | IR::Call { .. } => MemoryEffect::Write,
+        // may leave the block, so side effects can't move across it
+        IR::TruncateChecked { .. }
+        | IR::Add {
+            overflow: Overflow::Trap(_),
+            ..
+        }
+        | IR::Subtract {
+            overflow: Overflow::Trap(_),
+            ..
+        }
+        | IR::Multiply {
+            overflow: Overflow::Trap(_),
+            ..
+        } => MemoryEffect::Write,
+        // dividing by zero, or the minimum by -1, faults
+        IR::Divide { src2, .. } | IR::Remainder { src2, .. } if !is_safe_divisor(*src2) => {
+            MemoryEffect::Write
+        }
+        // nothing is known about what it does, and it may rely on pinned
+        // registers being set
+        IR::InlineBytes { .. } | IR::Pin { .. } => MemoryEffect::Write,
+        _ => MemoryEffect::None,
+    }
+}
+
+/// Whether dividing by `v` can never fault: a nonzero immediate, which for
+/// signed types must also be positive to rule out -1
+fn is_safe_divisor(v: Value) -> bool {
+    match v {
+        Value::Immediate { _type, value } if _type.is_signed() => {
+            let (_, max) = _type.range();
+            (1..=max).contains(&(value as isize as i128))
+        }
+        Value::Immediate { value, .. } => value != 0,
+        Value::Register(_) => false,
+    }
+}
+
+/// The comparison `code` ends by branching on, if nothing else in the block
+/// uses its result
+fn branch_comparison(code: &[IR]) -> Option<usize> {
+    let cond = match code.last()? {
+        IR::JumpIfEqual {
+            src_register: Value::Register(r),
+            ..
+        } => r,
+        _ => return None,
+    };
+    let def = code.iter().position(|inst| match inst {
+        IR::Compare { dest_register, .. } => dest_register == cond,
+        _ => false,
+    })?;
+    let other_uses = code[..code.len() - 1]
+        .iter()
+        .any(|inst| inst.get_used_registers().contains(&cond));
+    if other_uses {
+        None
+    } else {
+        Some(def)
+    }
+}
+
+/// For each instruction, the indices of the instructions it depends on
+fn build_dependencies(code: &[IR]) -> Vec<BTreeSet<usize>> {
+    let mut deps: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); code.len()];
+    let mut definitions: BTreeMap<RegisterIndex, usize> = BTreeMap::new();
+    let mut last_write: Option<usize> = None;
+    let mut reads_since_last_write: Vec<usize> = vec![];
+    let mut phis: Vec<usize> = vec![];
+
+    for (i, inst) in code.iter().enumerate() {
+        // phis take their values on the way into the block, so they all come
+        // first, in the order they were in
+        deps[i].extend(phis.iter().copied());
+        if let IR::Phi { .. } = inst {
+            phis.push(i);
+            continue;
+        }
+        for used in inst.get_used_registers() {
+            // registers defined in other blocks are already available
+            if let Some(def) = definitions.get(used) {
+                deps[i].insert(*def);
+            }
+        }
+        match memory_effect(inst) {
+            MemoryEffect::None => (),
+            MemoryEffect::Read => {
+                deps[i].extend(last_write);
+                reads_since_last_write.push(i);
+            }
+            MemoryEffect::Write => {
+                deps[i].extend(last_write);
+                deps[i].extend(reads_since_last_write.drain(..));
+                last_write = Some(i);
+            }
+        }
+        if inst.is_terminator() {
+            deps[i].extend(0..i);
+        }
+        for def in inst.get_defined_registers() {
+            definitions.insert(*def, i);
+        }
+    }
+
+    deps
+}
+
+/// Compute the order to issue the instructions of `code` in
+pub fn compute_schedule(code: &[IR]) -> Vec<usize> {
+    let deps = build_dependencies(code);
+    let mut users: Vec<Vec<usize>> = vec![vec![]; code.len()];
+    for (i, ds) in deps.iter().enumerate() {
+        for d in ds {
+            users[*d].push(i);
+        }
+    }
+
+    // priority is the longest latency path from the instruction to the end of
+    // the block; dependencies always point backwards so one reverse pass works
+    let mut priority = vec![0; code.len()];
+    for i in (0..code.len()).rev() {
+        let longest_user = users[i].iter().map(|u| priority[*u]).max().unwrap_or(0);
+        priority[i] = latency(&code[i]) + longest_user;
+    }
+
+    let mut remaining_deps: Vec<usize> = deps.iter().map(|d| d.len()).collect();
+    // the cycle each instruction's operands are available on
+    let mut earliest_start = vec![0; code.len()];
+    let mut ready: BTreeSet<usize> = (0..code.len())
+        .filter(|i| remaining_deps[*i] == 0)
+        .collect();
+    let mut cycle = 0;
+    let mut out = Vec::with_capacity(code.len());
+
+    while !ready.is_empty() {
+        // prefer what can issue soonest, then the critical path, then program order
+        let next = *ready
+            .iter()
+            .min_by_key(|i| {
+                (
+                    earliest_start[**i].max(cycle),
+                    std::cmp::Reverse(priority[**i]),
+                    **i,
+                )
+            })
+            .unwrap();
+        ready.remove(&next);
+        let issued_at = earliest_start[next].max(cycle);
+        cycle = issued_at + 1;
+        out.push(next);
+
+        for user in &users[next] {
+            earliest_start[*user] = earliest_start[*user].max(issued_at + latency(&code[next]));
+            remaining_deps[*user] -= 1;
+            if remaining_deps[*user] == 0 {
+                ready.insert(*user);
+            }
+        }
+    }
+    assert_eq!(out.len(), code.len());
+
+    // nothing else in the block uses the comparison the block branches on,
+    // so it can wait until right before the branch and be fused with it
+    if let Some(comparison) = branch_comparison(code) {
+        out.retain(|i| *i != comparison);
+        out.insert(out.len() - 1, comparison);
+    }
+
+    out
+}
+
+/// Reorder the instructions of the basic block in place
+pub fn schedule_basic_block(block: &mut BasicBlock) {
+    let code = block.instructions_mut();
+    let order = compute_schedule(code);
+    let mut slots: Vec<Option<IR>> = code.drain(..).map(Some).collect();
+    code.extend(order.into_iter().map(|i| slots[i].take().unwrap()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn register(v: Value) -> RegisterIndex {
+        match v {
+            Value::Register(r) => r,
+            _ => panic!("{:?} isn't a register", v),
+        }
+    }
+
+    /// Where the instruction defining `v` was scheduled in `block`
+    fn position(ctx: &Context, block: BasicBlockIndex, v: Value) -> usize {
+        let r = register(v);
+        ctx.basic_blocks
+            .get(block)
+            .unwrap()
+            .iterate_instructions()
+            .position(|inst| inst.get_defined_registers().contains(&&r))
+            .unwrap()
+    }
+
+    /// Where the first instruction matching `f` was scheduled in `block`
+    fn position_of(ctx: &Context, block: BasicBlockIndex, f: impl Fn(&IR) -> bool) -> usize {
+        ctx.basic_blocks
+            .get(block)
+            .unwrap()
+            .iterate_instructions()
+            .position(f)
+            .unwrap()
+    }
+
+    /// A context whose entry block takes two `U64` parameters and goes to a
+    /// second block, which is returned to be built
+    fn two_parameters() -> (Context, BasicBlockIndex, BasicBlockIndex, Value, Value) {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let body = ctx.new_basic_block();
+        let x = ctx.add_parameter(PrimitiveValue::U64);
+        let y = ctx.add_parameter(PrimitiveValue::U64);
+        ctx.build_basic_block(entry).jump(body);
+        (ctx, entry, body, x, y)
+    }
+
+    #[test]
+    fn independent_adds_are_interleaved() {
+        let (mut ctx, _, body, x, y) = two_parameters();
+        let bb = ctx.build_basic_block(body);
+        let x1 = bb.add(x, Value::u64(1));
+        let x2 = bb.add(x1, Value::u64(1));
+        let y1 = bb.add(y, Value::u64(1));
+        let y2 = bb.add(y1, Value::u64(1));
+        let sum = bb.add(x2, y2);
+        bb.ret_value(sum);
+        schedule_basic_block(ctx.build_basic_block(body));
+
+        let order = [x1, y1, x2, y2, sum]
+            .iter()
+            .map(|v| position(&ctx, body, *v))
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn loads_stay_after_stores_to_the_same_memory() {
+        let (mut ctx, _, body, x, y) = two_parameters();
+        let bb = ctx.build_basic_block(body);
+        let slot = bb.alloca(PrimitiveValue::U64, 8);
+        let value = bb.multiply(x, y);
+        bb.store(slot, value);
+        // loads are slow, so it'd go first if it could
+        let loaded = bb.load(slot);
+        let result = bb.add(loaded, x);
+        bb.ret_value(result);
+        schedule_basic_block(ctx.build_basic_block(body));
+
+        let store = position_of(&ctx, body, |inst| matches!(inst, IR::Store { .. }));
+        assert!(position(&ctx, body, value) < store);
+        assert!(store < position(&ctx, body, loaded));
+    }
+
+    #[test]
+    fn stores_stay_after_loads_of_the_same_memory() {
+        let (mut ctx, _, body, x, _) = two_parameters();
+        let bb = ctx.build_basic_block(body);
+        let slot = bb.alloca(PrimitiveValue::U64, 8);
+        let loaded = bb.load(slot);
+        bb.store(slot, x);
+        let result = bb.add(loaded, x);
+        bb.ret_value(result);
+        schedule_basic_block(ctx.build_basic_block(body));
+
+        let store = position_of(&ctx, body, |inst| matches!(inst, IR::Store { .. }));
+        assert!(position(&ctx, body, loaded) < store);
+    }
+
+    #[test]
+    fn divides_that_may_fault_stay_after_earlier_side_effects() {
+        let (mut ctx, _, body, x, y) = two_parameters();
+        let bb = ctx.build_basic_block(body);
+        bb.print_int(x, PrimitiveValue::U64);
+        // divides are slow, so these would go first if they could
+        let quotient = bb.divide(x, y);
+        let remainder = bb.remainder(x, y);
+        let result = bb.add(quotient, remainder);
+        bb.ret_value(result);
+        schedule_basic_block(ctx.build_basic_block(body));
+
+        let print = position_of(&ctx, body, |inst| matches!(inst, IR::PrintInt { .. }));
+        assert!(print < position(&ctx, body, quotient));
+        assert!(print < position(&ctx, body, remainder));
+    }
+
+    #[test]
+    fn divides_by_safe_constants_move_freely() {
+        let (mut ctx, _, body, x, _) = two_parameters();
+        let bb = ctx.build_basic_block(body);
+        bb.print_int(x, PrimitiveValue::U64);
+        let quotient = bb.divide(x, Value::u64(3));
+        bb.ret_value(quotient);
+        schedule_basic_block(ctx.build_basic_block(body));
+
+        assert_eq!(position(&ctx, body, quotient), 0);
+    }
+
+    #[test]
+    fn only_positive_signed_divisors_are_safe() {
+        assert!(is_safe_divisor(Value::i64(7)));
+        assert!(!is_safe_divisor(Value::i64(-1)));
+        assert!(!is_safe_divisor(Value::i8(0)));
+        assert!(!is_safe_divisor(Value::Immediate {
+            _type: PrimitiveValue::I128,
+            value: usize::MAX,
+        }));
+        assert!(is_safe_divisor(Value::u8(255)));
+        assert!(!is_safe_divisor(Value::u64(0)));
+    }
+
+    #[test]
+    fn phis_stay_first() {
+        let (mut ctx, entry, body, x, y) = two_parameters();
+        let constant = ctx.add_u64_constant(7);
+        let bb = ctx.build_basic_block(body);
+        // there's no builder for phis, so turn a copy into one
+        let phi = bb.copy(x);
+        let incoming = vec![(entry, x), (body, y)];
+        *bb.instructions_mut().last_mut().unwrap() = IR::Phi {
+            dest_register: register(phi),
+            incoming,
+        };
+        // nothing here depends on the phi, and loads go first when they can
+        let address = bb.constant_addr(constant);
+        let loaded = bb.load(address);
+        let result = bb.add(loaded, phi);
+        bb.ret_value(result);
+        schedule_basic_block(ctx.build_basic_block(body));
+
+        assert_eq!(position(&ctx, body, phi), 0);
+    }
+
+    #[test]
+    fn comparison_stays_before_the_branch_on_it() {
+        let (mut ctx, _, body, x, y) = two_parameters();
+        let then = ctx.new_basic_block();
+        let otherwise = ctx.new_basic_block();
+        let bb = ctx.build_basic_block(body);
+        let slot = bb.alloca(PrimitiveValue::U64, 8);
+        let cond = bb.compare(Comparison::Less, x, y);
+        let loaded = bb.load(slot);
+        let sum = bb.add(loaded, x);
+        bb.store(slot, sum);
+        bb.jump_if_equal(cond, then, otherwise);
+        ctx.build_basic_block(then).ret();
+        ctx.build_basic_block(otherwise).ret();
+        schedule_basic_block(ctx.build_basic_block(body));
+
+        let len = ctx
+            .basic_blocks
+            .get(body)
+            .unwrap()
+            .iterate_instructions()
+            .count();
+        assert_eq!(position(&ctx, body, cond), len - 2);
+    }
+}
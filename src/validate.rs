@@ -0,0 +1,621 @@
+//! Checks for mistakes in the IR that would otherwise only show up as
+//! misbehaving generated code.
+
+use crate::ir::*;
+use crate::reg_alloc;
+use petgraph::{
+    algo::tarjan_scc,
+    visit::{depth_first_search, DfsEvent, Reversed},
+};
+use std::collections::*;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// A loop in the CFG that can never reach a `Return`
+    InfiniteLoop(Vec<BasicBlockIndex>),
+    /// The operands of an instruction have different types
+    TypeMismatch {
+        block: BasicBlockIndex,
+        index: usize,
+        left: PrimitiveValue,
+        right: PrimitiveValue,
+    },
+    /// A conditional jump with the same block for both targets, which should
+    /// be an unconditional `Jump` instead
+    DegenerateConditionalJump {
+        block: BasicBlockIndex,
+        index: usize,
+        target: BasicBlockIndex,
+    },
+    /// A block (possibly empty) that doesn't end in a jump, return, or trap,
+    /// so control would run off its end into whatever code follows.  Blocks
+    /// that only forward to another block need an explicit `Jump`.
+    UnterminatedBlock(BasicBlockIndex),
+    /// A block that was created but never built, and isn't marked with
+    /// [`BasicBlock::set_placeholder`]; see [`Context::unbuilt_blocks`].
+    /// It's reported instead of being an `UnterminatedBlock`.
+    UnbuiltBlock(BasicBlockIndex),
+    /// A jump to a block that isn't in the `Context`, likely one made by a
+    /// different `Context`
+    UnknownBlock {
+        block: BasicBlockIndex,
+        index: usize,
+        target: BasicBlockIndex,
+    },
+    /// A `Load` or `Store` through a register that doesn't hold a pointer:
+    /// one that isn't the result of an `Alloca`, a 64 bit parameter, or a
+    /// copy or phi of one
+    NotAPointer {
+        block: BasicBlockIndex,
+        index: usize,
+        register: RegisterIndex,
+    },
+    /// A register read where it may not have been defined yet: before its
+    /// definition in the same block, in a block its definition doesn't
+    /// dominate, or without being defined anywhere
+    UseBeforeDefinition {
+        block: BasicBlockIndex,
+        index: usize,
+        register: RegisterIndex,
+    },
+    /// An immediate whose value doesn't fit in its type; see
+    /// [`PrimitiveValue::fits`]
+    ImmediateOutOfRange {
+        block: BasicBlockIndex,
+        index: usize,
+        _type: PrimitiveValue,
+        value: usize,
+    },
+}
+
+/// Run all of the checks, returning every problem found
+pub fn validate(ctx: &Context) -> Result<(), Vec<ValidationError>> {
+    let mut errors = vec![];
+    check_infinite_loops(ctx.basic_blocks(), &mut errors);
+    check_operand_types(ctx, &mut errors);
+    check_conditional_jumps(ctx, &mut errors);
+    check_terminators(ctx, &mut errors);
+    check_branch_targets(ctx, &mut errors);
+    check_pointer_operands(ctx, &mut errors);
+    check_uses_defined(ctx, &mut errors);
+    check_immediates(ctx, &mut errors);
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Finds strongly connected components of the CFG with no way out to a
+/// `Return` or `Trap`, i.e. loops that will run forever.
+fn check_infinite_loops(bbm: &BasicBlockManager, errors: &mut Vec<ValidationError>) {
+    let gd = reg_alloc::compute_graph(bbm);
+
+    let returning_nodes = bbm
+        .iterate_basic_blocks()
+        .filter(|(_, bb)| {
+            bb.iterate_instructions().any(|i| {
+                matches!(
+                    i,
+                    IR::Return | IR::ReturnValue { .. } | IR::ReturnStruct { .. } | IR::Trap { .. }
+                )
+            })
+        })
+        .map(|(idx, _)| gd.index_map[&idx]);
+    // walk the edges backwards from the returns to find everything that can get to one
+    let mut can_return = BTreeSet::new();
+    depth_first_search(Reversed(&gd.graph), returning_nodes, |event| {
+        if let DfsEvent::Discover(n, _) = event {
+            can_return.insert(n);
+        }
+    });
+
+    for scc in tarjan_scc(&gd.graph) {
+        let is_loop = scc.len() > 1 || gd.graph.contains_edge(scc[0], scc[0]);
+        if is_loop && !scc.iter().any(|n| can_return.contains(n)) {
+            let mut blocks = scc.iter().map(|n| gd.graph[*n]).collect::<Vec<_>>();
+            blocks.sort();
+            errors.push(ValidationError::InfiniteLoop(blocks));
+        }
+    }
+}
+
+/// The types of the operands of `inst` if they disagree
+pub fn operand_type_mismatch(
+    ctx: &Context,
+    inst: &IR,
+) -> Option<(PrimitiveValue, PrimitiveValue)> {
+    match inst {
+        IR::Add { src1, src2, .. }
+        | IR::Subtract { src1, src2, .. }
+        | IR::Multiply { src1, src2, .. }
+        | IR::Divide { src1, src2, .. }
+        | IR::Remainder { src1, src2, .. }
+        | IR::Compare { src1, src2, .. } => {
+            match (ctx.value_type(*src1), ctx.value_type(*src2)) {
+                (Some(left), Some(right)) if left != right => Some((left, right)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn check_operand_types(ctx: &Context, errors: &mut Vec<ValidationError>) {
+    for (block, index, inst) in ctx.iter_instructions() {
+        if let Some((left, right)) = operand_type_mismatch(ctx, inst) {
+            errors.push(ValidationError::TypeMismatch {
+                block,
+                index,
+                left,
+                right,
+            });
+        }
+    }
+}
+
+fn check_immediates(ctx: &Context, errors: &mut Vec<ValidationError>) {
+    for (block, index, inst) in ctx.iter_instructions() {
+        for v in inst.get_used_values() {
+            match v {
+                Value::Immediate { _type, value } if !_type.fits(value) => {
+                    errors.push(ValidationError::ImmediateOutOfRange {
+                        block,
+                        index,
+                        _type,
+                        value,
+                    });
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+fn check_conditional_jumps(ctx: &Context, errors: &mut Vec<ValidationError>) {
+    for (block, index, inst) in ctx.iter_instructions() {
+        match inst {
+            IR::JumpIfEqual {
+                true_bb_idx,
+                false_bb_idx,
+                ..
+            }
+            | IR::JumpIfNotEqual {
+                true_bb_idx,
+                false_bb_idx,
+                ..
+            } if true_bb_idx == false_bb_idx => {
+                errors.push(ValidationError::DegenerateConditionalJump {
+                    block,
+                    index,
+                    target: *true_bb_idx,
+                });
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Placeholders are left alone while they're empty or nothing reaches them,
+/// since they're waiting to be filled in
+fn check_terminators(ctx: &Context, errors: &mut Vec<ValidationError>) {
+    let unbuilt = ctx.unbuilt_blocks();
+    let reachable = reachable_blocks(&reg_alloc::compute_graph(ctx.basic_blocks()));
+    for (block, bb) in ctx.iterate_basic_blocks() {
+        let waiting = bb.iterate_instructions().next().is_none() || !reachable.contains(&block);
+        if bb.is_placeholder() && waiting {
+            continue;
+        }
+        if unbuilt.contains(&block) {
+            errors.push(ValidationError::UnbuiltBlock(block));
+        } else if !bb.is_terminated() {
+            errors.push(ValidationError::UnterminatedBlock(block));
+        }
+    }
+}
+
+fn check_branch_targets(ctx: &Context, errors: &mut Vec<ValidationError>) {
+    for (block, index, inst) in ctx.iter_instructions() {
+        for target in inst.branch_targets() {
+            if ctx.basic_blocks().get(target).is_none() {
+                errors.push(ValidationError::UnknownBlock {
+                    block,
+                    index,
+                    target,
+                });
+            }
+        }
+    }
+}
+
+/// The registers holding pointers.  Copies and phis start out assumed to be
+/// pointers, so loops passing a pointer around are found, and are ruled out
+/// if anything but a pointer goes into them.
+fn find_pointers(ctx: &Context) -> BTreeSet<RegisterIndex> {
+    let mut pointers: BTreeSet<RegisterIndex> = ctx
+        .iter_instructions()
+        .filter_map(|(_, _, inst)| match inst {
+            IR::Alloca { dest_register, .. }
+            | IR::ConstantAddr { dest_register, .. }
+            | IR::Parameter {
+                dest_register,
+                _type: PrimitiveValue::U64,
+                ..
+            }
+            | IR::Copy { dest_register, .. }
+            | IR::Phi { dest_register, .. } => Some(*dest_register),
+            _ => None,
+        })
+        .collect();
+    loop {
+        let mut changed = false;
+        for (_, _, inst) in ctx.iter_instructions() {
+            let (dest, sources) = match inst {
+                IR::Copy { dest_register, src } => (dest_register, vec![src]),
+                IR::Phi {
+                    dest_register,
+                    incoming,
+                } => (dest_register, incoming.iter().map(|(_, v)| v).collect()),
+                _ => continue,
+            };
+            let is_pointer = sources
+                .into_iter()
+                .all(|v| matches!(v, Value::Register(r) if pointers.contains(r)));
+            if !is_pointer && pointers.remove(dest) {
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    pointers
+}
+
+fn check_pointer_operands(ctx: &Context, errors: &mut Vec<ValidationError>) {
+    let pointers = find_pointers(ctx);
+    for (block, index, inst) in ctx.iter_instructions() {
+        let base = match inst {
+            IR::Load {
+                src_register: Value::Register(r),
+                ..
+            }
+            | IR::Store {
+                dest_register: Value::Register(r),
+                ..
+            } => r,
+            _ => continue,
+        };
+        if !pointers.contains(base) {
+            errors.push(ValidationError::NotAPointer {
+                block,
+                index,
+                register: *base,
+            });
+        }
+    }
+}
+
+/// The blocks control can reach from the entry
+fn reachable_blocks(gd: &reg_alloc::GraphData) -> BTreeSet<BasicBlockIndex> {
+    gd.index_map
+        .iter()
+        .filter(|(_, ni)| gd.depth_map.contains_key(ni))
+        .map(|(idx, _)| *idx)
+        .collect()
+}
+
+/// Every register read outside of a phi must be defined earlier in the
+/// block or be live coming into it.  Phis read on the edges into their block,
+/// and unreachable blocks never run, so those are skipped.
+fn check_uses_defined(ctx: &Context, errors: &mut Vec<ValidationError>) {
+    let bbm = ctx.basic_blocks();
+    let mut definitions: BTreeMap<RegisterIndex, (BasicBlockIndex, usize)> = BTreeMap::new();
+    let mut defined_once = true;
+    for (block, index, inst) in ctx.iter_instructions() {
+        for r in inst.get_defined_registers() {
+            defined_once &= definitions.insert(*r, (block, index)).is_none();
+        }
+    }
+    let gd = reg_alloc::compute_graph(bbm);
+    let reachable = reachable_blocks(&gd);
+    // the liveness queries need every register to be defined once
+    let gq = if defined_once {
+        Some(reg_alloc::GraphQuery::new(gd, bbm))
+    } else {
+        None
+    };
+
+    for (block, index, inst) in ctx.iter_instructions() {
+        if matches!(inst, IR::Phi { .. }) || !reachable.contains(&block) {
+            continue;
+        }
+        let defined = inst.get_defined_registers();
+        let mut used = inst.get_used_registers();
+        // `Load` lists its destination as used too
+        used.retain(|r| !defined.contains(r));
+        used.dedup();
+        for r in used {
+            let is_defined = match definitions.get(r) {
+                Some((def_block, def_index)) if *def_block == block => *def_index < index,
+                Some(_) => gq.as_ref().is_none_or(|gq| gq.is_live_in(*r, block)),
+                None => false,
+            };
+            if !is_defined {
+                errors.push(ValidationError::UseBeforeDefinition {
+                    block,
+                    index,
+                    register: *r,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(ctx: &mut Context) -> Vec<ValidationError> {
+        ctx.finalize();
+        ctx.validate().err().unwrap_or_default()
+    }
+
+    #[test]
+    fn two_block_infinite_loop() {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let a = ctx.new_basic_block();
+        let b = ctx.new_basic_block();
+        ctx.build_basic_block(entry).jump(a);
+        ctx.build_basic_block(a).jump(b);
+        ctx.build_basic_block(b).jump(a);
+
+        assert_eq!(
+            errors(&mut ctx),
+            vec![ValidationError::InfiniteLoop(vec![a, b])]
+        );
+    }
+
+    #[test]
+    fn loop_with_an_exit_is_fine() {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let x = ctx.add_parameter(PrimitiveValue::U64);
+        let a = ctx.new_basic_block();
+        let b = ctx.new_basic_block();
+        let exit = ctx.new_basic_block();
+        ctx.build_basic_block(entry).jump(a);
+        ctx.build_basic_block(a).jump(b);
+        ctx.build_basic_block(b).jump_if_equal(x, exit, a);
+        ctx.build_basic_block(exit).ret();
+
+        assert_eq!(errors(&mut ctx), vec![]);
+    }
+
+    #[test]
+    fn adding_u32_and_u64_is_a_type_mismatch() {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let x = ctx.add_parameter(PrimitiveValue::U32);
+        let y = ctx.add_parameter(PrimitiveValue::U64);
+        let bb = ctx.build_basic_block(entry);
+        let sum = bb.add(x, y);
+        bb.ret_value(sum);
+
+        assert_eq!(
+            errors(&mut ctx),
+            vec![ValidationError::TypeMismatch {
+                block: entry,
+                index: 2,
+                left: PrimitiveValue::U32,
+                right: PrimitiveValue::U64,
+            }]
+        );
+    }
+
+    #[test]
+    fn registers_take_the_type_of_their_definition() {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let x = ctx.add_parameter(PrimitiveValue::U16);
+        let ptr = ctx.add_parameter(PrimitiveValue::U64);
+        let bb = ctx.build_basic_block(entry);
+        let sum = bb.add(x, Value::u16(1));
+        let wide = bb.load_extended(ptr, PrimitiveValue::I64);
+        bb.ret_value(sum);
+
+        assert_eq!(errors(&mut ctx), vec![]);
+        assert_eq!(ctx.value_type(x), Some(PrimitiveValue::U16));
+        assert_eq!(ctx.value_type(sum), Some(PrimitiveValue::U16));
+        assert_eq!(ctx.value_type(wide), Some(PrimitiveValue::I64));
+    }
+
+    #[test]
+    fn conditional_jump_to_the_same_block_twice() {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let x = ctx.add_parameter(PrimitiveValue::U64);
+        let next = ctx.new_basic_block();
+        ctx.build_basic_block(entry).jump_if_equal(x, next, next);
+        ctx.build_basic_block(next).ret();
+
+        assert_eq!(
+            errors(&mut ctx),
+            vec![ValidationError::DegenerateConditionalJump {
+                block: entry,
+                index: 1,
+                target: next,
+            }]
+        );
+    }
+
+    #[test]
+    fn block_falling_through_into_the_next() {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let x = ctx.add_parameter(PrimitiveValue::U64);
+        let middle = ctx.new_basic_block();
+        let exit = ctx.new_basic_block();
+        ctx.build_basic_block(entry).jump(middle);
+        ctx.build_basic_block(middle)
+            .print_int(x, PrimitiveValue::U64);
+        ctx.build_basic_block(exit).ret();
+
+        assert_eq!(
+            errors(&mut ctx),
+            vec![ValidationError::UnterminatedBlock(middle)]
+        );
+    }
+
+    #[test]
+    fn forgotten_block_between_two_others() {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let forgotten = ctx.new_basic_block();
+        let exit = ctx.new_basic_block();
+        ctx.build_basic_block(entry).jump(exit);
+        ctx.build_basic_block(exit).ret();
+        assert_eq!(ctx.unbuilt_blocks(), vec![forgotten]);
+
+        // finalizing doesn't panic; the block is reported by index
+        assert_eq!(
+            errors(&mut ctx),
+            vec![ValidationError::UnbuiltBlock(forgotten)]
+        );
+        assert_eq!(forgotten.to_string(), "bb1");
+
+        // unless it's meant to be filled in later
+        ctx.build_basic_block(forgotten).set_placeholder(true);
+        assert!(ctx.unbuilt_blocks().is_empty());
+        assert_eq!(errors(&mut ctx), vec![]);
+        ctx.build_basic_block(forgotten).jump(exit);
+        assert_eq!(errors(&mut ctx), vec![]);
+    }
+
+    #[test]
+    fn placeholders_are_checked_once_filled_in_and_reachable() {
+        // the entry goes to the placeholder or straight to the exit, and the
+        // placeholder has an add but nothing after it yet
+        let build = |reached: bool, filled_in: bool| {
+            let mut ctx = Context::new();
+            let entry = ctx.new_basic_block();
+            let placeholder = ctx.new_basic_block();
+            let exit = ctx.new_basic_block();
+            let target = if reached { placeholder } else { exit };
+            ctx.build_basic_block(entry).jump(target);
+            ctx.build_basic_block(exit).ret();
+            let bb = ctx.build_basic_block(placeholder);
+            bb.set_placeholder(true);
+            if filled_in {
+                bb.add(Value::u64(1), Value::u64(2));
+            }
+            (ctx, placeholder)
+        };
+
+        let (mut ctx, _) = build(true, false);
+        assert_eq!(errors(&mut ctx), vec![]);
+        let (mut ctx, _) = build(false, true);
+        assert_eq!(errors(&mut ctx), vec![]);
+        let (mut ctx, placeholder) = build(true, true);
+        assert_eq!(
+            errors(&mut ctx),
+            vec![ValidationError::UnterminatedBlock(placeholder)]
+        );
+    }
+
+    #[test]
+    fn loads_and_stores_through_allocas_are_fine() {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let bb = ctx.build_basic_block(entry);
+        let slot = bb.alloca(PrimitiveValue::U32, 4);
+        bb.store(slot, Value::u32(7));
+        let same_slot = bb.copy(slot);
+        let loaded = bb.load(same_slot);
+        bb.ret_value(loaded);
+
+        assert_eq!(errors(&mut ctx), vec![]);
+    }
+
+    #[test]
+    fn loading_through_the_result_of_an_add() {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let x = ctx.add_parameter(PrimitiveValue::U32);
+        let bb = ctx.build_basic_block(entry);
+        let sum = bb.add(x, Value::u32(4));
+        let loaded = bb.load(sum);
+        bb.ret_value(loaded);
+
+        let register = match sum {
+            Value::Register(r) => r,
+            Value::Immediate { .. } => unreachable!(),
+        };
+        assert_eq!(
+            errors(&mut ctx),
+            vec![ValidationError::NotAPointer {
+                block: entry,
+                index: 2,
+                register,
+            }]
+        );
+    }
+
+    #[test]
+    fn immediate_too_big_for_its_type() {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let x = ctx.add_parameter(PrimitiveValue::U8);
+        let bb = ctx.build_basic_block(entry);
+        // built by hand, since `Value::immediate` wouldn't allow it
+        let too_big = Value::Immediate {
+            _type: PrimitiveValue::U8,
+            value: 0x1FF,
+        };
+        let sum = bb.add(x, too_big);
+        let fine = bb.add(sum, Value::u8(0xFF));
+        bb.ret_value(fine);
+
+        assert_eq!(
+            errors(&mut ctx),
+            vec![ValidationError::ImmediateOutOfRange {
+                block: entry,
+                index: 1,
+                _type: PrimitiveValue::U8,
+                value: 0x1FF,
+            }]
+        );
+    }
+
+    #[test]
+    fn r5_used_before_its_definition_in_the_block() {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let x = ctx.add_parameter(PrimitiveValue::U64);
+        let bb = ctx.build_basic_block(entry);
+        let mut value = x;
+        for i in 1..=4 {
+            value = bb.add(value, Value::u64(i));
+        }
+        let r5 = match value {
+            Value::Register(r) => r,
+            Value::Immediate { .. } => unreachable!(),
+        };
+        assert_eq!(r5.to_string(), "r5");
+        let doubled = bb.add(value, value);
+        bb.ret_value(doubled);
+        // `doubled` comes before `r5` is defined
+        bb.instructions_mut().swap(4, 5);
+
+        assert_eq!(
+            errors(&mut ctx),
+            vec![ValidationError::UseBeforeDefinition {
+                block: entry,
+                index: 4,
+                register: r5,
+            }]
+        );
+    }
+}
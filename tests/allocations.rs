@@ -0,0 +1,50 @@
+//! Everything a `Context` allocates is freed when it's dropped
+mod common;
+
+use common::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/// Counts the bytes allocated and not yet freed by each thread, so the test
+/// harness allocating on its own threads doesn't count
+struct Counting;
+
+thread_local! {
+    static ALLOCATED: Cell<isize> = const { Cell::new(0) };
+}
+
+/// Add `bytes` to the count for this thread, unless it's being torn down
+fn count(bytes: isize) {
+    let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + bytes));
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size() as isize);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        count(-(layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+#[test]
+fn dropped_contexts_free_everything() {
+    let build = || {
+        let mut ctx = conditional_print();
+        ctx.finalize();
+    };
+    // anything allocated once for the whole process
+    build();
+
+    let before = ALLOCATED.with(Cell::get);
+    for _ in 0..10_000 {
+        build();
+    }
+    assert_eq!(ALLOCATED.with(Cell::get), before);
+}
@@ -0,0 +1,655 @@
+mod common;
+
+use common::*;
+use shiba_jit::{
+    codegen::{cpu_features, x86_64::*, CpuFeatures},
+    ir::*,
+};
+
+#[test]
+fn copy_and_original_are_both_usable() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let copy = bb.copy(x);
+    let sum = bb.add(x, copy);
+    let product = bb.multiply(sum, copy);
+    bb.ret_value(product);
+    let f = compile::<extern "C" fn(u64) -> u64>(&mut ctx);
+
+    assert_eq!(f.call(3), 18);
+    assert_eq!(f.call(10), 200);
+}
+
+/// Run a function that loads `value` into a register and returns it
+fn load_immediate<R: Copy>(value: Value) -> R {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let bb = ctx.build_basic_block(entry);
+    let loaded = bb.copy(value);
+    bb.ret_value(loaded);
+    compile::<extern "C" fn() -> R>(&mut ctx).call()
+}
+
+#[test]
+fn immediates_of_every_width_load() {
+    for v in [0, 1, 0x7F, 0xAB, u8::MAX] {
+        assert_eq!(load_immediate::<u8>(Value::u8(v)), v);
+    }
+    for v in [0, -1, 0x55, i8::MIN, i8::MAX] {
+        assert_eq!(load_immediate::<i8>(Value::i8(v)), v);
+    }
+    for v in [0, 1, 0x1234, 0xFEDC, u16::MAX] {
+        assert_eq!(load_immediate::<u16>(Value::u16(v)), v);
+    }
+    for v in [0, -1, -0x1234, i16::MIN, i16::MAX] {
+        assert_eq!(load_immediate::<i16>(Value::i16(v)), v);
+    }
+    for v in [0, 1, 0x1234_5678, 0xFEDC_BA98, u32::MAX] {
+        assert_eq!(load_immediate::<u32>(Value::u32(v)), v);
+    }
+    for v in [0, -1, -0x1234_5678, i32::MIN, i32::MAX] {
+        assert_eq!(load_immediate::<i32>(Value::i32(v)), v);
+    }
+    for v in [0, 1, 0xFFFF_FFFF, 0x1234_5678_9ABC_DEF0, u64::MAX] {
+        assert_eq!(load_immediate::<u64>(Value::u64(v)), v);
+    }
+    for v in [0, -1, -0x1234_5678_9ABC, i64::MIN, i64::MAX] {
+        assert_eq!(load_immediate::<i64>(Value::i64(v)), v);
+    }
+}
+
+/// `x op y` on two `_type` arguments
+fn binary(_type: PrimitiveValue, op: fn(&mut BasicBlock, Value, Value) -> Value) -> Context {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(_type);
+    let y = ctx.add_parameter(_type);
+    let bb = ctx.build_basic_block(entry);
+    let result = op(bb, x, y);
+    bb.ret_value(result);
+    ctx
+}
+
+#[test]
+fn signed_division_rounds_toward_zero() {
+    let divide = compile::<extern "C" fn(i32, i32) -> i32>(&mut binary(
+        PrimitiveValue::I32,
+        BasicBlock::divide,
+    ));
+    let remainder = compile::<extern "C" fn(i32, i32) -> i32>(&mut binary(
+        PrimitiveValue::I32,
+        BasicBlock::remainder,
+    ));
+
+    assert_eq!(divide.call(-7, 2), -3);
+    assert_eq!(divide.call(7, -2), -3);
+    assert_eq!(divide.call(-7, -2), 3);
+    assert_eq!(remainder.call(-7, 2), -1);
+    assert_eq!(remainder.call(7, -2), 1);
+    assert_eq!(remainder.call(-7, -2), -1);
+}
+
+#[test]
+fn signed_64_bit_division_rounds_toward_zero() {
+    let divide = compile::<extern "C" fn(i64, i64) -> i64>(&mut binary(
+        PrimitiveValue::I64,
+        BasicBlock::divide,
+    ));
+    let remainder = compile::<extern "C" fn(i64, i64) -> i64>(&mut binary(
+        PrimitiveValue::I64,
+        BasicBlock::remainder,
+    ));
+
+    assert_eq!(divide.call(-7, 2), -3);
+    assert_eq!(divide.call(7, -2), -3);
+    assert_eq!(remainder.call(-7, 2), -1);
+    assert_eq!(remainder.call(i64::MIN + 1, 2), -1);
+}
+
+#[test]
+fn unsigned_division() {
+    let divide = compile::<extern "C" fn(u32, u32) -> u32>(&mut binary(
+        PrimitiveValue::U32,
+        BasicBlock::divide,
+    ));
+    let remainder = compile::<extern "C" fn(u32, u32) -> u32>(&mut binary(
+        PrimitiveValue::U32,
+        BasicBlock::remainder,
+    ));
+
+    // -7 as a u32
+    assert_eq!(divide.call(4_294_967_289, 2), 2_147_483_644);
+    assert_eq!(remainder.call(4_294_967_289, 2), 1);
+}
+
+/// `a op b` on two immediates, which is worked out while generating code
+fn constant(a: Value, b: Value, op: fn(&mut BasicBlock, Value, Value) -> Value) -> Context {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let bb = ctx.build_basic_block(entry);
+    let result = op(bb, a, b);
+    bb.ret_value(result);
+    ctx
+}
+
+#[test]
+fn constant_arithmetic_wraps_like_the_instructions() {
+    let ops: [fn(&mut BasicBlock, Value, Value) -> Value; 5] = [
+        BasicBlock::add,
+        BasicBlock::subtract,
+        BasicBlock::multiply,
+        BasicBlock::divide,
+        BasicBlock::remainder,
+    ];
+    for op in ops {
+        let at_runtime =
+            compile::<extern "C" fn(u8, u8) -> u8>(&mut binary(PrimitiveValue::U8, op));
+        for (a, b) in [(200, 100), (3, 5), (255, 255), (200, 7)] {
+            let folded =
+                compile::<extern "C" fn() -> u8>(&mut constant(Value::u8(a), Value::u8(b), op));
+            assert_eq!(folded.call(), at_runtime.call(a, b), "{} and {}", a, b);
+        }
+
+        let at_runtime =
+            compile::<extern "C" fn(i8, i8) -> i8>(&mut binary(PrimitiveValue::I8, op));
+        for (a, b) in [(-100, 7), (100, 100), (-128, -1), (127, -128)] {
+            let folded =
+                compile::<extern "C" fn() -> i8>(&mut constant(Value::i8(a), Value::i8(b), op));
+            assert_eq!(folded.call(), at_runtime.call(a, b), "{} and {}", a, b);
+        }
+    }
+
+    let sum = compile::<extern "C" fn() -> u8>(&mut constant(
+        Value::u8(200),
+        Value::u8(100),
+        BasicBlock::add,
+    ));
+    assert_eq!(sum.call(), 44);
+}
+
+/// Narrow the argument from `from` to `to`, returning `trapped` instead if it
+/// doesn't fit
+fn truncate_checked(from: PrimitiveValue, to: PrimitiveValue, trapped: Value) -> Context {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(from);
+    let trap = ctx.new_basic_block();
+    let bb = ctx.build_basic_block(entry);
+    let narrowed = bb.truncate_checked(x, to, trap);
+    bb.ret_value(narrowed);
+    ctx.build_basic_block(trap).ret_value(trapped);
+    ctx
+}
+
+#[test]
+fn checked_truncation_traps_when_the_value_does_not_fit() {
+    let mut ctx = truncate_checked(PrimitiveValue::U32, PrimitiveValue::U8, Value::u8(77));
+    let f = compile::<extern "C" fn(u32) -> u8>(&mut ctx);
+    assert_eq!(f.call(255), 255);
+    assert_eq!(f.call(3), 3);
+    assert_eq!(f.call(256), 77);
+    assert_eq!(f.call(u32::MAX), 77);
+
+    let mut ctx = truncate_checked(PrimitiveValue::I32, PrimitiveValue::I8, Value::i8(77));
+    let f = compile::<extern "C" fn(i32) -> i8>(&mut ctx);
+    assert_eq!(f.call(-128), -128);
+    assert_eq!(f.call(127), 127);
+    assert_eq!(f.call(-129), 77);
+    assert_eq!(f.call(128), 77);
+}
+
+/// `x + 1` on a `_type` argument, returning `trapped` instead if it
+/// overflows and `trap` is set
+fn add_one(_type: PrimitiveValue, trap: bool, trapped: Value) -> Context {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(_type);
+    let overflowed = ctx.new_basic_block();
+    let overflow = if trap {
+        Overflow::Trap(overflowed)
+    } else {
+        Overflow::Wrap
+    };
+    let bb = ctx.build_basic_block(entry);
+    let sum = bb.add_with_overflow(x, Value::immediate(_type, 1).unwrap(), overflow);
+    bb.ret_value(sum);
+    ctx.build_basic_block(overflowed).ret_value(trapped);
+    ctx
+}
+
+#[test]
+fn trapping_addition_branches_where_wrapping_gives_zero() {
+    let mut ctx = add_one(PrimitiveValue::U32, false, Value::u32(77));
+    let f = compile::<extern "C" fn(u32) -> u32>(&mut ctx);
+    assert_eq!(f.call(u32::MAX), 0);
+    assert_eq!(f.call(5), 6);
+
+    let mut ctx = add_one(PrimitiveValue::U32, true, Value::u32(77));
+    let f = compile::<extern "C" fn(u32) -> u32>(&mut ctx);
+    assert_eq!(f.call(u32::MAX), 77);
+    assert_eq!(f.call(u32::MAX - 1), u32::MAX);
+    assert_eq!(f.call(5), 6);
+
+    // signed overflow is past the largest positive value instead
+    let mut ctx = add_one(PrimitiveValue::I32, true, Value::i32(77));
+    let f = compile::<extern "C" fn(i32) -> i32>(&mut ctx);
+    assert_eq!(f.call(i32::MAX), 77);
+    assert_eq!(f.call(-1), 0);
+}
+
+/// `x * factor`, where `x` is the argument
+fn multiply_by(factor: u64) -> (Context, BasicBlockIndex) {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let product = bb.multiply(x, Value::u64(factor));
+    bb.ret_value(product);
+    (ctx, entry)
+}
+
+/// Whether `code` has any of the `imul` opcodes in it
+fn has_imul(code: &[u8]) -> bool {
+    code.windows(2).any(|w| w == [0x0F, 0xAF]) || code.iter().any(|b| *b == 0x69 || *b == 0x6B)
+}
+
+#[test]
+fn multiplying_by_a_power_of_two_is_a_shift() {
+    let (mut ctx, entry) = multiply_by(8);
+    let code = generate_recording_offsets(&mut ctx);
+    let multiply = instruction_code(&code, entry, 1);
+    // shl r64, imm8 is REX.W C1 /4 ib, maybe after a mov into the destination
+    let shl = &multiply[multiply.len() - 4..];
+    assert_eq!(shl[0] & 0xF8, 0x48, "{:02x?}", multiply);
+    assert_eq!(shl[1], 0xC1, "{:02x?}", multiply);
+    assert_eq!((shl[2] >> 3) & 7, 4, "{:02x?}", multiply);
+    assert_eq!(shl[3], 3);
+    assert!(!has_imul(multiply));
+
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(f.call(7), 56);
+}
+
+#[test]
+fn multiplying_by_five_is_one_lea() {
+    let (mut ctx, entry) = multiply_by(5);
+    let code = generate_recording_offsets(&mut ctx);
+    let multiply = instruction_code(&code, entry, 1);
+    // lea r64, [base + index * 4], with a displacement for some bases
+    assert_eq!(multiply[0] & 0xF8, 0x48, "{:02x?}", multiply);
+    assert_eq!(multiply[1], 0x8D, "{:02x?}", multiply);
+    assert!(multiply.len() <= 5, "{:02x?}", multiply);
+
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(f.call(7), 35);
+}
+
+#[test]
+fn multiplying_by_a_multiple_of_three_is_a_lea_and_a_shift() {
+    let (mut ctx, entry) = multiply_by(24);
+    let code = generate_recording_offsets(&mut ctx);
+    let multiply = instruction_code(&code, entry, 1);
+    assert_eq!(multiply[1], 0x8D, "{:02x?}", multiply);
+    assert_eq!(multiply[multiply.len() - 3], 0xC1, "{:02x?}", multiply);
+    assert!(!has_imul(multiply));
+
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(f.call(7), 168);
+}
+
+#[test]
+fn multiplying_by_other_constants_uses_imul() {
+    for factor in [7, 11, 1 << 40 | 3] {
+        let (mut ctx, entry) = multiply_by(factor);
+        let code = generate_recording_offsets(&mut ctx);
+        assert!(has_imul(instruction_code(&code, entry, 1)));
+        let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+        assert_eq!(f.call(7), 7 * factor);
+    }
+}
+
+#[test]
+fn shifting_by_a_constant_uses_an_immediate() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let shifted = bb.shift_left(x, Value::u64(3));
+    bb.ret_value(shifted);
+    let code = generate_recording_offsets(&mut ctx);
+    let shift = instruction_code(&code, entry, 1);
+    // shl r64, imm8 is REX.W C1 /4 ib, maybe after a mov into the destination
+    let (mov, shl) = shift.split_at(shift.len() - 4);
+    assert_eq!(shl[1], 0xC1, "{:02x?}", shift);
+    assert_eq!((shl[2] >> 3) & 7, 4, "{:02x?}", shift);
+    assert_eq!(shl[3], 3);
+    // which isn't into rcx for the count
+    assert!(mov.is_empty() || mov.len() == 3, "{:02x?}", shift);
+    assert!(mov.is_empty() || mov[2] & 7 == shl[2] & 7, "{:02x?}", shift);
+
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(f.call(5), 40);
+}
+
+/// A function returning its first `U64` parameter shifted by its second
+fn shift_by_parameter(
+    shift: fn(&mut BasicBlock, Value, Value) -> Value,
+) -> (Context, BasicBlockIndex) {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let y = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let shifted = shift(bb, x, y);
+    bb.ret_value(shifted);
+    (ctx, entry)
+}
+
+#[test]
+fn shifting_by_a_register_goes_through_cl() {
+    let (mut ctx, entry) = shift_by_parameter(BasicBlock::shift_left);
+    let options = CodeGenOptions {
+        cpu_features: Some(CpuFeatures::default()),
+        ..Default::default()
+    };
+    let code = generate_recording_offsets_with(&mut ctx, options);
+    let shift = instruction_code(&code, entry, 2);
+    // shl r64, cl is REX.W D3 /4
+    let shl = &shift[shift.len() - 3..];
+    assert_eq!(shl[1], 0xD3, "{:02x?}", shift);
+    assert_eq!((shl[2] >> 3) & 7, 4, "{:02x?}", shift);
+
+    let f: JitFunction<extern "C" fn(u64, u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(f.call(5, 3), 40);
+    assert_eq!(f.call(1, 63), 1 << 63);
+}
+
+#[test]
+fn shifting_by_a_register_uses_bmi2_when_allowed() {
+    if !cpu_features().bmi2 {
+        return;
+    }
+    let options = || CodeGenOptions {
+        cpu_features: Some(CpuFeatures {
+            bmi2: true,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let generate = |shift| {
+        let (mut ctx, entry) = shift_by_parameter(shift);
+        let code = generate_recording_offsets_with(&mut ctx, options());
+        // shlx, shrx and sarx are VEX encoded, 3 byte form since they're
+        // in the 0F38 map, with opcode F7
+        let shift = instruction_code(&code, entry, 2);
+        assert_eq!(shift.len(), 5, "{:02x?}", shift);
+        assert_eq!((shift[0], shift[3]), (0xC4, 0xF7), "{:02x?}", shift);
+        unsafe { code.into_function::<extern "C" fn(u64, u64) -> u64>() }
+    };
+
+    let shl = generate(BasicBlock::shift_left);
+    assert_eq!(shl.call(5, 3), 40);
+    assert_eq!(shl.call(1, 63), 1 << 63);
+    assert_eq!(shl.call(1, 64), 1);
+    let shr = generate(BasicBlock::shift_right);
+    assert_eq!(shr.call(1 << 63, 63), 1);
+    assert_eq!(shr.call(40, 67), 5);
+}
+
+#[test]
+fn shifting_a_constant_by_a_constant_folds() {
+    let shifted = compile::<extern "C" fn() -> u8>(&mut constant(
+        Value::u8(0x81),
+        Value::u8(1),
+        BasicBlock::shift_left,
+    ));
+    assert_eq!(shifted.call(), 2);
+    let shifted = compile::<extern "C" fn() -> i8>(&mut constant(
+        Value::i8(-128),
+        Value::i8(3),
+        BasicBlock::shift_right,
+    ));
+    assert_eq!(shifted.call(), -16);
+
+    let mut ctx = constant(Value::u64(1), Value::u64(40), BasicBlock::shift_left);
+    let entry = ctx.entry();
+    let code = generate_recording_offsets(&mut ctx);
+    let shift = instruction_code(&code, entry, 0);
+    assert!(
+        !shift.contains(&0xC1) && !shift.contains(&0xD3),
+        "{:02x?}",
+        shift
+    );
+}
+
+/// The register holding `value`
+fn register(value: Value) -> RegisterIndex {
+    match value {
+        Value::Register(r) => r,
+        Value::Immediate { .. } => panic!("{:?} isn't in a register", value),
+    }
+}
+
+#[test]
+fn subtracting_into_the_register_of_the_subtrahend() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let y = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let from_immediate = bb.subtract(Value::u64(5), x);
+    let from_register = bb.subtract(from_immediate, y);
+    bb.ret_value(from_register);
+
+    let mut options = CodeGenOptions::default();
+    options
+        .register_constraints
+        .fix(register(x), MachineRegister::R12)
+        .fix(register(from_immediate), MachineRegister::R12)
+        .fix(register(y), MachineRegister::R13)
+        .fix(register(from_register), MachineRegister::R13);
+    ctx.finalize();
+    let code = generate_code_with_options(&ctx, &options).unwrap();
+    assert_eq!(
+        code.machine_register(register(x)),
+        code.machine_register(register(from_immediate))
+    );
+    assert_eq!(
+        code.machine_register(register(y)),
+        code.machine_register(register(from_register))
+    );
+
+    let f: JitFunction<extern "C" fn(u64, u64) -> u64> = unsafe { code.into_function() };
+    // 5 - 2 - 10
+    assert_eq!(f.call(2, 10), -7i64 as u64);
+    assert_eq!(f.call(1, 1), 3);
+}
+
+/// The code for `x + y`, with `x`, `y` and the sum in the given registers
+fn add_in(x_in: MachineRegister, y_in: MachineRegister, sum_in: MachineRegister) -> Vec<u8> {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let y = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let sum = bb.add(x, y);
+    bb.ret_value(sum);
+
+    let mut options = CodeGenOptions {
+        record_instruction_offsets: true,
+        ..CodeGenOptions::default()
+    };
+    options
+        .register_constraints
+        .fix(register(x), x_in)
+        .fix(register(y), y_in)
+        .fix(register(sum), sum_in);
+    ctx.finalize();
+    let code = generate_code_with_options(&ctx, &options).unwrap();
+    let add = instruction_code(&code, entry, 2).to_vec();
+    let f: JitFunction<extern "C" fn(u64, u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(f.call(30, 12), 42);
+    assert_eq!(f.call(u64::MAX, 2), 1);
+    add
+}
+
+#[test]
+fn adding_into_a_third_register_is_one_lea() {
+    use MachineRegister::*;
+    let add = add_in(R12, R13, R14);
+    // lea r14, [r12 + r13] is REX.WRXB 8D /r with a SIB byte, and maybe a
+    // zero displacement
+    assert_eq!(add[..2], [0x4F, 0x8D], "{:02x?}", add);
+    assert_eq!(add[3], 0x2C, "{:02x?}", add);
+    assert!(add.len() <= 5, "{:02x?}", add);
+
+    // the sum can go over either operand with a single add instead
+    for (x_in, y_in, sum_in) in [(R12, R13, R12), (R12, R13, R13)] {
+        let add = add_in(x_in, y_in, sum_in);
+        assert_eq!(add.len(), 3, "{:02x?}", add);
+        assert!(matches!(add[1], 0x01 | 0x03), "{:02x?}", add);
+    }
+}
+
+#[test]
+fn registers_holding_constants_get_no_machine_register() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    // more constants than there are machine registers, all live at once
+    let constants = (1..=16).map(|i| bb.copy(Value::u64(i))).collect::<Vec<_>>();
+    let sum = constants.iter().fold(x, |sum, c| bb.add(sum, *c));
+    bb.ret_value(sum);
+    ctx.finalize();
+    let code = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
+    for c in &constants {
+        assert!(!code.register_map.contains_key(&register(*c)));
+    }
+    // so nothing had to be spilled
+    assert!(code.frame_layout.slots.is_empty());
+
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(f.call(100), 100 + 136);
+}
+
+#[test]
+fn carry_is_a_value_of_its_own() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U32);
+    let y = ctx.add_parameter(PrimitiveValue::U32);
+    let bb = ctx.build_basic_block(entry);
+    let (sum, carry) = bb.add_with_flag(x, y);
+    bb.print_int(sum, PrimitiveValue::U32);
+    bb.print_int(carry, PrimitiveValue::U32);
+    // both still usable after the prints
+    let combined = bb.add(sum, carry);
+    bb.ret_value(combined);
+    let f = compile::<extern "C" fn(u32, u32) -> u32>(&mut ctx);
+
+    assert_eq!(
+        capture_output(|| assert_eq!(f.call(u32::MAX, 1), 1)),
+        "0\n1\n"
+    );
+    assert_eq!(capture_output(|| assert_eq!(f.call(2, 3), 5)), "5\n0\n");
+
+    // signed types get the overflow instead
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::I32);
+    let bb = ctx.build_basic_block(entry);
+    let (_, overflowed) = bb.add_with_flag(x, Value::i32(1));
+    bb.ret_value(overflowed);
+    let f = compile::<extern "C" fn(i32) -> u32>(&mut ctx);
+    assert_eq!(f.call(i32::MAX), 1);
+    assert_eq!(f.call(-1), 0);
+}
+
+/// `op` applied to two 128 bit arguments, or to one and `constant`
+fn wide(
+    _type: PrimitiveValue,
+    op: fn(&mut BasicBlock, Value, Value) -> Value,
+    constant: Option<i128>,
+) -> JitFunction<extern "C" fn(u128, u128) -> u128> {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(_type);
+    let y = ctx.add_parameter(_type);
+    let bb = ctx.build_basic_block(entry);
+    let y = constant.map_or(y, |c| Value::immediate(_type, c).unwrap());
+    let result = op(bb, x, y);
+    bb.ret_value(result);
+    compile(&mut ctx)
+}
+
+#[test]
+fn wide_addition_carries_across_the_halves() {
+    let add = wide(PrimitiveValue::U128, BasicBlock::add, None);
+    let subtract = wide(PrimitiveValue::U128, BasicBlock::subtract, None);
+    let values = [
+        0,
+        1,
+        u64::MAX as u128,
+        1 << 64,
+        (1 << 64) + 5,
+        u128::MAX,
+        0x0123_4567_89AB_CDEF_FEDC_BA98_7654_3210,
+    ];
+    for a in values {
+        for b in values {
+            assert_eq!(add.call(a, b), a.wrapping_add(b), "{:#x} + {:#x}", a, b);
+            assert_eq!(
+                subtract.call(a, b),
+                a.wrapping_sub(b),
+                "{:#x} - {:#x}",
+                a,
+                b
+            );
+        }
+    }
+    assert_eq!(add.call(u64::MAX as u128, 1), 1 << 64);
+    assert_eq!(subtract.call(1 << 64, 1), u64::MAX as u128);
+
+    // signed constants are extended into the high half
+    let add_minus_one = wide(PrimitiveValue::I128, BasicBlock::add, Some(-1));
+    assert_eq!(add_minus_one.call(1 << 64, 0), u64::MAX as u128);
+    assert_eq!(add_minus_one.call(0, 0), u128::MAX);
+}
+
+#[test]
+fn wide_multiply_keeps_the_whole_product_of_64_bit_values() {
+    let multiply = wide(PrimitiveValue::U128, BasicBlock::multiply, None);
+    let values = [0, 1, 3, 0xFFFF_FFFF, 1 << 63, u64::MAX - 1, u64::MAX];
+    for a in values {
+        for b in values {
+            let expected = a as u128 * b as u128;
+            assert_eq!(
+                multiply.call(a as u128, b as u128),
+                expected,
+                "{:#x} * {:#x}",
+                a,
+                b
+            );
+        }
+    }
+    assert_eq!(
+        multiply.call(u64::MAX as u128, u64::MAX as u128),
+        0xFFFF_FFFF_FFFF_FFFE_0000_0000_0000_0001
+    );
+    // and the low 128 bits of wider products
+    let a = 0x0123_4567_89AB_CDEF_FEDC_BA98_7654_3210u128;
+    assert_eq!(multiply.call(a, a), a.wrapping_mul(a));
+
+    let by_constant = wide(
+        PrimitiveValue::U128,
+        BasicBlock::multiply,
+        Some(u64::MAX as i128),
+    );
+    assert_eq!(
+        by_constant.call(u64::MAX as u128, 0),
+        u64::MAX as u128 * u64::MAX as u128
+    );
+}
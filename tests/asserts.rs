@@ -0,0 +1,86 @@
+//! `IR::Assert`, which only generated code with debug assertions checks.
+//! The handler is global, so this is its own test binary.
+use shiba_jit::{codegen::x86_64::*, ir::interp, ir::*};
+use std::sync::Mutex;
+
+/// The messages of the assertions that failed
+static FAILED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+fn record_failure(message: &[u8]) {
+    FAILED
+        .lock()
+        .unwrap()
+        .push(String::from_utf8_lossy(message).into_owned());
+}
+
+/// Asserts that `x` isn't zero, and that the low byte of `y` isn't either,
+/// then returns `x + 1`
+fn checked_sum() -> (Context, ConstantIndex) {
+    let mut ctx = Context::new();
+    let x_message = ctx.add_constant(b"x is zero");
+    let y_message = ctx.add_constant(b"y is zero");
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let y = ctx.add_parameter(PrimitiveValue::U8);
+    let bb = ctx.build_basic_block(entry);
+    bb.assert(x, x_message);
+    bb.assert(y, y_message);
+    // an assertion that always holds
+    bb.assert(Value::u32(1), x_message);
+    let sum = bb.add(x, Value::u64(1));
+    bb.ret_value(sum);
+    ctx.finalize();
+    (ctx, x_message)
+}
+
+#[test]
+fn failing_assertions_call_the_handler() {
+    let (ctx, x_message) = checked_sum();
+    let options = CodeGenOptions {
+        debug_assertions: true,
+        ..CodeGenOptions::default()
+    };
+    let code = generate_code_with_options(&ctx, &options).unwrap();
+    let f: JitFunction<extern "C" fn(u64, u64) -> u64> = unsafe { code.into_function() };
+    set_assert_handler(Some(record_failure));
+
+    let call = |x, y| {
+        let returned = f.call(x, y);
+        (returned, std::mem::take(&mut *FAILED.lock().unwrap()))
+    };
+    assert_eq!(call(3, 4), (4, vec![]));
+    // only the low byte of `y` counts, and the code carries on afterwards
+    assert_eq!(call(3, 0x100), (4, vec!["y is zero".to_string()]));
+    assert_eq!(
+        call(0, 0),
+        (1, vec!["x is zero".to_string(), "y is zero".to_string()])
+    );
+    set_assert_handler(None);
+
+    // the interpreter stops at the first one
+    let error = interp::run_with_arguments(&ctx, &[0, 4]).unwrap_err();
+    assert!(
+        matches!(error.reason, interp::InterpErrorReason::AssertFailed(m) if m == x_message),
+        "{:?}",
+        error
+    );
+}
+
+#[test]
+fn assertions_are_elided_without_debug_assertions() {
+    let (ctx, _) = checked_sum();
+    let checked = CodeGenOptions {
+        debug_assertions: true,
+        ..CodeGenOptions::default()
+    };
+    let checked_len = generate_code_with_options(&ctx, &checked)
+        .unwrap()
+        .code()
+        .len();
+    let code = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
+    assert!(code.code().len() < checked_len);
+    // no handler is set, so a call to it would abort
+    let f: JitFunction<extern "C" fn(u64, u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(f.call(0, 0), 1);
+    assert_eq!(f.call(5, 6), 6);
+}
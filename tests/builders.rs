@@ -0,0 +1,406 @@
+mod common;
+
+use common::*;
+use shiba_jit::{codegen::x86_64::*, ir::*};
+
+#[test]
+fn if_else_selects_what_to_print() {
+    let mut ctx = Context::new();
+    let yes = ctx.add_constant(b"yes\n");
+    let no = ctx.add_constant(b"no\n");
+    let entry = ctx.new_basic_block();
+    let cond = ctx.add_parameter(PrimitiveValue::U64);
+    let join = ctx.build_if(
+        entry,
+        cond,
+        |then| {
+            then.push_instruction(IR::PrintConstant { constant_ref: yes });
+        },
+        |else_| {
+            else_.push_instruction(IR::PrintConstant { constant_ref: no });
+        },
+    );
+    ctx.build_basic_block(join).ret();
+    let f = compile::<extern "C" fn(u64)>(&mut ctx);
+
+    assert_eq!(capture_output(|| f.call(1)), "yes\n");
+    assert_eq!(capture_output(|| f.call(42)), "yes\n");
+    assert_eq!(capture_output(|| f.call(0)), "no\n");
+}
+
+#[test]
+fn while_loop_behaves_like_conditional_print() {
+    let mut ctx = Context::new();
+    let hello_world_const = ctx.add_constant(b"Hello, world\n");
+    let end_const = ctx.add_constant(b"Goodbye, world\n");
+    let prog_start = ctx.new_basic_block();
+
+    let prog_start_bb = ctx.build_basic_block(prog_start);
+    let counter = prog_start_bb.alloca(PrimitiveValue::U32, 4);
+    prog_start_bb.store(counter, Value::u32(0));
+
+    let loop_exit = ctx.build_while(
+        prog_start,
+        |header| {
+            let loaded_counter = header.load(counter);
+            header.subtract(Value::u32(4), loaded_counter)
+        },
+        |body| {
+            body.push_instruction(IR::PrintConstant {
+                constant_ref: hello_world_const,
+            });
+            let loaded_counter = body.load(counter);
+            let add_result = body.add(loaded_counter, Value::u32(1));
+            body.store(counter, add_result);
+        },
+    );
+    let loop_exit_bb = ctx.build_basic_block(loop_exit);
+    loop_exit_bb.push_instruction(IR::PrintConstant {
+        constant_ref: end_const,
+    });
+    loop_exit_bb.ret();
+    let with_while = compile::<extern "C" fn()>(&mut ctx);
+    let by_hand = compile::<extern "C" fn()>(&mut conditional_print());
+
+    let output = capture_output(|| with_while.call());
+    assert_eq!(output, capture_output(|| by_hand.call()));
+    assert_eq!(output, CONDITIONAL_PRINT_OUTPUT);
+}
+
+#[test]
+fn while_loop_that_never_runs() {
+    let mut ctx = Context::new();
+    let body_const = ctx.add_constant(b"body\n");
+    let entry = ctx.new_basic_block();
+    let exit = ctx.build_while(
+        entry,
+        |header| header.copy(Value::u64(0)),
+        |body| {
+            body.push_instruction(IR::PrintConstant {
+                constant_ref: body_const,
+            });
+        },
+    );
+    ctx.build_basic_block(exit).ret();
+    let f = compile::<extern "C" fn()>(&mut ctx);
+
+    assert_eq!(capture_output(|| f.call()), "");
+}
+
+/// The program from `examples/return_sum.rs`, which returns the sum of the
+/// numbers below `n`, before and after SSA construction
+fn return_sum(n: u32, in_ssa: bool) -> Context {
+    let mut ctx = Context::new();
+    let prog_start = ctx.new_basic_block();
+    let prog_start_bb = ctx.build_basic_block(prog_start);
+    let counter = prog_start_bb.alloca(PrimitiveValue::U32, 4);
+    let sum = prog_start_bb.alloca(PrimitiveValue::U32, 4);
+    prog_start_bb.store(counter, Value::u32(0));
+    prog_start_bb.store(sum, Value::u32(0));
+    let loop_exit = ctx.build_while(
+        prog_start,
+        |header| {
+            let loaded_counter = header.load(counter);
+            header.subtract(Value::u32(n), loaded_counter)
+        },
+        |body| {
+            let loaded_counter = body.load(counter);
+            let loaded_sum = body.load(sum);
+            let new_sum = body.add(loaded_sum, loaded_counter);
+            body.store(sum, new_sum);
+            let new_counter = body.add(loaded_counter, Value::u32(1));
+            body.store(counter, new_counter);
+        },
+    );
+    let loop_exit_bb = ctx.build_basic_block(loop_exit);
+    let result = loop_exit_bb.load(sum);
+    loop_exit_bb.ret_value(result);
+    if in_ssa {
+        ssa::construct(&mut ctx);
+    }
+    ctx
+}
+
+#[test]
+fn loop_returns_its_sum() {
+    for n in [0, 1, 10, 1000] {
+        for in_ssa in [false, true] {
+            let f = compile::<extern "C" fn() -> u32>(&mut return_sum(n, in_ssa));
+            assert_eq!(f.call(), (0..n).sum::<u32>(), "n = {}", n);
+        }
+    }
+}
+
+#[test]
+fn integers_print_by_their_signedness() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let signed = ctx.add_parameter(PrimitiveValue::I32);
+    let unsigned = ctx.add_parameter(PrimitiveValue::U32);
+    let bb = ctx.build_basic_block(entry);
+    bb.print_int(signed, PrimitiveValue::I32);
+    bb.print_int(unsigned, PrimitiveValue::U32);
+    bb.print_int(Value::i8(-1), PrimitiveValue::I8);
+    bb.print_int(Value::u8(255), PrimitiveValue::U8);
+    bb.ret();
+    let f = compile::<extern "C" fn(i32, u32)>(&mut ctx);
+
+    assert_eq!(
+        capture_output(|| f.call(-1, u32::MAX)),
+        "-1\n4294967295\n-1\n255\n"
+    );
+}
+
+#[test]
+fn code_starts_at_the_entry_block() {
+    let mut ctx = Context::new();
+    let wrong = ctx.add_constant(b"wrong\n");
+    let right = ctx.add_constant(b"right\n");
+    let first = ctx.new_basic_block();
+    let entry = ctx.new_basic_block();
+    let exit = ctx.new_basic_block();
+    let bb = ctx.build_basic_block(first);
+    bb.push_instruction(IR::PrintConstant {
+        constant_ref: wrong,
+    });
+    bb.ret();
+    ctx.build_basic_block(entry).jump(exit);
+    let bb = ctx.build_basic_block(exit);
+    bb.push_instruction(IR::PrintConstant {
+        constant_ref: right,
+    });
+    bb.ret();
+    ctx.set_entry(entry);
+    assert_eq!(ctx.entry(), entry);
+    let f = compile::<extern "C" fn()>(&mut ctx);
+
+    assert_eq!(capture_output(|| f.call()), "right\n");
+}
+
+#[test]
+fn entry_block_created_last_runs_first() {
+    let mut ctx = Context::new();
+    let start = ctx.add_constant(b"entry\n");
+    let first = ctx.add_constant(b"first\n");
+    let second = ctx.add_constant(b"second\n");
+    let print_second = ctx.new_basic_block();
+    let print_first = ctx.new_basic_block();
+    let entry = ctx.new_basic_block();
+    ctx.set_entry(entry);
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(print_second);
+    bb.push_instruction(IR::PrintConstant {
+        constant_ref: second,
+    });
+    let doubled = bb.add(x, x);
+    bb.ret_value(doubled);
+    let bb = ctx.build_basic_block(print_first);
+    bb.push_instruction(IR::PrintConstant {
+        constant_ref: first,
+    });
+    bb.jump(print_second);
+    let bb = ctx.build_basic_block(entry);
+    bb.push_instruction(IR::PrintConstant {
+        constant_ref: start,
+    });
+    bb.jump(print_first);
+    ctx.finalize();
+    let code = generate_code_with_options(&ctx, &capturing(CodeGenOptions::default())).unwrap();
+
+    // the entry is laid out right after the prologue, before the others
+    let (entry_start, _) = code.block_ranges[&entry];
+    assert!(entry_start.0 > code.start.0);
+    assert!(code
+        .block_ranges
+        .values()
+        .all(|(start, _)| entry_start.0 <= start.0));
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    let mut returned = 0;
+    assert_eq!(
+        capture_output(|| returned = f.call(21)),
+        "entry\nfirst\nsecond\n"
+    );
+    assert_eq!(returned, 42);
+}
+
+/// Print a greeting and return `x * 3 + 4`
+fn greet(ctx: &mut Context) {
+    let hello = ctx.add_constant(b"Hello again\n");
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    bb.push_instruction(IR::PrintConstant {
+        constant_ref: hello,
+    });
+    let product = bb.multiply(x, Value::u64(3));
+    let sum = bb.add(product, Value::u64(4));
+    bb.ret_value(sum);
+}
+
+#[test]
+fn cleared_context_builds_the_next_program() {
+    let mut ctx = return_sum(10, false);
+    let first = compile::<extern "C" fn() -> u32>(&mut ctx);
+    assert_eq!(first.call(), 45);
+
+    ctx.clear();
+    greet(&mut ctx);
+    ctx.finalize();
+    // numbered from the start again, so it's the same as a new context
+    let mut fresh = Context::new();
+    greet(&mut fresh);
+    fresh.finalize();
+    let options = CodeGenOptions::default();
+    let code = generate_code_with_options(&ctx, &options).unwrap();
+    let fresh_code = generate_code_with_options(&fresh, &options).unwrap();
+    assert_eq!(code.code(), fresh_code.code());
+
+    let f = compile::<extern "C" fn(u64) -> u64>(&mut ctx);
+    assert_eq!(
+        capture_output(|| assert_eq!(f.call(5), 19)),
+        "Hello again\n"
+    );
+    // the first program's code is still there
+    assert_eq!(first.call(), 45);
+}
+
+#[test]
+fn arguments_read_in_any_order_after_other_code() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let hello = ctx.add_constant(b"subtracting\n");
+    ctx.build_basic_block(entry)
+        .push_instruction(IR::PrintConstant {
+            constant_ref: hello,
+        });
+    // read once the block already has code, second argument first
+    let y = ctx.argument(1, PrimitiveValue::U64);
+    let x = ctx.argument(0, PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let difference = bb.subtract(x, y);
+    let sum = bb.add(difference, x);
+    bb.ret_value(sum);
+    let f = compile::<extern "C" fn(u64, u64) -> u64>(&mut ctx);
+
+    assert_eq!(
+        capture_output(|| assert_eq!(f.call(10, 3), 17)),
+        "subtracting\n"
+    );
+}
+
+#[test]
+fn parameters_added_after_reading_an_argument_come_after_it() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let z = ctx.argument(2, PrimitiveValue::U64);
+    // not argument 1, which nothing has read
+    let w = ctx.add_parameter(PrimitiveValue::U64);
+    let x_again = ctx.argument(0, PrimitiveValue::U64);
+    let v = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let mut result = bb.multiply(x, Value::u64(10));
+    for arg in [z, w, v].iter().copied() {
+        result = bb.multiply(result, Value::u64(10));
+        result = bb.add(result, arg);
+    }
+    result = bb.subtract(result, x_again);
+    bb.ret_value(result);
+    let f = compile::<extern "C" fn(u64, u64, u64, u64, u64) -> u64>(&mut ctx);
+
+    assert_eq!(f.call(1, 9, 2, 3, 4), 10_234 - 1);
+}
+
+#[test]
+fn then_falls_through_without_a_jump() {
+    let mut ctx = Context::new();
+    let first = ctx.add_constant(b"first\n");
+    let second = ctx.add_constant(b"second\n");
+    let entry = ctx.new_basic_block();
+    // created out of order, so they'd be laid out the wrong way round
+    let last = ctx.new_basic_block();
+    let next = ctx.new_basic_block();
+    let bb = ctx.build_basic_block(entry);
+    bb.push_instruction(IR::PrintConstant {
+        constant_ref: first,
+    });
+    bb.then(next);
+    let bb = ctx.build_basic_block(next);
+    bb.push_instruction(IR::PrintConstant {
+        constant_ref: second,
+    });
+    bb.then(last);
+    ctx.build_basic_block(last).ret();
+    let code = generate_recording_offsets(&mut ctx);
+
+    assert_eq!(instruction_code(&code, entry, 1), []);
+    assert_eq!(instruction_code(&code, next, 1), []);
+    assert_eq!(code.block_ranges[&next].0, code.block_ranges[&entry].1);
+    assert_eq!(code.block_ranges[&last].0, code.block_ranges[&next].1);
+    let f: JitFunction<extern "C" fn()> = unsafe { code.into_function() };
+    assert_eq!(capture_output(|| f.call()), "first\nsecond\n");
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Pair {
+    first: u64,
+    second: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Triple {
+    first: u64,
+    second: u64,
+    third: u64,
+}
+
+/// Return a struct of `fields` `U64`s: `x + 1`, `x * 2`, then `x - 3`
+fn return_struct(fields: usize) -> Context {
+    let mut ctx = Context::new();
+    ctx.set_return_struct(vec![PrimitiveValue::U64; fields]);
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let values = [
+        bb.add(x, Value::u64(1)),
+        bb.multiply(x, Value::u64(2)),
+        bb.subtract(x, Value::u64(3)),
+    ];
+    bb.ret_struct(&values[..fields]);
+    ctx
+}
+
+#[test]
+fn structs_are_returned_by_the_abi() {
+    // in rax:rdx
+    let mut ctx = return_struct(2);
+    let f = compile::<extern "C" fn(u64) -> Pair>(&mut ctx);
+    assert_eq!(
+        f.call(10),
+        Pair {
+            first: 11,
+            second: 20
+        }
+    );
+    assert_eq!(
+        f.call(u64::MAX),
+        Pair {
+            first: 0,
+            second: u64::MAX - 1
+        }
+    );
+
+    // through memory
+    let mut ctx = return_struct(3);
+    let f = compile::<extern "C" fn(u64) -> Triple>(&mut ctx);
+    assert_eq!(
+        f.call(10),
+        Triple {
+            first: 11,
+            second: 20,
+            third: 7
+        }
+    );
+}
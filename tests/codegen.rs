@@ -0,0 +1,865 @@
+mod common;
+
+use common::*;
+use shiba_jit::{codegen::x86_64::*, codegen::CpuFeatures, ir::*};
+
+#[test]
+fn codegen_works_without_optional_cpu_features() {
+    let mut ctx = conditional_print();
+    ctx.finalize();
+    let options = capturing(CodeGenOptions {
+        cpu_features: Some(CpuFeatures::default()),
+        ..Default::default()
+    });
+    let code = generate_code_with_options(&ctx, &options).unwrap();
+    assert_eq!(code.cpu_features, CpuFeatures::default());
+    let f: JitFunction<extern "C" fn()> = unsafe { code.into_function() };
+
+    assert_eq!(capture_output(|| f.call()), CONDITIONAL_PRINT_OUTPUT);
+}
+
+#[test]
+fn the_example_counts_its_loop_across_the_back_edge() {
+    let expected = format!("{}Goodbye, world\n", "Hello, world\n".repeat(4));
+    assert_eq!(CONDITIONAL_PRINT_OUTPUT, expected);
+    let run = |ctx: &mut Context, options: &CodeGenOptions| {
+        let f = compile_with::<extern "C" fn()>(ctx, options);
+        capture_output(|| f.call())
+    };
+
+    // the counter in memory
+    let mut ctx = conditional_print();
+    assert_eq!(run(&mut ctx, &CodeGenOptions::default()), expected);
+    // and in a register carried around the loop by a phi, with and without
+    // rbp to put it in
+    for omit_frame_pointer in [false, true] {
+        let mut ctx = conditional_print();
+        ssa::construct(&mut ctx);
+        let options = CodeGenOptions {
+            omit_frame_pointer,
+            ..Default::default()
+        };
+        assert_eq!(run(&mut ctx, &options), expected);
+    }
+}
+
+/// `x * factor + offset`
+fn affine(factor: u64, offset: u64) -> Context {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let product = bb.multiply(x, Value::u64(factor));
+    let sum = bb.add(product, Value::u64(offset));
+    bb.ret_value(sum);
+    ctx
+}
+
+#[test]
+fn each_function_prints_to_its_own_handler() {
+    let mut ctx = Context::new();
+    let greeting = ctx.add_constant(b"hi\n");
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::I64);
+    let bb = ctx.build_basic_block(entry);
+    bb.push_instruction(IR::PrintConstant {
+        constant_ref: greeting,
+    });
+    bb.print_int(x, PrimitiveValue::I64);
+    bb.ret();
+    ctx.finalize();
+    let compile = |output: &std::sync::Arc<std::sync::Mutex<Vec<u8>>>| {
+        let output = output.clone();
+        let options = CodeGenOptions {
+            print_handler: Some(PrintHandler::new(move |printed| {
+                output.lock().unwrap().extend_from_slice(printed)
+            })),
+            ..Default::default()
+        };
+        let code = generate_code_with_options(&ctx, &options).unwrap();
+        unsafe { code.into_function::<extern "C" fn(i64)>() }
+    };
+    let (first_output, second_output) = Default::default();
+    let first = compile(&first_output);
+    let second = compile(&second_output);
+
+    first.call(1);
+    second.call(-2);
+    first.call(3);
+    assert_eq!(&first_output.lock().unwrap()[..], b"hi\n1\nhi\n3\n");
+    assert_eq!(&second_output.lock().unwrap()[..], b"hi\n-2\n");
+}
+
+#[test]
+fn threads_compile_and_run_concurrently() {
+    let threads = (0..8u64)
+        .map(|i| {
+            std::thread::spawn(move || {
+                for round in 0..50u64 {
+                    let mut ctx = affine(i + 2, round);
+                    let f = compile::<extern "C" fn(u64) -> u64>(&mut ctx);
+                    for x in 0..10 {
+                        assert_eq!(f.call(x), x * (i + 2) + round);
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+fn code_is_dumped_to_a_file() {
+    let mut ctx = affine(3, 4);
+    ctx.finalize();
+    let path = std::env::temp_dir().join(format!("shiba-jit-dump-{}", std::process::id()));
+    let options = CodeGenOptions {
+        dump_code_to: Some(path.clone()),
+        ..Default::default()
+    };
+    let code = generate_code_with_options(&ctx, &options).unwrap();
+    let dumped = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(dumped, code.code());
+}
+
+#[test]
+fn failing_to_dump_code_is_an_error() {
+    let mut ctx = affine(3, 4);
+    ctx.finalize();
+    let options = CodeGenOptions {
+        dump_code_to: Some("/nonexistent/shiba-jit/dump".into()),
+        ..Default::default()
+    };
+    let error = generate_code_with_options(&ctx, &options).unwrap_err();
+    assert!(matches!(error.reason(), CodeGenErrorReason::DumpCode(_)));
+}
+
+#[test]
+fn code_over_the_size_limit_is_an_error() {
+    let mut ctx = affine(3, 4);
+    ctx.finalize();
+    let options = CodeGenOptions {
+        max_code_size: Some(4),
+        ..Default::default()
+    };
+    let error = generate_code_with_options(&ctx, &options).unwrap_err();
+    let size = match *error.reason() {
+        CodeGenErrorReason::CodeTooLarge { size, limit: 4 } => size,
+        ref reason => panic!("unexpected error {:?}", reason),
+    };
+    assert!(size > 4);
+
+    // exactly at the limit is fine
+    let options = CodeGenOptions {
+        max_code_size: Some(size),
+        ..Default::default()
+    };
+    let code = generate_code_with_options(&ctx, &options).unwrap();
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(f.call(5), 19);
+}
+
+#[test]
+fn compiled_bytes_are_the_generated_code() {
+    let mut ctx = affine(3, 4);
+    ctx.finalize();
+    let bytes = compiled_bytes(&ctx).unwrap();
+    let (buffer, start) = generate_code(&ctx).unwrap();
+    assert_eq!(bytes, &buffer[start.0..]);
+    let code = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
+    assert_eq!(bytes, code.code());
+    // nothing after the return
+    assert_eq!(bytes.last(), Some(&0xC3));
+}
+
+/// `x * factor + offset` in two blocks, with `Nop`s everywhere if `nops`
+fn affine_with_nops(factor: u64, offset: u64, nops: bool) -> Context {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let exit = ctx.new_basic_block();
+    let nop = |bb: &mut BasicBlock| {
+        if nops {
+            bb.push_instruction(IR::Nop);
+        }
+    };
+    let bb = ctx.build_basic_block(entry);
+    nop(bb);
+    let product = bb.multiply(x, Value::u64(factor));
+    nop(bb);
+    nop(bb);
+    bb.jump(exit);
+    let bb = ctx.build_basic_block(exit);
+    nop(bb);
+    let sum = bb.add(product, Value::u64(offset));
+    nop(bb);
+    bb.ret_value(sum);
+    ctx
+}
+
+#[test]
+fn nops_compile_to_nothing() {
+    let mut plain = affine_with_nops(3, 4, false);
+    plain.finalize();
+    let plain = generate_code_with_options(&plain, &CodeGenOptions::default()).unwrap();
+    let mut with_nops = affine_with_nops(3, 4, true);
+    with_nops.finalize();
+    let code = generate_code_with_options(&with_nops, &CodeGenOptions::default()).unwrap();
+    assert_eq!(code.code(), plain.code());
+
+    with_nops.strip_nops();
+    assert!(!with_nops
+        .iter_instructions()
+        .any(|(_, _, inst)| matches!(inst, IR::Nop)));
+    with_nops.finalize();
+    let stripped = generate_code_with_options(&with_nops, &CodeGenOptions::default()).unwrap();
+    assert_eq!(stripped.code(), plain.code());
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(f.call(5), 19);
+}
+
+/// Whether `bytes` is nothing but the recommended multi-byte nops
+fn is_all_nops(mut bytes: &[u8]) -> bool {
+    const NOPS: [&[u8]; 9] = [
+        &[0x90],
+        &[0x66, 0x90],
+        &[0x0F, 0x1F, 0x00],
+        &[0x0F, 0x1F, 0x40, 0x00],
+        &[0x0F, 0x1F, 0x44, 0x00, 0x00],
+        &[0x66, 0x0F, 0x1F, 0x44, 0x00, 0x00],
+        &[0x0F, 0x1F, 0x80, 0x00, 0x00, 0x00, 0x00],
+        &[0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+        &[0x66, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    ];
+    while !bytes.is_empty() {
+        match NOPS.iter().rev().find(|nop| bytes.starts_with(nop)) {
+            Some(nop) => bytes = &bytes[nop.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+#[test]
+fn functions_are_aligned_and_padded_with_nops() {
+    let mut first = affine(3, 1);
+    let mut second = affine(5, 2);
+    first.finalize();
+    second.finalize();
+    let functions = [("first", &first), ("second", &second)];
+    let generate = |function_alignment| {
+        let options = CodeGenOptions {
+            function_alignment,
+            ..Default::default()
+        };
+        generate_entry_points(&functions, &options).unwrap()
+    };
+
+    // packed together, the second starts where the first ends
+    let packed = generate(1);
+    let first_len = packed.entries["second"].0 - packed.entries["first"].0;
+
+    let aligned = generate(16);
+    let start = aligned.entries["first"].0;
+    let second_start = aligned.entries["second"].0;
+    assert_eq!(start % 16, 0);
+    assert_eq!(second_start % 16, 0);
+    let gap = &aligned.buffer[start + first_len..second_start];
+    assert!(!gap.is_empty() && gap.len() < 16, "{} byte gap", gap.len());
+    assert!(is_all_nops(gap), "{:02x?}", gap);
+
+    let f: extern "C" fn(u64) -> u64 = unsafe { aligned.function("second") }.unwrap();
+    assert_eq!(f(4), 22);
+}
+
+#[test]
+fn function_alignment_must_be_a_power_of_two() {
+    let mut ctx = affine(3, 1);
+    ctx.finalize();
+    for function_alignment in [0, 3].iter().copied() {
+        let options = CodeGenOptions {
+            function_alignment,
+            ..Default::default()
+        };
+        let error = generate_code_with_options(&ctx, &options).unwrap_err();
+        assert!(
+            matches!(error.reason(), CodeGenErrorReason::UnsupportedOptions(_)),
+            "{}: {:?}",
+            function_alignment,
+            error
+        );
+        let functions = [("f", &ctx)];
+        let error = generate_entry_points(&functions, &options).unwrap_err();
+        assert!(matches!(
+            error.reason(),
+            CodeGenErrorReason::UnsupportedOptions(_)
+        ));
+    }
+}
+
+#[test]
+fn cold_blocks_are_laid_out_last() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let error = ctx.new_basic_block();
+    let hot = ctx.new_basic_block();
+    let done = ctx.new_basic_block();
+    ctx.build_basic_block(entry).jump_if_equal(x, error, hot);
+    let error_bb = ctx.build_basic_block(error);
+    error_bb.set_cold(true);
+    error_bb.ret_value(Value::u64(99));
+    let hot_bb = ctx.build_basic_block(hot);
+    let doubled = hot_bb.add(x, x);
+    hot_bb.jump(done);
+    ctx.build_basic_block(done).ret_value(doubled);
+    ctx.finalize();
+
+    let code = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
+    let error_start = code.block_ranges[&error].0;
+    for block in [entry, hot, done] {
+        assert!(code.block_ranges[&block].1 <= error_start);
+    }
+
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(f.call(0), 99);
+    assert_eq!(f.call(4), 8);
+}
+
+/// `rdtsc`, with the 64 bit cycle count put together in rax and rdx left as
+/// it was
+const READ_CYCLE_COUNTER: &[u8] = &[
+    0x48, 0x89, 0xD1, // mov rcx, rdx
+    0x0F, 0x31, // rdtsc
+    0x48, 0xC1, 0xE2, 0x20, // shl rdx, 32
+    0x48, 0x09, 0xD0, // or rax, rdx
+    0x48, 0x89, 0xCA, // mov rdx, rcx
+];
+
+#[test]
+fn inline_code_reads_the_cycle_counter() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    // enough values to use every register, rdx included, across the code
+    let values = (1..=9)
+        .map(|i| bb.add(x, Value::u64(i)))
+        .collect::<Vec<_>>();
+    let start = bb.inline_bytes(READ_CYCLE_COUNTER, &[], 1)[0];
+    let end = bb.inline_bytes(READ_CYCLE_COUNTER, &[], 1)[0];
+    let elapsed = bb.subtract(end, start);
+    let result = values.into_iter().fold(elapsed, |a, b| bb.add(a, b));
+    bb.ret_value(result);
+    ctx.finalize();
+    let code = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
+    assert!(code
+        .register_map
+        .values()
+        .any(|mr| *mr == MachineRegister::Rdx));
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+
+    let before = unsafe { std::arch::x86_64::_rdtsc() };
+    let result = f.call(100);
+    let after = unsafe { std::arch::x86_64::_rdtsc() };
+    let elapsed = result - (9 * 100 + 45);
+    assert!(elapsed <= after - before, "{} cycles", elapsed);
+}
+
+#[test]
+fn jumping_to_a_block_from_another_context_is_an_error() {
+    let mut other = Context::new();
+    let stranger = (0..4).map(|_| other.new_basic_block()).last().unwrap();
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    ctx.build_basic_block(entry).jump(stranger);
+    ctx.finalize();
+
+    assert_eq!(
+        ctx.validate().unwrap_err(),
+        vec![shiba_jit::validate::ValidationError::UnknownBlock {
+            block: entry,
+            index: 0,
+            target: stranger,
+        }]
+    );
+    let error = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap_err();
+    assert!(
+        matches!(error.reason(), CodeGenErrorReason::UnknownBlock(b) if *b == stranger),
+        "{:?}",
+        error
+    );
+}
+
+#[test]
+fn contexts_must_be_finalized_before_generating_code() {
+    let not_finalized = |ctx: &Context| {
+        let error = generate_code_with_options(ctx, &CodeGenOptions::default()).unwrap_err();
+        assert!(
+            matches!(error.reason(), CodeGenErrorReason::NotFinalized),
+            "{:?}",
+            error
+        );
+        assert!(generate_code(ctx).is_err());
+    };
+    let mut ctx = conditional_print();
+    assert!(!ctx.is_finalized());
+    not_finalized(&ctx);
+
+    ctx.finalize();
+    let code = generate_code_with_options(&ctx, &capturing(CodeGenOptions::default())).unwrap();
+    let f: JitFunction<extern "C" fn()> = unsafe { code.into_function() };
+    assert_eq!(capture_output(|| f.call()), CONDITIONAL_PRINT_OUTPUT);
+
+    // changing it afterwards needs another finalize
+    let block = ctx.new_basic_block();
+    ctx.build_basic_block(block).ret();
+    not_finalized(&ctx);
+    ctx.finalize();
+    assert!(generate_code(&ctx).is_ok());
+}
+
+#[test]
+fn lowering_hook_sees_each_instruction_once_in_order() {
+    let mut ctx = conditional_print();
+    ctx.finalize();
+    let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let recorder = seen.clone();
+    let options = CodeGenOptions {
+        on_lower_instruction: Some(InstructionHook::new(move |block, index, inst, offset| {
+            let inst = format!("{:?}", inst);
+            recorder
+                .lock()
+                .unwrap()
+                .push((block, index, inst, offset.0));
+        })),
+        ..capturing(CodeGenOptions::default())
+    };
+    let code = generate_code_with_options(&ctx, &options).unwrap();
+    let seen = seen.lock().unwrap();
+
+    // every instruction of every block, blocks in the order they're laid out
+    let mut blocks = code.block_ranges.iter().collect::<Vec<_>>();
+    blocks.sort_by_key(|(_, (start, _))| start.0);
+    let expected = blocks
+        .iter()
+        .flat_map(|(block, _)| {
+            ctx.iter_instructions()
+                .filter(move |(b, _, _)| b == *block)
+                .map(|(b, index, inst)| (b, index, format!("{:?}", inst)))
+        })
+        .collect::<Vec<_>>();
+    let called = seen
+        .iter()
+        .map(|(block, index, inst, _)| (*block, *index, inst.clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(called, expected);
+    for window in seen.windows(2) {
+        assert!(window[0].3 <= window[1].3);
+    }
+    for (block, _, _, offset) in seen.iter() {
+        let (start, end) = code.block_ranges[block];
+        assert!(start.0 <= *offset && *offset <= end.0);
+    }
+
+    let f: JitFunction<extern "C" fn()> = unsafe { code.into_function() };
+    assert_eq!(capture_output(|| f.call()), CONDITIONAL_PRINT_OUTPUT);
+}
+
+#[test]
+fn offsets_cover_every_instruction_in_order() {
+    let mut ctx = conditional_print();
+    ctx.finalize();
+    let code = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
+    assert!(code.instruction_offsets.is_none());
+
+    let code = generate_recording_offsets(&mut ctx);
+    assert_offsets_cover_every_instruction(&ctx, &code);
+    let f: JitFunction<extern "C" fn()> = unsafe { code.into_function() };
+    assert_eq!(capture_output(|| f.call()), CONDITIONAL_PRINT_OUTPUT);
+}
+
+/// `1` if `x < y` and `2` if not.  If `reuse`, the `1` is the comparison's
+/// result, so it has to be materialized.
+fn less_than(reuse: bool) -> Context {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let y = ctx.add_parameter(PrimitiveValue::U64);
+    let not_less = ctx.new_basic_block();
+    let less = ctx.new_basic_block();
+    let bb = ctx.build_basic_block(entry);
+    let is_less = bb.compare(Comparison::Less, x, y);
+    bb.jump_if_equal(is_less, not_less, less);
+    ctx.build_basic_block(not_less).ret_value(Value::u32(2));
+    let one = if reuse { is_less } else { Value::u32(1) };
+    ctx.build_basic_block(less).ret_value(one);
+    ctx
+}
+
+/// Whether `code` has a `setcc` of the low byte of a register
+fn has_setcc(code: &[u8]) -> bool {
+    code.windows(2)
+        .any(|w| w[0] == 0x0F && (0x90..=0x9F).contains(&w[1]))
+}
+
+#[test]
+fn comparison_branched_on_right_away_is_fused() {
+    for reuse in [false, true] {
+        let mut ctx = less_than(reuse);
+        let entry = ctx.entry();
+        let code = generate_recording_offsets(&mut ctx);
+        // after the two parameters
+        let compare = instruction_code(&code, entry, 2);
+        assert_eq!(has_setcc(compare), reuse, "{:02x?}", compare);
+
+        let f: JitFunction<extern "C" fn(u64, u64) -> u32> = unsafe { code.into_function() };
+        assert_eq!(f.call(3, 4), 1);
+        assert_eq!(f.call(4, 4), 2);
+        assert_eq!(f.call(5, 4), 2);
+    }
+}
+
+#[test]
+fn unreachable_blocks_are_valid_and_emit_ud2() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let never = ctx.new_basic_block();
+    let fine = ctx.new_basic_block();
+    // only ever called with non-zero arguments
+    ctx.build_basic_block(entry).jump_if_equal(x, never, fine);
+    ctx.build_basic_block(never).unreachable();
+    let bb = ctx.build_basic_block(fine);
+    let doubled = bb.add(x, x);
+    bb.ret_value(doubled);
+    ctx.finalize();
+    assert_eq!(ctx.validate(), Ok(()));
+
+    let code = generate_recording_offsets(&mut ctx);
+    assert_eq!(instruction_code(&code, never, 0), [0x0F, 0x0B]);
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(f.call(5), 10);
+}
+
+/// Print `greeting` and then `x`
+fn greet_with(greeting: &[u8]) -> Context {
+    let mut ctx = Context::new();
+    let greeting = ctx.add_constant(greeting);
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    bb.push_instruction(IR::PrintConstant {
+        constant_ref: greeting,
+    });
+    bb.print_int(x, PrimitiveValue::U64);
+    bb.ret();
+    ctx
+}
+
+#[test]
+fn shared_constants_are_emitted_once() {
+    const SHARED: &[u8] = b"A greeting for everyone\n";
+    let mut first = greet_with(SHARED);
+    let mut second = greet_with(SHARED);
+    first.finalize();
+    second.finalize();
+    let functions = [("first", &first), ("second", &second)];
+    let code = generate_entry_points(&functions, &capturing(CodeGenOptions::default())).unwrap();
+    let copies = code
+        .buffer
+        .windows(SHARED.len())
+        .filter(|w| *w == SHARED)
+        .count();
+    assert_eq!(copies, 1);
+
+    let first: extern "C" fn(u64) = unsafe { code.function("first") }.unwrap();
+    let second: extern "C" fn(u64) = unsafe { code.function("second") }.unwrap();
+    assert_eq!(
+        capture_output(|| {
+            first(1);
+            second(2);
+        }),
+        "A greeting for everyone\n1\nA greeting for everyone\n2\n"
+    );
+}
+
+/// Returns 1 if `x` is zero and `x + 2` if not, with the block for zero
+/// created last
+fn is_zero_with_hint(hint: Option<BranchHint>) -> (Context, [BasicBlockIndex; 3]) {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let nonzero = ctx.new_basic_block();
+    let zero = ctx.new_basic_block();
+    ctx.build_basic_block(entry)
+        .jump_if_equal_with_hint(x, zero, nonzero, hint);
+    let bb = ctx.build_basic_block(nonzero);
+    let plus_two = bb.add(x, Value::u64(2));
+    bb.ret_value(plus_two);
+    ctx.build_basic_block(zero).ret_value(Value::u64(1));
+    ctx.finalize();
+    (ctx, [entry, nonzero, zero])
+}
+
+#[test]
+fn likely_target_is_fallen_through_to() {
+    for (hint, fallen_through) in [(None, 1), (Some(BranchHint::TrueLikely), 2)] {
+        let (ctx, blocks) = is_zero_with_hint(hint);
+        let code = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
+        let entry_end = code.block_ranges[&blocks[0]].1;
+        assert_eq!(
+            code.block_ranges[&blocks[fallen_through]].0, entry_end,
+            "{:?}",
+            hint
+        );
+        let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+        assert_eq!(f.call(0), 1);
+        assert_eq!(f.call(5), 7);
+    }
+}
+
+#[test]
+fn compiling_twice_gives_identical_bytes() {
+    let build = || {
+        let mut first = greet_with(b"first\n");
+        let mut second = affine(5, 2);
+        first.finalize();
+        second.finalize();
+        (first, second)
+    };
+    for padding in [Padding::Nops, Padding::Byte(0xCC)] {
+        let options = CodeGenOptions {
+            padding,
+            ..Default::default()
+        };
+        let compile = || {
+            let (first, second) = build();
+            let functions = [("first", &first), ("second", &second)];
+            generate_entry_points(&functions, &options).unwrap()
+        };
+        let (once, twice) = (compile(), compile());
+        assert_eq!(once.buffer[..], twice.buffer[..]);
+        assert_eq!(once.entries, twice.entries);
+
+        // the gap before the second function is filled as asked
+        let second = once.entries["second"].0;
+        // after the first function's return
+        let end = once.buffer[..second]
+            .iter()
+            .rposition(|b| *b == 0xC3)
+            .unwrap()
+            + 1;
+        let gap = &once.buffer[end..second];
+        assert!(!gap.is_empty());
+        match padding {
+            Padding::Byte(byte) => assert!(gap.iter().all(|b| *b == byte), "{:02x?}", gap),
+            Padding::Nops => assert!(is_all_nops(gap), "{:02x?}", gap),
+        }
+    }
+}
+
+/// Returns `constant` when `x` is zero, and `x + 100` otherwise
+fn constant_or_offset(constant: u64) -> (Context, BasicBlockIndex, BasicBlockIndex) {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let zero = ctx.new_basic_block();
+    let other = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    ctx.build_basic_block(entry).jump_if_equal(x, zero, other);
+    ctx.build_basic_block(zero).ret_value(Value::u64(constant));
+    let bb = ctx.build_basic_block(other);
+    let offset = bb.add(x, Value::u64(100));
+    bb.ret_value(offset);
+    ctx.finalize();
+    (ctx, zero, other)
+}
+
+/// The bytes of each block other than `block`
+fn other_blocks(code: &GeneratedCode, block: BasicBlockIndex) -> Vec<Vec<u8>> {
+    code.block_ranges
+        .iter()
+        .filter(|(b, _)| **b != block)
+        .map(|(_, (start, end))| code.code()[start.0..end.0].to_vec())
+        .collect()
+}
+
+#[test]
+fn recompiled_block_is_patched_in_place() {
+    let (mut ctx, zero, _) = constant_or_offset(10);
+    let options = CodeGenOptions {
+        block_patch_room: 16,
+        ..CodeGenOptions::default()
+    };
+    let code = generate_code_with_options(&ctx, &options).unwrap();
+    let mut f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(f.call(0), 10);
+    assert_eq!(f.call(5), 105);
+    let before = other_blocks(f.code(), zero);
+    let start = f.code().code().as_ptr();
+
+    for inst in ctx.build_basic_block(zero).instructions_mut() {
+        if let IR::ReturnValue { value } = inst {
+            *value = Value::u64(0x1234_5678_9ABC);
+        }
+    }
+    ctx.finalize();
+    f.recompile_block(&ctx, zero, &options).unwrap();
+
+    assert_eq!(f.call(0), 0x1234_5678_9ABC);
+    assert_eq!(f.call(5), 105);
+    assert_eq!(other_blocks(f.code(), zero), before);
+    assert_eq!(f.code().code().as_ptr(), start);
+}
+
+#[test]
+fn patch_that_does_not_fit_leaves_the_code_alone() {
+    let (mut ctx, zero, _) = constant_or_offset(10);
+    let options = CodeGenOptions::default();
+    let code = generate_code_with_options(&ctx, &options).unwrap();
+    let mut f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    let before = f.code().code().to_vec();
+
+    // a 64 bit immediate takes more bytes than a small one
+    for inst in ctx.build_basic_block(zero).instructions_mut() {
+        if let IR::ReturnValue { value } = inst {
+            *value = Value::u64(u64::MAX / 3);
+        }
+    }
+    ctx.finalize();
+    let error = f.recompile_block(&ctx, zero, &options).unwrap_err();
+
+    assert!(
+        matches!(error.reason(), CodeGenErrorReason::PatchTooLarge { .. }),
+        "{:?}",
+        error
+    );
+    assert_eq!(f.code().code(), &before[..]);
+    assert_eq!(f.call(0), 10);
+    assert_eq!(f.call(5), 105);
+}
+
+#[test]
+fn patch_needing_new_constants_is_refused_even_if_they_fit_the_padding() {
+    let mut ctx = Context::new();
+    let hi = ctx.add_constant(b"HI\n");
+    let entry = ctx.new_basic_block();
+    let bb = ctx.build_basic_block(entry);
+    bb.push_instruction(IR::PrintConstant { constant_ref: hi });
+    bb.ret();
+    ctx.finalize();
+    let options = CodeGenOptions {
+        block_patch_room: 16,
+        ..capturing(CodeGenOptions::default())
+    };
+    let code = generate_code_with_options(&ctx, &options).unwrap();
+    let mut f: JitFunction<extern "C" fn()> = unsafe { code.into_function() };
+    let before = f.code().code().to_vec();
+
+    // the new constant still fits before the aligned start of the function
+    let bye = ctx.add_constant(b"BYE\n");
+    for inst in ctx.build_basic_block(entry).instructions_mut() {
+        if let IR::PrintConstant { constant_ref } = inst {
+            *constant_ref = bye;
+        }
+    }
+    ctx.finalize();
+    let start = generate_code_with_options(&ctx, &options).unwrap().start;
+    assert_eq!(start, f.code().start);
+    let error = f.recompile_block(&ctx, entry, &options).unwrap_err();
+
+    assert!(
+        matches!(
+            error.reason(),
+            CodeGenErrorReason::CantPatchBlock("the constants changed")
+        ),
+        "{:?}",
+        error
+    );
+    assert_eq!(f.code().code(), &before[..]);
+    assert_eq!(capture_output(|| f.call()), "HI\n");
+}
+
+/// The code between the start of the function and its entry block
+fn prologue(code: &GeneratedCode, entry: BasicBlockIndex) -> &[u8] {
+    let (entry_start, _) = code.block_ranges[&entry];
+    &code.buffer[code.start.0..entry_start.0]
+}
+
+/// Whether `code` has a `sub rsp, imm`, with an 8 or 32 bit immediate
+fn has_sub_rsp(code: &[u8]) -> bool {
+    code.windows(3)
+        .any(|w| matches!(w, [0x48, 0x83 | 0x81, 0xEC]))
+}
+
+#[test]
+fn leaf_function_without_slots_does_not_move_rsp() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let doubled = bb.add(x, x);
+    bb.ret_value(doubled);
+    ctx.finalize();
+    let code = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
+
+    assert_eq!(code.frame_layout.frame_size, 0);
+    assert!(
+        !has_sub_rsp(prologue(&code, entry)),
+        "{:02x?}",
+        prologue(&code, entry)
+    );
+    assert!(!has_sub_rsp(code.code()), "{:02x?}", code.code());
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(f.call(21), 42);
+}
+
+/// How far rsp was from being 16 byte aligned each time
+/// [`record_alignment`] was called
+static MISALIGNMENT: std::sync::Mutex<Vec<u64>> = std::sync::Mutex::new(Vec::new());
+
+extern "C" fn record_alignment() {
+    let rsp: u64;
+    // rsp is aligned on the way into asm when the function was entered with
+    // it aligned for a call
+    unsafe { std::arch::asm!("mov {}, rsp", out(reg) rsp) };
+    MISALIGNMENT.lock().unwrap().push(rsp % 16);
+}
+
+#[test]
+fn rsp_is_aligned_for_calls_whatever_the_frame_holds() {
+    for slots in 0..4 {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let x = ctx.add_parameter(PrimitiveValue::U64);
+        let bb = ctx.build_basic_block(entry);
+        let mut sum = x;
+        for _ in 0..slots {
+            let slot = bb.alloca(PrimitiveValue::U64, 8);
+            bb.store(slot, x);
+            let loaded = bb.load(slot);
+            sum = bb.add(sum, loaded);
+        }
+        bb.call_host(
+            record_alignment as *const () as usize,
+            "record_alignment",
+            &[],
+        );
+        bb.ret_value(sum);
+        ctx.finalize();
+        let code = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
+        assert_eq!(code.frame_layout.slots.len(), slots);
+        let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+
+        assert_eq!(f.call(3), 3 * (slots as u64 + 1));
+        assert_eq!(
+            std::mem::take(&mut *MISALIGNMENT.lock().unwrap()),
+            [0],
+            "{} slots",
+            slots
+        );
+    }
+}
@@ -0,0 +1,149 @@
+//! Helpers shared by the tests that run generated code
+#![allow(dead_code)]
+
+use shiba_jit::{codegen::x86_64::*, ir::*};
+use std::cell::RefCell;
+
+/// Finalize `ctx` and generate code for it with the default options
+pub fn compile<F: Copy>(ctx: &mut Context) -> JitFunction<F> {
+    compile_with(ctx, &CodeGenOptions::default())
+}
+
+/// Finalize `ctx` and generate code for it with `options`
+pub fn compile_with<F: Copy>(ctx: &mut Context, options: &CodeGenOptions) -> JitFunction<F> {
+    ctx.finalize();
+    let code = generate_code_with_options(ctx, &capturing(options.clone())).unwrap();
+    unsafe { code.into_function() }
+}
+
+/// Finalize `ctx` and generate code for it, recording where each
+/// instruction's code starts
+pub fn generate_recording_offsets(ctx: &mut Context) -> GeneratedCode {
+    generate_recording_offsets_with(ctx, CodeGenOptions::default())
+}
+
+/// Like [`generate_recording_offsets`], but with the rest of `options`
+pub fn generate_recording_offsets_with(
+    ctx: &mut Context,
+    options: CodeGenOptions,
+) -> GeneratedCode {
+    ctx.finalize();
+    let options = CodeGenOptions {
+        record_instruction_offsets: true,
+        ..capturing(options)
+    };
+    generate_code_with_options(ctx, &options).unwrap()
+}
+
+/// The machine code for the `index`th instruction of `block`, from code
+/// generated by [`generate_recording_offsets`]
+pub fn instruction_code(code: &GeneratedCode, block: BasicBlockIndex, index: usize) -> &[u8] {
+    let offsets = code.instruction_offsets.as_ref().unwrap();
+    let position = offsets
+        .iter()
+        .position(|(b, i, _)| (*b, *i) == (block, index))
+        .expect("the instruction wasn't emitted");
+    let start = offsets[position].2;
+    let end = match offsets.get(position + 1) {
+        Some((next_block, _, next)) if *next_block == block => *next,
+        _ => code.block_ranges[&block].1,
+    };
+    &code.buffer[start.0..end.0]
+}
+
+/// Check that the offsets from [`generate_recording_offsets`] have every
+/// instruction of `ctx` exactly once, block by block in the order they're
+/// laid out, and only ever go forward
+pub fn assert_offsets_cover_every_instruction(ctx: &Context, code: &GeneratedCode) {
+    let offsets = code.instruction_offsets.as_ref().unwrap();
+    let mut blocks = code.block_ranges.iter().collect::<Vec<_>>();
+    blocks.sort_by_key(|(_, (start, _))| start.0);
+    let expected = blocks
+        .iter()
+        .flat_map(|(block, _)| {
+            ctx.iter_instructions()
+                .filter(move |(b, _, _)| b == *block)
+                .map(|(b, index, _)| (b, index))
+        })
+        .collect::<Vec<_>>();
+    let recorded = offsets
+        .iter()
+        .map(|(block, index, _)| (*block, *index))
+        .collect::<Vec<_>>();
+    assert_eq!(recorded, expected);
+    for window in offsets.windows(2) {
+        assert!(window[0].2 .0 <= window[1].2 .0, "{:?}", window);
+    }
+    for (block, _, offset) in offsets {
+        let (start, end) = code.block_ranges[block];
+        assert!(start.0 <= offset.0 && offset.0 <= end.0);
+    }
+}
+
+/// The program from `examples/conditional_print.rs`, which prints "Hello,
+/// world" four times in a loop and then "Goodbye, world", not finalized
+pub fn conditional_print() -> Context {
+    let mut ctx = Context::new();
+    let hello_world_const = ctx.add_constant(b"Hello, world\n");
+    let end_const = ctx.add_constant(b"Goodbye, world\n");
+    let prog_start = ctx.new_basic_block();
+    let loop_inner = ctx.new_basic_block();
+    let loop_outer = ctx.new_basic_block();
+    let loop_exit = ctx.new_basic_block();
+
+    let prog_start_bb = ctx.build_basic_block(prog_start);
+    let counter = prog_start_bb.alloca(PrimitiveValue::U32, 4);
+    prog_start_bb.store(counter, Value::u32(0));
+    prog_start_bb.then(loop_inner);
+
+    let inner_bb = ctx.build_basic_block(loop_inner);
+    inner_bb.push_instruction(IR::PrintConstant {
+        constant_ref: hello_world_const,
+    });
+    let loaded_counter = inner_bb.load(counter);
+    let add_result = inner_bb.add(loaded_counter, Value::u32(1));
+    inner_bb.store(counter, add_result);
+    let sub_result = inner_bb.subtract(Value::u32(4), add_result);
+
+    ctx.build_basic_block(loop_outer)
+        .add_parent(loop_inner)
+        .jump_if_equal(sub_result, loop_exit, loop_inner);
+
+    let loop_exit_bb = ctx.build_basic_block(loop_exit);
+    loop_exit_bb
+        .add_parent(loop_outer)
+        .push_instruction(IR::PrintConstant {
+            constant_ref: end_const,
+        });
+    loop_exit_bb.ret();
+    ctx
+}
+
+/// What [`conditional_print`] prints
+pub const CONDITIONAL_PRINT_OUTPUT: &str =
+    "Hello, world\nHello, world\nHello, world\nHello, world\nGoodbye, world\n";
+
+thread_local! {
+    /// What code generated with [`capturing`] options printed on this thread
+    static OUTPUT: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// `options` with a print handler recording what the code prints for
+/// [`capture_output`], unless they already have one
+pub fn capturing(options: CodeGenOptions) -> CodeGenOptions {
+    let record = |output: &[u8]| OUTPUT.with(|o| o.borrow_mut().extend_from_slice(output));
+    CodeGenOptions {
+        print_handler: options
+            .print_handler
+            .or_else(|| Some(PrintHandler::new(record))),
+        ..options
+    }
+}
+
+/// Run `f`, returning what code generated with [`capturing`] options printed
+/// while it ran
+pub fn capture_output(f: impl FnOnce()) -> String {
+    OUTPUT.with(|o| o.borrow_mut().clear());
+    f();
+    String::from_utf8(OUTPUT.with(|o| o.take())).unwrap()
+}
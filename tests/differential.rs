@@ -0,0 +1,188 @@
+//! Generated code should do what the interpreter does
+mod common;
+
+use common::*;
+use shiba_jit::{codegen::x86_64::*, ir::interp, ir::*};
+use std::sync::Mutex;
+
+#[allow(dead_code)]
+#[path = "../fuzz/fuzz_targets/program.rs"]
+mod program;
+
+static TRAPPED: Mutex<Option<u64>> = Mutex::new(None);
+/// Held while the trap handler is set
+static TRAPPING: Mutex<()> = Mutex::new(());
+
+fn record_trap(code: u64) {
+    *TRAPPED.lock().unwrap() = Some(code);
+}
+
+/// Run `ctx` with `args` in the interpreter and as generated code, checking
+/// they return the same value, trap the same way, and print the same thing.
+/// Programs the interpreter can't finish aren't run.
+fn check(ctx: &Context, args: &[u64]) {
+    let expected = match interp::run_with_arguments(ctx, args) {
+        Ok(expected) => expected,
+        Err(_) => return,
+    };
+    let code = generate_code_with_options(ctx, &capturing(CodeGenOptions::default())).unwrap();
+    let f: JitFunction<extern "C" fn(u64, u64) -> u64> = unsafe { code.into_function() };
+    let mut returned = 0;
+    let mut trapped = None;
+    // the trap handler is global
+    let _trapping = TRAPPING.lock().unwrap_or_else(|e| e.into_inner());
+    let output = capture_output(|| {
+        set_trap_handler(Some(record_trap));
+        *TRAPPED.lock().unwrap() = None;
+        returned = f.call(
+            args.first().copied().unwrap_or(0),
+            args.get(1).copied().unwrap_or(0),
+        );
+        trapped = TRAPPED.lock().unwrap().take();
+        set_trap_handler(None);
+    });
+
+    assert_eq!(trapped, expected.trap, "trap codes differ for {:?}", args);
+    assert_eq!(
+        output.as_bytes(),
+        expected.output,
+        "output differs for {:?}",
+        args
+    );
+    if let (None, Some(value), Some(_type)) = (trapped, expected.return_value, expected.return_type)
+    {
+        assert_eq!(
+            interp::truncate(returned, _type),
+            value,
+            "return values differ for {:?}",
+            args
+        );
+    }
+}
+
+#[test]
+fn example_prints_the_same() {
+    let mut ctx = conditional_print();
+    ctx.finalize();
+    let expected = interp::run(&ctx).unwrap();
+    assert_eq!(expected.output, CONDITIONAL_PRINT_OUTPUT.as_bytes());
+    check(&ctx, &[]);
+}
+
+#[test]
+fn arithmetic_matches() {
+    const OPS: [fn(&mut BasicBlock, Value, Value) -> Value; 7] = [
+        BasicBlock::add,
+        BasicBlock::subtract,
+        BasicBlock::multiply,
+        BasicBlock::divide,
+        BasicBlock::remainder,
+        BasicBlock::shift_left,
+        BasicBlock::shift_right,
+    ];
+    let types = [
+        PrimitiveValue::U8,
+        PrimitiveValue::I8,
+        PrimitiveValue::U32,
+        PrimitiveValue::I32,
+        PrimitiveValue::U64,
+        PrimitiveValue::I64,
+    ];
+    let values = [
+        0,
+        1,
+        2,
+        3,
+        7,
+        31,
+        0x80,
+        0xFF,
+        0x8000_0000,
+        u64::MAX,
+        u64::MAX - 6,
+    ];
+    for _type in types {
+        for op in OPS {
+            let mut ctx = Context::new();
+            let entry = ctx.new_basic_block();
+            let x = ctx.add_parameter(_type);
+            let y = ctx.add_parameter(_type);
+            let bb = ctx.build_basic_block(entry);
+            let result = op(bb, x, y);
+            bb.ret_value(result);
+            ctx.finalize();
+            for a in values {
+                for b in values {
+                    let args = [interp::truncate(a, _type), interp::truncate(b, _type)];
+                    check(&ctx, &args);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn loops_and_branches_match() {
+    // prints `i % m` for each `i` below `n`, then returns `n`
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let n = ctx.add_parameter(PrimitiveValue::U32);
+    let m = ctx.add_parameter(PrimitiveValue::U32);
+    let bb = ctx.build_basic_block(entry);
+    let i = bb.alloca(PrimitiveValue::U32, 4);
+    bb.store(i, Value::u32(0));
+    let exit = ctx.build_while(
+        entry,
+        |header| {
+            let i = header.load(i);
+            header.compare(Comparison::Less, i, n)
+        },
+        |body| {
+            let value = body.load(i);
+            let rest = body.remainder(value, m);
+            let next = body.add(value, Value::u32(1));
+            body.store(i, next);
+            body.print_int(rest, PrimitiveValue::U32);
+        },
+    );
+    let bb = ctx.build_basic_block(exit);
+    let counted = bb.load(i);
+    bb.ret_value(counted);
+    ctx.finalize();
+    assert_eq!(
+        interp::run_with_arguments(&ctx, &[10, 3]).unwrap().output,
+        b"0\n1\n2\n0\n1\n2\n0\n1\n2\n0\n"
+    );
+    for (n, m) in [(0, 1), (1, 1), (10, 3), (17, 5), (5, 0)] {
+        check(&ctx, &[n, m]);
+    }
+}
+
+#[test]
+fn random_programs_match() {
+    for entry in std::fs::read_dir(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/fuzz/corpus/differential"
+    ))
+    .unwrap()
+    {
+        let data = std::fs::read(entry.unwrap().path()).unwrap();
+        check(&program::build_context(&data), &[]);
+    }
+    // xorshift, so failures can be reproduced
+    let mut state = 0x9E37_79B9_7F4A_7C15u64;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    for _ in 0..500 {
+        let len = next() as usize % 200;
+        let data = (0..len).map(|_| next() as u8).collect::<Vec<_>>();
+        let ctx = program::build_context(&data);
+        if generate_code_with_options(&ctx, &CodeGenOptions::default()).is_ok() {
+            check(&ctx, &[]);
+        }
+    }
+}
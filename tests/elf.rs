@@ -0,0 +1,174 @@
+//! Linking the object files from `emit_elf` into a C program
+use shiba_jit::{
+    codegen::{elf::emit_elf, x86_64::*},
+    ir::*,
+};
+use std::process::Command;
+
+/// Calls the generated function, providing the host functions it calls
+const DRIVER: &str = r#"
+#include <stdint.h>
+#include <stdio.h>
+
+uint64_t greet(uint64_t x);
+
+void shiba_jit_guest_print(const char *buffer, uint64_t len) {
+    fwrite(buffer, 1, len, stdout);
+}
+
+void shiba_jit_guest_print_unsigned(uint64_t value) {
+    printf("%llu\n", (unsigned long long)value);
+}
+
+int main(void) {
+    printf("returned %llu\n", (unsigned long long)greet(5));
+    return 0;
+}
+"#;
+
+#[test]
+fn linked_object_runs() {
+    let mut ctx = Context::new();
+    let hello = ctx.add_constant(b"Hello, world\n");
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    bb.push_instruction(IR::PrintConstant {
+        constant_ref: hello,
+    });
+    let product = bb.multiply(x, Value::u64(3));
+    bb.print_int(product, PrimitiveValue::U64);
+    let sum = bb.add(product, Value::u64(4));
+    bb.ret_value(sum);
+    ctx.finalize();
+    let object = emit_elf(&ctx, "greet").unwrap();
+
+    let dir = std::env::temp_dir().join(format!("shiba-jit-elf-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("greet.o"), object).unwrap();
+    std::fs::write(dir.join("main.c"), DRIVER).unwrap();
+    let status = Command::new("cc")
+        .current_dir(&dir)
+        .args(["-o", "greet", "main.c", "greet.o"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let output = Command::new(dir.join("greet")).output().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Hello, world\n15\nreturned 19\n"
+    );
+}
+
+extern "C" fn host_double(x: u64) -> u64 {
+    x * 2
+}
+
+/// Read a little endian integer of `N` bytes at `at`
+fn read<const N: usize>(bytes: &[u8], at: usize) -> u64 {
+    let mut value = [0; 8];
+    value[..N].copy_from_slice(&bytes[at..at + N]);
+    u64::from_le_bytes(value)
+}
+
+/// The sections of an ELF64 object, by name
+fn sections(object: &[u8]) -> std::collections::HashMap<String, &[u8]> {
+    let header = |index: u64| (read::<8>(object, 0x28) + index * read::<2>(object, 0x3A)) as usize;
+    let contents = |index: u64| {
+        let at = header(index);
+        let (offset, size) = (read::<8>(object, at + 0x18), read::<8>(object, at + 0x20));
+        &object[offset as usize..(offset + size) as usize]
+    };
+    let names = contents(read::<2>(object, 0x3E));
+    (0..read::<2>(object, 0x3C))
+        .map(|index| {
+            let name = &names[read::<4>(object, header(index)) as usize..];
+            let name = &name[..name.iter().position(|b| *b == 0).unwrap()];
+            (String::from_utf8(name.to_vec()).unwrap(), contents(index))
+        })
+        .collect()
+}
+
+#[test]
+fn host_calls_are_relocations_against_their_symbols() {
+    const R_X86_64_PLT32: u64 = 4;
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let doubled = bb.call_host_with_result(
+        host_double as *const () as usize,
+        "host_double",
+        &[x],
+        PrimitiveValue::U64,
+    );
+    bb.print_int(doubled, PrimitiveValue::U64);
+    bb.ret_value(doubled);
+    ctx.finalize();
+    let object = emit_elf(&ctx, "double_and_print").unwrap();
+    let sections = sections(&object);
+    let (text, rela, symtab, strtab) = (
+        sections[".text"],
+        sections[".rela.text"],
+        sections[".symtab"],
+        sections[".strtab"],
+    );
+
+    let mut called = vec![];
+    for entry in rela.chunks(24) {
+        let (offset, info) = (read::<8>(entry, 0) as usize, read::<8>(entry, 8));
+        if info & 0xFFFF_FFFF != R_X86_64_PLT32 {
+            continue;
+        }
+        let symbol = &symtab[(info >> 32) as usize * 24..];
+        let name = &strtab[read::<4>(symbol, 0) as usize..];
+        let name = &name[..name.iter().position(|b| *b == 0).unwrap()];
+        called.push(String::from_utf8(name.to_vec()).unwrap());
+        // a call rel32 with the displacement left for the linker
+        assert_eq!(text[offset - 1], 0xE8);
+        assert_eq!(text[offset..offset + 4], [0; 4]);
+        // the symbol is undefined, to be provided by what it's linked into
+        assert_eq!(read::<2>(symbol, 6), 0);
+    }
+    called.sort();
+    assert_eq!(called, ["host_double", "shiba_jit_guest_print_unsigned"]);
+
+    // the address of the function in this process isn't in the object, like
+    // it is in code generated to run here
+    let address = (host_double as *const () as usize).to_le_bytes();
+    let contains_address = |code: &[u8]| code.windows(8).any(|w| w == address);
+    assert!(!contains_address(&object));
+    let jit = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
+    assert!(contains_address(jit.code()));
+}
+
+#[test]
+fn position_independent_code_is_only_made_for_objects() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    bb.print_int(x, PrimitiveValue::U64);
+    bb.ret_value(x);
+    ctx.finalize();
+    let options = CodeGenOptions {
+        position_independent: true,
+        ..Default::default()
+    };
+
+    // the host call would jump to the displacement left for the linker
+    let error = generate_code_with_options(&ctx, &options).unwrap_err();
+    assert!(matches!(
+        error.reason(),
+        CodeGenErrorReason::UnsupportedOptions(_)
+    ));
+    let error = generate_entry_points(&[("f", &ctx)], &options).unwrap_err();
+    assert!(matches!(
+        error.reason(),
+        CodeGenErrorReason::UnsupportedOptions(_)
+    ));
+    assert!(emit_elf(&ctx, "print_and_return").is_ok());
+}
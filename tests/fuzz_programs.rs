@@ -0,0 +1,43 @@
+//! What the `codegen` fuzz target does, on its corpus and on a fixed batch of
+//! random inputs, so it's checked without `cargo fuzz`
+use shiba_jit::codegen::x86_64::generate_code;
+
+#[allow(dead_code)]
+#[path = "../fuzz/fuzz_targets/program.rs"]
+mod program;
+
+fn compile(data: &[u8]) {
+    let ctx = program::build_context(data);
+    // type errors and the like are reported, not panicked on
+    let _ = generate_code(&ctx);
+}
+
+#[test]
+fn corpus_compiles() {
+    let corpus = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus/codegen");
+    let mut entries = 0;
+    for entry in std::fs::read_dir(corpus).unwrap() {
+        let data = std::fs::read(entry.unwrap().path()).unwrap();
+        let ctx = program::build_context(&data);
+        assert!(generate_code(&ctx).is_ok());
+        entries += 1;
+    }
+    assert!(entries > 0);
+}
+
+#[test]
+fn random_programs_compile_without_panicking() {
+    // xorshift, so failures can be reproduced
+    let mut state = 0x2545_F491_4F6C_DD1Du64;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    for _ in 0..2000 {
+        let len = next() as usize % 200;
+        let data = (0..len).map(|_| next() as u8).collect::<Vec<_>>();
+        compile(&data);
+    }
+}
@@ -0,0 +1,158 @@
+//! Calling host functions from generated code
+mod common;
+
+use common::*;
+use shiba_jit::{codegen::x86_64::*, ir::*};
+use std::sync::Mutex;
+
+/// The bytes [`read_bytes`] was given
+static READ: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+extern "C" fn read_bytes(bytes: *const u8, len: u64) {
+    let bytes = unsafe { std::slice::from_raw_parts(bytes, len as usize) };
+    READ.lock().unwrap().extend_from_slice(bytes);
+}
+
+#[test]
+fn host_reads_a_constant_through_its_address() {
+    const GREETING: &[u8] = b"Hello from a constant";
+    let mut ctx = Context::new();
+    let greeting = ctx.add_constant(GREETING);
+    let entry = ctx.new_basic_block();
+    let bb = ctx.build_basic_block(entry);
+    let address = bb.constant_addr(greeting);
+    bb.call_host(
+        read_bytes as *const () as usize,
+        "read_bytes",
+        &[address, Value::u64(GREETING.len() as u64)],
+    );
+    bb.ret();
+    let f = compile::<extern "C" fn()>(&mut ctx);
+
+    f.call();
+    assert_eq!(std::mem::take(&mut *READ.lock().unwrap()), GREETING);
+}
+
+/// The arguments [`record_pair`] was called with
+static PAIR: Mutex<Option<(u64, u64)>> = Mutex::new(None);
+
+extern "C" fn record_pair(a: u64, b: u64) {
+    *PAIR.lock().unwrap() = Some((a, b));
+}
+
+#[test]
+fn values_pinned_to_argument_registers_reach_the_host() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let y = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let a = bb.multiply(x, Value::u64(3));
+    let b = bb.add(y, Value::u64(4));
+    bb.pin(a, MachineRegister::Rdi);
+    bb.pin(b, MachineRegister::Rsi);
+    // the arguments are already where the host expects them
+    bb.call_host(record_pair as *const () as usize, "record_pair", &[]);
+    let sum = bb.add(a, b);
+    bb.ret_value(sum);
+    let f = compile::<extern "C" fn(u64, u64) -> u64>(&mut ctx);
+
+    assert_eq!(f.call(5, 6), 15 + 10);
+    assert_eq!(PAIR.lock().unwrap().take(), Some((15, 10)));
+}
+
+extern "C" fn double_in_place(value: *mut u64) {
+    unsafe { *value *= 2 };
+}
+
+#[test]
+fn host_writes_through_the_address_of_a_local() {
+    for in_ssa in [false, true] {
+        let mut ctx = Context::new();
+        let entry = ctx.new_basic_block();
+        let x = ctx.add_parameter(PrimitiveValue::U64);
+        let bb = ctx.build_basic_block(entry);
+        let local = bb.alloca(PrimitiveValue::U64, 8);
+        bb.store(local, x);
+        bb.call_host(
+            double_in_place as *const () as usize,
+            "double_in_place",
+            &[local],
+        );
+        let doubled = bb.load(local);
+        let result = bb.add(doubled, Value::u64(1));
+        bb.ret_value(result);
+        if in_ssa {
+            // the address escapes, so the local stays in memory
+            ssa::construct(&mut ctx);
+            assert!(ctx
+                .iter_instructions()
+                .any(|(_, _, inst)| matches!(inst, IR::Alloca { .. })));
+        }
+        let f = compile::<extern "C" fn(u64) -> u64>(&mut ctx);
+
+        assert_eq!(f.call(20), 41);
+    }
+}
+
+extern "C" fn answer() -> u64 {
+    42
+}
+
+extern "C" fn combine(a: u64, b: u64) -> u64 {
+    a * 1000 + b
+}
+
+/// Returns `combine(answer(), x + 1)` plus `x + 1` to `x + 4`, which are
+/// live across the calls, after printing what `answer` returned
+fn capture_answer() -> (Context, RegisterIndex) {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let kept = (1..=4)
+        .map(|i| bb.add(x, Value::u64(i)))
+        .collect::<Vec<_>>();
+    let answer = bb.call_host_with_result(
+        answer as *const () as usize,
+        "answer",
+        &[],
+        PrimitiveValue::U64,
+    );
+    // printing saves and restores the caller-saved registers around its call
+    bb.print_int(answer, PrimitiveValue::U64);
+    let combined = bb.call_host_with_result(
+        combine as *const () as usize,
+        "combine",
+        &[answer, kept[0]],
+        PrimitiveValue::U64,
+    );
+    let sum = kept.into_iter().fold(combined, |a, b| bb.add(a, b));
+    bb.ret_value(sum);
+    match answer {
+        Value::Register(r) => (ctx, r),
+        Value::Immediate { .. } => unreachable!(),
+    }
+}
+
+#[test]
+fn host_return_value_is_captured() {
+    use MachineRegister::*;
+    for register in [None, Some(Rdx), Some(R8), Some(R11), Some(Rbx)] {
+        let (mut ctx, answer) = capture_answer();
+        let mut options = CodeGenOptions::default();
+        if let Some(register) = register {
+            options.register_constraints.fix(answer, register);
+        }
+        let f = compile_with::<extern "C" fn(u64) -> u64>(&mut ctx, &options);
+
+        let mut returned = 0;
+        assert_eq!(capture_output(|| returned = f.call(10)), "42\n");
+        assert_eq!(
+            returned,
+            42 * 1000 + 11 + (11 + 12 + 13 + 14),
+            "in {:?}",
+            register
+        );
+    }
+}
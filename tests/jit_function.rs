@@ -0,0 +1,41 @@
+//! `JitFunction` owns the code it calls.  This is its own test binary so
+//! nothing else maps memory while the mappings are being looked at.
+mod common;
+
+use common::*;
+use shiba_jit::ir::*;
+
+/// The permissions of the mapping `address` is in, if it's mapped
+fn mapping_of(address: usize) -> Option<String> {
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+    maps.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let (start, end) = fields.next()?.split_once('-')?;
+        let start = usize::from_str_radix(start, 16).ok()?;
+        let end = usize::from_str_radix(end, 16).ok()?;
+        let permissions = fields.next()?;
+        (start..end)
+            .contains(&address)
+            .then(|| permissions.to_string())
+    })
+}
+
+#[test]
+fn dropping_the_function_unmaps_its_code() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let tripled = bb.multiply(x, Value::u64(3));
+    bb.ret_value(tripled);
+    let f = compile::<extern "C" fn(u64) -> u64>(&mut ctx);
+    // the context isn't needed to call it
+    drop(ctx);
+    assert_eq!(f.call(14), 42);
+
+    let address = f.code().code().as_ptr() as usize;
+    let permissions = mapping_of(address).expect("the code isn't mapped");
+    assert!(permissions.contains('x'), "{}", permissions);
+    drop(f);
+    assert_eq!(mapping_of(address), None);
+}
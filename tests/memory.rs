@@ -0,0 +1,265 @@
+mod common;
+
+use common::*;
+use shiba_jit::{codegen::x86_64::*, ir::*};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static OUT_OF_BOUNDS: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn record_out_of_bounds(address: u64) {
+    OUT_OF_BOUNDS.store(address, Ordering::SeqCst);
+}
+
+#[test]
+fn bounds_checked_loads_trap_outside_the_bounds() {
+    let memory = [11u64, 22, 33, 44];
+    let mut ctx = Context::new();
+    ctx.set_memory_bounds(MemoryBounds {
+        base: memory.as_ptr() as usize,
+        len: std::mem::size_of_val(&memory),
+        on_out_of_bounds: record_out_of_bounds,
+    });
+    let entry = ctx.new_basic_block();
+    let ptr = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let loaded = bb.load_extended(ptr, PrimitiveValue::U64);
+    bb.ret_value(loaded);
+    let f = compile::<extern "C" fn(*const u64) -> u64>(&mut ctx);
+
+    assert_eq!(f.call(&memory[0]), 11);
+    assert_eq!(f.call(&memory[3]), 44);
+    assert_eq!(OUT_OF_BOUNDS.load(Ordering::SeqCst), 0);
+
+    let past_the_end = memory.as_ptr().wrapping_add(4);
+    f.call(past_the_end);
+    assert_eq!(OUT_OF_BOUNDS.load(Ordering::SeqCst), past_the_end as u64);
+    // the pointee type isn't known, so 4 bytes are read, half of them past
+    // the end here
+    let straddling = (past_the_end as usize - 2) as *const u64;
+    f.call(straddling);
+    assert_eq!(OUT_OF_BOUNDS.load(Ordering::SeqCst), straddling as u64);
+}
+
+/// Store the second argument into the linear memory at the offset given by
+/// the first, then load it back
+fn linear_memory_round_trip() -> Context {
+    let mut ctx = Context::new();
+    ctx.add_linear_memory(2);
+    let entry = ctx.new_basic_block();
+    let offset = ctx.add_parameter(PrimitiveValue::U32);
+    let value = ctx.add_parameter(PrimitiveValue::U32);
+    let bb = ctx.build_basic_block(entry);
+    bb.mem_store(offset, value);
+    let loaded = bb.mem_load(offset);
+    bb.ret_value(loaded);
+    ctx
+}
+
+#[test]
+fn linear_memory_stores_and_loads() {
+    let mut ctx = linear_memory_round_trip();
+    let f = compile::<extern "C" fn(u32, u32) -> u32>(&mut ctx);
+
+    assert_eq!(f.call(0, 0xdead_beef), 0xdead_beef);
+    // right at the end of the first page, so it spills into the second
+    assert_eq!(f.call(65535, 0x0102_0304), 0x0102_0304);
+
+    let memory = ctx.linear_memory().unwrap().as_slice();
+    assert_eq!(memory[..4], 0xdead_beefu32.to_le_bytes());
+    assert_eq!(memory[65535..65539], 0x0102_0304u32.to_le_bytes());
+}
+
+#[test]
+fn linear_memory_outlives_the_context() {
+    let mut ctx = linear_memory_round_trip();
+    let f = compile::<extern "C" fn(u32, u32) -> u32>(&mut ctx);
+    drop(ctx);
+
+    assert_eq!(f.call(0, 7), 7);
+    assert_eq!(f.call(65535, 8), 8);
+}
+
+#[test]
+fn narrow_stores_leave_their_neighbors_alone() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let ptr = ctx.add_parameter(PrimitiveValue::U64);
+    let value = ctx.add_parameter(PrimitiveValue::U8);
+    let bb = ctx.build_basic_block(entry);
+    bb.store(ptr, value);
+    bb.ret();
+    let f = compile::<extern "C" fn(*mut u8, u8)>(&mut ctx);
+
+    let mut memory = [0xAAu8; 9];
+    f.call(&mut memory[4], 0x12);
+    assert_eq!(
+        memory,
+        [0xAA, 0xAA, 0xAA, 0xAA, 0x12, 0xAA, 0xAA, 0xAA, 0xAA]
+    );
+}
+
+#[test]
+fn slots_deeper_than_a_byte_displacement() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let slots = (0..40)
+        .map(|_| bb.alloca(PrimitiveValue::U64, 8))
+        .collect::<Vec<_>>();
+    for (i, slot) in slots.iter().enumerate() {
+        let value = bb.add(x, Value::u64(i as u64));
+        bb.store(*slot, value);
+    }
+    let loaded = [0, 20, 39].map(|i| bb.load(slots[i]));
+    let sum = bb.add(loaded[0], loaded[1]);
+    let sum = bb.add(sum, loaded[2]);
+    bb.ret_value(sum);
+    ctx.finalize();
+    let code = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
+    let deepest = code.frame_layout.slots.values().min().unwrap();
+    assert!(*deepest < -128, "the deepest slot is at {}", deepest);
+
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(f.call(100), 3 * 100 + 20 + 39);
+}
+
+#[test]
+fn values_above_u32_max_load_all_64_bits() {
+    // through a stack slot
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let slot = bb.alloca(PrimitiveValue::U64, 8);
+    bb.store(slot, x);
+    let loaded = bb.load(slot);
+    bb.ret_value(loaded);
+    let f = compile::<extern "C" fn(u64) -> u64>(&mut ctx);
+    assert_eq!(f.call(0x1_2345_6789), 0x1_2345_6789);
+    assert_eq!(f.call(u64::MAX), u64::MAX);
+
+    // and from a constant
+    let mut ctx = Context::new();
+    let big = ctx.add_u64_constant(0xFEDC_BA98_7654_3210);
+    let entry = ctx.new_basic_block();
+    let bb = ctx.build_basic_block(entry);
+    let address = bb.constant_addr(big);
+    let loaded = bb.load(address);
+    bb.ret_value(loaded);
+    let f = compile::<extern "C" fn() -> u64>(&mut ctx);
+    assert_eq!(f.call(), 0xFEDC_BA98_7654_3210);
+}
+
+/// Store the byte `0xFF` as an `I8` and load it back extended to `_type`
+fn load_ff_extended(_type: PrimitiveValue) -> Context {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let bb = ctx.build_basic_block(entry);
+    let slot = bb.alloca(PrimitiveValue::I8, 1);
+    bb.store(slot, Value::i8(-1));
+    let loaded = bb.load_extended(slot, _type);
+    bb.ret_value(loaded);
+    ctx
+}
+
+#[test]
+fn loads_extend_by_the_signedness_of_their_type() {
+    let f = compile::<extern "C" fn() -> i64>(&mut load_ff_extended(PrimitiveValue::I64));
+    assert_eq!(f.call(), -1);
+    let f = compile::<extern "C" fn() -> u64>(&mut load_ff_extended(PrimitiveValue::U64));
+    assert_eq!(f.call(), 255);
+    let f = compile::<extern "C" fn() -> i32>(&mut load_ff_extended(PrimitiveValue::I32));
+    assert_eq!(f.call(), -1);
+    let f = compile::<extern "C" fn() -> u32>(&mut load_ff_extended(PrimitiveValue::U32));
+    assert_eq!(f.call(), 255);
+}
+
+#[test]
+fn two_allocas_are_accepted_and_do_not_alias() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let y = ctx.add_parameter(PrimitiveValue::U8);
+    let bb = ctx.build_basic_block(entry);
+    let wide = bb.alloca(PrimitiveValue::U64, 8);
+    let narrow = bb.alloca(PrimitiveValue::U8, 1);
+    bb.store(wide, x);
+    bb.store(narrow, y);
+    let x_again = bb.load(wide);
+    let y_again = bb.load_extended(narrow, PrimitiveValue::U64);
+    bb.print_int(x_again, PrimitiveValue::U64);
+    bb.print_int(y_again, PrimitiveValue::U64);
+    bb.ret();
+    ctx.finalize();
+    assert_eq!(ctx.validate(), Ok(()));
+
+    let code = generate_code_with_options(&ctx, &capturing(CodeGenOptions::default())).unwrap();
+    let offsets = code.frame_layout.slots.values().collect::<Vec<_>>();
+    assert_eq!(offsets.len(), 2);
+    // the eight bytes of one don't overlap the byte of the other
+    let (wide_at, narrow_at) = (*offsets[0], *offsets[1]);
+    assert!(wide_at + 8 <= narrow_at || narrow_at < wide_at);
+    let f: JitFunction<extern "C" fn(u64, u8)> = unsafe { code.into_function() };
+    assert_eq!(
+        capture_output(|| f.call(u64::MAX, 7)),
+        format!("{}\n7\n", u64::MAX)
+    );
+}
+
+#[test]
+fn constant_registers_are_stored_as_immediates() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let ptr = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let constant = bb.copy(Value::u64(0x1234_5678));
+    bb.store(ptr, constant);
+    bb.ret();
+    let code = generate_recording_offsets(&mut ctx);
+
+    // nothing for the copy, and `mov QWORD [ptr], imm32` for the store
+    assert_eq!(instruction_code(&code, entry, 1), []);
+    let store = instruction_code(&code, entry, 2);
+    assert!(
+        matches!(store, [0x48 | 0x49, 0xC7, modrm, ..] if modrm >> 3 & 7 == 0),
+        "{:02x?}",
+        store
+    );
+    assert_eq!(store[store.len() - 4..], 0x1234_5678u32.to_le_bytes());
+
+    let f: JitFunction<extern "C" fn(*mut u64)> = unsafe { code.into_function() };
+    let mut memory = u64::MAX;
+    f.call(&mut memory);
+    assert_eq!(memory, 0x1234_5678);
+}
+
+#[test]
+fn typed_constants_load_as_the_numbers_they_were() {
+    const WIDE: u64 = 0x0102_0304_0506_0708;
+    const NARROW: u32 = 0xA0B0_C0D0;
+    let mut ctx = Context::new();
+    let wide = ctx.add_u64_constant(WIDE);
+    let narrow = ctx.add_u32_constant(NARROW);
+    let entry = ctx.new_basic_block();
+    let bb = ctx.build_basic_block(entry);
+    let wide_address = bb.constant_addr(wide);
+    let narrow_address = bb.constant_addr(narrow);
+    let wide_value = bb.load(wide_address);
+    let narrow_value = bb.load(narrow_address);
+    bb.print_int(wide_value, PrimitiveValue::U64);
+    bb.print_int(narrow_value, PrimitiveValue::U32);
+    bb.ret();
+    ctx.finalize();
+    let code = generate_code_with_options(&ctx, &capturing(CodeGenOptions::default())).unwrap();
+
+    // little endian, like x86_64 loads them
+    let bytes = &code.buffer[..];
+    assert!(bytes.windows(8).any(|w| w == WIDE.to_le_bytes()));
+    assert!(bytes.windows(4).any(|w| w == NARROW.to_le_bytes()));
+    let f: JitFunction<extern "C" fn()> = unsafe { code.into_function() };
+    assert_eq!(
+        capture_output(|| f.call()),
+        format!("{}\n{}\n", WIDE, NARROW)
+    );
+}
@@ -0,0 +1,262 @@
+//! Tooling written outside the crate that walks and rewrites the IR
+mod common;
+
+use common::*;
+use shiba_jit::{
+    ir::*,
+    reg_alloc::{compute_graph, GraphQuery},
+};
+
+#[test]
+fn iterate_instructions_of_the_example() {
+    let ctx = conditional_print();
+    let instructions = ctx.iter_instructions().collect::<Vec<_>>();
+    // alloca, store, jump; print, load, add, store, subtract; branch;
+    // print, return
+    assert_eq!(instructions.len(), 11);
+    let positions = instructions
+        .iter()
+        .map(|(bb_idx, i, _)| (*bb_idx, *i))
+        .collect::<Vec<_>>();
+    let blocks = ctx
+        .basic_blocks()
+        .iterate_basic_blocks()
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+    let expected = [3, 5, 1, 2]
+        .iter()
+        .zip(&blocks)
+        .flat_map(|(len, bb_idx)| (0..*len).map(move |i| (*bb_idx, i)))
+        .collect::<Vec<_>>();
+    assert_eq!(positions, expected);
+    assert!(matches!(instructions[0].2, IR::Alloca { .. }));
+    assert!(matches!(instructions[8].2, IR::JumpIfEqual { .. }));
+    assert!(matches!(instructions[10].2, IR::Return));
+}
+
+/// A pass a user might write: `x + 0` is just `x`
+fn remove_adds_of_zero(ctx: &mut Context) {
+    ctx.map_instructions(|inst| {
+        if let IR::Add {
+            dest_register,
+            src1,
+            src2: Value::Immediate { value: 0, .. },
+            overflow: Overflow::Wrap,
+        } = *inst
+        {
+            *inst = IR::Copy {
+                dest_register,
+                src: src1,
+            };
+        }
+    });
+}
+
+#[test]
+fn user_pass_replaces_adds_of_zero_with_copies() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let same = bb.add(x, Value::u64(0));
+    let more = bb.add(same, Value::u64(3));
+    bb.ret_value(more);
+
+    remove_adds_of_zero(&mut ctx);
+    let instructions = ctx
+        .iter_instructions()
+        .map(|(_, _, inst)| inst)
+        .collect::<Vec<_>>();
+    assert!(matches!(
+        instructions[1],
+        IR::Copy {
+            src: Value::Register(_),
+            ..
+        }
+    ));
+    assert!(matches!(instructions[2], IR::Add { .. }));
+
+    let f = compile::<extern "C" fn(u64) -> u64>(&mut ctx);
+    assert_eq!(f.call(5), 8);
+}
+
+#[test]
+fn user_pass_that_changes_branches_updates_the_cfg() {
+    let mut ctx = conditional_print();
+    let blocks = ctx
+        .basic_blocks()
+        .iterate_basic_blocks()
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+    let (loop_inner, loop_outer, loop_exit) = (blocks[1], blocks[2], blocks[3]);
+    assert_eq!(
+        ctx.predecessors(loop_exit).collect::<Vec<_>>(),
+        [loop_outer]
+    );
+
+    // never loop
+    ctx.map_instructions(|inst| {
+        if let IR::JumpIfEqual { false_bb_idx, .. } = *inst {
+            if false_bb_idx == loop_inner {
+                *inst = IR::Jump { bb_idx: loop_exit };
+            }
+        }
+    });
+    assert_eq!(ctx.successors(loop_outer).collect::<Vec<_>>(), [loop_exit]);
+    assert_eq!(ctx.predecessors(loop_inner).count(), 1);
+
+    let f = compile::<extern "C" fn()>(&mut ctx);
+    assert_eq!(
+        capture_output(|| f.call()),
+        "Hello, world\nGoodbye, world\n"
+    );
+}
+
+#[test]
+fn the_example_has_one_back_edge() {
+    let mut ctx = conditional_print();
+    ctx.finalize();
+    let bbm = ctx.basic_blocks();
+    let gq = GraphQuery::new(compute_graph(bbm), bbm);
+    let blocks = bbm
+        .iterate_basic_blocks()
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+    let (loop_inner, loop_outer) = (blocks[1], blocks[2]);
+    assert_eq!(
+        gq.back_edges().collect::<Vec<_>>(),
+        [(loop_outer, loop_inner)]
+    );
+    assert_eq!(gq.loop_depth(loop_inner), 1);
+}
+
+#[test]
+fn the_example_in_ssa_form_has_no_allocas() {
+    let mut ctx = conditional_print();
+    ssa::construct(&mut ctx);
+    assert!(!ctx.iter_instructions().any(|(_, _, inst)| matches!(
+        inst,
+        IR::Alloca { .. } | IR::Load { .. } | IR::Store { .. }
+    )));
+    assert!(ctx
+        .iter_instructions()
+        .any(|(_, _, inst)| matches!(inst, IR::Phi { .. })));
+
+    let f = compile::<extern "C" fn()>(&mut ctx);
+    assert_eq!(capture_output(|| f.call()), CONDITIONAL_PRINT_OUTPUT);
+}
+
+#[test]
+fn constant_address_in_a_loop_is_computed_once_before_it() {
+    let mut ctx = Context::new();
+    let seven = ctx.add_u32_constant(7);
+    let entry = ctx.new_basic_block();
+    let n = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let counter = bb.alloca(PrimitiveValue::U64, 8);
+    bb.store(counter, n);
+    let print_seven = |bb: &mut BasicBlock| {
+        let address = bb.constant_addr(seven);
+        let value = bb.load(address);
+        bb.print_int(value, PrimitiveValue::U32);
+    };
+    let exit = ctx.build_while(
+        entry,
+        |header| header.load(counter),
+        |body| {
+            print_seven(body);
+            let left = body.load(counter);
+            let left = body.subtract(left, Value::u64(1));
+            body.store(counter, left);
+        },
+    );
+    let bb = ctx.build_basic_block(exit);
+    print_seven(bb);
+    bb.ret();
+
+    ctx.hoist_constant_addrs();
+    let addrs = ctx
+        .iter_instructions()
+        .filter(|(_, _, inst)| matches!(inst, IR::ConstantAddr { .. }))
+        .map(|(block, _, _)| block)
+        .collect::<Vec<_>>();
+    assert_eq!(addrs, [entry]);
+
+    let f = compile::<extern "C" fn(u64)>(&mut ctx);
+    assert_eq!(capture_output(|| f.call(3)), "7\n7\n7\n7\n");
+}
+
+#[test]
+fn unused_arithmetic_is_eliminated() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let unused = bb.add(x, Value::u64(5));
+    let also_unused = bb.multiply(unused, Value::u64(3));
+    let doubled = bb.add(x, x);
+    bb.ret_value(doubled);
+    ctx.finalize();
+    let register = |v| match v {
+        Value::Register(r) => r,
+        Value::Immediate { .. } => unreachable!(),
+    };
+    let bbm = ctx.basic_blocks();
+    let gq = GraphQuery::new(compute_graph(bbm), bbm);
+    assert!(gq.is_dead(register(also_unused)));
+    // only dead once what uses it is gone
+    assert!(!gq.is_dead(register(unused)));
+    assert!(!gq.is_dead(register(doubled)));
+
+    assert_eq!(ctx.eliminate_dead_code(), 2);
+    let left = ctx
+        .iter_instructions()
+        .map(|(_, _, inst)| inst.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(left.len(), 3, "{:?}", left);
+    assert!(matches!(left[0], IR::Parameter { .. }));
+    assert!(matches!(left[1], IR::Add { dest_register, .. } if dest_register == register(doubled)));
+    assert!(matches!(left[2], IR::ReturnValue { .. }));
+
+    let f = compile::<extern "C" fn(u64) -> u64>(&mut ctx);
+    assert_eq!(f.call(21), 42);
+}
+
+#[test]
+fn edges_of_the_example() {
+    let ctx = conditional_print();
+    let blocks = ctx
+        .basic_blocks()
+        .iterate_basic_blocks()
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+    let [start, loop_inner, loop_outer, loop_exit] = [blocks[0], blocks[1], blocks[2], blocks[3]];
+    let edges = blocks
+        .iter()
+        .flat_map(|from| ctx.successors(*from).map(move |to| (*from, to)))
+        .collect::<Vec<_>>();
+    // the inner block falls through to the outer one, which branches back
+    assert_eq!(
+        edges,
+        [
+            (start, loop_inner),
+            (loop_inner, loop_outer),
+            (loop_outer, loop_exit),
+            (loop_outer, loop_inner),
+        ]
+    );
+    // the same edges, seen from the other end
+    for block in &blocks {
+        let predecessors = ctx.predecessors(*block).collect::<Vec<_>>();
+        let expected = edges
+            .iter()
+            .filter(|(_, to)| to == block)
+            .map(|(from, _)| *from)
+            .collect::<Vec<_>>();
+        assert_eq!(predecessors, expected);
+    }
+    assert_eq!(
+        ctx.predecessors(loop_inner).collect::<Vec<_>>(),
+        [start, loop_outer]
+    );
+}
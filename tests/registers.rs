@@ -0,0 +1,391 @@
+mod common;
+
+use common::*;
+use shiba_jit::{codegen::x86_64::*, ir::*};
+
+/// A function of one argument `x` that prints `x + 1` to `x + 10`, which
+/// all have to be in registers at once along with `x`
+fn eleven_live_values() -> Context {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let values = (1..=10)
+        .map(|i| bb.add(x, Value::u64(i)))
+        .collect::<Vec<_>>();
+    for v in values {
+        bb.print_int(v, PrimitiveValue::U64);
+    }
+    bb.ret();
+    ctx
+}
+
+#[test]
+fn omitting_the_frame_pointer_frees_up_rbp() {
+    let mut ctx = eleven_live_values();
+    ctx.finalize();
+    let options = CodeGenOptions {
+        omit_frame_pointer: true,
+        ..capturing(CodeGenOptions::default())
+    };
+    let code = generate_code_with_options(&ctx, &options).unwrap();
+    assert!(!code.frame_layout.frame_pointer);
+    assert!(code
+        .register_map
+        .values()
+        .any(|mr| *mr == MachineRegister::Rbp));
+    let f: JitFunction<extern "C" fn(u64)> = unsafe { code.into_function() };
+
+    let expected = (101..=110).map(|v| format!("{}\n", v)).collect::<String>();
+    assert_eq!(capture_output(|| f.call(100)), expected);
+}
+
+#[test]
+fn parameters_stay_in_their_argument_registers() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let parameters = (0..6)
+        .map(|_| ctx.add_parameter(PrimitiveValue::U64))
+        .collect::<Vec<_>>();
+    let bb = ctx.build_basic_block(entry);
+    // only as many values as there are registers
+    let hundreds = bb.multiply(parameters[2], Value::u64(100));
+    let tens = bb.multiply(parameters[4], Value::u64(10));
+    let sum = bb.add(hundreds, tens);
+    let sum = bb.add(sum, parameters[5]);
+    bb.ret_value(sum);
+    ctx.finalize();
+    let code = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
+
+    let machine_register = |value| match value {
+        Value::Register(r) => code.machine_register(r),
+        Value::Immediate { .. } => None,
+    };
+    // rdi, rsi and rcx aren't allocated
+    assert_eq!(machine_register(parameters[2]), Some(MachineRegister::Rdx));
+    assert_eq!(machine_register(parameters[4]), Some(MachineRegister::R8));
+    assert_eq!(machine_register(parameters[5]), Some(MachineRegister::R9));
+
+    let f: JitFunction<extern "C" fn(u64, u64, u64, u64, u64, u64) -> u64> =
+        unsafe { code.into_function() };
+    assert_eq!(f.call(1, 2, 3, 4, 5, 6), 356);
+}
+
+#[test]
+fn arguments_past_the_sixth_come_from_the_stack() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let parameters = (0..8)
+        .map(|_| ctx.add_parameter(PrimitiveValue::U64))
+        .collect::<Vec<_>>();
+    let bb = ctx.build_basic_block(entry);
+    // weighted, so arguments in the wrong place give the wrong sum
+    let weighted = parameters
+        .iter()
+        .enumerate()
+        .map(|(i, p)| bb.multiply(*p, Value::u64(i as u64 + 1)))
+        .collect::<Vec<_>>();
+    let sum = weighted.into_iter().reduce(|a, b| bb.add(a, b)).unwrap();
+    bb.ret_value(sum);
+    let f = compile::<extern "C" fn(u64, u64, u64, u64, u64, u64, u64, u64) -> u64>(&mut ctx);
+
+    assert_eq!(f.call(1, 1, 1, 1, 1, 1, 1, 1), 36);
+    assert_eq!(f.call(0, 0, 0, 0, 0, 0, 1000, 0), 7000);
+    assert_eq!(f.call(0, 0, 0, 0, 0, 0, 0, 1000), 8000);
+    assert_eq!(
+        f.call(1, 2, 3, 4, 5, 6, 7, 8),
+        (1..=8).map(|i| i * i).sum::<u64>()
+    );
+}
+
+#[test]
+fn constrained_registers_get_their_machine_registers() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let values = (1..=5)
+        .map(|i| bb.add(x, Value::u64(i)))
+        .collect::<Vec<_>>();
+    let sum = values.iter().copied().reduce(|a, b| bb.add(a, b)).unwrap();
+    // could go in the register of `sum`, which isn't used after it
+    let doubled = bb.add(sum, sum);
+    bb.ret_value(doubled);
+    ctx.finalize();
+    let register = |value| match value {
+        Value::Register(r) => r,
+        Value::Immediate { .. } => unreachable!(),
+    };
+
+    let mut options = CodeGenOptions::default();
+    options
+        .register_constraints
+        .fix(register(values[1]), MachineRegister::R15)
+        .fix(register(values[3]), MachineRegister::R14)
+        .must_not_alias(register(sum), register(doubled));
+    let code = generate_code_with_options(&ctx, &options).unwrap();
+    let register_map = compute_register_map(ctx.basic_blocks(), &options);
+    for map in [&register_map, &code.register_map] {
+        assert_eq!(map[&register(values[1])], MachineRegister::R15);
+        assert_eq!(map[&register(values[3])], MachineRegister::R14);
+        assert_ne!(map[&register(sum)], map[&register(doubled)]);
+        // nothing else gets the fixed registers
+        let fixed = map
+            .values()
+            .filter(|mr| matches!(mr, MachineRegister::R14 | MachineRegister::R15))
+            .count();
+        assert_eq!(fixed, 2);
+    }
+
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(f.call(10), 2 * (5 * 10 + 15));
+}
+
+/// Add up `base + 1` and `base + 2` to `base + 10` with all of them live at
+/// once, so one more than there are registers for along with `base`
+fn sum_of_eleven(bb: &mut BasicBlock, base: Value) -> Value {
+    let first = bb.add(base, Value::u64(1));
+    let rest = (2..=10)
+        .map(|i| bb.add(base, Value::u64(i)))
+        .collect::<Vec<_>>();
+    let sum = rest.into_iter().reduce(|a, b| bb.add(a, b)).unwrap();
+    bb.add(sum, first)
+}
+
+#[test]
+fn spilled_values_that_are_never_live_together_share_a_slot() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let r = sum_of_eleven(bb, x);
+    let result = sum_of_eleven(bb, r);
+    bb.ret_value(result);
+    ctx.finalize();
+    let code = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
+
+    // `x + 1` is spilled, and later `r + 1` goes in the same slot
+    assert_eq!(code.frame_layout.slots.len(), 1);
+    assert_eq!(code.frame_layout.frame_size, 8);
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    // r = 10x + 55
+    assert_eq!(f.call(1), 10 * 65 + 55);
+}
+
+#[test]
+fn spilled_values_are_loaded_in_the_blocks_using_them() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let sum_block = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let values = (1..=11)
+        .map(|i| bb.add(x, Value::u64(i)))
+        .collect::<Vec<_>>();
+    bb.jump(sum_block);
+    let bb = ctx.build_basic_block(sum_block);
+    let sum = values.iter().copied().reduce(|a, b| bb.add(a, b)).unwrap();
+    bb.ret_value(sum);
+    ctx.finalize();
+    let code = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
+    assert!(!code.frame_layout.slots.is_empty());
+
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(f.call(100), 11 * 100 + 66);
+}
+
+#[test]
+fn values_live_across_a_loop_are_spilled_around_it() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let values = (1..=9)
+        .map(|i| bb.add(x, Value::u64(i)))
+        .collect::<Vec<_>>();
+    let counter = bb.alloca(PrimitiveValue::U64, 8);
+    let sum = bb.alloca(PrimitiveValue::U64, 8);
+    bb.store(counter, Value::u64(0));
+    bb.store(sum, Value::u64(0));
+    // sum += counter + x for each counter below 5
+    let exit = ctx.build_while(
+        entry,
+        |header| {
+            let loaded_counter = header.load(counter);
+            header.subtract(Value::u64(5), loaded_counter)
+        },
+        |body| {
+            let loaded_counter = body.load(counter);
+            let loaded_sum = body.load(sum);
+            let term = body.add(loaded_counter, x);
+            let new_sum = body.add(loaded_sum, term);
+            body.store(sum, new_sum);
+            let new_counter = body.add(loaded_counter, Value::u64(1));
+            body.store(counter, new_counter);
+        },
+    );
+    let bb = ctx.build_basic_block(exit);
+    let loaded_sum = bb.load(sum);
+    let result = values.into_iter().fold(loaded_sum, |a, b| bb.add(a, b));
+    bb.ret_value(result);
+    // the counter and the sum become phis in the loop header
+    ssa::construct(&mut ctx);
+    ctx.finalize();
+    let code = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
+    assert!(!code.frame_layout.slots.is_empty());
+
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(
+        f.call(100),
+        (0..5).sum::<u64>() + 5 * 100 + (1..=9).sum::<u64>() + 9 * 100
+    );
+}
+
+#[test]
+fn offsets_cover_the_original_instructions_when_spilling() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let result = sum_of_eleven(bb, x);
+    bb.ret_value(result);
+    let code = generate_recording_offsets(&mut ctx);
+    assert!(!code.frame_layout.slots.is_empty());
+
+    // the spill code is part of the instructions it was added for
+    assert_offsets_cover_every_instruction(&ctx, &code);
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(f.call(2), 10 * 2 + 55);
+}
+
+#[test]
+fn frame_layout_saves_r12_when_it_is_used() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let tripled = bb.multiply(x, Value::u64(3));
+    let result = bb.add(tripled, x);
+    bb.ret_value(result);
+    ctx.finalize();
+    let mut options = CodeGenOptions::default();
+    if let Value::Register(r) = tripled {
+        options.register_constraints.fix(r, MachineRegister::R12);
+    }
+    let code = generate_code_with_options(&ctx, &options).unwrap();
+
+    let layout = &code.frame_layout;
+    let saved = layout
+        .saved_registers
+        .iter()
+        .map(|(mr, _)| *mr)
+        .collect::<Vec<_>>();
+    use MachineRegister::*;
+    assert_eq!(saved, [Rbp, Rbx, R12, Rdi, Rsi]);
+    assert!(layout.frame_pointer);
+    // rbp is pushed first, and the rest are pushed below the slots
+    assert_eq!(layout.saved_registers[0].1, -8);
+    let below_slots = -8 - layout.frame_size as i32;
+    for (k, (mr, offset)) in layout.saved_registers[1..].iter().enumerate() {
+        assert_eq!(*offset, below_slots - 8 - 8 * k as i32, "{:?}", mr);
+    }
+
+    // and r12 really is given back as it was
+    let f = code.buffer.ptr(code.start);
+    let (returned, r12): (u64, u64);
+    unsafe {
+        std::arch::asm!(
+            "call {f}",
+            f = in(reg) f,
+            inout("rdi") 5u64 => _,
+            inout("r12") 0x1234_5678_9ABC_DEF0u64 => r12,
+            lateout("rax") returned,
+            clobber_abi("C"),
+        );
+    }
+    assert_eq!(returned, 20);
+    assert_eq!(r12, 0x1234_5678_9ABC_DEF0);
+}
+
+#[test]
+fn spill_report_lists_each_spill_and_its_reloads() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let mut result = x;
+    for _ in 0..3 {
+        result = sum_of_eleven(bb, result);
+    }
+    bb.ret_value(result);
+    ctx.finalize();
+    let code = generate_code_with_options(&ctx, &CodeGenOptions::default()).unwrap();
+
+    // each sum takes 19 instructions after the parameter, and `base + 1` is
+    // spilled until it's added at the end
+    let defined_at = |index| {
+        ctx.iter_instructions()
+            .nth(index)
+            .map(|(_, _, inst)| *inst.get_defined_registers()[0])
+            .unwrap()
+    };
+    let report = &code.spill_report;
+    assert_eq!(report.spills.len(), 3, "{:#?}", report);
+    for (k, spill) in report.spills.iter().enumerate() {
+        assert_eq!(spill.register, defined_at(1 + 19 * k));
+        assert_eq!(spill.reloads, [(entry, 19 + 19 * k)]);
+        // none of them are live at the same time
+        assert_eq!(spill.slot, report.spills[0].slot);
+    }
+    assert_eq!(
+        code.frame_layout.slots.values().collect::<Vec<_>>(),
+        [&report.spills[0].slot]
+    );
+
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    // each sum is 10 times its base plus 55
+    assert_eq!(f.call(1), ((10 + 55) * 10 + 55) * 10 + 55);
+}
+
+#[test]
+fn nothing_is_reported_without_spilling() {
+    let mut ctx = eleven_live_values();
+    ctx.finalize();
+    let options = CodeGenOptions {
+        omit_frame_pointer: true,
+        ..CodeGenOptions::default()
+    };
+    let code = generate_code_with_options(&ctx, &options).unwrap();
+    assert_eq!(code.spill_report, SpillReport::default());
+}
+
+#[test]
+fn spilled_operand_is_added_straight_from_its_slot() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let bb = ctx.build_basic_block(entry);
+    let sum = sum_of_eleven(bb, x);
+    bb.ret_value(sum);
+    let code = generate_recording_offsets(&mut ctx);
+    let spill = &code.spill_report.spills[..];
+    assert_eq!(spill.len(), 1, "{:?}", spill);
+    assert_eq!(spill[0].reloads, [(entry, 19)]);
+
+    // add r64, [rbp + disp32] is REX.W 03 /r with mod 10 and rm 101, maybe
+    // after a mov into the destination, and the slot isn't loaded first
+    let add = instruction_code(&code, entry, 19);
+    let at = add.len() - 7;
+    assert_eq!(add[at] & 0xF8, 0x48, "{:02x?}", add);
+    assert_eq!(add[at + 1], 0x03, "{:02x?}", add);
+    assert_eq!(add[at + 2] & 0xC7, 0x85, "{:02x?}", add);
+    let mut displacement = [0; 4];
+    displacement.copy_from_slice(&add[at + 3..]);
+    let displacement = i32::from_le_bytes(displacement);
+    // rbp points 8 bytes below the stack pointer on entry
+    assert_eq!(displacement, spill[0].slot + 8);
+    assert!(at == 0 || add[at - 2] == 0x89, "{:02x?}", add);
+
+    let f: JitFunction<extern "C" fn(u64) -> u64> = unsafe { code.into_function() };
+    assert_eq!(f.call(3), 10 * 3 + 55);
+}
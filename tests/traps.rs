@@ -0,0 +1,34 @@
+mod common;
+
+use common::*;
+use shiba_jit::{codegen::x86_64::*, ir::*};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static TRAPPED_WITH: AtomicU64 = AtomicU64::new(0);
+
+fn record_trap(code: u64) {
+    TRAPPED_WITH.store(code, Ordering::SeqCst);
+}
+
+#[test]
+fn traps_call_the_handler_with_their_code() {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let x = ctx.add_parameter(PrimitiveValue::U64);
+    let abort = ctx.new_basic_block();
+    let fine = ctx.new_basic_block();
+    ctx.build_basic_block(entry).jump_if_equal(x, abort, fine);
+    ctx.build_basic_block(abort).trap(42);
+    let fine_bb = ctx.build_basic_block(fine);
+    fine_bb.print_int(x, PrimitiveValue::U64);
+    fine_bb.ret();
+    let f = compile::<extern "C" fn(u64)>(&mut ctx);
+
+    set_trap_handler(Some(record_trap));
+    assert_eq!(capture_output(|| f.call(7)), "7\n");
+    assert_eq!(TRAPPED_WITH.load(Ordering::SeqCst), 0);
+    // the function returns once the handler does
+    assert_eq!(capture_output(|| f.call(0)), "");
+    assert_eq!(TRAPPED_WITH.load(Ordering::SeqCst), 42);
+    set_trap_handler(None);
+}
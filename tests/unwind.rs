@@ -0,0 +1,69 @@
+use shiba_jit::{codegen::x86_64::*, ir::*};
+use std::ffi::c_void;
+use std::sync::Mutex;
+
+extern "C" {
+    fn _Unwind_Backtrace(
+        trace: extern "C" fn(*mut c_void, *mut c_void) -> i32,
+        arg: *mut c_void,
+    ) -> i32;
+    fn _Unwind_GetIP(ctx: *mut c_void) -> usize;
+}
+
+/// The return addresses on the stack when the host function was called
+static FRAMES: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+extern "C" fn record_frame(ctx: *mut c_void, frames: *mut c_void) -> i32 {
+    let frames = unsafe { &mut *(frames as *mut Vec<usize>) };
+    frames.push(unsafe { _Unwind_GetIP(ctx) });
+    // _URC_NO_REASON
+    0
+}
+
+extern "C" fn take_backtrace() {
+    let mut frames = vec![];
+    unsafe { _Unwind_Backtrace(record_frame, &mut frames as *mut Vec<usize> as *mut c_void) };
+    *FRAMES.lock().unwrap() = frames;
+}
+
+fn backtrace_through_jit(emit_unwind_info: bool) -> (Vec<usize>, std::ops::Range<usize>) {
+    let mut ctx = Context::new();
+    let entry = ctx.new_basic_block();
+    let bb = ctx.build_basic_block(entry);
+    bb.call_host(take_backtrace as *const () as usize, "take_backtrace", &[]);
+    bb.ret();
+    ctx.finalize();
+    let options = CodeGenOptions {
+        emit_unwind_info,
+        ..Default::default()
+    };
+    let code = generate_code_with_options(&ctx, &options).unwrap();
+    let start = code.code().as_ptr() as usize;
+    let range = start..start + code.code().len();
+    let f: JitFunction<extern "C" fn()> = unsafe { code.into_function() };
+    f.call();
+    let frames = std::mem::take(&mut *FRAMES.lock().unwrap());
+    (frames, range)
+}
+
+#[test]
+fn backtrace_walks_through_jit_frame() {
+    let (frames, range) = backtrace_through_jit(true);
+    let jit_frame = frames
+        .iter()
+        .position(|ip| range.contains(ip))
+        .expect("no frame in the generated code");
+    // the unwinder got past the generated code back into the test
+    assert!(
+        frames.len() > jit_frame + 2,
+        "the backtrace stopped at the JIT frame: {:x?}",
+        frames
+    );
+
+    // without it the unwinder doesn't know how to get out of the frame
+    let (frames, range) = backtrace_through_jit(false);
+    assert_eq!(
+        frames.iter().rposition(|ip| range.contains(ip)),
+        Some(frames.len() - 1)
+    );
+}